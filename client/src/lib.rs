@@ -0,0 +1,381 @@
+//! Typed client for the Ahtohallan backend API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), ahtohallan_client::Error> {
+//! use ahtohallan_client::{ChatRequest, Client, UploadFile, UploadOptions};
+//!
+//! let client = Client::new("http://localhost:3000");
+//! let file = UploadFile::new("notes.md", std::fs::read("notes.md").unwrap());
+//! let job = client.upload(vec![file], &UploadOptions::default()).await?;
+//! println!("queued as {}", job.job_id);
+//!
+//! let answer = client.chat(&ChatRequest::new("What do my notes say?")).await?;
+//! println!("{} ({})", answer.answer, answer.sources.join(", "));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests go through reqwest with the `native` feature (the default) and
+//! through gloo-net in the browser with `wasm`.
+
+#[cfg(feature = "native")]
+mod sse;
+mod transport;
+mod types;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub use types::*;
+
+use transport::{Body, HttpRequest, HttpResponse, Method, Transport};
+
+/// A failed API call.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The backend couldn't be reached or the connection broke
+    #[error("request failed: {0}")]
+    Transport(String),
+    /// The backend answered with an error
+    #[error("{message} ({code})")]
+    Api {
+        // Absent for errors reported mid-stream, after the 200 was sent
+        status: Option<u16>,
+        code: String,
+        message: String,
+        // Matches the backend's log line for this request
+        request_id: Option<String>,
+        retryable: bool,
+    },
+    /// The response wasn't the JSON this client expects
+    #[error("unexpected response: {0}")]
+    Decode(String),
+}
+
+impl Error {
+    /// Whether the same request may succeed if sent again later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(_) => true,
+            Error::Api { retryable, .. } => *retryable,
+            Error::Decode(_) => false,
+        }
+    }
+}
+
+/// `{"error": {...}}`, the body of every error the backend returns.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ApiErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    code: String,
+    message: String,
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    retryable: bool,
+}
+
+impl ApiErrorBody {
+    fn into_error(self, status: Option<u16>) -> Error {
+        Error::Api {
+            status,
+            code: self.code,
+            message: self.message,
+            request_id: self.request_id,
+            retryable: self.retryable,
+        }
+    }
+}
+
+/// A connection to one backend, optionally authenticated with an API key.
+/// Cheap to clone; clones share the connection pool.
+#[derive(Clone)]
+pub struct Client {
+    base_url: String,
+    api_key: Option<String>,
+    transport: Transport,
+}
+
+impl Client {
+    /// A client for the backend at `base_url`, e.g. `http://localhost:3000`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+            transport: Transport::new(),
+        }
+    }
+
+    /// Sends `key` as `Authorization: Bearer <key>`, as backends with
+    /// `api_keys` configured require.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Readiness of the backend. A degraded backend answers 503 but still
+    /// describes itself, so that is returned rather than an error.
+    pub async fn health(&self) -> Result<Health, Error> {
+        let response = self.send(Method::Get, "/health", Body::Empty).await?;
+        if response.status == 503 {
+            return parse(&response.body);
+        }
+        decode(response)
+    }
+
+    /// Names of the indexed documents.
+    pub async fn documents(&self) -> Result<Vec<String>, Error> {
+        #[derive(Deserialize)]
+        struct Documents {
+            documents: Vec<String>,
+        }
+
+        let response = self.send(Method::Get, "/documents", Body::Empty).await?;
+        decode::<Documents>(response).map(|body| body.documents)
+    }
+
+    /// Queues `files` for ingestion. They are indexed in the background;
+    /// poll [`Client::job`] with the returned id to follow along.
+    pub async fn upload(
+        &self,
+        files: Vec<UploadFile>,
+        options: &UploadOptions,
+    ) -> Result<UploadAccepted, Error> {
+        let mut path = format!("/upload?on_conflict={}", options.on_conflict.as_str());
+        if let Some(collection) = &options.collection {
+            path.push_str("&collection=");
+            path.push_str(&encode_query_value(collection));
+        }
+        if let Some(date) = &options.date {
+            path.push_str("&date=");
+            path.push_str(&encode_query_value(date));
+        }
+        let response = self.send(Method::Post, &path, Body::Files(files)).await?;
+        decode(response)
+    }
+
+    /// Queues `text` for ingestion as a Markdown document named after
+    /// `title`. Poll [`Client::job`] as for uploads.
+    pub async fn ingest_text(
+        &self,
+        title: &str,
+        text: &str,
+        options: &UploadOptions,
+    ) -> Result<UploadAccepted, Error> {
+        let body = serde_json::json!({
+            "title": title,
+            "text": text,
+            "on_conflict": options.on_conflict.as_str(),
+            "collection": options.collection,
+            "date": options.date,
+        });
+        let response = self
+            .send(Method::Post, "/ingest_text", Body::Json(body))
+            .await?;
+        decode(response)
+    }
+
+    /// Queues a GitHub repository's Markdown and source files for ingestion,
+    /// named by their paths. Poll [`Client::job`] as for uploads.
+    pub async fn ingest_repo(&self, request: &RepoRequest) -> Result<UploadAccepted, Error> {
+        let response = self
+            .send(Method::Post, "/ingest_repo", json(request)?)
+            .await?;
+        decode(response)
+    }
+
+    /// The models Ollama has pulled, any of which [`ChatRequest::model`]
+    /// may name.
+    pub async fn models(&self) -> Result<Models, Error> {
+        let response = self.send(Method::Get, "/models", Body::Empty).await?;
+        decode(response)
+    }
+
+    /// Progress of an upload or reindex job.
+    pub async fn job(&self, job_id: &str) -> Result<UploadJob, Error> {
+        let path = format!("/jobs/{}", encode_query_value(job_id));
+        let response = self.send(Method::Get, &path, Body::Empty).await?;
+        decode(response)
+    }
+
+    /// Answers a question from the indexed documents in one response.
+    pub async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, Error> {
+        let response = self.send(Method::Post, "/chat", json(request)?).await?;
+        decode(response)
+    }
+
+    /// Answers a question as it is generated, calling `on_event` for each
+    /// part. Returns once the answer is complete; an error reported
+    /// mid-answer is returned as [`Error::Api`] without a status.
+    #[cfg(feature = "native")]
+    pub async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        mut on_event: impl FnMut(StreamEvent),
+    ) -> Result<(), Error> {
+        let request = self.request(Method::Post, "/chat/stream", json(request)?);
+        let mut parser = sse::Parser::default();
+        let mut outcome = None;
+
+        let response = self
+            .transport
+            .stream(request, &mut |chunk| {
+                for (event, data) in parser.feed(chunk) {
+                    match StreamEvent::parse(&event, &data) {
+                        Ok(Some(StreamEvent::Done)) => {
+                            on_event(StreamEvent::Done);
+                            outcome = Some(Ok(()));
+                        }
+                        Ok(Some(event)) => on_event(event),
+                        Ok(None) => {}
+                        Err(error) => outcome = Some(Err(error)),
+                    }
+                    if outcome.is_some() {
+                        return false;
+                    }
+                }
+                true
+            })
+            .await?;
+
+        if let Some(response) = response {
+            return decode::<serde_json::Value>(response).map(|_| ());
+        }
+        outcome.unwrap_or_else(|| {
+            Err(Error::Transport(
+                "the stream ended before the answer was complete".to_string(),
+            ))
+        })
+    }
+
+    /// Answers every request independently in one call (`POST /chat/batch`),
+    /// returning the outcomes in order. One failed question doesn't fail the
+    /// others; only the batch as a whole being rejected is an `Err`.
+    pub async fn chat_batch(&self, requests: &[ChatRequest]) -> Result<BatchAnswers, Error> {
+        #[derive(Deserialize)]
+        struct Batch {
+            results: Vec<BatchResult>,
+            total_ms: u64,
+        }
+
+        #[derive(Deserialize)]
+        struct BatchResult {
+            query: String,
+            #[serde(flatten)]
+            response: Option<ChatResponse>,
+            #[serde(default)]
+            error: Option<ApiErrorBody>,
+        }
+
+        let body = serde_json::json!({ "queries": requests });
+        let response = self.send(Method::Post, "/chat/batch", json(&body)?).await?;
+        let batch: Batch = decode(response)?;
+        let answers = batch
+            .results
+            .into_iter()
+            .map(|result| BatchAnswer {
+                query: result.query,
+                result: match (result.response, result.error) {
+                    (_, Some(error)) => Err(error.into_error(None)),
+                    (Some(response), None) => Ok(response),
+                    (None, None) => Err(Error::Decode("result without an answer".to_string())),
+                },
+            })
+            .collect();
+        Ok(BatchAnswers {
+            answers,
+            total_ms: batch.total_ms,
+        })
+    }
+
+    /// The passages most similar to a query, without generating an answer.
+    pub async fn search(&self, request: &SearchRequest) -> Result<SearchResponse, Error> {
+        let response = self.send(Method::Post, "/search", json(request)?).await?;
+        decode(response)
+    }
+
+    /// Vectors for `texts` from the model the index uses, in order. The
+    /// backend needs `embed_api_keys` set and one of them as the API key.
+    pub async fn embed(&self, texts: &[String]) -> Result<EmbedResponse, Error> {
+        let body = serde_json::json!({ "texts": texts });
+        let response = self.send(Method::Post, "/embed", Body::Json(body)).await?;
+        decode(response)
+    }
+
+    /// Removes a document and its chunks from the index.
+    pub async fn delete(&self, filename: &str) -> Result<(), Error> {
+        let body = serde_json::json!({ "filename": filename });
+        let response = self.send(Method::Post, "/delete", Body::Json(body)).await?;
+        decode::<serde_json::Value>(response).map(|_| ())
+    }
+
+    /// Removes every document, or only `collection`'s, with their chunks.
+    pub async fn reset(&self, collection: Option<&str>) -> Result<(), Error> {
+        let body = serde_json::json!({ "confirm": true, "collection": collection });
+        let response = self.send(Method::Post, "/reset", Body::Json(body)).await?;
+        decode::<serde_json::Value>(response).map(|_| ())
+    }
+
+    fn request(&self, method: Method, path: &str, body: Body) -> HttpRequest {
+        HttpRequest {
+            method,
+            url: format!("{}{}", self.base_url, path),
+            api_key: self.api_key.clone(),
+            body,
+        }
+    }
+
+    async fn send(&self, method: Method, path: &str, body: Body) -> Result<HttpResponse, Error> {
+        self.transport.send(self.request(method, path, body)).await
+    }
+}
+
+fn json(value: &impl Serialize) -> Result<Body, Error> {
+    serde_json::to_value(value)
+        .map(Body::Json)
+        .map_err(|e| Error::Decode(e.to_string()))
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|e| Error::Decode(e.to_string()))
+}
+
+/// The body of a successful response, or the error the backend described.
+/// Errors from outside the API's handlers (a proxy, an unsupported
+/// encoding) carry no JSON body and get code `http_<status>`.
+fn decode<T: DeserializeOwned>(response: HttpResponse) -> Result<T, Error> {
+    if (200..300).contains(&response.status) {
+        return parse(&response.body);
+    }
+    match serde_json::from_slice::<ErrorBody>(&response.body) {
+        Ok(body) => Err(body.error.into_error(Some(response.status))),
+        Err(_) => Err(Error::Api {
+            status: Some(response.status),
+            code: format!("http_{}", response.status),
+            message: String::from_utf8_lossy(&response.body).trim().to_string(),
+            request_id: None,
+            retryable: response.status == 429 || response.status >= 500,
+        }),
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for query values
+/// and path segments.
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
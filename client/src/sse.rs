@@ -0,0 +1,52 @@
+//! Incremental parsing of `text/event-stream` bodies.
+
+/// Splits a server-sent event stream into `(event, data)` pairs as bytes
+/// arrive. Chunks may end anywhere, even inside a UTF-8 character.
+#[derive(Default)]
+pub(crate) struct Parser {
+    buffer: Vec<u8>,
+    event: String,
+    data: Vec<String>,
+}
+
+impl Parser {
+    /// Feeds the next chunk of the body, returning the events it completed.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<(String, String)> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                // A blank line dispatches the event; unnamed ones are "message"
+                if !self.data.is_empty() {
+                    let event = match std::mem::take(&mut self.event) {
+                        event if event.is_empty() => "message".to_string(),
+                        event => event,
+                    };
+                    events.push((event, self.data.join("\n")));
+                    self.data.clear();
+                }
+                self.event.clear();
+                continue;
+            }
+            if line.starts_with(':') {
+                // Comment, e.g. a keep-alive
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event = value.to_string(),
+                "data" => self.data.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        events
+    }
+}
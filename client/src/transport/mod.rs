@@ -0,0 +1,41 @@
+//! The HTTP layer under [`crate::Client`], one implementation per feature.
+
+#[cfg(feature = "native")]
+mod native;
+#[cfg(all(feature = "wasm", not(feature = "native")))]
+mod wasm;
+
+#[cfg(feature = "native")]
+pub(crate) use native::Transport;
+#[cfg(all(feature = "wasm", not(feature = "native")))]
+pub(crate) use wasm::Transport;
+
+#[cfg(not(any(feature = "native", feature = "wasm")))]
+compile_error!("ahtohallan-client needs the `native` or `wasm` feature");
+
+use crate::UploadFile;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Method {
+    Get,
+    Post,
+}
+
+pub(crate) enum Body {
+    Empty,
+    Json(serde_json::Value),
+    // Sent as multipart/form-data, one `files` field each
+    Files(Vec<UploadFile>),
+}
+
+pub(crate) struct HttpRequest {
+    pub(crate) method: Method,
+    pub(crate) url: String,
+    pub(crate) api_key: Option<String>,
+    pub(crate) body: Body,
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    pub(crate) body: Vec<u8>,
+}
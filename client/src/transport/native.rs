@@ -0,0 +1,82 @@
+//! reqwest transport for native targets.
+
+use futures_util::StreamExt;
+use reqwest::multipart::{Form, Part};
+
+use super::{Body, HttpRequest, HttpResponse, Method};
+use crate::Error;
+
+#[derive(Clone)]
+pub(crate) struct Transport {
+    http: reqwest::Client,
+}
+
+impl Transport {
+    pub(crate) fn new() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub(crate) async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let response = self.build(request).send().await.map_err(transport_error)?;
+        let status = response.status().as_u16();
+        let body = response.bytes().await.map_err(transport_error)?;
+        Ok(HttpResponse {
+            status,
+            body: body.to_vec(),
+        })
+    }
+
+    /// Hands a successful response's body to `on_chunk` as it arrives, until
+    /// it ends or `on_chunk` returns false. An error status is returned with
+    /// its body instead.
+    pub(crate) async fn stream(
+        &self,
+        request: HttpRequest,
+        on_chunk: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<Option<HttpResponse>, Error> {
+        let response = self.build(request).send().await.map_err(transport_error)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.bytes().await.map_err(transport_error)?;
+            return Ok(Some(HttpResponse {
+                status: status.as_u16(),
+                body: body.to_vec(),
+            }));
+        }
+
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            if !on_chunk(&chunk.map_err(transport_error)?) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    fn build(&self, request: HttpRequest) -> reqwest::RequestBuilder {
+        let builder = match request.method {
+            Method::Get => self.http.get(&request.url),
+            Method::Post => self.http.post(&request.url),
+        };
+        let builder = match request.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        };
+        match request.body {
+            Body::Empty => builder,
+            Body::Json(value) => builder.json(&value),
+            Body::Files(files) => {
+                let form = files.into_iter().fold(Form::new(), |form, file| {
+                    form.part("files", Part::bytes(file.content).file_name(file.name))
+                });
+                builder.multipart(form)
+            }
+        }
+    }
+}
+
+fn transport_error(error: reqwest::Error) -> Error {
+    Error::Transport(error.to_string())
+}
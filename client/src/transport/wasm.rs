@@ -0,0 +1,59 @@
+//! gloo-net transport for the browser.
+
+use gloo_net::http::{Request, RequestBuilder};
+use web_sys::{Blob, FormData};
+
+use super::{Body, HttpRequest, HttpResponse, Method};
+use crate::Error;
+
+#[derive(Clone)]
+pub(crate) struct Transport;
+
+impl Transport {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+
+    pub(crate) async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let builder = match request.method {
+            Method::Get => Request::get(&request.url),
+            Method::Post => Request::post(&request.url),
+        };
+        let builder = match &request.api_key {
+            Some(key) => builder.header("Authorization", &format!("Bearer {}", key)),
+            None => builder,
+        };
+
+        let response = match request.body {
+            Body::Empty => builder.send().await,
+            Body::Json(value) => builder.json(&value).map_err(transport_error)?.send().await,
+            Body::Files(files) => form_request(builder, files)?.send().await,
+        }
+        .map_err(transport_error)?;
+
+        let status = response.status();
+        let body = response.binary().await.map_err(transport_error)?;
+        Ok(HttpResponse { status, body })
+    }
+}
+
+fn form_request(builder: RequestBuilder, files: Vec<crate::UploadFile>) -> Result<Request, Error> {
+    let form = FormData::new().map_err(js_error)?;
+    for file in files {
+        let bytes = js_sys::Uint8Array::from(file.content.as_slice());
+        let blob =
+            Blob::new_with_u8_array_sequence(&js_sys::Array::of1(&bytes)).map_err(js_error)?;
+        form.append_with_blob_and_filename("files", &blob, &file.name)
+            .map_err(js_error)?;
+    }
+    // The browser sets the multipart boundary itself
+    builder.body(form).map_err(transport_error)
+}
+
+fn transport_error(error: gloo_net::Error) -> Error {
+    Error::Transport(error.to_string())
+}
+
+fn js_error(error: wasm_bindgen::JsValue) -> Error {
+    Error::Transport(format!("{:?}", error))
+}
@@ -0,0 +1,511 @@
+//! Request and response bodies of the backend API.
+
+use serde::{Deserialize, Serialize};
+
+/// `GET /health`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Health {
+    // "ok" when answers can be generated, "degraded" otherwise
+    pub status: String,
+    pub embedding_model: String,
+    pub embedding_ready: bool,
+    pub ollama: bool,
+    pub model: String,
+    pub model_available: bool,
+    pub model_loaded: bool,
+    pub documents: usize,
+    pub chunks: usize,
+    pub uptime_secs: u64,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// A file to upload: its name (whose extension picks the extractor) and
+/// contents.
+#[derive(Clone, Debug)]
+pub struct UploadFile {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+impl UploadFile {
+    pub fn new(name: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            content,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    pub on_conflict: OnConflict,
+    // Collection to file the uploaded documents under
+    pub collection: Option<String>,
+    // Date (YYYY-MM-DD) to give the uploaded documents, for recency ranking
+    pub date: Option<String>,
+}
+
+/// What to do when an upload's filename is already indexed with different
+/// content.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnConflict {
+    /// Swap the new content in for the old document's chunks
+    #[default]
+    Replace,
+    /// Keep both, indexing the new content as `name.v2.ext`, `name.v3.ext`, ...
+    Version,
+}
+
+impl OnConflict {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            OnConflict::Replace => "replace",
+            OnConflict::Version => "version",
+        }
+    }
+}
+
+/// `POST /ingest_repo`: a public GitHub repository whose Markdown and
+/// source files to index.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RepoRequest {
+    // https://github.com/<owner>/<repo>
+    pub url: String,
+    // Branch, tag or commit; the default branch when `None`
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // Globs of the paths to index; every supported file when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    // Globs of the paths to leave out; the backend skips dependencies and
+    // build output when `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+}
+
+impl RepoRequest {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            ..Self::default()
+        }
+    }
+}
+
+/// `202 Accepted` from `POST /upload` and `POST /ingest_repo`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UploadAccepted {
+    pub job_id: String,
+}
+
+/// `GET /jobs/:id`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UploadJob {
+    pub id: String,
+    pub status: JobStatus,
+    pub percent: u8,
+    pub files: Vec<FileProgress>,
+    pub total_chunks: usize,
+    pub duplicates_skipped: usize,
+}
+
+impl UploadJob {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Completed | JobStatus::Failed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileProgress {
+    pub filename: String,
+    pub stage: FileStage,
+    pub percent: u8,
+    pub chunks: usize,
+    pub duplicates_skipped: usize,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStage {
+    Queued,
+    Extracting,
+    Chunking,
+    Embedding,
+    Summarizing,
+    Done,
+    // Identical to the indexed copy, so nothing was re-embedded
+    Unchanged,
+    Failed,
+}
+
+/// `POST /chat` and `POST /chat/stream`. Only `query` is required; the
+/// rest default the way the backend does.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatRequest {
+    pub query: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deep_think: bool,
+    pub length: AnswerLength,
+    // Overrides the backend's `min_similarity` for this request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_similarity: Option<f32>,
+    // Have the LLM rephrase the query and search with every phrasing
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub rewrite_query: bool,
+    // Check the answer's sentences against the retrieved context afterwards
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub verify: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    // Earlier turns, sent to the model as chat messages
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<HistoryTurn>,
+    // Only search these documents; every document when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    // Session to append this turn to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    // Return per-stage timings with the answer
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub debug: bool,
+    // Ollama model to answer with; the backend's default when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // What goes into the prompt for each retrieved chunk
+    pub strategy: RetrievalStrategy,
+    // Overrides the backend's `recency_weight` for this request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_weight: Option<f32>,
+    // Only search chunks tagged with one of these keywords or entities
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    // Only search documents carrying one of these category tags
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Chunks retrieved into the prompt; the backend's default when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+}
+
+impl ChatRequest {
+    pub fn new(query: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryTurn {
+    // "user" or "assistant"
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnswerLength {
+    Short,
+    #[default]
+    Normal,
+    Detailed,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetrievalStrategy {
+    // The matched chunks themselves
+    #[default]
+    Chunk,
+    // The parent section around each matched chunk
+    Parent,
+}
+
+/// `GET /models`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Models {
+    // Model answers use when a request names none
+    pub default: String,
+    pub models: Vec<LlmModel>,
+}
+
+/// A model Ollama has pulled.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LlmModel {
+    pub name: String,
+    // Bytes on disk
+    pub size: u64,
+    #[serde(default)]
+    pub modified_at: String,
+    #[serde(default)]
+    pub family: String,
+    #[serde(default)]
+    pub parameter_size: String,
+    #[serde(default)]
+    pub quantization_level: String,
+}
+
+/// `POST /chat`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatResponse {
+    pub answer: String,
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub warning: Option<String>,
+    #[serde(default)]
+    pub scores: Vec<ChunkScore>,
+    #[serde(default)]
+    pub rewrites: Vec<String>,
+    #[serde(default)]
+    pub steps: Vec<ThinkStep>,
+    #[serde(default)]
+    pub citations: Vec<Citation>,
+    #[serde(default)]
+    pub grounding: Option<Grounding>,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    // Set when the content-safety filter refused the query or changed the answer
+    #[serde(default)]
+    pub moderation: Option<ModerationDecision>,
+}
+
+/// `POST /chat/batch`.
+#[derive(Debug)]
+pub struct BatchAnswers {
+    // One per request, in the order they were sent
+    pub answers: Vec<BatchAnswer>,
+    pub total_ms: u64,
+}
+
+#[derive(Debug)]
+pub struct BatchAnswer {
+    pub query: String,
+    pub result: Result<ChatResponse, crate::Error>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChunkScore {
+    pub source: String,
+    pub score: f32,
+}
+
+/// One sub-question of a deep-think answer.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThinkStep {
+    pub question: String,
+    pub answer: String,
+    pub sources: Vec<String>,
+}
+
+/// The context block an `[n]` marker in the answer refers to.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Citation {
+    pub number: usize,
+    pub source: String,
+    pub chunk_id: u64,
+    pub score: f32,
+    pub text: String,
+}
+
+/// How well an answer is supported by its context (`verify`).
+#[derive(Clone, Debug, Deserialize)]
+pub struct Grounding {
+    // Share of checked sentences that are supported, 0-1
+    pub score: f32,
+    pub sentences: Vec<SentenceSupport>,
+    #[serde(default)]
+    pub warning: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SentenceSupport {
+    pub text: String,
+    pub support: f32,
+    pub supported: bool,
+}
+
+/// What the backend's content-safety filter did to a query or answer.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModerationDecision {
+    pub action: ModerationAction,
+    // Blocked terms found
+    #[serde(default)]
+    pub terms: Vec<String>,
+    // Category the LLM judge flagged
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    /// The query was refused
+    Refused,
+    /// Blocked terms were masked in the answer
+    Scrubbed,
+    /// The answer was replaced with a refusal
+    Withheld,
+}
+
+/// A `moderation` stream event: the answer to show in place of what was
+/// streamed (or is about to be, for a refused query).
+#[derive(Clone, Debug, Deserialize)]
+pub struct Moderation {
+    pub answer: String,
+    pub decision: ModerationDecision,
+}
+
+/// Milliseconds per answering stage (`debug`); 0 for stages that didn't run.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Timings {
+    pub rewrite_ms: u64,
+    pub think_ms: u64,
+    pub embed_ms: u64,
+    pub search_ms: u64,
+    pub prompt_ms: u64,
+    pub generate_ms: u64,
+    pub verify_ms: u64,
+    pub total_ms: u64,
+}
+
+/// One part of a streamed answer, in the order the backend sends them:
+/// the retrieval results first, then the tokens, then the checks.
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    Warning(String),
+    Rewrites(Vec<String>),
+    Steps(Vec<ThinkStep>),
+    Sources(Vec<String>),
+    Scores(Vec<ChunkScore>),
+    Citations(Vec<Citation>),
+    Token(String),
+    Grounding(Grounding),
+    Timings(Timings),
+    Moderation(Moderation),
+    Done,
+}
+
+#[cfg(feature = "native")]
+impl StreamEvent {
+    /// The event for one SSE message; `None` for kinds this client doesn't
+    /// know, and the backend's error for `error`.
+    pub(crate) fn parse(event: &str, data: &str) -> Result<Option<Self>, crate::Error> {
+        #[derive(Deserialize)]
+        struct Content {
+            content: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Message {
+            message: String,
+        }
+
+        fn parse<T: serde::de::DeserializeOwned>(data: &str) -> Result<T, crate::Error> {
+            crate::parse(data.as_bytes())
+        }
+
+        let event = match event {
+            "warning" => StreamEvent::Warning(parse::<Message>(data)?.message),
+            "rewrites" => StreamEvent::Rewrites(parse(data)?),
+            "steps" => StreamEvent::Steps(parse(data)?),
+            "sources" => StreamEvent::Sources(parse(data)?),
+            "scores" => StreamEvent::Scores(parse(data)?),
+            "citations" => StreamEvent::Citations(parse(data)?),
+            "token" => StreamEvent::Token(parse::<Content>(data)?.content),
+            "grounding" => StreamEvent::Grounding(parse(data)?),
+            "timings" => StreamEvent::Timings(parse(data)?),
+            "moderation" => StreamEvent::Moderation(parse(data)?),
+            "done" => StreamEvent::Done,
+            "error" => return Err(parse::<crate::ApiErrorBody>(data)?.into_error(None)),
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+}
+
+/// `POST /embed`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmbedResponse {
+    pub model: String,
+    pub dimensions: usize,
+    // One vector per input text, in order
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+/// `POST /search`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SearchRequest {
+    pub query: String,
+    // Results to return; the backend caps it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_similarity: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_weight: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl SearchRequest {
+    pub fn new(query: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchResponse {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
+/// A retrieved passage and where it sits in its document.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchResult {
+    pub id: u64,
+    pub source: String,
+    pub score: f32,
+    pub text: String,
+    #[serde(default)]
+    pub chunk_index: Option<usize>,
+    // Byte range of `text` in the document's extracted text
+    #[serde(default)]
+    pub start: Option<usize>,
+    #[serde(default)]
+    pub end: Option<usize>,
+    // Entities and key phrases extracted from the chunk
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
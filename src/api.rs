@@ -0,0 +1,90 @@
+//! Where the backend is and how to talk to it. Every request URL is built
+//! from one base URL, chosen in the settings panel and remembered in
+//! localStorage, so a built frontend can be pointed at a backend on another
+//! machine. Requests carry the saved API key, if any.
+
+use crate::{load_setting, save_setting};
+use gloo_net::http::{Request, RequestBuilder};
+
+/// The backend used until another is saved in the settings panel. Set
+/// `AHTOHALLAN_BACKEND_URL` when building to change it.
+pub const DEFAULT_BACKEND_URL: &str = match option_env!("AHTOHALLAN_BACKEND_URL") {
+    Some(url) => url,
+    None => "http://localhost:3000",
+};
+
+/// localStorage key remembering the backend URL picked in the settings panel.
+const BACKEND_URL_KEY: &str = "ahtohallan.backend_url";
+/// localStorage key remembering the API key entered in the settings panel.
+const API_KEY_KEY: &str = "ahtohallan.api_key";
+
+/// The backend's base URL, without a trailing slash.
+pub fn backend_url() -> String {
+    load_setting(BACKEND_URL_KEY)
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_BACKEND_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// The URL of `path` (starting with `/`) on the backend.
+pub fn url(path: &str) -> String {
+    format!("{}{}", backend_url(), path)
+}
+
+/// Saves `url` as the backend to use from now on; an empty one goes back to
+/// the default. Only `http://` and `https://` URLs are accepted.
+pub fn set_backend_url(url: &str) -> Result<(), String> {
+    let url = url.trim().trim_end_matches('/');
+    let valid = url.is_empty()
+        || ["http://", "https://"]
+            .iter()
+            .any(|scheme| url.len() > scheme.len() && url.starts_with(scheme));
+    if !valid {
+        return Err("Enter a URL starting with http:// or https://".to_string());
+    }
+    save_setting(BACKEND_URL_KEY, url);
+    Ok(())
+}
+
+/// The API key sent as `Authorization: Bearer <key>`, if one is saved.
+pub fn api_key() -> Option<String> {
+    load_setting(API_KEY_KEY).filter(|key| !key.is_empty())
+}
+
+/// Saves `key` for every request from now on; an empty one sends none.
+pub fn set_api_key(key: &str) {
+    save_setting(API_KEY_KEY, key.trim());
+}
+
+/// The `Authorization` header value for the saved API key.
+pub fn authorization() -> Option<String> {
+    api_key().map(|key| format!("Bearer {}", key))
+}
+
+fn authorized(request: RequestBuilder) -> RequestBuilder {
+    match authorization() {
+        Some(value) => request.header("Authorization", &value),
+        None => request,
+    }
+}
+
+/// A GET request for `path` on the backend.
+pub fn get(path: &str) -> RequestBuilder {
+    authorized(Request::get(&url(path)))
+}
+
+/// A POST request for `path` on the backend.
+pub fn post(path: &str) -> RequestBuilder {
+    authorized(Request::post(&url(path)))
+}
+
+/// A PATCH request for `path` on the backend.
+pub fn patch(path: &str) -> RequestBuilder {
+    authorized(Request::patch(&url(path)))
+}
+
+/// A DELETE request for `path` on the backend.
+pub fn delete(path: &str) -> RequestBuilder {
+    authorized(Request::delete(&url(path)))
+}
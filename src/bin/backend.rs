@@ -1,561 +1,11379 @@
 use axum::{
-    extract::{Multipart, State},
-    http::StatusCode,
-    response::{IntoResponse, Json, Response},
-    routing::{get, post},
+    async_trait,
+    body::Bytes,
+    extract::{
+        rejection::{BytesRejection, JsonRejection},
+        ConnectInfo, DefaultBodyLimit, FromRequest, FromRequestParts, Multipart, Path as UrlPath,
+        Query, Request, State,
+    },
+    http::Uri,
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Redirect, Response,
+    },
+    routing::{delete, get, patch, post},
     Router,
 };
-use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use ndarray::{Array1, ArrayView1};
+use fastembed::{EmbeddingModel, InitOptions, ModelInfo, TextEmbedding};
+use futures_util::StreamExt;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use memmap2::Mmap;
+use ndarray::{Array1, Array2, ArrayView1};
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashSet,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
+    convert::Infallible,
     future::Future,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    path::{Path, PathBuf},
     pin::Pin,
-    sync::{Arc, Mutex, RwLock},
-    time::Duration,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch, Semaphore},
+    task::JoinSet,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tower::Service;
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, Any, CorsLayer},
+    decompression::RequestDecompressionLayer,
 };
-use tower_http::cors::CorsLayer;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 // Data struct
 #[derive(Clone)]
 struct AppState {
+    config: Arc<Config>,
     vector_store: Arc<RwLock<VectorStore>>,
-    embedding_model: Arc<Mutex<TextEmbedding>>,
+    // Embeds queries and answer checks, on its own ONNX session so bulk
+    // ingestion never queues ahead of a chat request
+    embedding_model: Arc<TextEmbedding>,
+    // Second instance of the same model for uploads, reindexing and /embed
+    ingest_embedding_model: Arc<TextEmbedding>,
+    // Bounds how many ingestion batches embed at once; query embeddings bypass it
+    embedding_pool: Arc<Semaphore>,
     ollama_client: reqwest::Client,
+    // Serializes snapshot writes so an older snapshot never overwrites a newer one
+    persist_lock: Arc<tokio::sync::Mutex<()>>,
+    jobs: Arc<RwLock<HashMap<String, UploadJob>>>,
+    upload_queue: mpsc::UnboundedSender<QueuedUpload>,
+    // Held for the whole of a background index rebuild; one runs at a time
+    reindex_lock: Arc<tokio::sync::Mutex<()>>,
+    // Shared by each file's ingest, taken exclusively to swap in a rebuilt index
+    ingest_lock: Arc<tokio::sync::RwLock<()>>,
+    // Measures prompts against the model's context window
+    tokens: Arc<TokenCounter>,
+    // Published conversations by share token; held while persisting them
+    shares: Arc<tokio::sync::Mutex<HashMap<String, SharedConversation>>>,
+    // Server-side conversations by session id; held while persisting them
+    sessions: Arc<tokio::sync::Mutex<HashMap<String, Session>>>,
+    // Evaluation runs by id; held while persisting them
+    evals: Arc<tokio::sync::Mutex<HashMap<String, EvalRun>>>,
+    // Held while appending to or rotating an audit log
+    audit_lock: Arc<tokio::sync::Mutex<()>>,
+    // Last corpus overview with the fingerprint of the corpus it describes;
+    // held while generating so concurrent requests share one LLM call
+    overview: Arc<tokio::sync::Mutex<Option<(u64, OverviewResponse)>>>,
+    // What the watch folder ingested or removed lately, newest last
+    watch_activity: Arc<Mutex<VecDeque<WatchEvent>>>,
+    // When `/health` last embedded its probe successfully
+    embedding_checked: Arc<Mutex<Option<Instant>>>,
+    started_at: Instant,
+    // Whose documents and sessions `vector_store` and `sessions` hold
+    tenant: String,
+    // Other tenants' data, loaded on their first request
+    tenants: Arc<RwLock<HashMap<String, TenantData>>>,
+    rate_limiter: Arc<RateLimiter>,
+    // Reads text aloud for POST /tts, when `tts` is configured
+    speech: Option<Arc<dyn SpeechSynthesizer>>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+struct Config {
+    data_dir: PathBuf,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    // Words per parent section, the larger passage the `parent` strategy
+    // puts in the prompt in place of each matched chunk
+    parent_size: usize,
+    // Ollama model answers are generated with unless a request picks another
+    model: String,
+    // Vision-capable Ollama model uploaded images are described with
+    vision_model: String,
+    // Also describe the figures embedded in uploaded PDFs, indexing each
+    // description as chunks of its page; at most `max_pdf_images` per PDF
+    describe_pdf_images: bool,
+    max_pdf_images: usize,
+    // fastembed model for chunks and queries, by variant name or model code;
+    // changing it requires POST /reindex
+    embedding_model: String,
+    embedding_workers: usize,
+    // Chunks scoring below this cosine similarity never reach the prompt
+    min_similarity: f32,
+    // Documents picked by summary similarity before searching chunks (0 = off)
+    routing_documents: usize,
+    // MMR relevance/diversity trade-off for retrieval (1.0 = relevance only)
+    mmr_lambda: f32,
+    // Boost the `recency` stage gives a brand-new document (0 = off), and
+    // the age in days at which the boost has halved
+    recency_weight: f32,
+    recency_half_life_days: f32,
+    // Upload jobs allowed to wait or run at once before /upload answers 503
+    max_pending_uploads: usize,
+    // Files of one upload extracted and embedded at once (0 = CPU count)
+    upload_concurrency: usize,
+    // Neighbouring chunks added on each side of a hit by the expand stage
+    neighbor_window: usize,
+    // How chunk embeddings are kept in memory and in the snapshot
+    embedding_storage: EmbeddingStorage,
+    // Keep chunk embeddings in a memory-mapped file the OS pages in on
+    // demand, for corpora bigger than RAM
+    mmap_embeddings: bool,
+    // Bearer tokens accepted by POST /embed; the endpoint is off while empty
+    embed_api_keys: Vec<String>,
+    // API key -> tenant name. Once set, every request needs a key and only
+    // sees its tenant's documents and sessions
+    api_keys: HashMap<String, String>,
+    // Bearer tokens accepted by the /admin endpoints; they are off while empty
+    admin_api_keys: Vec<String>,
+    // Chat and upload requests each client (API key, else IP address) may
+    // make per minute, in bursts of up to as many (0 = unlimited)
+    rate_limit_per_minute: u32,
+    // Largest upload request body accepted, in megabytes
+    max_upload_mb: usize,
+    // Address the API listens on
+    listen_addr: SocketAddr,
+    // PEM certificate chain and private key; HTTPS is served once both are set
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    // Port answering plain HTTP with a redirect to the HTTPS address
+    http_redirect_port: Option<u16>,
+    // Host name clients reach HTTPS at, which the redirect sends them to
+    public_host: Option<String>,
+    // Browser origins allowed to call the API (`--allow-any-origin` allows all)
+    cors_origins: Vec<String>,
+    cors_methods: Vec<String>,
+    // Request headers browsers may send
+    cors_headers: Vec<String>,
+    // Texts one POST /embed request may carry
+    max_embed_batch: usize,
+    // Queries one POST /chat/batch request may carry, and how many of them
+    // are answered at once
+    max_batch_queries: usize,
+    batch_concurrency: usize,
+    // Questions one evaluation set may hold
+    max_eval_questions: usize,
+    // Append every answered question to `audit.jsonl` in the data directory
+    audit_log: bool,
+    // Size at which the audit log is rotated, in megabytes, and how many
+    // rotated files (`audit.1.jsonl` newest) are kept
+    audit_log_max_mb: u64,
+    audit_log_files: usize,
+    // phi3's tokenizer.json; fetched from Hugging Face when unset
+    tokenizer: Option<PathBuf>,
+    // Prompt templates by name; `grounded` and `deep_think` are built in
+    prompts: HashMap<String, String>,
+    // Directory whose `<name>.txt` files are loaded as templates too
+    prompt_dir: Option<PathBuf>,
+    // Retrieval stages, run in order for every query
+    pipeline: Vec<RetrievalStage>,
+    // Categories the LLM tags uploaded documents with (empty = no tagging)
+    categories: Vec<String>,
+    // Directory whose .md and .pdf files are kept indexed for the default
+    // tenant: new and changed files are ingested, deleted ones removed
+    watch_dir: Option<PathBuf>,
+    // Kinds of personal data masked in uploaded text before it is chunked,
+    // summarized or sent to the LLM (empty = off)
+    redact_pii: Vec<PiiKind>,
+    // Words and phrases that get a query refused and are masked in answers
+    blocked_terms: Vec<String>,
+    // Also have the LLM judge queries and answers against
+    // `moderation_categories`, refusing or withholding what it flags
+    moderation_llm: bool,
+    moderation_categories: Vec<String>,
+    // Reply given in place of a refused query or withheld answer
+    moderation_refusal: String,
+    // whisper.cpp's command-line program (`whisper-cli`) and the ggml model
+    // POST /transcribe runs it with; the endpoint is off until both are set
+    whisper_cli: Option<PathBuf>,
+    whisper_model: Option<PathBuf>,
+    // Converts recorded audio to the 16 kHz mono WAV whisper.cpp reads
+    ffmpeg: PathBuf,
+    // Speech POST /tts reads text aloud with; the endpoint is off while unset
+    tts: Option<TtsConfig>,
+}
+
+/// Where `POST /tts` gets speech from, picked with `provider`.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+enum TtsConfig {
+    /// The piper program with one of its `.onnx` voices, run on this machine
+    Piper { piper: PathBuf, model: PathBuf },
+    /// An OpenAI-compatible speech endpoint, such as
+    /// `https://api.openai.com/v1/audio/speech`
+    Openai {
+        url: String,
+        api_key: Option<String>,
+        model: String,
+        voice: String,
+    },
 }
 
+/// Precision of stored chunk embeddings. Switching converts the index the
+/// next time it is loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EmbeddingStorage {
+    /// Full-precision floats, 4 bytes per dimension
+    #[default]
+    F32,
+    /// One signed byte per dimension plus a per-vector scale, ~4x smaller
+    Int8,
+}
+
+/// One step of the retrieval pipeline. `dense`, `sparse` and `fuse` produce
+/// the candidate list; the others reorder or filter it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RetrievalStage {
+    /// Limit the search to documents whose summaries match the query
+    Route,
+    /// Embedding similarity search
+    Dense,
+    /// BM25 keyword search
+    Sparse,
+    /// Reciprocal rank fusion of the dense and sparse lists
+    Fuse,
+    /// Reorder by similarity plus coverage of the query's terms
+    Rerank,
+    /// Maximal marginal relevance diversification
+    Mmr,
+    /// Widen each hit with its neighbouring chunks from the same document
+    Expand,
+    /// Stitch overlapping chunks and drop near-duplicates
+    Dedupe,
+    /// Keep only the sentences of each chunk that bear on the query
+    Compress,
+    /// Boost chunks from newer documents
+    Recency,
+    /// Drop chunks below the similarity threshold
+    Threshold,
+}
+
+/// Personal data `redact_pii` can mask, each replaced by a placeholder
+/// naming its kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PiiKind {
+    /// Email addresses, masked as `[EMAIL]`
+    Email,
+    /// Phone numbers written with a country code, area code or separators,
+    /// masked as `[PHONE]`
+    Phone,
+    /// US social security, UK national insurance and Aadhaar numbers,
+    /// masked as `[NATIONAL_ID]`
+    NationalId,
+}
+
+#[derive(Default, Serialize, Deserialize)]
 struct VectorStore {
+    documents: HashMap<String, DocumentRecord>,
     chunks: Vec<ChunkData>,
+    next_chunk_id: u64,
+    // Chunk text hash -> chunk id, rebuilt on load
+    #[serde(skip)]
+    content_index: HashMap<u64, u64>,
+    // Chunk embeddings as rows in `chunks` order, built by the first search
+    // after the chunks change
+    #[serde(skip)]
+    matrix: OnceLock<EmbeddingMatrix>,
+    // Format new chunk embeddings are stored in
+    #[serde(skip)]
+    storage: EmbeddingStorage,
+    // Name of the file in the data directory holding `Mapped` embeddings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    embedding_file: Option<String>,
+    // That file, opened; new embeddings are appended to it while set
+    #[serde(skip)]
+    mapped: Option<Arc<EmbeddingFile>>,
+    // Model the chunk embeddings came from and their length, so embeddings
+    // of different models are never compared
+    #[serde(default)]
+    embedding_model: String,
+    #[serde(default)]
+    embedding_dimensions: usize,
 }
 
-#[derive(Clone)]
+/// A normalized chunk embedding, at full precision or scalar-quantized.
+/// Snapshots written before quantization existed hold plain arrays.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredEmbedding {
+    F32(Vec<f32>),
+    // Dimension i is approximately `values[i] * scale`
+    Int8 { scale: f32, values: Vec<i8> },
+    // A record of the store's embedding file
+    Mapped { offset: u64, dimensions: usize },
+}
+
+/// Embeddings appended to a file and read back through a memory map, so
+/// the OS pages them in on demand instead of the store holding them all.
+/// Records are never rewritten: space held by removed chunks is reclaimed
+/// by compacting into a new file when the store is loaded.
+struct EmbeddingFile {
+    // Format of every record in the file
+    storage: EmbeddingStorage,
+    // Append handle and the file's length
+    writer: Mutex<(std::fs::File, u64)>,
+    // Remapped when a read reaches past the end of the current map
+    map: RwLock<Mmap>,
+}
+
+/// Every chunk embedding stacked into one contiguous row-major matrix.
+enum EmbeddingMatrix {
+    F32(Array2<f32>),
+    // Row i is scaled by `scales[i]`
+    Int8 {
+        values: Array2<i8>,
+        scales: Vec<f32>,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DocumentRecord {
+    text: String,
+    // ISO 639-3 code, `None` when the text is too short to tell
+    #[serde(default)]
+    language: Option<String>,
+    // SHA-256 of the uploaded file, used to skip unchanged re-uploads
+    #[serde(default)]
+    content_hash: Option<String>,
+    #[serde(default)]
+    summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    summary_embedding: Vec<f32>,
+    // Ids of the document's chunks in reading order, for neighbour expansion
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunk_ids: Vec<u64>,
+    // Parent sections of the document, and the section each of `chunk_ids`
+    // belongs to; empty for documents indexed before sections were kept
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sections: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunk_sections: Vec<usize>,
+    // Absent for documents ingested before reports were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    report: Option<IngestionReport>,
+    // Named collections the document belongs to, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    collections: Vec<String>,
+    // Categories from `Config::categories` the LLM filed the document under
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    // When the document was written or took effect (YYYY-MM-DD); recency
+    // ranking falls back to the ingestion time without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
+    // Ingested from `Config::watch_dir`, so deleting the file removes it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    watched: bool,
+    // Descriptions of the figures in a PDF, indexed after its text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    figures: Vec<Figure>,
+    // Tables found in a PDF, indexed after its text as Markdown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tables: Vec<Table>,
+}
+
+/// A figure embedded in a PDF, as the vision model described it.
+#[derive(Clone, Serialize, Deserialize)]
+struct Figure {
+    // 1-based
+    page: usize,
+    description: String,
+}
+
+/// A table in a PDF, rebuilt from where its text sits on the page.
+#[derive(Clone, Serialize, Deserialize)]
+struct Table {
+    // 1-based
+    page: usize,
+    // A Markdown table, its first row the header
+    markdown: String,
+}
+
+/// How a document's ingestion went, kept so extraction problems can be
+/// diagnosed after the fact.
+#[derive(Clone, Serialize, Deserialize)]
+struct IngestionReport {
+    // "markdown", "lopdf" or "pdf-extract"
+    extractor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pages: Option<usize>,
+    characters: usize,
+    chunks: usize,
+    // Chunks dropped because identical text was already indexed
+    filtered_chunks: usize,
+    #[serde(default)]
+    warnings: Vec<String>,
+    // Personal data masked in the text, by kind
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    redactions: BTreeMap<PiiKind, usize>,
+    duration_ms: u64,
+    // Unix seconds
+    ingested_at: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct ChunkData {
+    id: u64,
     text: String,
-    embedding: Vec<f32>,
+    // L2-normalized, so a dot product with it is a cosine similarity
+    embedding: StoredEmbedding,
     source: String,
+    // Other documents containing this exact text; the chunk outlives `source`
+    // while any of them remain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    shared_with: Vec<String>,
+    // Named entities and key phrases, for filtering searches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keywords: Vec<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 struct ChatRequest {
     query: String,
     #[serde(default)]
     deep_think: bool,
+    #[serde(default)]
+    length: AnswerLength,
+    // Overrides `Config::min_similarity` for this request
+    #[serde(default)]
+    min_similarity: Option<f32>,
+    // Have the LLM rephrase the query and search with every phrasing
+    #[serde(default)]
+    rewrite_query: bool,
+    // Check the answer's sentences against the retrieved context afterwards
+    #[serde(default)]
+    verify: bool,
+    // Prompt template to answer with; `grounded` (or `deep_think`) by default
+    #[serde(default)]
+    template: Option<String>,
+    // Earlier turns, sent to the model as chat messages
+    #[serde(default)]
+    history: Vec<HistoryTurn>,
+    // Only search these documents; every document when empty
+    #[serde(default)]
+    sources: Vec<String>,
+    // Only search documents in this collection
+    #[serde(default)]
+    collection: Option<String>,
+    // Session to append this turn to; its earlier turns are the history
+    // when `history` is empty
+    #[serde(default)]
+    session_id: Option<String>,
+    // Return per-stage `timings` with the answer
+    #[serde(default)]
+    debug: bool,
+    // Ollama model to answer with instead of `Config::model`
+    #[serde(default)]
+    model: Option<String>,
+    // What goes into the prompt for each retrieved chunk
+    #[serde(default)]
+    strategy: RetrievalStrategy,
+    // Overrides `Config::recency_weight` for this request
+    #[serde(default)]
+    recency_weight: Option<f32>,
+    // Only search chunks tagged with one of these keywords or entities
+    #[serde(default)]
+    keywords: Vec<String>,
+    // Only search documents carrying one of these category tags
+    #[serde(default)]
+    tags: Vec<String>,
+    // Chunks retrieved into the prompt, capped at `MAX_TOP_K`; `TOP_K` if unset
+    #[serde(default)]
+    top_k: Option<usize>,
+}
+
+/// Questions for `POST /chat/batch`, each a bare query string or a full
+/// chat request with its own options.
+#[derive(Deserialize)]
+struct BatchChatRequest {
+    queries: Vec<BatchQuery>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchQuery {
+    Text(String),
+    Request(Box<ChatRequest>),
+}
+
+#[derive(Deserialize)]
+struct HistoryTurn {
+    // "user" or "assistant"
+    role: String,
+    content: String,
+}
+
+/// Verbosity preset chosen in the UI; scales the generation budget and adds
+/// a phrasing hint to the prompt.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AnswerLength {
+    Short,
+    #[default]
+    Normal,
+    Detailed,
+}
+
+/// How retrieved chunks become context blocks.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RetrievalStrategy {
+    /// The matched chunks themselves
+    #[default]
+    Chunk,
+    /// The parent section around each matched chunk
+    Parent,
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    #[serde(default)]
+    on_conflict: OnConflict,
+    // Collection to file the uploaded documents under
+    #[serde(default)]
+    collection: Option<String>,
+    // Date (YYYY-MM-DD) to give the uploaded documents
+    #[serde(default)]
+    date: Option<String>,
+}
+
+/// What to do when an upload's filename is already indexed with different
+/// content.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OnConflict {
+    /// Swap the new content in for the old document's chunks
+    #[default]
+    Replace,
+    /// Keep both, indexing the new content as `name.v2.ext`, `name.v3.ext`, ...
+    Version,
 }
 
 #[derive(Serialize)]
 struct ChatResponse {
     answer: String,
     sources: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    scores: Vec<ChunkScore>,
+    // Rephrasings searched alongside the query when `rewrite_query` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rewrites: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<ThinkStep>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    citations: Vec<Citation>,
+    // Present when `verify` was requested and the answer had sentences to check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grounding: Option<Grounding>,
+    // Present when `debug` was requested and the answer came from the model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<StageTimings>,
+    // Present when the content-safety filter refused the query or changed
+    // the answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moderation: Option<ModerationDecision>,
 }
 
+/// One line of an evaluation set: a question, the document that should
+/// answer it and, optionally, a reference answer.
 #[derive(Deserialize)]
-struct DeleteRequest {
-    filename: String,
+struct EvalCase {
+    question: String,
+    expected_source: String,
+    #[serde(default)]
+    expected_answer: Option<String>,
 }
 
-#[derive(Serialize)]
-struct DeleteResponse {
-    status: String,
-    message: String,
+#[derive(Deserialize)]
+struct EvalParams {
+    // Label shown when comparing runs
+    #[serde(default)]
+    name: Option<String>,
+    // Retrieved chunks counted by recall@k and MRR
+    #[serde(default)]
+    k: Option<usize>,
+    // Skip generation and only measure retrieval
+    #[serde(default)]
+    retrieval_only: bool,
 }
 
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+/// An evaluation set run against the index as it was at the time, with the
+/// settings that produced it so runs can be compared.
+#[derive(Clone, Serialize, Deserialize)]
+struct EvalRun {
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    status: JobStatus,
+    k: usize,
+    retrieval_only: bool,
+    questions: usize,
+    completed: usize,
+    // Unix seconds
+    started_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    settings: EvalSettings,
+    // Present once the run has finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metrics: Option<EvalMetrics>,
+    // Left out of run listings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    results: Vec<EvalResult>,
 }
 
-#[derive(Deserialize)]
-struct OllamaResponse {
-    response: String,
+#[derive(Clone, Serialize, Deserialize)]
+struct EvalSettings {
+    chunk_size: usize,
+    chunk_overlap: usize,
+    min_similarity: f32,
+    pipeline: Vec<RetrievalStage>,
+    embedding_model: String,
+    model: String,
+    documents: usize,
+    chunks: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct EvalMetrics {
+    // Share of questions whose expected source is in the top k chunks
+    recall_at_k: f32,
+    // Mean of 1 / rank of the expected source's first chunk (0 when missed)
+    mrr: f32,
+    // Mean cosine similarity of answers to the reference answers, for the
+    // questions that have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answer_similarity: Option<f32>,
+    // Share of answers that list the expected source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_cited: Option<f32>,
+    // Questions whose retrieval or generation failed; they count as misses
+    errors: usize,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+struct EvalResult {
+    question: String,
+    expected_source: String,
+    // 1-based position of the expected source's first chunk within the top k
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rank: Option<usize>,
+    // Sources of the top k chunks, best first
+    retrieved: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answer_similarity: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_cited: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A rating of one answer, sent from the 👍/👎 buttons.
 #[derive(Deserialize)]
-struct OllamaStreamResponse {
-    response: String,
-    done: bool,
+struct FeedbackRequest {
+    query: String,
+    answer: String,
+    #[serde(default)]
+    sources: Vec<String>,
+    rating: Rating,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    session_id: Option<String>,
 }
 
-// ============================================================================
-// VECTOR STORE IMPLEMENTATION
-// ============================================================================
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Rating {
+    Up,
+    Down,
+}
 
-impl VectorStore {
-    fn new() -> Self {
-        Self { chunks: Vec::new() }
-    }
+/// One line of `feedback.jsonl`.
+#[derive(Serialize, Deserialize)]
+struct FeedbackRecord {
+    id: String,
+    // Unix seconds
+    created_at: u64,
+    rating: Rating,
+    query: String,
+    answer: String,
+    sources: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+}
 
-    fn add(&mut self, text: String, embedding: Vec<f32>, source: String) {
-        self.chunks.push(ChunkData {
-            text,
-            embedding,
-            source,
-        });
-    }
+#[derive(Deserialize)]
+struct FeedbackParams {
+    // Only export ratings of this kind
+    #[serde(default)]
+    rating: Option<Rating>,
+}
 
-    fn remove_by_source(&mut self, source: &str) -> usize {
-        let initial_count = self.chunks.len();
-        self.chunks.retain(|chunk| chunk.source != source);
-        initial_count - self.chunks.len()
-    }
+#[derive(Serialize)]
+struct EvalsResponse {
+    runs: Vec<EvalRun>,
+}
 
-    fn search(&self, query_embedding: &[f32], top_k: usize) -> Vec<(String, String, f32)> {
-        if self.chunks.is_empty() {
-            return Vec::new();
-        }
+#[derive(Serialize)]
+struct BatchChatResponse {
+    // One per query, in the order they were sent
+    results: Vec<BatchChatResult>,
+    answered: usize,
+    failed: usize,
+    total_ms: u64,
+}
 
-        let query_vec = Array1::from_vec(query_embedding.to_vec());
-        let mut scores: Vec<(usize, f32)> = self
-            .chunks
-            .iter()
-            .enumerate()
-            .map(|(idx, chunk)| {
-                let chunk_vec = Array1::from_vec(chunk.embedding.clone());
-                let similarity = cosine_similarity(query_vec.view(), chunk_vec.view());
-                (idx, similarity)
-            })
-            .collect();
+/// A batch query with either its answer's fields or an `error`.
+#[derive(Serialize)]
+struct BatchChatResult {
+    query: String,
+    #[serde(flatten)]
+    response: Option<ChatResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
 
-        // Sort by similarity (highest first)
-        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+/// Milliseconds spent in each stage of answering a question. Stages that
+/// didn't run are 0.
+#[derive(Clone, Default, Serialize)]
+struct StageTimings {
+    // LLM rephrasing of the query (`rewrite_query`)
+    rewrite_ms: u64,
+    // LLM sub-question splitting and answering (`deep_think`)
+    think_ms: u64,
+    embed_ms: u64,
+    search_ms: u64,
+    prompt_ms: u64,
+    // Until Ollama's answer is complete
+    generate_ms: u64,
+    verify_ms: u64,
+    total_ms: u64,
+}
 
-        scores
-            .into_iter()
-            .take(top_k)
-            .map(|(idx, score)| {
-                let chunk = &self.chunks[idx];
-                (chunk.text.clone(), chunk.source.clone(), score)
-            })
-            .collect()
+impl StageTimings {
+    fn log(&self) {
+        info!(
+            "Answered in {} ms (rewrite {}, think {}, embed {}, search {}, prompt {}, generate {}, verify {})",
+            self.total_ms,
+            self.rewrite_ms,
+            self.think_ms,
+            self.embed_ms,
+            self.search_ms,
+            self.prompt_ms,
+            self.generate_ms,
+            self.verify_ms
+        );
     }
+}
 
-    fn count(&self) -> usize {
-        self.chunks.len()
-    }
+fn ms_since(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
 }
 
-fn cosine_similarity(a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
-    let dot = a.dot(&b);
-    let norm_a = a.dot(&a).sqrt();
-    let norm_b = b.dot(&b).sqrt();
+/// How well an answer is supported by the context it was generated from.
+#[derive(Clone, Serialize)]
+struct Grounding {
+    // Share of checked sentences that are supported, 0-1
+    score: f32,
+    sentences: Vec<SentenceSupport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
 
-    if norm_a == 0.0 || norm_b == 0.0 {
-        return 0.0;
-    }
+#[derive(Clone, Serialize)]
+struct SentenceSupport {
+    text: String,
+    // Similarity to the closest context passage
+    support: f32,
+    supported: bool,
+}
 
-    dot / (norm_a * norm_b)
+/// A numbered context block the answer may cite as `[number]`.
+#[derive(Clone, Serialize)]
+struct Citation {
+    number: usize,
+    source: String,
+    chunk_id: u64,
+    score: f32,
+    // The block's text as the model saw it
+    text: String,
 }
 
-// ============================================================================
-// TEXT PROCESSING
-// ============================================================================
+/// One sub-question of a deep-think answer and what the documents say about it.
+#[derive(Clone, Serialize)]
+struct ThinkStep {
+    question: String,
+    answer: String,
+    sources: Vec<String>,
+}
 
-fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
-    let words: Vec<&str> = text.split_whitespace().collect();
+/// Similarity of one retrieved chunk to the query, best first.
+#[derive(Clone, Serialize)]
+struct ChunkScore {
+    source: String,
+    score: f32,
+}
 
-    if words.is_empty() {
-        return Vec::new();
-    }
+/// Whether answers can be generated right now, for the UI's status indicator.
+#[derive(Serialize)]
+struct HealthResponse {
+    // "ok" when embeddings work and Ollama is up with the model pulled,
+    // "degraded" otherwise
+    status: &'static str,
+    embedding_model: String,
+    // A probe sentence embedded successfully
+    embedding_ready: bool,
+    ollama: bool,
+    model: String,
+    model_available: bool,
+    // Already in memory, so the first answer won't wait for it to load
+    model_loaded: bool,
+    documents: usize,
+    chunks: usize,
+    uptime_secs: u64,
+}
 
-    let mut chunks = Vec::new();
-    let mut start = 0;
+/// `GET /config`: the settings clients check uploads against before
+/// sending them.
+#[derive(Serialize)]
+struct PublicConfig {
+    // Largest request body `/upload` accepts, in MB
+    max_upload_mb: usize,
+    // File name endings `/upload` indexes, e.g. ".md"
+    upload_extensions: Vec<String>,
+    // `POST /transcribe` is set up
+    transcription: bool,
+    // `POST /tts` is set up
+    speech: bool,
+}
 
-    while start < words.len() {
-        let end = (start + chunk_size).min(words.len());
-        let chunk = words[start..end].join(" ");
-        chunks.push(chunk);
+#[derive(Deserialize)]
+struct SimilarParams {
+    // Results to return, capped at `MAX_SIMILAR_RESULTS`
+    #[serde(default)]
+    limit: Option<usize>,
+}
 
-        if end >= words.len() {
-            break;
-        }
+#[derive(Serialize)]
+struct SimilarDocumentsResponse {
+    document: String,
+    similar: Vec<SimilarDocument>,
+}
 
-        start += chunk_size - overlap;
-    }
+#[derive(Serialize)]
+struct SimilarDocument {
+    name: String,
+    // Cosine similarity of the two documents' mean chunk embeddings
+    score: f32,
+    // Chunks the two documents contain verbatim
+    shared_chunks: usize,
+}
 
-    chunks
+#[derive(Serialize)]
+struct RelatedChunksResponse {
+    chunk_id: u64,
+    related: Vec<RelatedChunk>,
 }
 
-fn extract_text_from_md(content: &[u8]) -> Result<String, String> {
-    String::from_utf8(content.to_vec()).map_err(|e| format!("Invalid UTF-8: {}", e))
+#[derive(Serialize)]
+struct RelatedChunk {
+    id: u64,
+    source: String,
+    score: f32,
+    text: String,
 }
 
-fn extract_text_from_pdf(content: &[u8]) -> Result<String, String> {
-    // Try lopdf first
-    match lopdf::Document::load_mem(content) {
-        Ok(doc) => {
-            let mut text = String::new();
-            let pages = doc.get_pages();
+/// A read-only snapshot of a conversation, published under a share token.
+#[derive(Clone, Serialize, Deserialize)]
+struct SharedConversation {
+    messages: Vec<SharedMessage>,
+    // Unix seconds
+    created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
 
-            for (page_num, _) in pages.iter() {
-                if let Ok(page_text) = doc.extract_text(&[*page_num]) {
-                    text.push_str(&page_text);
-                    text.push('\n');
-                }
-            }
+#[derive(Clone, Serialize, Deserialize)]
+struct SharedMessage {
+    // "user" or "assistant"
+    role: String,
+    content: String,
+    #[serde(default)]
+    sources: Vec<String>,
+}
 
-            if !text.trim().is_empty() {
-                return Ok(text);
-            }
-        }
-        Err(e) => {
-            warn!("lopdf failed: {}, trying pdf-extract", e);
-        }
-    }
+#[derive(Deserialize)]
+struct ShareRequest {
+    messages: Vec<SharedMessage>,
+    // Never expires when absent
+    #[serde(default)]
+    expires_in_hours: Option<u64>,
+}
 
-    // Fallback to pdf-extract
-    match pdf_extract::extract_text_from_mem(content) {
-        Ok(text) => {
-            if text.trim().is_empty() {
-                Err("PDF contains no extractable text".to_string())
-            } else {
-                Ok(text)
-            }
-        }
-        Err(e) => Err(format!("Failed to extract PDF text: {}", e)),
-    }
+/// A conversation kept on the server so it can be resumed later.
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    // Taken from the first question when not given
+    title: String,
+    // Unix seconds
+    created_at: u64,
+    updated_at: u64,
+    messages: Vec<SessionMessage>,
 }
 
-// ============================================================================
-// HELPERS
-// ============================================================================
+#[derive(Clone, Serialize, Deserialize)]
+struct SessionMessage {
+    // "user" or "assistant"
+    role: String,
+    content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<String>,
+    // Unix seconds
+    created_at: u64,
+}
 
-async fn warm_up_ollama(client: &reqwest::Client) {
-    info!("🔥 Warming up Ollama connection...");
-    let _ = client
-        .post("http://localhost:11434/api/generate")
-        .json(&serde_json::json!({
-            "model": "phi3",
-            "prompt": "Hi",
-            "stream": false,
-            "options": {"num_predict": 1}
-        }))
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await;
-    info!("✅ Ollama warm-up complete");
+#[derive(Default, Deserialize)]
+struct CreateSessionRequest {
+    #[serde(default)]
+    title: Option<String>,
 }
 
-// ============================================================================
-// HANDLERS
-// ============================================================================
+#[derive(Deserialize)]
+struct RenameSessionRequest {
+    title: String,
+}
 
-async fn health_check() -> &'static str {
-    "OK"
+#[derive(Serialize)]
+struct TenantsResponse {
+    tenants: Vec<TenantUsage>,
+}
+
+#[derive(Serialize)]
+struct TenantUsage {
+    name: String,
+    api_keys: usize,
+    // Whether the tenant's data is in memory; the counts are `None` until it is
+    loaded: bool,
+    documents: Option<usize>,
+    chunks: Option<usize>,
+    sessions: Option<usize>,
+    // Size of the tenant's store snapshot on disk
+    store_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct TruncateSessionParams {
+    // Index of the first message to remove
+    from: usize,
+}
+
+#[derive(Serialize)]
+struct SessionSummary {
+    id: String,
+    title: String,
+    created_at: u64,
+    updated_at: u64,
+    messages: usize,
+}
+
+#[derive(Serialize)]
+struct SessionsResponse {
+    sessions: Vec<SessionSummary>,
+}
+
+#[derive(Serialize)]
+struct SessionMessagesResponse {
+    id: String,
+    title: String,
+    messages: Vec<SessionMessage>,
+}
+
+#[derive(Serialize)]
+struct ShareResponse {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct EmbedRequest {
+    texts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct EmbedResponse {
+    model: String,
+    dimensions: usize,
+    // One vector per input text, in order
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct DeleteRequest {
+    filename: String,
+}
+
+/// `GET /export`: a tenant's whole index in a form another backend can
+/// `POST /import` without re-embedding, every embedding in full precision.
+#[derive(Serialize, Deserialize)]
+struct IndexArchive {
+    // Always `ARCHIVE_FORMAT`
+    format: String,
+    version: u32,
+    // Unix seconds
+    exported_at: u64,
+    embedding_model: String,
+    embedding_dimensions: usize,
+    documents: HashMap<String, DocumentRecord>,
+    chunks: Vec<ChunkData>,
+    next_chunk_id: u64,
+}
+
+#[derive(Deserialize)]
+struct TranscribeParams {
+    // Spoken language as an ISO 639-1 code or BCP 47 tag ("de", "pt-BR");
+    // whisper.cpp detects it when unset
+    language: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TranscribeResponse {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct TtsRequest {
+    text: String,
+    // Relative reading speed, 1.0 being the voice's own
+    #[serde(default)]
+    speed: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    // Replace an index that already holds documents
+    #[serde(default)]
+    replace: bool,
+}
+
+/// `POST /reset`: removes every document, or a collection's documents.
+#[derive(Deserialize)]
+struct ResetRequest {
+    // Must be true; guards against wiping the index by accident
+    #[serde(default)]
+    confirm: bool,
+    #[serde(default)]
+    collection: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteResponse {
+    status: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    query: String,
+    // Results to return, capped at `MAX_SEARCH_RESULTS`
+    #[serde(default)]
+    top_k: Option<usize>,
+    // Overrides `Config::min_similarity` for this request
+    #[serde(default)]
+    min_similarity: Option<f32>,
+    // Only search these documents; every document when empty
+    #[serde(default)]
+    sources: Vec<String>,
+    // Only search documents in this collection
+    #[serde(default)]
+    collection: Option<String>,
+    // Overrides `Config::recency_weight` for this request
+    #[serde(default)]
+    recency_weight: Option<f32>,
+    // Only search chunks tagged with one of these keywords or entities
+    #[serde(default)]
+    keywords: Vec<String>,
+    // Only search documents carrying one of these category tags
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    query: String,
+    results: Vec<SearchResult>,
+}
+
+/// A retrieved passage and where it sits in its document.
+#[derive(Serialize)]
+struct SearchResult {
+    id: u64,
+    source: String,
+    score: f32,
+    text: String,
+    // Position of the chunk among its document's chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_index: Option<usize>,
+    // Byte range of `text` in the document's extracted text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<usize>,
+    // Entities and key phrases extracted from the chunk
+    keywords: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TemplatesResponse {
+    templates: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DocumentsResponse {
+    documents: Vec<String>,
+    // Document name -> its size, so a listing needs no request per document
+    sizes: BTreeMap<String, DocumentSize>,
+}
+
+#[derive(Serialize)]
+struct DocumentSize {
+    chunks: usize,
+    words: usize,
+}
+
+#[derive(Serialize)]
+struct CollectionsResponse {
+    collections: Vec<CollectionInfo>,
+}
+
+#[derive(Serialize)]
+struct TagsResponse {
+    tags: Vec<TagInfo>,
+}
+
+#[derive(Serialize)]
+struct TagInfo {
+    name: String,
+    documents: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CollectionInfo {
+    name: String,
+    documents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CollectionDocumentsRequest {
+    documents: Vec<String>,
+}
+
+/// `PATCH /documents/:name`: metadata to change; `"date": null` clears it.
+#[derive(Deserialize)]
+struct UpdateDocumentRequest {
+    date: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OverviewParams {
+    // Regenerate even if the corpus hasn't changed since the last overview
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Clone, Serialize)]
+struct OverviewResponse {
+    // Absent when the corpus is empty or Ollama couldn't write one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overview: Option<String>,
+    documents: usize,
+    chunks: usize,
+    words: usize,
+    topics: Vec<DocumentTopics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    // Unix seconds
+    generated_at: u64,
+}
+
+/// What one document covers, from its ingestion summary.
+#[derive(Clone, Serialize)]
+struct DocumentTopics {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    chunks: usize,
+    words: usize,
+}
+
+/// `GET /documents/:name/preview`: the text a document was indexed from.
+#[derive(Serialize)]
+struct DocumentPreview {
+    name: String,
+    // "markdown", "pdf" or "text"
+    format: &'static str,
+    // The extracted text, one entry per page for PDFs
+    pages: Vec<String>,
+    // Cut off after `MAX_PREVIEW_CHARS`
+    truncated: bool,
+}
+
+/// `GET /documents/:name/stats`: size and provenance of one document.
+#[derive(Serialize)]
+struct DocumentStats {
+    name: String,
+    chunks: usize,
+    words: usize,
+    characters: usize,
+    // PDFs only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<usize>,
+    // From the ingestion report; absent for documents ingested before
+    // reports were kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ingested_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    // Model the document's chunks were embedded with
+    embedding_model: String,
+    embedding_dimensions: usize,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    documents: usize,
+    chunks: usize,
+    languages: Vec<LanguageCount>,
+}
+
+#[derive(Serialize)]
+struct LanguageCount {
+    code: String,
+    name: String,
+    documents: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileStage {
+    Queued,
+    Extracting,
+    Chunking,
+    Embedding,
+    Summarizing,
+    Done,
+    // Identical to the indexed copy, so nothing was re-embedded
+    Unchanged,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+struct FileProgress {
+    filename: String,
+    stage: FileStage,
+    percent: u8,
+    chunks: usize,
+    duplicates_skipped: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct UploadJob {
+    id: String,
+    status: JobStatus,
+    percent: u8,
+    files: Vec<FileProgress>,
+    total_chunks: usize,
+    duplicates_skipped: usize,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+    // Only this tenant can poll the job
+    #[serde(skip)]
+    tenant: String,
+}
+
+/// Files handed from `/upload` to the background worker, keyed by their
+/// index in `UploadJob::files`.
+struct QueuedUpload {
+    job_id: String,
+    tenant: String,
+    files: Vec<(usize, String, Bytes)>,
+    on_conflict: OnConflict,
+    collection: Option<String>,
+    date: Option<String>,
+    // Files from the watch folder rather than `/upload`
+    watched: bool,
+}
+
+/// Body of every error response: `{"error": {...}}`.
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+/// A failed request. `request_id` is logged alongside the message so a
+/// report from the UI can be matched to the server log; `retryable` tells
+/// clients whether the same request may succeed later.
+#[derive(Clone, Debug, Serialize)]
+struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    request_id: String,
+    retryable: bool,
+}
+
+/// One message of an Ollama `/api/chat` conversation.
+#[derive(Serialize, Deserialize)]
+struct OllamaMessage {
+    // "system", "user" or "assistant"
+    role: String,
+    content: String,
+}
+
+impl OllamaMessage {
+    fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: OllamaMessage,
+}
+
+#[derive(Deserialize)]
+struct OllamaStreamResponse {
+    // The final line may carry an empty message or none at all
+    #[serde(default)]
+    message: Option<OllamaMessage>,
+    done: bool,
+}
+
+// ============================================================================
+// CONFIGURATION
+// ============================================================================
+
+/// Placeholders every prompt template must contain. A template is the
+/// system message; the question follows as the user message. Templates may
+/// also use `{question}`, `{history}` (earlier turns are then not sent as
+/// messages), `{findings}` (deep think), `{instruction}` (answer length) and
+/// `{language}` (the question's language).
+const REQUIRED_PLACEHOLDERS: [&str; 1] = ["{context}"];
+
+const GROUNDED_TEMPLATE: &str = r#"You answer questions about the user's documents using ONLY the context below. Cite the numbered blocks you use, like [1] or [2]. If the answer is not in the context, say "I don't know based on the provided documents." Answer in {language}.{instruction}
+
+Context:
+{context}"#;
+
+const DEEP_THINK_TEMPLATE: &str = r#"Answer the question using ONLY the findings and context below, combining the findings into one coherent answer. Cite the numbered context blocks you use, like [1] or [2]. If they do not answer it, say "I don't know based on the provided documents." Answer in {language}.{instruction}
+
+Findings:
+{findings}
+
+Context:
+{context}"#;
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("data"),
+            chunk_size: 256,
+            parent_size: 768,
+            chunk_overlap: 50,
+            model: "phi3".to_string(),
+            vision_model: "llava".to_string(),
+            describe_pdf_images: true,
+            max_pdf_images: 20,
+            embedding_model: "AllMiniLML6V2".to_string(),
+            embedding_workers: 2,
+            min_similarity: 0.3,
+            routing_documents: 3,
+            mmr_lambda: 0.7,
+            recency_weight: 0.0,
+            recency_half_life_days: 180.0,
+            max_pending_uploads: 8,
+            upload_concurrency: 0,
+            neighbor_window: 1,
+            embedding_storage: EmbeddingStorage::F32,
+            mmap_embeddings: false,
+            embed_api_keys: Vec::new(),
+            api_keys: HashMap::new(),
+            admin_api_keys: Vec::new(),
+            rate_limit_per_minute: 60,
+            max_upload_mb: 50,
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            tls_cert: None,
+            tls_key: None,
+            http_redirect_port: None,
+            public_host: None,
+            cors_origins: vec![
+                "http://localhost:8080".to_string(),
+                "http://127.0.0.1:8080".to_string(),
+            ],
+            cors_methods: ["GET", "POST", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            cors_headers: ["content-type", "authorization"].map(String::from).to_vec(),
+            max_embed_batch: 64,
+            max_batch_queries: 50,
+            batch_concurrency: 2,
+            max_eval_questions: 500,
+            audit_log: false,
+            audit_log_max_mb: 10,
+            audit_log_files: 5,
+            tokenizer: None,
+            prompts: HashMap::new(),
+            prompt_dir: None,
+            pipeline: vec![
+                RetrievalStage::Route,
+                RetrievalStage::Dense,
+                RetrievalStage::Mmr,
+                RetrievalStage::Expand,
+                RetrievalStage::Dedupe,
+                RetrievalStage::Recency,
+                RetrievalStage::Threshold,
+            ],
+            categories: [
+                "invoice",
+                "contract",
+                "specification",
+                "meeting notes",
+                "report",
+                "policy",
+                "manual",
+                "article",
+            ]
+            .map(String::from)
+            .to_vec(),
+            redact_pii: Vec::new(),
+            watch_dir: None,
+            blocked_terms: Vec::new(),
+            moderation_llm: false,
+            moderation_categories: [
+                "violence",
+                "weapons",
+                "self-harm",
+                "sexual content",
+                "hate speech",
+                "illegal drugs",
+            ]
+            .map(String::from)
+            .to_vec(),
+            moderation_refusal: "I can't help with that request.".to_string(),
+            whisper_cli: None,
+            whisper_model: None,
+            ffmpeg: PathBuf::from("ffmpeg"),
+            tts: None,
+        }
+    }
+}
+
+/// The fastembed model called `name`, either its variant name
+/// (`BGESmallENV15`) or its model code (`Xenova/bge-small-en-v1.5`).
+fn embedding_model_info(name: &str) -> Option<ModelInfo<EmbeddingModel>> {
+    TextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| {
+            format!("{:?}", info.model).eq_ignore_ascii_case(name)
+                || info.model_code.eq_ignore_ascii_case(name)
+        })
+}
+
+impl Config {
+    /// Loads `ahtohallan.toml` (or the file named by `AHTOHALLAN_CONFIG`),
+    /// falling back to defaults for anything missing.
+    fn load() -> Self {
+        let path =
+            std::env::var("AHTOHALLAN_CONFIG").unwrap_or_else(|_| "ahtohallan.toml".to_string());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str::<Config>(&contents) {
+                Ok(config) => {
+                    info!("⚙️  Loaded config from {}", path);
+                    config
+                }
+                Err(e) => {
+                    warn!("⚠️  Invalid config {}: {}, using defaults", path, e);
+                    Config::default()
+                }
+            },
+            Err(_) => {
+                info!("⚙️  No config file at {}, using defaults", path);
+                Config::default()
+            }
+        };
+
+        // chunk_text advances by chunk_size - overlap, so it must stay positive
+        if config.chunk_size == 0 || config.chunk_overlap >= config.chunk_size {
+            let defaults = Config::default();
+            warn!(
+                "⚠️  Invalid chunking settings ({} / {}), using {} / {}",
+                config.chunk_size,
+                config.chunk_overlap,
+                defaults.chunk_size,
+                defaults.chunk_overlap
+            );
+            config.chunk_size = defaults.chunk_size;
+            config.chunk_overlap = defaults.chunk_overlap;
+        }
+        if config.parent_size < config.chunk_size {
+            warn!(
+                "⚠️  parent_size {} is smaller than chunk_size {}, using {}",
+                config.parent_size,
+                config.chunk_size,
+                config.chunk_size * 3
+            );
+            config.parent_size = config.chunk_size * 3;
+        }
+
+        match model_name(&config.model) {
+            Ok(model) => config.model = model,
+            Err(_) => {
+                let defaults = Config::default();
+                warn!(
+                    "⚠️  Invalid model {:?}, using {}",
+                    config.model, defaults.model
+                );
+                config.model = defaults.model;
+            }
+        }
+        match embedding_model_info(&config.embedding_model) {
+            Some(info) => config.embedding_model = format!("{:?}", info.model),
+            None => {
+                let defaults = Config::default();
+                warn!(
+                    "⚠️  Unknown embedding_model {:?}, using {}",
+                    config.embedding_model, defaults.embedding_model
+                );
+                config.embedding_model = defaults.embedding_model;
+            }
+        }
+        config.embedding_workers = config.embedding_workers.max(1);
+        config.min_similarity = config.min_similarity.clamp(-1.0, 1.0);
+        config.mmr_lambda = config.mmr_lambda.clamp(0.0, 1.0);
+        let mut categories: Vec<String> = Vec::new();
+        for category in &config.categories {
+            let category = category.trim();
+            if !category.is_empty() && !categories.iter().any(|c| c == category) {
+                categories.push(category.to_string());
+            }
+        }
+        config.categories = categories;
+        config.redact_pii.sort();
+        config.redact_pii.dedup();
+        for terms in [&mut config.blocked_terms, &mut config.moderation_categories] {
+            let mut kept: Vec<String> = Vec::new();
+            for term in terms.iter() {
+                let term = term.trim().to_lowercase();
+                if !term.is_empty() && !kept.contains(&term) {
+                    kept.push(term);
+                }
+            }
+            *terms = kept;
+        }
+        if config.moderation_llm && config.moderation_categories.is_empty() {
+            warn!("⚠️  Ignoring moderation_llm: moderation_categories is empty");
+            config.moderation_llm = false;
+        }
+        if config.moderation_refusal.trim().is_empty() {
+            config.moderation_refusal = Config::default().moderation_refusal;
+        }
+        config.recency_weight = config.recency_weight.clamp(0.0, 1.0);
+        config.recency_half_life_days = config.recency_half_life_days.max(1.0);
+        config.max_pending_uploads = config.max_pending_uploads.max(1);
+        if config.upload_concurrency == 0 {
+            config.upload_concurrency =
+                std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        }
+        config.max_embed_batch = config.max_embed_batch.max(1);
+        config.max_batch_queries = config.max_batch_queries.max(1);
+        config.batch_concurrency = config.batch_concurrency.max(1);
+        config.max_eval_questions = config.max_eval_questions.max(1);
+        config.audit_log_max_mb = config.audit_log_max_mb.max(1);
+        config.audit_log_files = config.audit_log_files.max(1);
+        config.max_upload_mb = config.max_upload_mb.max(1);
+        config.api_keys.retain(|_, tenant| {
+            let valid = tenant_name(tenant);
+            if !valid {
+                warn!(
+                    "⚠️  Ignoring API key for tenant {:?}: names are 1-64 letters, digits, - or _",
+                    tenant
+                );
+            }
+            valid
+        });
+        if config.http_redirect_port.is_some() && config.tls_cert.is_none() {
+            warn!("⚠️  Ignoring http_redirect_port: it needs tls_cert and tls_key");
+            config.http_redirect_port = None;
+        }
+        config.public_host = config
+            .public_host
+            .take()
+            .map(|host| host.trim().trim_end_matches('/').to_string())
+            .filter(|host| !host.is_empty());
+        if config.http_redirect_port.is_some() && config.public_host.is_none() {
+            warn!("⚠️  Ignoring http_redirect_port: it needs public_host to redirect to");
+            config.http_redirect_port = None;
+        }
+        config.load_prompts();
+
+        let retrieves = config
+            .pipeline
+            .iter()
+            .any(|stage| matches!(stage, RetrievalStage::Dense | RetrievalStage::Sparse));
+        if !retrieves {
+            warn!(
+                "⚠️  Pipeline {:?} has no dense or sparse stage, using the default",
+                config.pipeline
+            );
+            config.pipeline = Config::default().pipeline;
+        }
+
+        config
+    }
+
+    /// Adds the templates in `prompt_dir`, drops templates missing a required
+    /// placeholder and fills in the built-in ones that weren't overridden.
+    fn load_prompts(&mut self) {
+        if let Some(dir) = &self.prompt_dir {
+            match std::fs::read_dir(dir) {
+                Ok(entries) => {
+                    for path in entries.flatten().map(|entry| entry.path()) {
+                        if path.extension().is_none_or(|ext| ext != "txt") {
+                            continue;
+                        }
+                        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                            continue;
+                        };
+                        match std::fs::read_to_string(&path) {
+                            Ok(template) => {
+                                self.prompts.insert(name.to_string(), template);
+                            }
+                            Err(e) => warn!("⚠️  Can't read prompt {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                Err(e) => warn!("⚠️  Can't read prompt_dir {}: {}", dir.display(), e),
+            }
+        }
+
+        self.prompts.retain(|name, template| {
+            let missing: Vec<&str> = REQUIRED_PLACEHOLDERS
+                .iter()
+                .copied()
+                .filter(|placeholder| !template.contains(placeholder))
+                .collect();
+            if !missing.is_empty() {
+                warn!(
+                    "⚠️  Prompt template {} lacks {}, ignoring it",
+                    name,
+                    missing.join(" and ")
+                );
+            }
+            missing.is_empty()
+        });
+        for (name, template) in [
+            ("grounded", GROUNDED_TEMPLATE),
+            ("deep_think", DEEP_THINK_TEMPLATE),
+        ] {
+            self.prompts
+                .entry(name.to_string())
+                .or_insert_with(|| template.to_string());
+        }
+    }
+
+    /// Whether the embedding model maps every language into one space, so a
+    /// German question can retrieve English passages.
+    fn multilingual_embeddings(&self) -> bool {
+        let description = self.embedding_model_info().description.to_lowercase();
+        description.contains("multilingual") || description.contains("multi-lingual")
+    }
+
+    /// The fastembed model `embedding_model` names, which `load` checked.
+    fn embedding_model_info(&self) -> ModelInfo<EmbeddingModel> {
+        embedding_model_info(&self.embedding_model).expect("embedding_model checked on load")
+    }
+
+    fn store_path(&self) -> PathBuf {
+        self.data_dir.join("store.json")
+    }
+
+    fn shares_path(&self) -> PathBuf {
+        self.data_dir.join("shares.json")
+    }
+
+    fn sessions_path(&self) -> PathBuf {
+        self.data_dir.join("sessions.json")
+    }
+
+    fn evals_path(&self) -> PathBuf {
+        self.data_dir.join("evals.json")
+    }
+
+    fn feedback_path(&self) -> PathBuf {
+        self.data_dir.join("feedback.jsonl")
+    }
+
+    /// Where moderation events are appended, one JSON object per line.
+    fn moderation_path(&self) -> PathBuf {
+        self.data_dir.join("moderation.jsonl")
+    }
+
+    /// The live audit log for `generation` 0, and its rotated copies after.
+    fn audit_path(&self, generation: usize) -> PathBuf {
+        if generation == 0 {
+            self.data_dir.join("audit.jsonl")
+        } else {
+            self.data_dir.join(format!("audit.{}.jsonl", generation))
+        }
+    }
+
+    /// The CORS policy from `cors_*`, skipping entries that don't parse.
+    /// With `allow_any_origin` every origin is allowed.
+    fn cors_layer(&self, allow_any_origin: bool) -> CorsLayer {
+        fn parsed<T>(values: &[String], what: &str, parse: impl Fn(&str) -> Option<T>) -> Vec<T> {
+            values
+                .iter()
+                .filter_map(|value| {
+                    let item = parse(value);
+                    if item.is_none() {
+                        warn!("⚠️  Ignoring invalid CORS {} {:?}", what, value);
+                    }
+                    item
+                })
+                .collect()
+        }
+
+        let origins = if allow_any_origin {
+            AllowOrigin::from(Any)
+        } else {
+            AllowOrigin::list(parsed(&self.cors_origins, "origin", |origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/')).ok()
+            }))
+        };
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(parsed(&self.cors_methods, "method", |method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes()).ok()
+            }))
+            .allow_headers(parsed(&self.cors_headers, "header", |name| {
+                HeaderName::from_bytes(name.as_bytes()).ok()
+            }))
+            // The web UI waits as long as a busy upload queue asks
+            .expose_headers([header::RETRY_AFTER])
+    }
+
+    /// Server settings for `tls_cert` and `tls_key`, `None` while neither is
+    /// set. A missing half or an unreadable file is an error rather than a
+    /// silent fall back to plain HTTP.
+    fn tls_config(&self) -> Result<Option<Arc<ServerConfig>>, String> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (None, None) => return Ok(None),
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Err("tls_cert and tls_key must be set together".to_string()),
+        };
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("reading certificate {}: {}", cert.display(), e))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| format!("reading private key {}: {}", key.display(), e))?;
+
+        let mut tls = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| format!("invalid certificate or key: {}", e))?;
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Some(Arc::new(tls)))
+    }
+
+    /// This config with `data_dir` moved to `tenant`'s own directory.
+    fn for_tenant(&self, tenant: &str) -> Config {
+        Config {
+            data_dir: self.data_dir.join("tenants").join(tenant),
+            ..self.clone()
+        }
+    }
+}
+
+// ============================================================================
+// VECTOR STORE IMPLEMENTATION
+// ============================================================================
+
+impl VectorStore {
+    fn empty(config: &Config) -> Self {
+        let model = config.embedding_model_info();
+        Self {
+            storage: config.embedding_storage,
+            embedding_model: config.embedding_model.clone(),
+            embedding_dimensions: model.dim,
+            ..Self::default()
+        }
+    }
+
+    /// An empty store continuing this one's chunk ids and embedding file,
+    /// to rebuild the index into with the configured model.
+    fn empty_like(&self, config: &Config) -> Self {
+        Self {
+            next_chunk_id: self.next_chunk_id,
+            embedding_file: self.embedding_file.clone(),
+            mapped: self.mapped.clone(),
+            ..Self::empty(config)
+        }
+    }
+
+    /// Refuses to mix embeddings of different models: a store embedded with
+    /// another model than the configured one must be rebuilt first.
+    fn check_embedding_model(&self, config: &Config) -> Result<(), ApiError> {
+        if self.chunks.is_empty() || self.embedding_model == config.embedding_model {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "reindex_required",
+            format!(
+                "The index was embedded with {} ({} dimensions) but embedding_model is {} ({} dimensions); POST /reindex to re-embed it",
+                self.embedding_model,
+                self.embedding_dimensions,
+                config.embedding_model,
+                config.embedding_model_info().dim
+            ),
+        ))
+    }
+
+    /// Restores the snapshot written by `persist_store`, starting empty if
+    /// there is none or it can't be read, then brings its embeddings into
+    /// the configured format and place.
+    fn load(config: &Config) -> Self {
+        let path = config.store_path();
+        let parsed =
+            std::fs::read(&path).map(|bytes| serde_json::from_slice::<VectorStore>(&bytes));
+        let mut store = match parsed {
+            Ok(Ok(mut store)) => {
+                store.storage = config.embedding_storage;
+                store.rebuild_content_index();
+                // Stores written before language detection existed
+                for record in store.documents.values_mut() {
+                    if record.language.is_none() {
+                        record.language = detect_language(&record.text);
+                    }
+                }
+                // ...and before keyword extraction
+                for chunk in &mut store.chunks {
+                    if chunk.keywords.is_empty() {
+                        chunk.keywords = extract_keywords(&chunk.text);
+                    }
+                }
+                // Stores written before the model was recorded all used
+                // all-MiniLM-L6-v2
+                if store.embedding_model.is_empty() {
+                    store.embedding_model = format!("{:?}", EmbeddingModel::AllMiniLML6V2);
+                    store.embedding_dimensions = 384;
+                }
+                if store.chunks.is_empty() {
+                    let empty = Self::empty(config);
+                    store.embedding_model = empty.embedding_model;
+                    store.embedding_dimensions = empty.embedding_dimensions;
+                }
+                if let Err(error) = store.check_embedding_model(config) {
+                    error!("❌ {}", error.message);
+                }
+                store
+            }
+            Ok(Err(e)) => {
+                error!("Failed to parse store snapshot {}: {}", path.display(), e);
+                Self::empty(config)
+            }
+            Err(_) => Self::empty(config),
+        };
+        store.prepare_embeddings(config);
+        store
+    }
+
+    /// Normalizes the chunk embeddings, converts them to `embedding_storage`
+    /// and moves them into a fresh embedding file when `mmap_embeddings` is
+    /// set (or back into memory when it isn't). An embedding file mostly
+    /// taken up by removed chunks is compacted the same way. After a rewrite
+    /// the snapshot is saved at once, so it never points into a deleted file.
+    fn prepare_embeddings(&mut self, config: &Config) {
+        let previous = self.embedding_file.clone();
+        if let Some(name) = &previous {
+            match EmbeddingFile::open(&config.data_dir.join(name)) {
+                Ok(file) => self.mapped = Some(Arc::new(file)),
+                // Rewriting now would turn every mapped embedding into an
+                // empty one and delete the file; leave both for a restart
+                Err(e) => {
+                    error!(
+                        "Failed to open embedding file {}: {}; its chunks can't be retrieved until it opens again or POST /reindex",
+                        name, e
+                    );
+                    return;
+                }
+            }
+        }
+
+        // Snapshots written before embeddings were normalized on insert
+        for chunk in &mut self.chunks {
+            if let StoredEmbedding::F32(vector) = &mut chunk.embedding {
+                normalize(vector);
+            }
+        }
+
+        let storage = self.storage;
+        let in_place = self.chunks.iter().all(|chunk| match &chunk.embedding {
+            StoredEmbedding::Mapped { .. } => config.mmap_embeddings,
+            embedding => !config.mmap_embeddings && embedding.storage() == Some(storage),
+        });
+        let file_current = match &self.mapped {
+            Some(file) => {
+                let live: u64 = self
+                    .chunks
+                    .iter()
+                    .filter_map(|chunk| match chunk.embedding {
+                        StoredEmbedding::Mapped { dimensions, .. } => {
+                            Some(file.record_bytes(dimensions) as u64)
+                        }
+                        _ => None,
+                    })
+                    .sum();
+                let garbage = file
+                    .len()
+                    .saturating_sub(EMBEDDING_FILE_HEADER.len() as u64 + live);
+                file.storage == storage && garbage <= live
+            }
+            None => !config.mmap_embeddings,
+        };
+        if in_place && file_current {
+            return;
+        }
+
+        let mut file = None;
+        if config.mmap_embeddings {
+            let name = format!("embeddings-{}.bin", uuid::Uuid::new_v4().simple());
+            match EmbeddingFile::create(&config.data_dir.join(&name), storage) {
+                Ok(created) => file = Some((name, created)),
+                Err(e) => error!(
+                    "Failed to create embedding file {}: {}; keeping embeddings in memory",
+                    name, e
+                ),
+            }
+        }
+
+        let old = self.mapped.take();
+        for chunk in &mut self.chunks {
+            let vector = match (&chunk.embedding, &old) {
+                (&StoredEmbedding::Mapped { offset, dimensions }, Some(old)) => old
+                    .read(offset, dimensions)
+                    .map(|embedding| embedding.to_f32())
+                    .unwrap_or_default(),
+                (embedding, _) => embedding.to_f32(),
+            };
+            let embedding = StoredEmbedding::new(vector, storage);
+            chunk.embedding = match &file {
+                Some((_, file)) => file.append(&embedding).unwrap_or(embedding),
+                None => embedding,
+            };
+        }
+        info!(
+            "💾 Rewrote {} chunk embeddings as {:?}{}",
+            self.chunks.len(),
+            storage,
+            match &file {
+                Some((name, _)) => format!(" in {}", name),
+                None => String::new(),
+            }
+        );
+        self.matrix.take();
+        self.embedding_file = file.as_ref().map(|(name, _)| name.clone());
+        self.mapped = file.map(|(_, file)| Arc::new(file));
+
+        let path = config.store_path();
+        let saved = std::fs::create_dir_all(&config.data_dir)
+            .and_then(|_| serde_json::to_vec(&*self).map_err(std::io::Error::from))
+            .and_then(|bytes| {
+                let tmp_path = path.with_extension("json.tmp");
+                std::fs::write(&tmp_path, bytes)?;
+                std::fs::rename(&tmp_path, &path)
+            });
+        match (saved, previous) {
+            (Err(e), _) => error!("Failed to persist store to {}: {}", path.display(), e),
+            (Ok(()), Some(previous)) if self.embedding_file.as_ref() != Some(&previous) => {
+                if let Err(e) = std::fs::remove_file(config.data_dir.join(&previous)) {
+                    warn!("Failed to remove old embedding file {}: {}", previous, e);
+                }
+            }
+            (Ok(()), _) => {}
+        }
+    }
+
+    /// A chunk's embedding as floats, wherever it's stored; empty when its
+    /// record can't be read.
+    fn embedding(&self, chunk: &ChunkData) -> Vec<f32> {
+        match (&chunk.embedding, &self.mapped) {
+            (&StoredEmbedding::Mapped { offset, dimensions }, Some(file)) => file
+                .read(offset, dimensions)
+                .map(|embedding| embedding.to_f32())
+                .unwrap_or_default(),
+            (embedding, _) => embedding.to_f32(),
+        }
+    }
+
+    /// A chunk's cosine similarity to a normalized query, reading a mapped
+    /// embedding in place.
+    fn embedding_dot(&self, chunk: &ChunkData, query: &[f32]) -> f32 {
+        match (&chunk.embedding, &self.mapped) {
+            (&StoredEmbedding::Mapped { offset, dimensions }, Some(file)) => {
+                file.dot(offset, dimensions, query).unwrap_or(0.0)
+            }
+            (embedding, _) => embedding.dot(query),
+        }
+    }
+
+    /// Stores a chunk and returns its id, unless identical text is already
+    /// stored: then the existing chunk is shared with `source` and `None` is
+    /// returned.
+    fn add(&mut self, text: String, embedding: Vec<f32>, source: String) -> Option<u64> {
+        let hash = content_hash(&text);
+
+        if let Some(&existing_id) = self.content_index.get(&hash) {
+            if let Some(existing) = self
+                .chunk_position(existing_id)
+                .map(|index| &mut self.chunks[index])
+                .filter(|chunk| chunk.text == text)
+            {
+                if existing.source != source && !existing.shared_with.contains(&source) {
+                    existing.shared_with.push(source);
+                }
+                return None;
+            }
+        }
+
+        let id = self.next_chunk_id;
+        self.next_chunk_id += 1;
+        self.matrix.take();
+        let mut embedding = StoredEmbedding::new(embedding, self.storage);
+        if let Some(file) = &self.mapped {
+            // Kept in memory (and the snapshot) if the file can't take it
+            embedding = file.append(&embedding).unwrap_or(embedding);
+        }
+        self.chunks.push(ChunkData {
+            id,
+            keywords: extract_keywords(&text),
+            text,
+            embedding,
+            source,
+            shared_with: Vec::new(),
+        });
+        self.content_index.insert(hash, id);
+        Some(id)
+    }
+
+    /// Where chunk `id` is in `chunks`. Chunks are appended with increasing
+    /// ids and only ever removed, so they stay sorted by id; an imported
+    /// archive that isn't is scanned instead.
+    fn chunk_position(&self, id: u64) -> Option<usize> {
+        match self.chunks.binary_search_by_key(&id, |chunk| chunk.id) {
+            Ok(index) => Some(index),
+            Err(_) => self.chunks.iter().position(|chunk| chunk.id == id),
+        }
+    }
+
+    /// Forgets the content hashes of chunks that were removed, given as
+    /// `(hash, id)`, unless a chunk with the same text has taken the hash over.
+    fn forget_content(&mut self, removed: Vec<(u64, u64)>) {
+        for (hash, id) in removed {
+            if self.content_index.get(&hash) == Some(&id) {
+                self.content_index.remove(&hash);
+            }
+        }
+    }
+
+    fn rebuild_content_index(&mut self) {
+        self.content_index = self
+            .chunks
+            .iter()
+            .map(|chunk| (content_hash(&chunk.text), chunk.id))
+            .collect();
+    }
+
+    /// Stores `source`'s extracted text, keeping the collections, date and
+    /// watch folder mark of a previous version.
+    fn set_document(&mut self, source: String, text: String, content_hash: String) {
+        let language = detect_language(&text);
+        let (collections, date, watched) = self
+            .documents
+            .get(&source)
+            .map(|record| {
+                (
+                    record.collections.clone(),
+                    record.date.clone(),
+                    record.watched,
+                )
+            })
+            .unwrap_or_default();
+        self.documents.insert(
+            source,
+            DocumentRecord {
+                text,
+                language,
+                content_hash: Some(content_hash),
+                summary: None,
+                summary_embedding: Vec::new(),
+                chunk_ids: Vec::new(),
+                sections: Vec::new(),
+                chunk_sections: Vec::new(),
+                report: None,
+                collections,
+                tags: Vec::new(),
+                date,
+                watched,
+                figures: Vec::new(),
+                tables: Vec::new(),
+            },
+        );
+    }
+
+    /// Records the reading order of `source`'s chunks, given their texts in
+    /// document order. Call once the chunks are in the store.
+    fn set_chunk_order(&mut self, source: &str, chunks: &[String]) {
+        let ids = chunks
+            .iter()
+            .filter_map(|text| self.content_index.get(&content_hash(text)).copied())
+            .collect();
+        if let Some(record) = self.documents.get_mut(source) {
+            record.chunk_ids = ids;
+        }
+    }
+
+    /// Sets or clears the date of `source`. Returns whether it changed.
+    fn set_date(&mut self, source: &str, date: Option<String>) -> bool {
+        match self.documents.get_mut(source) {
+            Some(record) if record.date != date => {
+                record.date = date;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Unix seconds the recency stage dates `source` by: its date when set,
+    /// else when it was ingested.
+    fn document_time(&self, source: &str) -> Option<u64> {
+        let record = self.documents.get(source)?;
+        record
+            .date
+            .as_deref()
+            .and_then(parse_date)
+            .or(record.report.as_ref().map(|report| report.ingested_at))
+    }
+
+    /// Stores the parent sections of `source`, with the section of each chunk
+    /// in the order given to `set_chunk_order`.
+    fn set_sections(&mut self, source: &str, sections: Vec<String>, chunk_sections: Vec<usize>) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.sections = sections;
+            record.chunk_sections = chunk_sections;
+        }
+    }
+
+    /// Replaces each chunk with the parent section it belongs to, keeping the
+    /// best-ranked chunk of a section when several match. Chunks of documents
+    /// without sections are left as they are.
+    fn parent_sections(
+        &self,
+        chunks: Vec<RetrievedChunk>,
+        chunk_size: usize,
+    ) -> Vec<RetrievedChunk> {
+        let mut seen = HashSet::new();
+        let mut parents = Vec::with_capacity(chunks.len());
+        for mut chunk in chunks {
+            let section = self.documents.get(&chunk.source).and_then(|record| {
+                if record.chunk_sections.len() != record.chunk_ids.len() {
+                    return None;
+                }
+                let pos = record.chunk_ids.iter().position(|&id| id == chunk.id)?;
+                let section = record.chunk_sections[pos];
+                Some((section, record.sections.get(section)?))
+            });
+            if let Some((section, text)) = section {
+                if !seen.insert((chunk.source.clone(), section)) {
+                    continue;
+                }
+                chunk.parts = text.split_whitespace().count().div_ceil(chunk_size).max(1);
+                chunk.text = text.clone();
+            }
+            parents.push(chunk);
+        }
+        parents
+    }
+
+    /// The chunk at `idx` joined with up to `window` neighbours on each side
+    /// from its document, and how many chunks that spans. `None` when the
+    /// document's chunk order is unknown (stores indexed before it was kept).
+    fn expand_chunk(&self, idx: usize, window: usize) -> Option<(String, usize)> {
+        let chunk = &self.chunks[idx];
+        let order = &self.documents.get(&chunk.source)?.chunk_ids;
+        let pos = order.iter().position(|&id| id == chunk.id)?;
+        let start = pos.saturating_sub(window);
+        let end = (pos + window + 1).min(order.len());
+
+        let mut text = String::new();
+        let mut parts = 0;
+        for id in &order[start..end] {
+            let Some(neighbor) = self.chunk_position(*id).map(|index| &self.chunks[index]) else {
+                continue;
+            };
+            text = if text.is_empty() {
+                neighbor.text.clone()
+            } else {
+                stitch_chunks(&text, &neighbor.text)
+                    .unwrap_or_else(|| format!("{} {}", text, neighbor.text))
+            };
+            parts += 1;
+        }
+        Some((text, parts))
+    }
+
+    fn set_report(&mut self, source: &str, report: IngestionReport) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.report = Some(report);
+        }
+    }
+
+    fn set_figures(&mut self, source: &str, figures: Vec<Figure>) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.figures = figures;
+        }
+    }
+
+    fn set_tables(&mut self, source: &str, tables: Vec<Table>) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.tables = tables;
+        }
+    }
+
+    fn set_summary(&mut self, source: &str, summary: String, embedding: Vec<f32>) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.summary = Some(summary);
+            record.summary_embedding = embedding;
+        }
+    }
+
+    /// First retrieval stage: the `count` documents whose summaries best match
+    /// the query, plus any document without a summary embedding. `None` when
+    /// routing is off or would not narrow the search.
+    fn route_documents(&self, query_embedding: &[f32], count: usize) -> Option<HashSet<String>> {
+        if count == 0 || self.documents.len() <= count {
+            return None;
+        }
+
+        let query_vec = ArrayView1::from(query_embedding);
+        let mut scored: Vec<(&String, f32)> = Vec::new();
+        let mut unsummarized = HashSet::new();
+        for (name, record) in &self.documents {
+            if record.summary_embedding.len() == query_embedding.len() {
+                let summary_vec = ArrayView1::from(record.summary_embedding.as_slice());
+                scored.push((name, cosine_similarity(query_vec, summary_vec)));
+            } else {
+                unsummarized.insert(name.clone());
+            }
+        }
+
+        if scored.len() <= count {
+            return None;
+        }
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        let mut routed = unsummarized;
+        routed.extend(scored.into_iter().take(count).map(|(name, _)| name.clone()));
+        Some(routed)
+    }
+
+    /// Number of documents per detected language, most common first.
+    fn language_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for language in self.documents.values().filter_map(|d| d.language.clone()) {
+            *counts.entry(language).or_default() += 1;
+        }
+
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    fn remove_by_source(&mut self, source: &str) -> usize {
+        self.documents.remove(source);
+        self.remove_chunks(source)
+    }
+
+    /// Drops `source`'s reference to every chunk it contains, returning how
+    /// many chunks it referenced. Shared chunks pass to their next document.
+    fn remove_chunks(&mut self, source: &str) -> usize {
+        let mut removed = 0;
+        let mut dropped = Vec::new();
+        self.matrix.take();
+
+        self.chunks.retain_mut(|chunk| {
+            if let Some(pos) = chunk.shared_with.iter().position(|s| s == source) {
+                chunk.shared_with.remove(pos);
+                removed += 1;
+                return true;
+            }
+
+            if chunk.source != source {
+                return true;
+            }
+
+            removed += 1;
+            if chunk.shared_with.is_empty() {
+                dropped.push((content_hash(&chunk.text), chunk.id));
+                false
+            } else {
+                chunk.source = chunk.shared_with.remove(0);
+                true
+            }
+        });
+
+        self.forget_content(dropped);
+        removed
+    }
+
+    /// Swaps `source`'s chunks for `replacement`, returning the ids of the
+    /// chunks that were stored rather than shared with an existing one.
+    fn replace_chunks(
+        &mut self,
+        source: &str,
+        replacement: Vec<(String, Vec<f32>)>,
+    ) -> HashSet<u64> {
+        self.remove_chunks(source);
+        replacement
+            .into_iter()
+            .filter_map(|(chunk, embedding)| self.add(chunk, embedding, source.to_string()))
+            .collect()
+    }
+
+    /// Copies document `name` and its chunks, embeddings included, from `other`.
+    fn copy_document(&mut self, other: &VectorStore, name: &str) {
+        let Some(record) = other.documents.get(name) else {
+            return;
+        };
+
+        let chunks: Vec<&ChunkData> = if record.chunk_ids.is_empty() {
+            other
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.source == name || chunk.shared_with.iter().any(|s| s == name))
+                .collect()
+        } else {
+            let by_id: HashMap<u64, &ChunkData> =
+                other.chunks.iter().map(|chunk| (chunk.id, chunk)).collect();
+            record
+                .chunk_ids
+                .iter()
+                .filter_map(|id| by_id.get(id).copied())
+                .collect()
+        };
+
+        let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+        for chunk in chunks {
+            self.add(chunk.text.clone(), other.embedding(chunk), name.to_string());
+        }
+        self.documents.insert(
+            name.to_string(),
+            DocumentRecord {
+                chunk_ids: Vec::new(),
+                ..record.clone()
+            },
+        );
+        if !record.chunk_ids.is_empty() {
+            self.set_chunk_order(name, &texts);
+        }
+    }
+
+    /// Undoes a partial ingest of `source`: removes the chunks it added and the
+    /// references it took on existing chunks.
+    fn remove_chunk_ids(&mut self, ids: &HashSet<u64>, source: &str) {
+        let mut dropped = Vec::new();
+        self.matrix.take();
+        self.chunks.retain_mut(|chunk| {
+            chunk.shared_with.retain(|s| s != source);
+            if ids.contains(&chunk.id) {
+                dropped.push((content_hash(&chunk.text), chunk.id));
+                return false;
+            }
+            true
+        });
+        self.forget_content(dropped);
+    }
+
+    fn document_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.documents.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Every collection with its documents, both sorted by name.
+    fn collections(&self) -> Vec<CollectionInfo> {
+        let mut collections: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (name, record) in &self.documents {
+            for collection in &record.collections {
+                collections
+                    .entry(collection.as_str())
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+        collections
+            .into_iter()
+            .map(|(name, mut documents)| {
+                documents.sort();
+                CollectionInfo {
+                    name: name.to_string(),
+                    documents,
+                }
+            })
+            .collect()
+    }
+
+    /// Names of the documents in `collection`.
+    fn collection_documents(&self, collection: &str) -> HashSet<String> {
+        self.documents
+            .iter()
+            .filter(|(_, record)| record.collections.iter().any(|c| c == collection))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Files `source` under `collection`. Returns whether anything changed.
+    fn add_to_collection(&mut self, source: &str, collection: &str) -> bool {
+        let Some(record) = self.documents.get_mut(source) else {
+            return false;
+        };
+        match record
+            .collections
+            .binary_search_by(|c| c.as_str().cmp(collection))
+        {
+            Ok(_) => false,
+            Err(position) => {
+                record.collections.insert(position, collection.to_string());
+                true
+            }
+        }
+    }
+
+    /// Takes `source` out of `collection`. Returns whether it was in it.
+    fn remove_from_collection(&mut self, source: &str, collection: &str) -> bool {
+        let Some(record) = self.documents.get_mut(source) else {
+            return false;
+        };
+        let before = record.collections.len();
+        record.collections.retain(|c| c != collection);
+        record.collections.len() != before
+    }
+
+    fn set_tags(&mut self, source: &str, tags: Vec<String>) {
+        if let Some(record) = self.documents.get_mut(source) {
+            record.tags = tags;
+        }
+    }
+
+    /// Every tag in use, with its documents, sorted by name.
+    fn tags(&self) -> Vec<TagInfo> {
+        let mut tags: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (name, record) in &self.documents {
+            for tag in &record.tags {
+                tags.entry(tag.as_str()).or_default().push(name.clone());
+            }
+        }
+        tags.into_iter()
+            .map(|(name, mut documents)| {
+                documents.sort();
+                TagInfo {
+                    name: name.to_string(),
+                    documents,
+                }
+            })
+            .collect()
+    }
+
+    /// Names of the documents tagged with any of `tags`.
+    fn tagged_documents(&self, tags: &[String]) -> HashSet<String> {
+        self.documents
+            .iter()
+            .filter(|(_, record)| record.tags.iter().any(|tag| tags.contains(tag)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Every chunk embedding as one contiguous row-major matrix in the
+    /// store's format. Rows of another dimension than the first chunk's (a
+    /// different model) are zero.
+    fn embedding_matrix(&self) -> &EmbeddingMatrix {
+        self.matrix.get_or_init(|| {
+            let dimensions = self.chunks.first().map_or(0, |chunk| chunk.embedding.len());
+            let shape = (self.chunks.len(), dimensions);
+            let fits = |chunk: &&ChunkData| chunk.embedding.len() == dimensions;
+            match self.storage {
+                EmbeddingStorage::F32 => {
+                    let mut matrix = Array2::zeros(shape);
+                    for (mut row, chunk) in matrix.rows_mut().into_iter().zip(&self.chunks) {
+                        if fits(&chunk) {
+                            row.assign(&Array1::from(chunk.embedding.to_f32()));
+                        }
+                    }
+                    EmbeddingMatrix::F32(matrix)
+                }
+                EmbeddingStorage::Int8 => {
+                    let mut values = Array2::zeros(shape);
+                    let mut scales = vec![0.0; self.chunks.len()];
+                    for ((mut row, scale), chunk) in values
+                        .rows_mut()
+                        .into_iter()
+                        .zip(&mut scales)
+                        .zip(&self.chunks)
+                    {
+                        if let StoredEmbedding::Int8 {
+                            scale: chunk_scale,
+                            values: chunk_values,
+                        } = &chunk.embedding
+                        {
+                            if fits(&chunk) {
+                                row.assign(&ArrayView1::from(chunk_values.as_slice()));
+                                *scale = *chunk_scale;
+                            }
+                        }
+                    }
+                    EmbeddingMatrix::Int8 { values, scales }
+                }
+            }
+        })
+    }
+
+    /// Top-`top_k` chunks by cosine similarity to the query as (chunk index,
+    /// similarity), optionally limited to chunks belonging to one of `sources`.
+    /// Stored embeddings are normalized, so one matrix-vector product scores
+    /// the whole store; quantized ones are scored against the float query
+    /// without decoding them. Memory-mapped ones are scored one by one.
+    fn search(
+        &self,
+        query_embedding: &[f32],
+        top_k: usize,
+        filter: &ChunkFilter,
+    ) -> Vec<(usize, f32)> {
+        if top_k == 0 {
+            return Vec::new();
+        }
+        let mut query = query_embedding.to_vec();
+        normalize(&mut query);
+        let similarities = if self.mapped.is_some() {
+            // Reads each vector in place rather than copying the file into memory
+            self.chunks
+                .iter()
+                .map(|chunk| self.embedding_dot(chunk, &query))
+                .collect()
+        } else {
+            let matrix = self.embedding_matrix();
+            if matrix.dimensions() != query.len() {
+                return Vec::new();
+            }
+            matrix.scores(ArrayView1::from(query.as_slice()))
+        };
+
+        let mut scores: Vec<(usize, f32)> = similarities
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(idx, _)| filter.allows(&self.chunks[idx]))
+            .collect();
+
+        // Only the best `top_k` need sorting (highest first)
+        let by_similarity = |a: &(usize, f32), b: &(usize, f32)| b.1.partial_cmp(&a.1).unwrap();
+        if scores.len() > top_k {
+            scores.select_nth_unstable_by(top_k - 1, by_similarity);
+            scores.truncate(top_k);
+        }
+        scores.sort_by(by_similarity);
+        scores
+    }
+
+    /// BM25 inverse document frequency of each of `terms` over every chunk,
+    /// so words found nearly everywhere weigh close to nothing.
+    fn term_weights(&self, terms: &HashSet<String>) -> HashMap<String, f32> {
+        let mut containing: HashMap<&String, usize> = HashMap::new();
+        for chunk in &self.chunks {
+            let words: HashSet<String> = keyword_words(&chunk.text).collect();
+            for term in terms.iter().filter(|term| words.contains(*term)) {
+                *containing.entry(term).or_default() += 1;
+            }
+        }
+        let total = self.chunks.len() as f32;
+        terms
+            .iter()
+            .map(|term| {
+                let containing = containing.get(term).copied().unwrap_or(0) as f32;
+                let weight = ((total - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+                (term.clone(), weight)
+            })
+            .collect()
+    }
+
+    /// Top-`top_k` chunks by BM25 keyword score as (chunk index, score), for
+    /// exact terms (names, codes, identifiers) embeddings tend to blur.
+    fn keyword_search(&self, query: &str, top_k: usize, filter: &ChunkFilter) -> Vec<(usize, f32)> {
+        const K1: f32 = 1.2;
+        const B: f32 = 0.75;
+
+        let terms = keyword_terms(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let term_counts: Vec<(usize, HashMap<String, usize>, usize)> = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| filter.allows(chunk))
+            .map(|(idx, chunk)| {
+                let mut counts: HashMap<String, usize> = HashMap::new();
+                let mut length = 0;
+                for word in keyword_words(&chunk.text) {
+                    length += 1;
+                    if terms.contains(&word) {
+                        *counts.entry(word).or_default() += 1;
+                    }
+                }
+                (idx, counts, length)
+            })
+            .collect();
+        if term_counts.is_empty() {
+            return Vec::new();
+        }
+
+        let total = term_counts.len() as f32;
+        let average_length =
+            term_counts.iter().map(|(_, _, len)| *len).sum::<usize>() as f32 / total;
+        let idf: HashMap<&String, f32> = terms
+            .iter()
+            .map(|term| {
+                let containing = term_counts
+                    .iter()
+                    .filter(|(_, counts, _)| counts.contains_key(term))
+                    .count() as f32;
+                (
+                    term,
+                    ((total - containing + 0.5) / (containing + 0.5) + 1.0).ln(),
+                )
+            })
+            .collect();
+
+        let mut scores: Vec<(usize, f32)> = term_counts
+            .into_iter()
+            .filter(|(_, counts, _)| !counts.is_empty())
+            .map(|(idx, counts, length)| {
+                let norm = K1 * (1.0 - B + B * length as f32 / average_length.max(1.0));
+                let score = counts
+                    .iter()
+                    .map(|(term, &tf)| {
+                        let tf = tf as f32;
+                        idf[term] * tf * (K1 + 1.0) / (tf + norm)
+                    })
+                    .sum();
+                (idx, score)
+            })
+            .collect();
+
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        scores.truncate(top_k);
+        scores
+    }
+
+    /// Cosine similarity of one stored chunk to the query.
+    fn similarity(&self, idx: usize, query_embedding: &[f32]) -> f32 {
+        let mut query = query_embedding.to_vec();
+        normalize(&mut query);
+        self.embedding_dot(&self.chunks[idx], &query)
+    }
+
+    /// Maximal marginal relevance: greedily picks `top_k` of the (chunk index,
+    /// relevance) candidates, trading relevance against redundancy with what
+    /// is already picked. `lambda` 1.0 ranks purely by relevance.
+    fn mmr_select(
+        &self,
+        mut candidates: Vec<(usize, f32)>,
+        top_k: usize,
+        lambda: f32,
+    ) -> Vec<(usize, f32)> {
+        // Stored embeddings are normalized, so their dot product is the cosine
+        let vectors: HashMap<usize, Array1<f32>> = candidates
+            .iter()
+            .map(|&(idx, _)| (idx, Array1::from(self.embedding(&self.chunks[idx]))))
+            .collect();
+        let mut selected: Vec<(usize, f32)> = Vec::with_capacity(top_k);
+
+        while selected.len() < top_k && !candidates.is_empty() {
+            let mmr = |&(idx, relevance): &(usize, f32)| {
+                let redundancy = selected
+                    .iter()
+                    .map(|(picked, _)| vectors[&idx].dot(&vectors[picked]))
+                    .fold(0.0, f32::max);
+                lambda * relevance - (1.0 - lambda) * redundancy
+            };
+
+            let best = (0..candidates.len())
+                .max_by(|&a, &b| {
+                    mmr(&candidates[a])
+                        .partial_cmp(&mmr(&candidates[b]))
+                        .unwrap()
+                })
+                .unwrap();
+            selected.push(candidates.remove(best));
+        }
+
+        selected
+    }
+
+    /// The `limit` other documents most similar to `name` as (name, centroid
+    /// similarity, chunks shared verbatim), best first. A document's centroid
+    /// is the mean of its chunk embeddings; `None` for unknown documents and
+    /// documents without chunks.
+    fn similar_documents(&self, name: &str, limit: usize) -> Option<Vec<(String, f32, usize)>> {
+        // One pass sums every document's embeddings; cosine similarity
+        // ignores scale, so the sums score the same as the means would
+        let mut sums: HashMap<&str, Array1<f32>> = HashMap::new();
+        let mut shared: HashMap<&str, usize> = HashMap::new();
+        for chunk in &self.chunks {
+            let embedding = Array1::from(self.embedding(chunk));
+            let in_name = chunk_of(chunk, name);
+            for owner in std::iter::once(&chunk.source).chain(&chunk.shared_with) {
+                match sums.get_mut(owner.as_str()) {
+                    Some(sum) => *sum += &embedding,
+                    None => {
+                        sums.insert(owner, embedding.clone());
+                    }
+                }
+                if in_name && owner != name {
+                    *shared.entry(owner).or_default() += 1;
+                }
+            }
+        }
+
+        let centroid = sums.get(name)?;
+        let mut similar: Vec<(String, f32, usize)> = self
+            .documents
+            .keys()
+            .filter(|other| *other != name)
+            .filter_map(|other| {
+                let score = cosine_similarity(centroid.view(), sums.get(other.as_str())?.view());
+                let shared = shared.get(other.as_str()).copied().unwrap_or(0);
+                Some((other.clone(), score, shared))
+            })
+            .collect();
+
+        similar.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        similar.truncate(limit);
+        Some(similar)
+    }
+
+    /// The `limit` other chunks most similar to chunk `id`, best first.
+    fn related_chunks(&self, id: u64, limit: usize) -> Option<Vec<(&ChunkData, f32)>> {
+        let chunk = &self.chunks[self.chunk_position(id)?];
+        let mut related: Vec<(usize, f32)> = self
+            .search(&self.embedding(chunk), limit + 1, &ChunkFilter::default())
+            .into_iter()
+            .filter(|&(idx, _)| self.chunks[idx].id != id)
+            .collect();
+        related.truncate(limit);
+        Some(
+            related
+                .into_iter()
+                .map(|(idx, score)| (&self.chunks[idx], score))
+                .collect(),
+        )
+    }
+
+    fn count(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+/// Whether a chunk belongs to document `name`, as its source or a sharer.
+fn chunk_of(chunk: &ChunkData, name: &str) -> bool {
+    chunk.source == name || chunk.shared_with.iter().any(|s| s == name)
+}
+
+/// Which chunks a search may return.
+#[derive(Clone, Copy, Default)]
+struct ChunkFilter<'a> {
+    // Documents the chunk must belong to; all when `None`
+    sources: Option<&'a HashSet<String>>,
+    // Keywords the chunk must be tagged with at least one of; any when empty
+    keywords: &'a [String],
+}
+
+impl ChunkFilter<'_> {
+    fn allows(&self, chunk: &ChunkData) -> bool {
+        let in_sources = self.sources.is_none_or(|sources| {
+            sources.contains(&chunk.source) || chunk.shared_with.iter().any(|s| sources.contains(s))
+        });
+        in_sources
+            && (self.keywords.is_empty()
+                || self.keywords.iter().any(|wanted| {
+                    chunk
+                        .keywords
+                        .iter()
+                        .any(|keyword| mentions(keyword, wanted))
+                }))
+    }
+}
+
+/// Whether `keyword` contains `term` as whole words, ignoring case, so
+/// `gdpr` matches the keyword `GDPR compliance` but not `GDPRs`.
+fn mentions(keyword: &str, term: &str) -> bool {
+    let keyword: Vec<String> = keyword_words(keyword).collect();
+    let term: Vec<String> = keyword_words(term).collect();
+    !term.is_empty()
+        && keyword
+            .windows(term.len())
+            .any(|window| window == term.as_slice())
+}
+
+/// Detects the dominant language of `text` as an ISO 639-3 code.
+fn detect_language(text: &str) -> Option<String> {
+    whatlang::detect(text)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().code().to_string())
+}
+
+fn language_name(code: &str) -> String {
+    whatlang::Lang::from_code(code)
+        .map(|lang| lang.eng_name().to_string())
+        .unwrap_or_else(|| code.to_string())
+}
+
+fn content_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl StoredEmbedding {
+    /// Normalizes `vector` and stores it in `storage`'s format. Int8 maps
+    /// each vector's largest magnitude to 127.
+    fn new(mut vector: Vec<f32>, storage: EmbeddingStorage) -> Self {
+        normalize(&mut vector);
+        match storage {
+            EmbeddingStorage::F32 => StoredEmbedding::F32(vector),
+            EmbeddingStorage::Int8 => {
+                let max = vector.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+                let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                let values = vector
+                    .iter()
+                    .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+                    .collect();
+                StoredEmbedding::Int8 { scale, values }
+            }
+        }
+    }
+
+    /// The in-memory format; `None` for a record of the embedding file.
+    fn storage(&self) -> Option<EmbeddingStorage> {
+        match self {
+            StoredEmbedding::F32(_) => Some(EmbeddingStorage::F32),
+            StoredEmbedding::Int8 { .. } => Some(EmbeddingStorage::Int8),
+            StoredEmbedding::Mapped { .. } => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            StoredEmbedding::F32(vector) => vector.len(),
+            StoredEmbedding::Int8 { values, .. } => values.len(),
+            StoredEmbedding::Mapped { dimensions, .. } => *dimensions,
+        }
+    }
+
+    /// The vector as floats; approximate when quantized. Mapped embeddings
+    /// are read through `VectorStore::embedding` instead and are empty here.
+    fn to_f32(&self) -> Vec<f32> {
+        match self {
+            StoredEmbedding::F32(vector) => vector.clone(),
+            StoredEmbedding::Int8 { scale, values } => {
+                values.iter().map(|&v| v as f32 * scale).collect()
+            }
+            StoredEmbedding::Mapped { .. } => Vec::new(),
+        }
+    }
+
+    /// Dot product with a float vector: the cosine similarity when `query`
+    /// is normalized. 0 when the dimensions differ, and for mapped
+    /// embeddings (see `VectorStore::embedding_dot`).
+    fn dot(&self, query: &[f32]) -> f32 {
+        if self.len() != query.len() {
+            return 0.0;
+        }
+        match self {
+            StoredEmbedding::F32(vector) => {
+                ArrayView1::from(vector.as_slice()).dot(&ArrayView1::from(query))
+            }
+            StoredEmbedding::Int8 { scale, values } => {
+                scale * int8_dot(ArrayView1::from(values.as_slice()), query)
+            }
+            StoredEmbedding::Mapped { .. } => 0.0,
+        }
+    }
+}
+
+/// Start of every embedding file: a magic string, then the record format.
+const EMBEDDING_FILE_HEADER: [u8; 16] = *b"AHTOEMB1\0\0\0\0\0\0\0\0";
+
+impl EmbeddingFile {
+    /// Starts an empty file holding records in `storage`'s format.
+    fn create(path: &Path, storage: EmbeddingStorage) -> std::io::Result<Self> {
+        let mut header = EMBEDDING_FILE_HEADER;
+        header[8] = match storage {
+            EmbeddingStorage::F32 => 0,
+            EmbeddingStorage::Int8 => 1,
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, header)?;
+        Self::open(path)
+    }
+
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)?;
+        // SAFETY: the file is only ever appended to, so bytes already mapped
+        // never change underneath the map
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < EMBEDDING_FILE_HEADER.len() || map[..8] != EMBEDDING_FILE_HEADER[..8] {
+            return Err(invalid("not an embedding file"));
+        }
+        let storage = match map[8] {
+            0 => EmbeddingStorage::F32,
+            1 => EmbeddingStorage::Int8,
+            _ => return Err(invalid("unknown embedding format")),
+        };
+        let len = map.len() as u64;
+        Ok(Self {
+            storage,
+            writer: Mutex::new((file, len)),
+            map: RwLock::new(map),
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.writer.lock().unwrap().1
+    }
+
+    fn record_bytes(&self, dimensions: usize) -> usize {
+        match self.storage {
+            EmbeddingStorage::F32 => dimensions * 4,
+            // The scale, then one byte per dimension
+            EmbeddingStorage::Int8 => 4 + dimensions,
+        }
+    }
+
+    /// Appends `embedding`, which must be in the file's format, and returns
+    /// the reference to it. On failure `embedding` is handed back.
+    fn append(&self, embedding: &StoredEmbedding) -> Result<StoredEmbedding, StoredEmbedding> {
+        use std::io::Write;
+
+        let mut bytes = Vec::with_capacity(self.record_bytes(embedding.len()));
+        match (embedding, self.storage) {
+            (StoredEmbedding::F32(vector), EmbeddingStorage::F32) => {
+                bytes.extend(vector.iter().flat_map(|x| x.to_le_bytes()));
+            }
+            (StoredEmbedding::Int8 { scale, values }, EmbeddingStorage::Int8) => {
+                bytes.extend(scale.to_le_bytes());
+                bytes.extend(values.iter().map(|&v| v as u8));
+            }
+            _ => return Err(embedding.clone()),
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        let (file, len) = &mut *writer;
+        if let Err(e) = file.write_all(&bytes) {
+            error!("Failed to append to embedding file: {}", e);
+            // A partial write leaves bytes no record points at
+            *len = file.metadata().map_or(*len, |metadata| metadata.len());
+            return Err(embedding.clone());
+        }
+        let offset = *len;
+        *len += bytes.len() as u64;
+        Ok(StoredEmbedding::Mapped {
+            offset,
+            dimensions: embedding.len(),
+        })
+    }
+
+    /// Runs `read` on the record at `offset`, remapping the file first if the
+    /// record was appended after the current map was made. `None` when the
+    /// record lies past the end of the file.
+    fn with_record<T>(
+        &self,
+        offset: u64,
+        dimensions: usize,
+        read: impl FnOnce(&[u8]) -> T,
+    ) -> Option<T> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start + self.record_bytes(dimensions);
+        {
+            let map = self.map.read().unwrap();
+            if end <= map.len() {
+                return Some(read(&map[start..end]));
+            }
+        }
+
+        let mut map = self.map.write().unwrap();
+        if end > map.len() {
+            let writer = self.writer.lock().unwrap();
+            // SAFETY: as in `open`
+            match unsafe { Mmap::map(&writer.0) } {
+                Ok(remapped) => *map = remapped,
+                Err(e) => {
+                    error!("Failed to remap embedding file: {}", e);
+                    return None;
+                }
+            }
+        }
+        (end <= map.len()).then(|| read(&map[start..end]))
+    }
+
+    fn read(&self, offset: u64, dimensions: usize) -> Option<StoredEmbedding> {
+        self.with_record(offset, dimensions, |bytes| match self.storage {
+            EmbeddingStorage::F32 => StoredEmbedding::F32(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            ),
+            EmbeddingStorage::Int8 => StoredEmbedding::Int8 {
+                scale: f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                values: bytes[4..].iter().map(|&b| b as i8).collect(),
+            },
+        })
+    }
+
+    /// Dot product of the record at `offset` with a float vector, decoded in
+    /// place. 0 when the dimensions differ.
+    fn dot(&self, offset: u64, dimensions: usize, query: &[f32]) -> Option<f32> {
+        if dimensions != query.len() {
+            return Some(0.0);
+        }
+        self.with_record(offset, dimensions, |bytes| match self.storage {
+            EmbeddingStorage::F32 => bytes
+                .chunks_exact(4)
+                .zip(query)
+                .map(|(b, &x)| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * x)
+                .sum(),
+            EmbeddingStorage::Int8 => {
+                let scale = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                scale
+                    * bytes[4..]
+                        .iter()
+                        .zip(query)
+                        .map(|(&b, &x)| b as i8 as f32 * x)
+                        .sum::<f32>()
+            }
+        })
+    }
+}
+
+impl EmbeddingMatrix {
+    fn dimensions(&self) -> usize {
+        match self {
+            EmbeddingMatrix::F32(matrix) => matrix.ncols(),
+            EmbeddingMatrix::Int8 { values, .. } => values.ncols(),
+        }
+    }
+
+    /// Each row's dot product with `query`, in row order.
+    fn scores(&self, query: ArrayView1<f32>) -> Array1<f32> {
+        match self {
+            EmbeddingMatrix::F32(matrix) => matrix.dot(&query),
+            EmbeddingMatrix::Int8 { values, scales } => {
+                let query = query.as_slice().expect("query vectors are contiguous");
+                values
+                    .rows()
+                    .into_iter()
+                    .zip(scales)
+                    .map(|(row, scale)| scale * int8_dot(row, query))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Dot product of quantized values with a float vector, before scaling.
+fn int8_dot(values: ArrayView1<i8>, query: &[f32]) -> f32 {
+    values
+        .iter()
+        .zip(query)
+        .map(|(&value, &x)| value as f32 * x)
+        .sum()
+}
+
+/// Scales `vector` to unit length in place; a zero vector stays zero.
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn cosine_similarity(a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
+    let dot = a.dot(&b);
+    let norm_a = a.dot(&a).sqrt();
+    let norm_b = b.dot(&b).sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+// ============================================================================
+// TEXT PROCESSING
+// ============================================================================
+
+fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    if words.is_empty() {
+        return Vec::new();
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < words.len() {
+        let end = (start + chunk_size).min(words.len());
+        let chunk = words[start..end].join(" ");
+        chunks.push(chunk);
+
+        if end >= words.len() {
+            break;
+        }
+
+        start += chunk_size - overlap;
+    }
+
+    chunks
+}
+
+/// Keywords kept per chunk, entities first.
+const MAX_CHUNK_KEYWORDS: usize = 10;
+/// Entities among them, leaving the rest for key phrases.
+const MAX_CHUNK_ENTITIES: usize = 5;
+/// Longest key phrase or entity, in words.
+const MAX_KEYWORD_WORDS: usize = 3;
+
+/// Words that end a key phrase and never start an entity.
+const STOPWORDS: &[&str] = &[
+    "a", "about", "above", "across", "after", "again", "against", "all", "also", "among", "an",
+    "and", "any", "are", "as", "at", "be", "because", "been", "before", "below", "between", "both",
+    "but", "by", "can", "could", "did", "do", "does", "down", "during", "each", "few", "for",
+    "from", "further", "had", "has", "have", "he", "her", "here", "his", "how", "i", "if", "in",
+    "into", "is", "it", "its", "just", "many", "may", "more", "most", "much", "must", "no", "not",
+    "of", "off", "on", "once", "only", "onto", "or", "other", "our", "out", "over", "own", "per",
+    "same", "she", "should", "so", "some", "such", "than", "that", "the", "their", "them", "then",
+    "there", "these", "they", "this", "those", "through", "to", "under", "until", "up", "upon",
+    "very", "via", "was", "we", "well", "were", "what", "when", "where", "which", "while", "who",
+    "why", "will", "with", "within", "without", "would", "yet", "you", "your",
+];
+
+/// Named entities and key phrases of a chunk. Entities are acronyms and runs
+/// of capitalized words that don't merely start a sentence; key phrases are
+/// scored RAKE-style, each word by how many other words it co-occurs with
+/// in phrases over how often it appears.
+fn extract_keywords(text: &str) -> Vec<String> {
+    let is_stopword = |word: &str| STOPWORDS.contains(&word.to_lowercase().as_str());
+    let mut keywords: Vec<String> = Vec::new();
+    let keep = |keyword: String, keywords: &mut Vec<String>| {
+        if !keywords
+            .iter()
+            .any(|kept| kept.to_lowercase() == keyword.to_lowercase())
+        {
+            keywords.push(keyword);
+        }
+    };
+
+    // Entities, in order of appearance
+    let mut run: Vec<&str> = Vec::new();
+    let mut sentence_start = true;
+    for token in text.split_whitespace() {
+        let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+        let letters = word.chars().filter(|c| c.is_alphabetic()).count();
+        let acronym = letters >= 2 && word.chars().all(|c| c.is_uppercase() || c.is_ascii_digit());
+        let capitalized = word.chars().next().is_some_and(char::is_uppercase)
+            && letters >= 2
+            && !is_stopword(word);
+        if acronym || (capitalized && (!sentence_start || !run.is_empty())) {
+            run.push(word);
+        } else if !run.is_empty() {
+            keep(run.join(" "), &mut keywords);
+            run.clear();
+        }
+        let ends_clause = token.ends_with(['.', ',', ';', ':', '!', '?']);
+        if (ends_clause || run.len() == MAX_KEYWORD_WORDS) && !run.is_empty() {
+            keep(run.join(" "), &mut keywords);
+            run.clear();
+        }
+        sentence_start = token.ends_with(['.', '!', '?']);
+    }
+    if !run.is_empty() {
+        keep(run.join(" "), &mut keywords);
+    }
+    keywords.truncate(MAX_CHUNK_ENTITIES);
+
+    // Key phrases: runs of content words between stopwords and punctuation
+    let mut phrases: Vec<Vec<String>> = Vec::new();
+    for clause in text.split(|c: char| {
+        matches!(
+            c,
+            '.' | ',' | ';' | ':' | '!' | '?' | '(' | ')' | '"' | '\n'
+        )
+    }) {
+        let mut phrase = Vec::new();
+        for word in clause.split_whitespace() {
+            let word = word
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase();
+            if word.chars().count() < 3
+                || word.chars().all(|c| c.is_numeric())
+                || is_stopword(&word)
+            {
+                phrases.push(std::mem::take(&mut phrase));
+            } else {
+                phrase.push(word);
+            }
+        }
+        phrases.push(phrase);
+    }
+    phrases.retain(|phrase| !phrase.is_empty() && phrase.len() <= MAX_KEYWORD_WORDS);
+
+    let mut frequency: HashMap<&str, f32> = HashMap::new();
+    let mut degree: HashMap<&str, f32> = HashMap::new();
+    for phrase in &phrases {
+        for word in phrase {
+            *frequency.entry(word).or_default() += 1.0;
+            *degree.entry(word).or_default() += phrase.len() as f32;
+        }
+    }
+    let mut scored: Vec<(String, f32)> = phrases
+        .iter()
+        .map(|phrase| {
+            let score = phrase
+                .iter()
+                .map(|word| degree[word.as_str()] / frequency[word.as_str()])
+                .sum();
+            (phrase.join(" "), score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    for (phrase, _) in scored {
+        if keywords.len() >= MAX_CHUNK_KEYWORDS {
+            break;
+        }
+        keep(phrase, &mut keywords);
+    }
+    keywords
+}
+
+/// Splits `text` into parent sections of at most `section_size` words,
+/// breaking at blank lines where it can, and gives the section holding the
+/// middle of each chunk `chunk_text` makes with the same settings.
+fn split_sections(
+    text: &str,
+    section_size: usize,
+    chunk_size: usize,
+    overlap: usize,
+) -> (Vec<String>, Vec<usize>) {
+    // Paragraphs longer than a section are cut into section-sized pieces
+    let mut pieces: Vec<Vec<&str>> = Vec::new();
+    for paragraph in text.split("\n\n") {
+        let words: Vec<&str> = paragraph.split_whitespace().collect();
+        pieces.extend(words.chunks(section_size).map(|piece| piece.to_vec()));
+    }
+
+    let mut sections: Vec<Vec<&str>> = Vec::new();
+    let mut starts = Vec::new();
+    let mut position = 0;
+    for piece in pieces {
+        match sections.last_mut() {
+            Some(section) if section.len() + piece.len() <= section_size => {
+                section.extend(piece.iter())
+            }
+            _ => {
+                starts.push(position);
+                sections.push(piece.clone());
+            }
+        }
+        position += piece.len();
+    }
+
+    let step = chunk_size - overlap;
+    let chunk_sections = (0..chunk_text(text, chunk_size, overlap).len())
+        .map(|i| {
+            let middle = (i * step + chunk_size / 2).min(position.saturating_sub(1));
+            starts
+                .partition_point(|&start| start <= middle)
+                .saturating_sub(1)
+        })
+        .collect();
+    let sections = sections.into_iter().map(|words| words.join(" ")).collect();
+    (sections, chunk_sections)
+}
+
+/// Text pulled out of an uploaded file, and how it was obtained.
+struct Extraction {
+    text: String,
+    extractor: &'static str,
+    pages: Option<usize>,
+    // Problems that didn't stop extraction, e.g. pages without text
+    warnings: Vec<String>,
+    // Figures found in a PDF, for the vision model to describe
+    images: Vec<PageImage>,
+    // Tables found in a PDF
+    tables: Vec<Table>,
+}
+
+/// An image from a PDF page, as JPEG or PNG.
+struct PageImage {
+    // 1-based
+    page: usize,
+    data: Vec<u8>,
+}
+
+/// Splits text into trimmed sentences at `.`, `!` or `?` followed by
+/// whitespace, and at line breaks.
+fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    for line in text.lines() {
+        let mut start = 0;
+        let mut chars = line.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            let at_boundary = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+            if matches!(c, '.' | '!' | '?') && at_boundary {
+                sentences.push(line[start..pos + 1].trim().to_string());
+                start = pos + 1;
+            }
+        }
+        sentences.push(line[start..].trim().to_string());
+    }
+    sentences.retain(|sentence| !sentence.is_empty());
+    sentences
+}
+
+/// Byte range of `passage` in `text`, matching word by word so differences
+/// in whitespace (chunks are joined with single spaces) don't matter.
+fn locate_passage(text: &str, passage: &str) -> Option<(usize, usize)> {
+    let passage: Vec<&str> = passage.split_whitespace().collect();
+    let first = *passage.first()?;
+    let words: Vec<(usize, &str)> = text
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect();
+
+    (0..words.len().saturating_sub(passage.len() - 1))
+        .filter(|&i| words[i].1 == first)
+        .find(|&i| {
+            words[i..i + passage.len()]
+                .iter()
+                .zip(&passage)
+                .all(|((_, word), expected)| word == expected)
+        })
+        .map(|i| {
+            let (last_start, last) = words[i + passage.len() - 1];
+            (words[i].0, last_start + last.len())
+        })
+}
+
+/// Removes `[1]`-style citation markers (`[1, 2]` included) from text.
+fn strip_citation_markers(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        let marker = rest[start + 1..].find(']').filter(|&len| {
+            let inner = &rest[start + 1..start + 1 + len];
+            inner.chars().any(|c| c.is_ascii_digit())
+                && inner
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ',' || c == ' ')
+        });
+        match marker {
+            Some(len) => {
+                stripped.push_str(rest[..start].trim_end());
+                rest = &rest[start + len + 2..];
+            }
+            None => {
+                stripped.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Candidate patterns for each kind of personal data. Matches still have to
+/// pass `plausible_pii` before they are masked.
+fn pii_patterns() -> &'static [(PiiKind, Regex)] {
+    static PATTERNS: OnceLock<Vec<(PiiKind, Regex)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (
+                PiiKind::Email,
+                r"(?i)\b[a-z0-9][a-z0-9._%+-]*@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b",
+            ),
+            // SSN 123-45-6789, NI number AB 12 34 56 C, Aadhaar 2345 6789 0123
+            (
+                PiiKind::NationalId,
+                r"\b(?:\d{3}-\d{2}-\d{4}|[A-CEGHJ-PR-TW-Z]{2} ?\d{2} ?\d{2} ?\d{2} ?[A-D]|[2-9]\d{3} \d{4} \d{4})\b",
+            ),
+            (
+                PiiKind::Phone,
+                r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{1,4}\)[ .-]?)?\d{2,4}(?:[ .-]?\d{2,4}){1,4}",
+            ),
+        ]
+        .into_iter()
+        .map(|(kind, pattern)| (kind, Regex::new(pattern).unwrap()))
+        .collect()
+    })
+}
+
+/// Heuristics weeding out pattern matches that are probably not personal
+/// data: numbers embedded in longer tokens, dates, invalid SSN ranges and
+/// bare digit runs such as order numbers.
+fn plausible_pii(kind: PiiKind, text: &str, start: usize, end: usize) -> bool {
+    let found = &text[start..end];
+    let digits = found.chars().filter(|c| c.is_ascii_digit()).count();
+    match kind {
+        PiiKind::Email => true,
+        PiiKind::NationalId => match found.split('-').collect::<Vec<_>>()[..] {
+            [area, group, serial] => {
+                !matches!(area, "000" | "666")
+                    && !area.starts_with('9')
+                    && group != "00"
+                    && serial != "0000"
+            }
+            _ => true,
+        },
+        PiiKind::Phone => {
+            let embedded = text[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | '/' | '+'))
+                || text[end..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_alphanumeric() || matches!(c, '-' | '/'));
+            let marked = found.starts_with('+') || found.contains('(');
+            let separated = found.contains([' ', '.', '-']);
+            !embedded && (7..=15).contains(&digits) && (marked || (separated && digits >= 10))
+        }
+    }
+}
+
+/// Masks the `kinds` of personal data found in `text`, returning the masked
+/// text and how many matches of each kind were replaced.
+fn redact_pii(text: &str, kinds: &[PiiKind]) -> (String, BTreeMap<PiiKind, usize>) {
+    let mut redacted = text.to_string();
+    let mut counts = BTreeMap::new();
+    for (kind, pattern) in pii_patterns() {
+        if !kinds.contains(kind) {
+            continue;
+        }
+        let placeholder = match kind {
+            PiiKind::Email => "[EMAIL]",
+            PiiKind::Phone => "[PHONE]",
+            PiiKind::NationalId => "[NATIONAL_ID]",
+        };
+        let mut count = 0;
+        let source = redacted.clone();
+        redacted = pattern
+            .replace_all(&source, |caps: &Captures| {
+                let found = caps.get(0).unwrap();
+                if plausible_pii(*kind, &source, found.start(), found.end()) {
+                    count += 1;
+                    placeholder.to_string()
+                } else {
+                    found.as_str().to_string()
+                }
+            })
+            .into_owned();
+        if count > 0 {
+            counts.insert(*kind, count);
+        }
+    }
+    (redacted, counts)
+}
+
+/// `redact_pii`, adding how many matches of each kind were replaced to
+/// `counts`.
+fn redact_pii_counted(
+    text: &str,
+    kinds: &[PiiKind],
+    counts: &mut BTreeMap<PiiKind, usize>,
+) -> String {
+    let (redacted, found) = redact_pii(text, kinds);
+    for (kind, count) in found {
+        *counts.entry(kind).or_default() += count;
+    }
+    redacted
+}
+
+/// `max_images` caps the figures kept from a PDF; 0 keeps none.
+fn extract_text(filename: &str, content: &[u8], max_images: usize) -> Result<Extraction, String> {
+    let result = if filename.ends_with(".md") {
+        extract_text_from_md(content)
+    } else if filename.ends_with(".pdf") {
+        extract_text_from_pdf(content, max_images)
+    } else if is_source_file(filename) {
+        extract_text_from_source(content)
+    } else {
+        return Err(format!("Unsupported file type: {}", filename));
+    };
+
+    result.map_err(|e| format!("Failed to parse {}: {}", filename, e))
+}
+
+fn extract_text_from_md(content: &[u8]) -> Result<Extraction, String> {
+    let text = String::from_utf8(content.to_vec()).map_err(|e| format!("Invalid UTF-8: {}", e))?;
+    Ok(Extraction {
+        text,
+        extractor: "markdown",
+        pages: None,
+        warnings: Vec::new(),
+        images: Vec::new(),
+        tables: Vec::new(),
+    })
+}
+
+/// Separates the pages of a PDF's extracted text. It is whitespace, so
+/// chunking and sentence splitting pass over it; only previews split on it.
+const PAGE_BREAK: char = '\u{c}';
+
+/// One text for all of a PDF's pages, with `PAGE_BREAK` between them.
+fn join_pages(pages: &[String]) -> String {
+    pages
+        .iter()
+        .map(|page| page.trim_end())
+        .collect::<Vec<_>>()
+        .join(&format!("\n{}\n", PAGE_BREAK))
+}
+
+/// Code and plain-text files from a repository, indexed as they are.
+fn extract_text_from_source(content: &[u8]) -> Result<Extraction, String> {
+    let text = String::from_utf8(content.to_vec()).map_err(|e| format!("Invalid UTF-8: {}", e))?;
+    Ok(Extraction {
+        text,
+        extractor: "source",
+        pages: None,
+        warnings: Vec::new(),
+        images: Vec::new(),
+        tables: Vec::new(),
+    })
+}
+
+fn extract_text_from_pdf(content: &[u8], max_images: usize) -> Result<Extraction, String> {
+    let mut warnings = Vec::new();
+    let mut page_count = None;
+    let mut images = Vec::new();
+    let mut tables = Vec::new();
+
+    // Try lopdf first
+    match lopdf::Document::load_mem(content) {
+        Ok(doc) => {
+            if max_images > 0 {
+                images = pdf_images(&doc, max_images, &mut warnings);
+            }
+            let pages = doc.get_pages();
+            let mut page_texts = Vec::with_capacity(pages.len());
+            let mut empty_pages = 0;
+
+            for (&page, &page_id) in &pages {
+                tables.extend(
+                    page_tables(&doc, page_id)
+                        .into_iter()
+                        .map(|markdown| Table {
+                            page: page as usize,
+                            markdown,
+                        }),
+                );
+            }
+            for (page_num, _) in pages.iter() {
+                match doc.extract_text(&[*page_num]) {
+                    Ok(page_text) if !page_text.trim().is_empty() => page_texts.push(page_text),
+                    _ => {
+                        empty_pages += 1;
+                        page_texts.push(String::new());
+                    }
+                }
+            }
+            page_count = Some(pages.len());
+            let text = join_pages(&page_texts);
+
+            if !text.trim().is_empty() {
+                if empty_pages > 0 {
+                    warnings.push(format!(
+                        "{} of {} pages yielded no text (scanned images?)",
+                        empty_pages,
+                        pages.len()
+                    ));
+                }
+                return Ok(Extraction {
+                    text,
+                    extractor: "lopdf",
+                    pages: page_count,
+                    warnings,
+                    images,
+                    tables,
+                });
+            }
+            warnings.push("lopdf found no text, fell back to pdf-extract".to_string());
+        }
+        Err(e) => {
+            warn!("lopdf failed: {}, trying pdf-extract", e);
+            warnings.push(format!(
+                "lopdf could not parse the file ({}), fell back to pdf-extract",
+                e
+            ));
+        }
+    }
+
+    // Fallback to pdf-extract
+    match pdf_extract::extract_text_from_mem_by_pages(content) {
+        Ok(page_texts) => {
+            let text = join_pages(&page_texts);
+            if text.trim().is_empty() {
+                Err("PDF contains no extractable text".to_string())
+            } else {
+                Ok(Extraction {
+                    text,
+                    extractor: "pdf-extract",
+                    pages: page_count.or(Some(page_texts.len())),
+                    warnings,
+                    images,
+                    tables,
+                })
+            }
+        }
+        Err(e) => Err(format!("Failed to extract PDF text: {}", e)),
+    }
+}
+
+/// A run of text shown on a PDF page: where it starts on the baseline,
+/// roughly where it ends, and its font size, all in page space.
+struct TextRun {
+    x: f32,
+    end: f32,
+    y: f32,
+    size: f32,
+    text: String,
+}
+
+/// `a` then `b`, for PDF's row-vector `[a b c d e f]` matrices.
+fn matrix_product(a: [f32; 6], b: [f32; 6]) -> [f32; 6] {
+    [
+        a[0] * b[0] + a[1] * b[2],
+        a[0] * b[1] + a[1] * b[3],
+        a[2] * b[0] + a[3] * b[2],
+        a[2] * b[1] + a[3] * b[3],
+        a[4] * b[0] + a[5] * b[2] + b[4],
+        a[4] * b[1] + a[5] * b[3] + b[5],
+    ]
+}
+
+fn translation(x: f32, y: f32) -> [f32; 6] {
+    [1.0, 0.0, 0.0, 1.0, x, y]
+}
+
+/// Where each run of text on a page is shown, following the content
+/// stream's text and graphics state. Glyph widths aren't looked up, so
+/// each character is taken to be half an em wide; good enough to tell the
+/// space between words from the gap between columns.
+fn page_text_runs(doc: &lopdf::Document, page_id: lopdf::ObjectId) -> Vec<TextRun> {
+    use lopdf::Object;
+
+    let Ok(content) = doc.get_and_decode_page_content(page_id) else {
+        return Vec::new();
+    };
+    let encodings: HashMap<Vec<u8>, lopdf::Encoding> = doc
+        .get_page_fonts(page_id)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(name, font)| Some((name, font.get_font_encoding(doc).ok()?)))
+        .collect();
+    let number = |operands: &[Object], i: usize| {
+        operands
+            .get(i)
+            .and_then(|o| o.as_float().ok())
+            .unwrap_or(0.0)
+    };
+
+    let mut runs = Vec::new();
+    let identity = translation(0.0, 0.0);
+    let (mut ctm, mut saved) = (identity, Vec::new());
+    let (mut tm, mut tlm) = (identity, identity);
+    let (mut font, mut size, mut leading) = (None, 0.0, 0.0);
+    for op in &content.operations {
+        let operands = &op.operands;
+        let mut shown = Vec::new();
+        match op.operator.as_str() {
+            "q" => saved.push(ctm),
+            "Q" => ctm = saved.pop().unwrap_or(identity),
+            "cm" if operands.len() == 6 => {
+                let m = std::array::from_fn(|i| number(operands, i));
+                ctm = matrix_product(m, ctm);
+            }
+            "BT" => (tm, tlm) = (identity, identity),
+            "Tf" => {
+                font = operands
+                    .first()
+                    .and_then(|o| o.as_name().ok())
+                    .and_then(|name| encodings.get(name));
+                size = number(operands, 1);
+            }
+            "TL" => leading = number(operands, 0),
+            "Td" | "TD" => {
+                let (x, y) = (number(operands, 0), number(operands, 1));
+                if op.operator == "TD" {
+                    leading = -y;
+                }
+                tlm = matrix_product(translation(x, y), tlm);
+                tm = tlm;
+            }
+            "Tm" if operands.len() == 6 => {
+                tlm = std::array::from_fn(|i| number(operands, i));
+                tm = tlm;
+            }
+            "T*" => {
+                tlm = matrix_product(translation(0.0, -leading), tlm);
+                tm = tlm;
+            }
+            "Tj" => shown.extend(operands.first()),
+            "'" | "\"" => {
+                tlm = matrix_product(translation(0.0, -leading), tlm);
+                tm = tlm;
+                shown.extend(operands.last());
+            }
+            "TJ" => {
+                if let Some(Ok(items)) = operands.first().map(|o| o.as_array()) {
+                    shown.extend(items);
+                }
+            }
+            _ => {}
+        }
+
+        for item in shown {
+            match item {
+                Object::String(bytes, _) => {
+                    let Some(text) = font.and_then(|f| lopdf::Document::decode_text(f, bytes).ok())
+                    else {
+                        continue;
+                    };
+                    let start = matrix_product(tm, ctm);
+                    let advance = text.chars().count() as f32 * size * 0.5;
+                    tm = matrix_product(translation(advance, 0.0), tm);
+                    let end = matrix_product(tm, ctm);
+                    let scale = start[2].hypot(start[3]);
+                    if !text.trim().is_empty() {
+                        runs.push(TextRun {
+                            x: start[4],
+                            end: end[4],
+                            y: start[5],
+                            size: (size * scale).abs(),
+                            text,
+                        });
+                    }
+                }
+                // Kerning in thousandths of an em, negative moving right
+                other => {
+                    if let Ok(offset) = other.as_float() {
+                        tm = matrix_product(translation(-offset / 1000.0 * size, 0.0), tm);
+                    }
+                }
+            }
+        }
+    }
+    runs
+}
+
+/// A line of a page split at its wide gaps: `(start, end, text)` per cell.
+type TableLine = Vec<(f32, f32, String)>;
+
+/// The page's text as lines top to bottom, each split into cells where the
+/// space between two runs is wider than a word gap.
+fn page_lines(mut runs: Vec<TextRun>) -> Vec<(f32, TableLine)> {
+    runs.sort_by(|a, b| b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x)));
+    let mut lines: Vec<(f32, f32, Vec<TextRun>)> = Vec::new();
+    for run in runs {
+        match lines.last_mut() {
+            Some((y, size, line)) if (*y - run.y).abs() <= size.max(run.size) * 0.4 => {
+                *size = size.max(run.size);
+                line.push(run);
+            }
+            _ => lines.push((run.y, run.size, vec![run])),
+        }
+    }
+
+    lines
+        .into_iter()
+        .map(|(y, size, mut line)| {
+            line.sort_by(|a, b| a.x.total_cmp(&b.x));
+            let mut cells: TableLine = Vec::new();
+            for run in line {
+                match cells.last_mut() {
+                    Some((_, end, text)) if run.x - *end < size * 0.8 => {
+                        if run.x - *end > size * 0.1 && !text.ends_with(' ') {
+                            text.push(' ');
+                        }
+                        text.push_str(&run.text);
+                        *end = end.max(run.end);
+                    }
+                    _ => cells.push((run.x, run.end, run.text)),
+                }
+            }
+            let cells = cells
+                .into_iter()
+                .map(|(x, end, text)| {
+                    (
+                        x,
+                        end,
+                        text.split_whitespace().collect::<Vec<_>>().join(" "),
+                    )
+                })
+                .collect();
+            (y - size, cells)
+        })
+        .collect()
+}
+
+/// Fewest rows, header included, that make a table.
+const MIN_TABLE_ROWS: usize = 3;
+/// Cells averaging more words than this are taken for columns of prose.
+const MAX_CELL_WORDS: f32 = 6.0;
+/// A wider gap between lines, in points, ends a table.
+const MAX_ROW_GAP: f32 = 40.0;
+
+/// The tables on a PDF page as Markdown: runs of consecutive lines split
+/// into two or more cells that line up in columns.
+fn page_tables(doc: &lopdf::Document, page_id: lopdf::ObjectId) -> Vec<String> {
+    let lines = page_lines(page_text_runs(doc, page_id));
+    let mut tables = Vec::new();
+    let mut block: Vec<&TableLine> = Vec::new();
+    let mut previous_bottom = f32::MAX;
+    for (bottom, cells) in lines.iter().chain(std::iter::once(&(f32::MIN, Vec::new()))) {
+        let line_gap = previous_bottom - bottom;
+        previous_bottom = *bottom;
+        if cells.len() >= 2 && (block.is_empty() || line_gap < MAX_ROW_GAP) {
+            block.push(cells);
+            continue;
+        }
+        tables.extend(markdown_table(&block));
+        block.clear();
+        if cells.len() >= 2 {
+            block.push(cells);
+        }
+    }
+    tables
+}
+
+/// Lines as a Markdown table, with the columns of the line that has the
+/// most cells; `None` if they don't make one.
+fn markdown_table(lines: &[&TableLine]) -> Option<String> {
+    if lines.len() < MIN_TABLE_ROWS {
+        return None;
+    }
+    let widest = lines.iter().max_by_key(|line| line.len())?;
+    let columns: Vec<f32> = widest.iter().map(|(x, _, _)| *x).collect();
+
+    let mut rows = Vec::with_capacity(lines.len());
+    let (mut filled, mut words) = (0, 0);
+    for line in lines {
+        let mut row = vec![String::new(); columns.len()];
+        for (x, end, text) in line.iter() {
+            // The column the cell overlaps most, so right-aligned numbers
+            // land under their header too
+            let column = (0..columns.len())
+                .max_by(|&a, &b| {
+                    let overlap = |i: usize| {
+                        let (start, stop) = (columns[i], widest[i].1);
+                        stop.min(*end) - start.max(*x)
+                    };
+                    overlap(a).total_cmp(&overlap(b))
+                })
+                .unwrap_or(0);
+            if !row[column].is_empty() {
+                row[column].push(' ');
+            }
+            row[column].push_str(text);
+            words += text.split_whitespace().count();
+        }
+        filled += row.iter().filter(|cell| !cell.is_empty()).count();
+        rows.push(row);
+    }
+    if filled * 10 < lines.len() * columns.len() * 6
+        || words as f32 / filled as f32 > MAX_CELL_WORDS
+    {
+        return None;
+    }
+
+    let row_line = |row: &[String]| {
+        let cells: Vec<String> = row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+        format!("| {} |", cells.join(" | "))
+    };
+    let mut table = vec![
+        row_line(&rows[0]),
+        format!("|{}", " --- |".repeat(columns.len())),
+    ];
+    table.extend(rows[1..].iter().map(|row| row_line(row)));
+    Some(table.join("\n"))
+}
+
+/// Images narrower or shorter than this are taken for icons, logos or
+/// decoration rather than figures.
+const MIN_FIGURE_PIXELS: i64 = 100;
+
+/// The figures of a PDF worth describing, first `max` by page, each image
+/// once however many pages show it. Images in encodings the vision model
+/// can't be given are counted in a warning.
+fn pdf_images(doc: &lopdf::Document, max: usize, warnings: &mut Vec<String>) -> Vec<PageImage> {
+    let mut images = Vec::new();
+    let mut seen = HashSet::new();
+    let (mut unsupported, mut over_limit) = (0, 0);
+    for (page, page_id) in doc.get_pages() {
+        for image in doc.get_page_images(page_id).unwrap_or_default() {
+            if !seen.insert(image.id)
+                || image.width < MIN_FIGURE_PIXELS
+                || image.height < MIN_FIGURE_PIXELS
+            {
+                continue;
+            }
+            if images.len() == max {
+                over_limit += 1;
+                continue;
+            }
+            match encode_pdf_image(doc, &image) {
+                Some(data) => images.push(PageImage {
+                    page: page as usize,
+                    data,
+                }),
+                None => unsupported += 1,
+            }
+        }
+    }
+    if unsupported > 0 {
+        warnings.push(format!(
+            "{} images are in an encoding that can't be described",
+            unsupported
+        ));
+    }
+    if over_limit > 0 {
+        warnings.push(format!(
+            "{} images past the first {} were not described (max_pdf_images)",
+            over_limit, max
+        ));
+    }
+    images
+}
+
+/// A PDF image as a file the vision model reads: JPEGs as they are, 8-bit
+/// grey or RGB pixels as PNG. `None` for other encodings and colour spaces.
+fn encode_pdf_image(doc: &lopdf::Document, image: &lopdf::xobject::PdfImage) -> Option<Vec<u8>> {
+    let filters = image.filters.clone().unwrap_or_default();
+    if filters == ["DCTDecode"] {
+        return Some(image.content.to_vec());
+    }
+    if image.bits_per_component != Some(8)
+        || matches!(image.color_space.as_deref(), Some("Indexed" | "DeviceCMYK"))
+    {
+        return None;
+    }
+    let pixels = if filters.is_empty() {
+        image.content.to_vec()
+    } else {
+        doc.get_object(image.id)
+            .and_then(|object| object.as_stream())
+            .and_then(|stream| stream.decompressed_content())
+            .ok()?
+    };
+
+    let (width, height) = (
+        u32::try_from(image.width).ok()?,
+        u32::try_from(image.height).ok()?,
+    );
+    let area = width as usize * height as usize;
+    let picture = match pixels.len().checked_div(area)? {
+        1 => image::DynamicImage::ImageLuma8(image::GrayImage::from_raw(
+            width,
+            height,
+            pixels[..area].to_vec(),
+        )?),
+        3 => image::DynamicImage::ImageRgb8(image::RgbImage::from_raw(
+            width,
+            height,
+            pixels[..area * 3].to_vec(),
+        )?),
+        _ => return None,
+    };
+    let mut png = std::io::Cursor::new(Vec::new());
+    picture.write_to(&mut png, image::ImageFormat::Png).ok()?;
+    Some(png.into_inner())
+}
+
+// ============================================================================
+// RETRIEVAL
+// ============================================================================
+
+/// Chunks sharing at least this fraction of the smaller one's words are
+/// near-duplicates; only the better-scored one is kept.
+const NEAR_DUPLICATE_RATIO: f32 = 0.8;
+/// Shortest boundary overlap worth stitching two chunks together over.
+const MIN_STITCH_WORDS: usize = 8;
+
+/// Chunks retrieved into the prompt per query.
+const TOP_K: usize = 5;
+/// Most chunks a chat request may ask for with `top_k`.
+const MAX_TOP_K: usize = 20;
+/// Candidates fetched per final result, so later stages have room to reorder,
+/// merge and drop.
+const CANDIDATE_FACTOR: usize = 4;
+/// Reciprocal rank fusion damping constant.
+const RRF_K: f32 = 60.0;
+/// Weight of query-term coverage against similarity in the rerank stage.
+const RERANK_TERM_WEIGHT: f32 = 0.2;
+/// Share of a chunk's best sentence score another sentence needs to survive
+/// the compress stage.
+const COMPRESS_KEEP_RATIO: f32 = 0.5;
+
+/// A search hit on its way into the prompt. `score` is its cosine similarity
+/// to the query; `rank` is what the pipeline currently orders by. `parts`
+/// counts the chunks stitched into `text`, which scales its share of the
+/// context budget.
+struct RetrievedChunk {
+    index: usize,
+    id: u64,
+    text: String,
+    source: String,
+    score: f32,
+    rank: f32,
+    parts: usize,
+    // The hit chunk's keywords, read with its text so they can't go stale
+    keywords: Vec<String>,
+}
+
+/// Per-request retrieval settings, each defaulting to its `Config` value.
+#[derive(Clone, Copy)]
+struct SearchOptions<'a> {
+    min_similarity: f32,
+    top_k: usize,
+    // Only search these documents; every document when `None`
+    scope: Option<&'a HashSet<String>>,
+    // Weight of the `recency` stage's boost
+    recency_weight: f32,
+    // Only search chunks tagged with one of these keywords; any when empty
+    keywords: &'a [String],
+}
+
+impl SearchOptions<'_> {
+    fn new(config: &Config, top_k: usize) -> Self {
+        Self {
+            min_similarity: config.min_similarity,
+            top_k,
+            scope: None,
+            recency_weight: config.recency_weight,
+            keywords: &[],
+        }
+    }
+}
+
+/// Pipeline output: the chunks for the prompt, best first, and those the
+/// threshold stage turned away.
+struct Retrieval {
+    chunks: Vec<RetrievedChunk>,
+    rejected: Vec<RetrievedChunk>,
+}
+
+/// The documents a request is limited to, or `None` to search everything:
+/// the named `sources`, narrowed to those in `collection` and to those
+/// carrying any of `tags` when given. Names that aren't indexed are an error
+/// rather than silently ignored.
+fn source_scope(
+    store: &VectorStore,
+    sources: &[String],
+    collection: Option<&str>,
+    tags: &[String],
+) -> Result<Option<HashSet<String>>, ApiError> {
+    if let Some(name) = sources
+        .iter()
+        .find(|name| !store.documents.contains_key(*name))
+    {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", name),
+        ));
+    }
+    let mut scope = (!sources.is_empty()).then(|| sources.iter().cloned().collect::<HashSet<_>>());
+
+    if let Some(collection) = collection {
+        let members = store.collection_documents(collection);
+        if members.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "collection_not_found",
+                format!("Collection {} not found", collection),
+            ));
+        }
+        scope = Some(match scope {
+            Some(scope) => scope.intersection(&members).cloned().collect(),
+            None => members,
+        });
+    }
+
+    if !tags.is_empty() {
+        let tagged = store.tagged_documents(tags);
+        if tagged.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "tag_not_found",
+                format!("No documents are tagged {}", tags.join(" or ")),
+            ));
+        }
+        scope = Some(match scope {
+            Some(scope) => scope.intersection(&tagged).cloned().collect(),
+            None => tagged,
+        });
+    }
+    Ok(scope)
+}
+
+/// Longest collection name accepted, in characters.
+const MAX_COLLECTION_NAME: usize = 64;
+
+/// Trims a collection name and checks it is 1–64 letters, digits, spaces,
+/// dots, dashes or underscores, so it reads cleanly in URLs and the UI.
+fn collection_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_COLLECTION_NAME
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '.' | '-' | '_'));
+    if !valid {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_collection",
+            format!(
+                "Collection names are 1-{} letters, digits, spaces, dots, dashes or underscores",
+                MAX_COLLECTION_NAME
+            ),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Unix seconds at midnight UTC of a `YYYY-MM-DD` date.
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day): (i64, i64, i64) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if year < 1970 || !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch, counting years from March so leap days fall last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days as u64 * 86_400)
+}
+
+/// Checks a document date is a real `YYYY-MM-DD` date from 1970 on.
+fn document_date(date: &str) -> Result<String, ApiError> {
+    let date = date.trim();
+    match parse_date(date) {
+        Some(_) => Ok(date.to_string()),
+        None => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_date",
+            format!("{} is not a date like 2024-05-31", date),
+        )),
+    }
+}
+
+/// Runs the configured retrieval stages for one query, returning at most
+/// `top_k` chunks. With a `scope`, only those documents are searched and
+/// summary routing is skipped.
+fn run_pipeline(
+    store: &VectorStore,
+    config: &Config,
+    query: &str,
+    query_embedding: &[f32],
+    options: &SearchOptions,
+) -> Retrieval {
+    let SearchOptions {
+        min_similarity,
+        top_k,
+        scope,
+        recency_weight,
+        keywords,
+    } = *options;
+    let pool = top_k * CANDIDATE_FACTOR;
+    let mut routed = scope.cloned();
+    let mut dense: Vec<(usize, f32)> = Vec::new();
+    let mut sparse: Vec<(usize, f32)> = Vec::new();
+    let mut chunks: Vec<RetrievedChunk> = Vec::new();
+    let mut rejected = Vec::new();
+
+    let to_chunks = |hits: &[(usize, f32)], similarities: bool| -> Vec<RetrievedChunk> {
+        hits.iter()
+            .map(|&(index, rank)| {
+                let chunk = &store.chunks[index];
+                RetrievedChunk {
+                    index,
+                    id: chunk.id,
+                    text: chunk.text.clone(),
+                    source: chunk.source.clone(),
+                    score: if similarities {
+                        rank
+                    } else {
+                        store.similarity(index, query_embedding)
+                    },
+                    rank,
+                    parts: 1,
+                    keywords: chunk.keywords.clone(),
+                }
+            })
+            .collect()
+    };
+
+    for stage in &config.pipeline {
+        match stage {
+            RetrievalStage::Route if scope.is_none() => {
+                routed = store.route_documents(query_embedding, config.routing_documents);
+                if let Some(routed) = &routed {
+                    info!("Routed query to {} documents by summary", routed.len());
+                }
+            }
+            RetrievalStage::Route => {}
+            RetrievalStage::Dense => {
+                let filter = ChunkFilter {
+                    sources: routed.as_ref(),
+                    keywords,
+                };
+                dense = store.search(query_embedding, pool, &filter);
+                chunks = to_chunks(&dense, true);
+            }
+            RetrievalStage::Sparse => {
+                let filter = ChunkFilter {
+                    sources: routed.as_ref(),
+                    keywords,
+                };
+                sparse = store.keyword_search(query, pool, &filter);
+                chunks = to_chunks(&sparse, false);
+            }
+            RetrievalStage::Fuse => {
+                let mut fused: HashMap<usize, f32> = HashMap::new();
+                for list in [&dense, &sparse] {
+                    for (rank, &(index, _)) in list.iter().enumerate() {
+                        *fused.entry(index).or_default() += 1.0 / (RRF_K + rank as f32 + 1.0);
+                    }
+                }
+                let mut fused: Vec<(usize, f32)> = fused.into_iter().collect();
+                fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+                fused.truncate(pool);
+                chunks = to_chunks(&fused, false);
+            }
+            RetrievalStage::Rerank => {
+                let terms = keyword_terms(query);
+                for chunk in &mut chunks {
+                    let words: HashSet<String> = keyword_words(&chunk.text).collect();
+                    let coverage = if terms.is_empty() {
+                        0.0
+                    } else {
+                        terms.intersection(&words).count() as f32 / terms.len() as f32
+                    };
+                    chunk.rank = chunk.score + RERANK_TERM_WEIGHT * coverage;
+                }
+                chunks.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap());
+            }
+            RetrievalStage::Mmr => {
+                let candidates = chunks
+                    .iter()
+                    .map(|chunk| (chunk.index, chunk.score))
+                    .collect();
+                let picked = store.mmr_select(candidates, top_k * 2, config.mmr_lambda);
+                let mut by_index: HashMap<usize, RetrievedChunk> =
+                    chunks.drain(..).map(|chunk| (chunk.index, chunk)).collect();
+                chunks = picked
+                    .into_iter()
+                    .filter_map(|(index, _)| by_index.remove(&index))
+                    .collect();
+            }
+            RetrievalStage::Expand if config.neighbor_window > 0 => {
+                for chunk in &mut chunks {
+                    if let Some((text, parts)) =
+                        store.expand_chunk(chunk.index, config.neighbor_window)
+                    {
+                        chunk.text = text;
+                        chunk.parts = parts;
+                    }
+                }
+            }
+            RetrievalStage::Expand => {}
+            RetrievalStage::Dedupe => chunks = dedupe_results(chunks),
+            RetrievalStage::Recency if recency_weight > 0.0 => {
+                let now = unix_now();
+                let half_life = config.recency_half_life_days * 86_400.0;
+                for chunk in &mut chunks {
+                    if let Some(time) = store.document_time(&chunk.source) {
+                        let age = now.saturating_sub(time) as f32;
+                        chunk.rank += recency_weight * 0.5f32.powf(age / half_life);
+                    }
+                }
+                chunks.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap());
+            }
+            RetrievalStage::Recency => {}
+            RetrievalStage::Compress => {
+                let weights = store.term_weights(&keyword_terms(query));
+                for chunk in &mut chunks {
+                    if let Some(text) = compress_chunk(&chunk.text, &weights) {
+                        chunk.text = text;
+                    }
+                }
+            }
+            RetrievalStage::Threshold => {
+                let (kept, below): (Vec<_>, Vec<_>) = chunks
+                    .into_iter()
+                    .partition(|chunk| chunk.score >= min_similarity);
+                chunks = kept;
+                rejected.extend(below);
+            }
+        }
+    }
+
+    chunks.truncate(top_k);
+    Retrieval { chunks, rejected }
+}
+
+/// Combines the pipeline results of several phrasings of one query: each
+/// chunk keeps its best similarity, overlaps are collapsed again and the best
+/// `top_k` are kept.
+fn merge_retrievals(retrievals: Vec<Retrieval>, top_k: usize) -> Retrieval {
+    if retrievals.len() == 1 {
+        return retrievals.into_iter().next().unwrap();
+    }
+
+    let mut chunks = Vec::new();
+    let mut rejected = Vec::new();
+    for retrieval in retrievals {
+        chunks.extend(retrieval.chunks);
+        rejected.extend(retrieval.rejected);
+    }
+
+    chunks.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    let mut seen = HashSet::new();
+    chunks.retain(|chunk| seen.insert(chunk.index));
+    let mut chunks = dedupe_results(chunks);
+    chunks.truncate(top_k);
+
+    rejected.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    rejected.retain(|chunk| seen.insert(chunk.index));
+    Retrieval { chunks, rejected }
+}
+
+/// Lowercased alphanumeric words of `text`, for keyword matching.
+fn keyword_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 1)
+        .map(|word| word.to_lowercase())
+}
+
+fn keyword_terms(query: &str) -> HashSet<String> {
+    keyword_words(query).collect()
+}
+
+/// The sentences of `text` relevant to the query, in their original order
+/// with `…` marking dropped stretches. Each sentence scores the summed
+/// `weights` of the query terms it contains. `None` when nothing would be
+/// dropped or no sentence matches.
+fn compress_chunk(text: &str, weights: &HashMap<String, f32>) -> Option<String> {
+    let sentences = split_sentences(text);
+    if sentences.len() < 2 || weights.is_empty() {
+        return None;
+    }
+
+    let scores: Vec<f32> = sentences
+        .iter()
+        .map(|sentence| {
+            let words: HashSet<String> = keyword_words(sentence).collect();
+            weights
+                .iter()
+                .filter(|(term, _)| words.contains(*term))
+                .map(|(_, weight)| weight)
+                .sum()
+        })
+        .collect();
+
+    let best = scores.iter().copied().fold(0.0, f32::max);
+    if best <= 0.0 {
+        return None;
+    }
+    let keep: Vec<bool> = scores
+        .iter()
+        .map(|&score| score >= best * COMPRESS_KEEP_RATIO)
+        .collect();
+    if keep.iter().all(|&kept| kept) {
+        return None;
+    }
+
+    let mut compressed = String::new();
+    let mut skipped = false;
+    for (sentence, &kept) in sentences.iter().zip(&keep) {
+        if !kept {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            compressed.push_str(if compressed.is_empty() {
+                "… "
+            } else {
+                " … "
+            });
+        } else if !compressed.is_empty() {
+            compressed.push(' ');
+        }
+        compressed.push_str(sentence);
+        skipped = false;
+    }
+    if skipped {
+        compressed.push_str(" …");
+    }
+    Some(compressed)
+}
+
+/// Collapses overlapping hits so the same passage never fills the context
+/// twice: neighbouring chunks from one document are stitched at their shared
+/// boundary, and near-identical chunks are dropped. Expects hits best-first.
+fn dedupe_results(results: Vec<RetrievedChunk>) -> Vec<RetrievedChunk> {
+    let mut kept: Vec<RetrievedChunk> = Vec::new();
+
+    'results: for hit in results {
+        for chunk in kept.iter_mut().filter(|chunk| chunk.source == hit.source) {
+            if let Some(stitched) = stitch_chunks(&chunk.text, &hit.text) {
+                chunk.text = stitched;
+                chunk.parts += hit.parts;
+                continue 'results;
+            }
+            if word_containment(&chunk.text, &hit.text) >= NEAR_DUPLICATE_RATIO {
+                continue 'results;
+            }
+        }
+
+        // Identical boilerplate can also repeat across documents
+        if kept
+            .iter()
+            .any(|chunk| word_containment(&chunk.text, &hit.text) >= NEAR_DUPLICATE_RATIO)
+        {
+            continue;
+        }
+
+        kept.push(hit);
+    }
+
+    kept
+}
+
+/// Joins two chunks when one ends with the words the other starts with, as
+/// consecutive chunks of a document do.
+fn stitch_chunks(a: &str, b: &str) -> Option<String> {
+    fn join(first: &[&str], second: &[&str]) -> Option<String> {
+        let max = first.len().min(second.len()) - 1;
+        (MIN_STITCH_WORDS..=max)
+            .rev()
+            .find(|&n| first[first.len() - n..] == second[..n])
+            .map(|n| [first, &second[n..]].concat().join(" "))
+    }
+
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let b: Vec<&str> = b.split_whitespace().collect();
+    if a.len().min(b.len()) <= MIN_STITCH_WORDS {
+        return None;
+    }
+
+    join(&a, &b).or_else(|| join(&b, &a))
+}
+
+/// Fraction of the smaller text's distinct words that also appear in the other.
+fn word_containment(a: &str, b: &str) -> f32 {
+    let a: HashSet<&str> = a.split_whitespace().collect();
+    let b: HashSet<&str> = b.split_whitespace().collect();
+    let smaller = a.len().min(b.len());
+    if smaller == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / smaller as f32
+}
+
+// ============================================================================
+// HELPERS
+// ============================================================================
+
+/// Hugging Face repository whose tokenizer matches the generation model.
+const TOKENIZER_REPO: &str = "microsoft/Phi-3-mini-4k-instruct";
+
+/// Counts prompt tokens with phi3's own tokenizer, falling back to an
+/// estimate until it has loaded, or when it could not be.
+struct TokenCounter {
+    tokenizer: OnceLock<tokenizers::Tokenizer>,
+}
+
+impl TokenCounter {
+    /// Loads `config.tokenizer`, or downloads the tokenizer from
+    /// `TOKENIZER_REPO` into the Hugging Face cache, in the background so a
+    /// slow network doesn't hold up startup.
+    fn load(config: &Config) -> Arc<Self> {
+        let counter = Arc::new(Self {
+            tokenizer: OnceLock::new(),
+        });
+        let loading = counter.clone();
+        let configured = config.tokenizer.clone();
+        tokio::task::spawn_blocking(move || {
+            let path = match configured {
+                Some(path) => Ok(path),
+                None => hf_hub::api::sync::Api::new()
+                    .and_then(|api| api.model(TOKENIZER_REPO.to_string()).get("tokenizer.json"))
+                    .map_err(|e| e.to_string()),
+            };
+            let tokenizer = path.and_then(|path| {
+                tokenizers::Tokenizer::from_file(&path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))
+            });
+
+            match tokenizer {
+                Ok(tokenizer) => {
+                    let _ = loading.tokenizer.set(tokenizer);
+                    info!("🔤 Loaded the phi3 tokenizer");
+                }
+                Err(e) => warn!(
+                    "⚠️  Could not load the phi3 tokenizer ({}), estimating prompt sizes instead",
+                    e
+                ),
+            }
+        });
+        counter
+    }
+
+    fn count(&self, text: &str) -> usize {
+        self.tokenizer
+            .get()
+            .and_then(|tokenizer| tokenizer.encode(text, false).ok())
+            .map_or_else(|| estimate_tokens(text), |encoding| encoding.len())
+    }
+
+    /// The longest prefix of `text` that fits in `max_tokens`, cut at a word
+    /// boundary.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        if max_tokens == 0 {
+            return "";
+        }
+        let end = match self
+            .tokenizer
+            .get()
+            .and_then(|tokenizer| tokenizer.encode(text, false).ok())
+        {
+            Some(encoding) if encoding.len() <= max_tokens => return text,
+            Some(encoding) => encoding.get_offsets()[max_tokens - 1].1,
+            None if estimate_tokens(text) <= max_tokens => return text,
+            None => text
+                .char_indices()
+                .nth(max_tokens * CHARS_PER_TOKEN)
+                .map_or(text.len(), |(end, _)| end),
+        };
+
+        let end = (0..=end.min(text.len()))
+            .rev()
+            .find(|&i| text.is_char_boundary(i))
+            .unwrap_or(0);
+        match text[..end].rfind(char::is_whitespace) {
+            Some(boundary) if boundary > 0 => text[..boundary].trim_end(),
+            _ => &text[..end],
+        }
+    }
+}
+
+/// Characters per token assumed without a tokenizer. Most text averages
+/// more, so estimates err towards fitting the context window.
+const CHARS_PER_TOKEN: usize = 3;
+
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        let error = Self {
+            status,
+            code,
+            message: message.into(),
+            request_id: uuid::Uuid::new_v4().to_string(),
+            retryable: false,
+        };
+        warn!("[{}] {}: {}", error.request_id, error.code, error.message);
+        error
+    }
+
+    fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(ErrorResponse { error: self })).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(rejection.status(), "invalid_request", rejection.body_text())
+    }
+}
+
+async fn embed_texts(state: &AppState, texts: Vec<String>) -> Result<Vec<Vec<f32>>, String> {
+    let embedding_model = state.embedding_model.clone();
+    tokio::task::spawn_blocking(move || embedding_model.embed(texts, None))
+        .await
+        .unwrap()
+        .map_err(|e| e.to_string())
+}
+
+/// Embeds `chunks` in `EMBED_BATCH_SIZE` batches on the ingestion model,
+/// bounded by the embedding pool, handing each batch to `on_batch` (with
+/// the index of its first chunk) as soon as it completes. Batches may
+/// complete out of order.
+async fn embed_batches(
+    state: &AppState,
+    chunks: &[String],
+    mut on_batch: impl FnMut(usize, Vec<Vec<f32>>),
+) -> Result<(), String> {
+    let mut tasks = JoinSet::new();
+
+    for (batch_index, batch) in chunks.chunks(EMBED_BATCH_SIZE).enumerate() {
+        let pool = state.embedding_pool.clone();
+        let embedding_model = state.ingest_embedding_model.clone();
+        let batch = batch.to_vec();
+
+        tasks.spawn(async move {
+            let _permit = pool.acquire_owned().await.unwrap();
+            let result = tokio::task::spawn_blocking(move || embedding_model.embed(batch, None))
+                .await
+                .unwrap();
+            (batch_index * EMBED_BATCH_SIZE, result)
+        });
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+        // A batch whose task panicked fails the whole call like one whose
+        // embedding failed
+        let batch = joined
+            .map_err(|e| format!("embedding task failed: {}", e))
+            .and_then(|(start, result)| {
+                result
+                    .map(|embeddings| (start, embeddings))
+                    .map_err(|e| e.to_string())
+            });
+        match batch {
+            Ok((start, embeddings)) => on_batch(start, embeddings),
+            Err(e) => {
+                tasks.abort_all();
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Like `embed_batches`, but returns all embeddings in input order.
+async fn embed_batches_collect(
+    state: &AppState,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, String> {
+    let mut embeddings = vec![Vec::new(); texts.len()];
+    embed_batches(state, texts, |start, batch| {
+        for (offset, embedding) in batch.into_iter().enumerate() {
+            embeddings[start + offset] = embedding;
+        }
+    })
+    .await?;
+    Ok(embeddings)
+}
+
+/// Writes the whole store to `data_dir/store.json` via a temp file + rename,
+/// so a crash mid-write never leaves a truncated snapshot behind.
+async fn persist_store(state: &AppState) {
+    let _guard = state.persist_lock.lock().await;
+
+    let snapshot = {
+        let store = state.vector_store.read().unwrap();
+        serde_json::to_vec(&*store)
+    };
+
+    let bytes = match snapshot {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to serialize store: {}", e);
+            return;
+        }
+    };
+
+    let path = state.config.store_path();
+    let tmp_path = path.with_extension("json.tmp");
+
+    let result = async {
+        tokio::fs::create_dir_all(&state.config.data_dir).await?;
+        tokio::fs::write(&tmp_path, &bytes).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!("Failed to persist store to {}: {}", path.display(), e);
+    }
+}
+
+async fn warm_up_ollama(client: &reqwest::Client, model: &str) {
+    info!("🔥 Warming up Ollama connection...");
+    let _ = client
+        .post("http://localhost:11434/api/generate")
+        .json(&serde_json::json!({
+            "model": model,
+            "prompt": "Hi",
+            "stream": false,
+            "options": {"num_predict": 1}
+        }))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await;
+    info!("✅ Ollama warm-up complete");
+}
+
+/// Words of a document shown to the model when summarizing it
+const SUMMARY_INPUT_WORDS: usize = 1000;
+/// Words kept from the start of a document when no summary can be generated
+const FALLBACK_SUMMARY_WORDS: usize = 120;
+
+/// Asks Ollama for a short summary of a document, used to route queries to
+/// likely documents. Falls back to the document's opening words.
+async fn summarize_document(state: &AppState, text: &str) -> String {
+    let excerpt: Vec<&str> = text.split_whitespace().take(SUMMARY_INPUT_WORDS).collect();
+    let prompt = format!(
+        "Summarize what this document is about in 2-3 sentences, naming its main topics.\n\nDocument:\n{}\n\nSummary:",
+        excerpt.join(" ")
+    );
+
+    match complete(state, &state.config.model, &prompt, 0.2, 128, 60).await {
+        Ok(summary) if !summary.is_empty() => summary,
+        other => {
+            if let Err(e) = other {
+                warn!(
+                    "Summary generation failed, using document opening: {}",
+                    e.message
+                );
+            }
+            excerpt[..excerpt.len().min(FALLBACK_SUMMARY_WORDS)].join(" ")
+        }
+    }
+}
+
+/// What the vision model is asked to put in an image's description, which is
+/// indexed in place of the text an image lacks.
+const IMAGE_PROMPT: &str = "Describe this image for a search index. Say what kind of image it is (diagram, chart, table, screenshot, photo, ...) and what it shows. Transcribe every piece of text in it exactly, including labels, legends and axis titles. For diagrams, name the components and how they connect; for charts, give the values and trends. Reply with the description only.";
+
+/// Indexable text for an uploaded image: its description by
+/// `Config::vision_model`, headed by the file name so the image's own name
+/// is searchable too.
+async fn describe_image(
+    state: &AppState,
+    filename: &str,
+    content: &[u8],
+) -> Result<Extraction, String> {
+    let description = vision_description(state, content).await.map_err(|e| {
+        format!(
+            "Failed to describe {} with {}: {} (images need a vision model: ollama pull {})",
+            filename, state.config.vision_model, e, state.config.vision_model
+        )
+    })?;
+    Ok(Extraction {
+        text: format!("Image: {}\n\n{}", filename, description),
+        extractor: "vision",
+        pages: None,
+        warnings: Vec::new(),
+        images: Vec::new(),
+        tables: Vec::new(),
+    })
+}
+
+/// Describes the figures found in a PDF, giving up after the first failure
+/// (most likely a missing vision model) with a warning rather than failing
+/// the whole document.
+async fn describe_figures(
+    state: &AppState,
+    images: Vec<PageImage>,
+    warnings: &mut Vec<String>,
+    redactions: &mut BTreeMap<PiiKind, usize>,
+) -> Vec<Figure> {
+    let mut figures = Vec::with_capacity(images.len());
+    let total = images.len();
+    for image in images {
+        match vision_description(state, &image.data).await {
+            Ok(description) => figures.push(Figure {
+                page: image.page,
+                description: redact_pii_counted(&description, &state.config.redact_pii, redactions),
+            }),
+            Err(e) => {
+                warn!("Failed to describe a figure: {}", e);
+                warnings.push(format!(
+                    "{} of {} figures could not be described by {}: {}",
+                    total - figures.len(),
+                    total,
+                    state.config.vision_model,
+                    e
+                ));
+                break;
+            }
+        }
+    }
+    figures
+}
+
+/// The chunks a document's figures are indexed as: each description
+/// chunked on its own, every chunk naming the figure's page.
+fn figure_chunks(figures: &[Figure], chunk_size: usize, overlap: usize) -> Vec<String> {
+    figures
+        .iter()
+        .flat_map(|figure| {
+            chunk_text(&figure.description, chunk_size, overlap)
+                .into_iter()
+                .map(move |chunk| format!("[Figure, page {}] {}", figure.page, chunk))
+        })
+        .collect()
+}
+
+/// The chunks a document's tables are indexed as: as many rows as fit in
+/// `chunk_size` words at a time, each chunk repeating the header and naming
+/// the table's page.
+fn table_chunks(tables: &[Table], chunk_size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    for table in tables {
+        let mut lines = table.markdown.lines();
+        let header = lines.by_ref().take(2).collect::<Vec<_>>().join("\n");
+        let header_words = header.split_whitespace().count();
+        let mut rows: Vec<&str> = Vec::new();
+        let mut words = header_words;
+        for line in lines {
+            let line_words = line.split_whitespace().count();
+            if !rows.is_empty() && words + line_words > chunk_size {
+                chunks.push(format!(
+                    "[Table, page {}]\n{}\n{}",
+                    table.page,
+                    header,
+                    rows.join("\n")
+                ));
+                rows.clear();
+                words = header_words;
+            }
+            rows.push(line);
+            words += line_words;
+        }
+        chunks.push(format!(
+            "[Table, page {}]\n{}\n{}",
+            table.page,
+            header,
+            rows.join("\n")
+        ));
+    }
+    chunks
+}
+
+/// The chunks indexed after a document's text, for its tables and figures.
+fn attached_chunks(
+    tables: &[Table],
+    figures: &[Figure],
+    chunk_size: usize,
+    overlap: usize,
+) -> Vec<String> {
+    let mut chunks = table_chunks(tables, chunk_size);
+    chunks.extend(figure_chunks(figures, chunk_size, overlap));
+    chunks
+}
+
+/// Makes each chunk its own parent section, after the text's.
+fn add_attached_sections(
+    sections: &mut Vec<String>,
+    chunk_sections: &mut Vec<usize>,
+    attached_chunks: &[String],
+) {
+    for chunk in attached_chunks {
+        chunk_sections.push(sections.len());
+        sections.push(chunk.clone());
+    }
+}
+
+/// `Config::vision_model`'s description of an image, per `IMAGE_PROMPT`.
+async fn vision_description(state: &AppState, content: &[u8]) -> Result<String, String> {
+    use base64::Engine;
+
+    let request = serde_json::json!({
+        "model": state.config.vision_model,
+        "messages": [{
+            "role": "user",
+            "content": IMAGE_PROMPT,
+            "images": [base64::engine::general_purpose::STANDARD.encode(content)],
+        }],
+        "stream": false,
+        "options": {"temperature": 0.1, "num_predict": 768},
+    });
+    let description = send_to_ollama(state, &request, 300)
+        .await
+        .map_err(|e| e.message)?
+        .json::<OllamaResponse>()
+        .await
+        .map(|r| r.message.content.trim().to_string())
+        .map_err(|e| format!("Failed to parse Ollama response: {}", e))?;
+    if description.is_empty() {
+        return Err("the description came back empty".to_string());
+    }
+    Ok(description)
+}
+
+/// Category tags a document gets at most.
+const MAX_DOCUMENT_TAGS: usize = 2;
+
+/// Asks Ollama which of `Config::categories` a document belongs to. Replies
+/// naming anything else are ignored, so a confused model tags nothing rather
+/// than inventing categories.
+async fn classify_document(state: &AppState, text: &str) -> Vec<String> {
+    let categories = &state.config.categories;
+    if categories.is_empty() {
+        return Vec::new();
+    }
+    let excerpt: Vec<&str> = text.split_whitespace().take(SUMMARY_INPUT_WORDS).collect();
+    let prompt = format!(
+        "Which of these categories does the document belong to: {}? Reply with the one or two that fit best, comma-separated, and nothing else.\n\nDocument:\n{}\n\nCategories:",
+        categories.join(", "),
+        excerpt.join(" ")
+    );
+
+    let reply = match complete(state, &state.config.model, &prompt, 0.0, 24, 60).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!(
+                "Tagging failed, leaving the document untagged: {}",
+                e.message
+            );
+            return Vec::new();
+        }
+    };
+    let mut tags: Vec<String> = Vec::new();
+    for answer in reply.split([',', '\n']) {
+        let answer = answer.trim_matches(|c: char| !c.is_alphanumeric());
+        if let Some(category) = categories
+            .iter()
+            .find(|category| category.eq_ignore_ascii_case(answer))
+        {
+            if !tags.contains(category) && tags.len() < MAX_DOCUMENT_TAGS {
+                tags.push(category.clone());
+            }
+        }
+    }
+    tags.sort();
+    tags
+}
+
+/// Tokens of document summaries shown to the model for a corpus overview.
+const OVERVIEW_INPUT_TOKENS: usize = 1500;
+
+/// Asks Ollama for a few sentences on what the corpus as a whole covers,
+/// written from the documents' summaries. Summaries that don't fit in
+/// `OVERVIEW_INPUT_TOKENS` are left out and counted instead.
+async fn corpus_overview(state: &AppState, topics: &[DocumentTopics]) -> Result<String, ApiError> {
+    let mut listing = String::new();
+    let mut used = 0;
+    let mut omitted = 0;
+    for topic in topics {
+        let line = format!(
+            "- {}: {}\n",
+            topic.name,
+            topic.summary.as_deref().unwrap_or("(no summary)")
+        );
+        let tokens = state.tokens.count(&line);
+        if used + tokens > OVERVIEW_INPUT_TOKENS {
+            omitted += 1;
+            continue;
+        }
+        used += tokens;
+        listing.push_str(&line);
+    }
+    if omitted > 0 {
+        listing.push_str(&format!("- ...and {} more documents\n", omitted));
+    }
+
+    let prompt = format!(
+        "These are summaries of the documents in a collection. Describe the collection as a whole in 3-5 sentences: the main subject areas it covers and how the documents relate. Do not list every document.\n\nDocuments:\n{}\nOverview:",
+        listing
+    );
+    complete(state, &state.config.model, &prompt, 0.3, 256, 120).await
+}
+
+/// Rephrasings requested from the model when rewriting a query.
+const MAX_QUERY_REWRITES: usize = 3;
+/// Sub-questions a deep-think answer is broken into at most.
+const MAX_SUB_QUESTIONS: usize = 3;
+
+/// Asks Ollama to restate a (possibly terse) query as fuller search queries.
+/// Returns the original query first, followed by up to `MAX_QUERY_REWRITES`
+/// distinct rewrites; only the original if Ollama can't help.
+async fn rewrite_query(state: &AppState, model: &str, query: &str) -> Vec<String> {
+    let prompt = format!(
+        "Rewrite this search query so it finds the right passages in a document collection. Give {} different, more complete phrasings that keep its meaning, one per line, without numbering or commentary.\n\nQuery: {}\n\nRewrites:",
+        MAX_QUERY_REWRITES, query
+    );
+
+    let mut queries = vec![query.to_string()];
+    match complete(state, model, &prompt, 0.3, 128, 30).await {
+        Ok(rewrites) => {
+            for line in list_items(&rewrites) {
+                if !queries.iter().any(|q| q.eq_ignore_ascii_case(line)) {
+                    queries.push(line.to_string());
+                }
+                if queries.len() > MAX_QUERY_REWRITES {
+                    break;
+                }
+            }
+        }
+        Err(e) => warn!(
+            "Query rewrite failed, searching with the original: {}",
+            e.message
+        ),
+    }
+    queries
+}
+
+/// Asks Ollama to break a question into the simpler questions that together
+/// answer it. A question that needs no splitting (or a failed request) comes
+/// back as the only sub-question.
+async fn decompose_question(state: &AppState, model: &str, query: &str) -> Vec<String> {
+    let prompt = format!(
+        "Break this question into at most {} simpler questions that can each be answered by looking something up in a document collection and that together answer it. If it is already simple, repeat it unchanged. Write one question per line, without numbering or commentary.\n\nQuestion: {}\n\nSub-questions:",
+        MAX_SUB_QUESTIONS, query
+    );
+
+    let mut questions: Vec<String> = Vec::new();
+    match complete(state, model, &prompt, 0.2, 160, 60).await {
+        Ok(text) => {
+            for line in list_items(&text) {
+                if !questions.iter().any(|q| q.eq_ignore_ascii_case(line)) {
+                    questions.push(line.to_string());
+                }
+                if questions.len() == MAX_SUB_QUESTIONS {
+                    break;
+                }
+            }
+        }
+        Err(e) => warn!(
+            "Question decomposition failed, answering it whole: {}",
+            e.message
+        ),
+    }
+
+    if questions.is_empty() {
+        questions.push(query.to_string());
+    }
+    questions
+}
+
+/// Non-empty lines of a model-written list, without numbering, bullets or
+/// surrounding quotes.
+fn list_items(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| {
+            line.trim()
+                .trim_start_matches(|c: char| {
+                    c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*')
+                })
+                .trim()
+                .trim_matches('"')
+                .trim()
+        })
+        .filter(|line| !line.is_empty())
+}
+
+/// Runs a one-off, non-streamed generation and returns the trimmed text.
+async fn complete(
+    state: &AppState,
+    model: &str,
+    prompt: &str,
+    temperature: f64,
+    num_predict: u32,
+    timeout_secs: u64,
+) -> Result<String, ApiError> {
+    let request = serde_json::json!({
+        "model": model,
+        "messages": [OllamaMessage::new("user", prompt)],
+        "stream": false,
+        "options": {
+            "temperature": temperature,
+            "num_predict": num_predict,
+        }
+    });
+
+    let response = send_to_ollama(state, &request, timeout_secs).await?;
+    response
+        .json::<OllamaResponse>()
+        .await
+        .map(|r| r.message.content.trim().to_string())
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "invalid_llm_response",
+                format!("Failed to parse Ollama response: {}", e),
+            )
+            .retryable()
+        })
+}
+
+// ============================================================================
+// UPLOAD JOBS
+// ============================================================================
+
+const EMBED_BATCH_SIZE: usize = 32;
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+/// Suggested wait before retrying an upload rejected because the queue is full.
+const UPLOAD_RETRY_AFTER_SECS: u32 = 15;
+
+impl FileProgress {
+    fn queued(filename: String) -> Self {
+        Self {
+            filename,
+            stage: FileStage::Queued,
+            percent: 0,
+            chunks: 0,
+            duplicates_skipped: 0,
+            error: None,
+        }
+    }
+
+    fn failed(filename: String, error: String) -> Self {
+        Self {
+            filename,
+            stage: FileStage::Failed,
+            percent: 100,
+            chunks: 0,
+            duplicates_skipped: 0,
+            error: Some(error),
+        }
+    }
+}
+
+impl UploadJob {
+    fn update_percent(&mut self) {
+        if self.files.is_empty() {
+            return;
+        }
+        let sum: usize = self.files.iter().map(|f| f.percent as usize).sum();
+        self.percent = (sum / self.files.len()) as u8;
+    }
+}
+
+/// Registers a new job, dropping finished ones older than `JOB_RETENTION`.
+fn insert_job(state: &AppState, job: UploadJob) {
+    let mut jobs = state.jobs.write().unwrap();
+    jobs.retain(|_, job| {
+        job.finished_at
+            .is_none_or(|finished| finished.elapsed() < JOB_RETENTION)
+    });
+    jobs.insert(job.id.clone(), job);
+}
+
+/// Marks a job finished once all its files are processed.
+fn finish_job(state: &AppState, job_id: &str) {
+    let total_chunks = state.vector_store.read().unwrap().count();
+    if let Some(job) = state.jobs.write().unwrap().get_mut(job_id) {
+        let all_failed = job.files.iter().all(|f| f.stage == FileStage::Failed);
+        job.status = if all_failed && !job.files.is_empty() {
+            JobStatus::Failed
+        } else {
+            JobStatus::Completed
+        };
+        job.percent = 100;
+        job.total_chunks = total_chunks;
+        job.duplicates_skipped = job.files.iter().map(|f| f.duplicates_skipped).sum();
+        job.finished_at = Some(Instant::now());
+    }
+}
+
+fn update_file_progress(
+    state: &AppState,
+    job_id: &str,
+    index: usize,
+    update: impl FnOnce(&mut FileProgress),
+) {
+    let mut jobs = state.jobs.write().unwrap();
+    if let Some(job) = jobs.get_mut(job_id) {
+        if let Some(file) = job.files.get_mut(index) {
+            update(file);
+        }
+        job.update_percent();
+    }
+}
+
+fn fail_file(state: &AppState, job_id: &str, index: usize, error: String) {
+    warn!("{}", error);
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Failed;
+        file.percent = 100;
+        file.error = Some(error);
+    });
+}
+
+/// Processes queued uploads one job at a time so bulk ingestion never runs
+/// inside a request handler. A job's files are ingested `upload_concurrency`
+/// at a time; files sharing a name go in upload order so the last one wins.
+/// Stops at shutdown after finishing the running job, so its documents are
+/// saved whole.
+async fn upload_worker(
+    root: AppState,
+    mut queue: mpsc::UnboundedReceiver<QueuedUpload>,
+    shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let mut upload = tokio::select! {
+            biased;
+            _ = shutdown_requested(shutdown.clone()) => break,
+            upload = queue.recv() => match upload {
+                Some(upload) => upload,
+                None => break,
+            },
+        };
+        let state = root.for_tenant(&upload.tenant);
+        if let Some(job) = state.jobs.write().unwrap().get_mut(&upload.job_id) {
+            job.status = JobStatus::Running;
+        }
+
+        let mut by_name: Vec<Vec<(usize, String, Bytes)>> = Vec::new();
+        let mut positions = HashMap::new();
+        for file in std::mem::take(&mut upload.files) {
+            let position = *positions.entry(file.1.clone()).or_insert_with(|| {
+                by_name.push(Vec::new());
+                by_name.len() - 1
+            });
+            by_name[position].push(file);
+        }
+
+        let mut outcomes = futures_util::stream::iter(by_name)
+            .map(|files| async {
+                let mut processed = false;
+                for (index, filename, content) in files {
+                    processed |= ingest_file(&state, &upload, index, filename, content).await;
+                }
+                processed
+            })
+            .buffer_unordered(state.config.upload_concurrency);
+        let mut any_processed = false;
+        while let Some(processed) = outcomes.next().await {
+            any_processed |= processed;
+        }
+
+        if any_processed {
+            persist_store(&state).await;
+        }
+
+        finish_job(&state, &upload.job_id);
+        if upload.watched {
+            record_watch_results(&state, &upload.job_id);
+        }
+    }
+
+    queue.close();
+    let mut dropped = 0;
+    while queue.try_recv().is_ok() {
+        dropped += 1;
+    }
+    if dropped > 0 {
+        warn!(
+            "⚠️  Dropped {} queued upload jobs on shutdown; upload them again after restart",
+            dropped
+        );
+    }
+}
+
+/// Extracts, chunks and embeds a single file, reporting each stage on the job.
+/// Returns whether the store changed.
+async fn ingest_file(
+    state: &AppState,
+    upload: &QueuedUpload,
+    index: usize,
+    mut filename: String,
+    content: Bytes,
+) -> bool {
+    let job_id = upload.job_id.as_str();
+    let collection = upload.collection.as_deref();
+    info!("Processing file: {} ({} bytes)", filename, content.len());
+    let _ingest = state.ingest_lock.read().await;
+    let started = Instant::now();
+
+    let content_hash = format!("{:x}", Sha256::digest(&content));
+    let previous_hash = {
+        let store = state.vector_store.read().unwrap();
+        store
+            .documents
+            .get(&filename)
+            .map(|doc| doc.content_hash.clone())
+    };
+    let mut replacing = previous_hash.is_some();
+    if previous_hash.flatten().as_deref() == Some(content_hash.as_str()) {
+        info!("{} is already indexed and unchanged, skipping", filename);
+        update_file_progress(state, job_id, index, |file| {
+            file.stage = FileStage::Unchanged;
+            file.percent = 100;
+        });
+        // Still file it under the requested collection and date
+        let mut store = state.vector_store.write().unwrap();
+        let filed =
+            collection.is_some_and(|collection| store.add_to_collection(&filename, collection));
+        let dated = upload.date.is_some() && store.set_date(&filename, upload.date.clone());
+        // An upload that turns up in the watch folder becomes watched
+        let watched = upload.watched
+            && store
+                .documents
+                .get_mut(&filename)
+                .is_some_and(|record| !std::mem::replace(&mut record.watched, true));
+        return filed || dated || watched;
+    }
+
+    if replacing && matches!(upload.on_conflict, OnConflict::Version) {
+        let versioned = next_version_name(&state.vector_store.read().unwrap(), &filename);
+        info!(
+            "{} already indexed, storing new version as {}",
+            filename, versioned
+        );
+        filename = versioned;
+        replacing = false;
+        update_file_progress(state, job_id, index, |file| {
+            file.filename = filename.clone();
+        });
+    }
+
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Extracting;
+    });
+
+    // Extract text based on file type; images are described by the vision model
+    let name = filename.clone();
+    let extraction = if is_image_file(&filename) {
+        describe_image(state, &filename, &content).await
+    } else {
+        let max_images = if state.config.describe_pdf_images {
+            state.config.max_pdf_images
+        } else {
+            0
+        };
+        tokio::task::spawn_blocking(move || extract_text(&name, &content, max_images))
+            .await
+            .unwrap()
+    };
+    let extraction = match extraction {
+        Ok(extraction) => extraction,
+        Err(e) => {
+            fail_file(state, job_id, index, e);
+            return false;
+        }
+    };
+
+    let Extraction {
+        text,
+        extractor,
+        pages,
+        mut warnings,
+        images,
+        tables,
+    } = extraction;
+    if text.trim().is_empty() {
+        fail_file(
+            state,
+            job_id,
+            index,
+            format!("No text extracted from {}", filename),
+        );
+        return false;
+    }
+
+    info!("Extracted {} characters from {}", text.len(), filename);
+    let (text, mut redactions) = redact_pii(&text, &state.config.redact_pii);
+    if !redactions.is_empty() {
+        info!("Redacted {:?} in {}", redactions, filename);
+    }
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Chunking;
+        file.percent = 10;
+    });
+
+    let tables: Vec<Table> = tables
+        .into_iter()
+        .map(|table| Table {
+            markdown: redact_pii_counted(
+                &table.markdown,
+                &state.config.redact_pii,
+                &mut redactions,
+            ),
+            ..table
+        })
+        .collect();
+    if !tables.is_empty() {
+        info!("Found {} tables in {}", tables.len(), filename);
+    }
+    let figures = if images.is_empty() {
+        Vec::new()
+    } else {
+        info!("Describing {} figures in {}", images.len(), filename);
+        describe_figures(state, images, &mut warnings, &mut redactions).await
+    };
+
+    // Chunk text (256 words with 50 word overlap by default for better retrieval precision)
+    let mut chunks = chunk_text(&text, state.config.chunk_size, state.config.chunk_overlap);
+    let attached_chunks = attached_chunks(
+        &tables,
+        &figures,
+        state.config.chunk_size,
+        state.config.chunk_overlap,
+    );
+    chunks.extend(attached_chunks.iter().cloned());
+    info!("Created {} chunks from {}", chunks.len(), filename);
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Embedding;
+        file.percent = 20;
+    });
+
+    // Generate embeddings in parallel batches, inserting each batch as it lands
+    // so a large document never holds the store lock (or memory) all at once.
+    // A changed document is swapped in whole instead, so the old version keeps
+    // answering queries until the new one is complete.
+    let chunk_count = chunks.len();
+    let mut added_ids = HashSet::new();
+    let mut replacement = Vec::new();
+    let mut embedded = 0;
+    let result = embed_batches(state, &chunks, |start, embeddings| {
+        embedded += embeddings.len();
+        if replacing {
+            replacement.extend(chunks[start..].iter().cloned().zip(embeddings));
+        } else {
+            let mut store = state.vector_store.write().unwrap();
+            for (offset, embedding) in embeddings.into_iter().enumerate() {
+                let chunk = chunks[start + offset].clone();
+                if let Some(id) = store.add(chunk, embedding, filename.clone()) {
+                    added_ids.insert(id);
+                }
+            }
+        }
+
+        let percent = 20 + 70 * embedded / chunk_count;
+        update_file_progress(state, job_id, index, |file| {
+            file.percent = percent.min(99) as u8;
+        });
+    })
+    .await;
+
+    if let Err(e) = result {
+        // Roll back the batches that already made it into the store; a
+        // replacement never touched it, so the old version stays intact
+        if !replacing {
+            state
+                .vector_store
+                .write()
+                .unwrap()
+                .remove_chunk_ids(&added_ids, &filename);
+        }
+        fail_file(
+            state,
+            job_id,
+            index,
+            format!("Failed to generate embeddings for {}: {}", filename, e),
+        );
+        return false;
+    }
+
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Summarizing;
+        file.percent = 90;
+    });
+    let summary = summarize_document(state, &text).await;
+    let tags = classify_document(state, &text).await;
+    let summary_embedding = embed_batches_collect(state, std::slice::from_ref(&summary))
+        .await
+        .map(|mut embeddings| embeddings.remove(0))
+        .unwrap_or_else(|e| {
+            warn!("Failed to embed summary of {}: {}", filename, e);
+            warnings.push(format!(
+                "Summary could not be embedded ({}), so queries are never routed to this document",
+                e
+            ));
+            Vec::new()
+        });
+
+    let characters = text.chars().count();
+    let (mut sections, mut chunk_sections) = split_sections(
+        &text,
+        state.config.parent_size,
+        state.config.chunk_size,
+        state.config.chunk_overlap,
+    );
+    add_attached_sections(&mut sections, &mut chunk_sections, &attached_chunks);
+    let duplicates = {
+        let mut store = state.vector_store.write().unwrap();
+        if replacing {
+            added_ids = store.replace_chunks(&filename, replacement);
+        }
+        let duplicates = chunk_count - added_ids.len();
+        store.set_document(filename.clone(), text, content_hash);
+        if let Some(collection) = collection {
+            store.add_to_collection(&filename, collection);
+        }
+        if upload.date.is_some() {
+            store.set_date(&filename, upload.date.clone());
+        }
+        store.set_chunk_order(&filename, &chunks);
+        store.set_sections(&filename, sections, chunk_sections);
+        store.set_figures(&filename, figures);
+        store.set_tables(&filename, tables);
+        store.set_summary(&filename, summary, summary_embedding);
+        store.set_tags(&filename, tags);
+        if let Some(record) = store.documents.get_mut(&filename) {
+            record.watched |= upload.watched;
+        }
+        if store.documents[&filename].language.is_none() {
+            warnings.push("Language could not be detected".to_string());
+        }
+        store.set_report(
+            &filename,
+            IngestionReport {
+                extractor: extractor.to_string(),
+                pages,
+                characters,
+                chunks: added_ids.len(),
+                filtered_chunks: duplicates,
+                warnings,
+                redactions,
+                duration_ms: started.elapsed().as_millis() as u64,
+                ingested_at: unix_now(),
+            },
+        );
+        duplicates
+    };
+
+    update_file_progress(state, job_id, index, |file| {
+        file.stage = FileStage::Done;
+        file.percent = 100;
+        file.chunks = added_ids.len();
+        file.duplicates_skipped = duplicates;
+    });
+    info!(
+        "Successfully processed {} ({} duplicate chunks skipped)",
+        filename, duplicates
+    );
+    true
+}
+
+/// First free `name.vN.ext` (N >= 2) for a document that is already indexed.
+fn next_version_name(store: &VectorStore, filename: &str) -> String {
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (filename, String::new()),
+    };
+
+    (2..)
+        .map(|version| format!("{}.v{}{}", stem, version, extension))
+        .find(|name| !store.documents.contains_key(name))
+        .unwrap()
+}
+
+// ============================================================================
+// REPOSITORY INGESTION
+// ============================================================================
+
+/// Extensions of the code and plain-text files indexed besides Markdown and
+/// PDF, as found in repositories.
+const SOURCE_EXTENSIONS: &[&str] = &[
+    "rs", "py", "js", "jsx", "ts", "tsx", "go", "java", "kt", "scala", "c", "h", "cc", "cpp",
+    "hpp", "cs", "rb", "php", "swift", "sh", "sql", "html", "css", "toml", "yaml", "yml", "json",
+    "txt", "rst",
+];
+/// Paths left out of a repository when the request gives no `exclude`:
+/// dependencies, build output, hidden directories and lock files.
+const REPO_DEFAULT_EXCLUDES: &[&str] = &[
+    "**/node_modules/**",
+    "**/target/**",
+    "**/vendor/**",
+    "**/dist/**",
+    "**/build/**",
+    "**/.*/**",
+    "**/*.lock",
+    "**/package-lock.json",
+];
+/// Most files one repository may contribute; narrow larger ones with globs.
+const MAX_REPO_FILES: usize = 2000;
+/// Larger files are skipped as generated or data rather than prose or code.
+const MAX_REPO_FILE_BYTES: u64 = 1024 * 1024;
+/// How far a repository may unpack, as a multiple of the upload limit.
+const MAX_REPO_UNPACK_FACTOR: usize = 10;
+/// Most entries a repository tarball may hold, skipped ones included.
+const MAX_REPO_ENTRIES: usize = 100_000;
+const REPO_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Extensions of the images indexed by their vision-model description.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
+fn is_image_file(name: &str) -> bool {
+    name.rsplit_once('.').is_some_and(|(_, extension)| {
+        IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+fn is_source_file(name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, extension)| SOURCE_EXTENSIONS.contains(&extension))
+}
+
+/// `POST /ingest_repo` with a JSON body: a public GitHub repository.
+#[derive(Deserialize)]
+struct RepoRequest {
+    url: String,
+    // Branch, tag or commit; the default branch when absent
+    #[serde(default, rename = "ref")]
+    reference: Option<String>,
+    #[serde(default)]
+    include: Vec<String>,
+    // `REPO_DEFAULT_EXCLUDES` when absent
+    #[serde(default)]
+    exclude: Option<Vec<String>>,
+    #[serde(default)]
+    collection: Option<String>,
+}
+
+/// A repository tarball to index, fetched or uploaded.
+struct RepoSource {
+    // Prefix of every document name, e.g. `tokio` for `tokio/src/lib.rs`
+    name: String,
+    archive: Bytes,
+    include: Vec<String>,
+    exclude: Option<Vec<String>>,
+    collection: Option<String>,
+}
+
+/// Indexes a repository's Markdown and source files, each under its path
+/// prefixed with the repository name. Takes either a JSON body naming a
+/// public GitHub repository, which is downloaded as a tarball, or a form
+/// with the tarball as `archive`. The files go through the upload queue as
+/// one job, replacing earlier versions and skipping unchanged ones.
+async fn ingest_repo_handler(TenantState(state): TenantState, request: Request) -> Response {
+    if let Err(error) = state
+        .vector_store
+        .read()
+        .unwrap()
+        .check_embedding_model(&state.config)
+    {
+        return error.into_response();
+    }
+    if let Some(response) = upload_queue_full(&state) {
+        return response;
+    }
+
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    let source = if is_form {
+        match Multipart::from_request(request, &()).await {
+            Ok(multipart) => read_repo_form(multipart, &state.config).await,
+            Err(rejection) => Err(ApiError::new(
+                rejection.status(),
+                "invalid_request",
+                rejection.body_text(),
+            )),
+        }
+    } else {
+        match Json::<RepoRequest>::from_request(request, &()).await {
+            Ok(Json(payload)) => fetch_github_repo(payload, &state.config).await,
+            Err(rejection) => Err(ApiError::from(rejection)),
+        }
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(error) => return error.into_response(),
+    };
+
+    let collection = match source.collection.as_deref().map(collection_name) {
+        Some(Ok(name)) => Some(name),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+    let include = match glob_set(&source.include) {
+        Ok(globs) => globs,
+        Err(error) => return error.into_response(),
+    };
+    let exclude = match &source.exclude {
+        Some(patterns) => glob_set(patterns),
+        None => glob_set(REPO_DEFAULT_EXCLUDES),
+    };
+    let exclude = match exclude {
+        Ok(globs) => globs,
+        Err(error) => return error.into_response(),
+    };
+
+    let max_bytes = state.config.max_upload_mb * 1024 * 1024 * MAX_REPO_UNPACK_FACTOR;
+    let archive = source.archive;
+    let unpacked = tokio::task::spawn_blocking(move || {
+        read_repo_tarball(&archive, &include, &exclude, max_bytes, MAX_REPO_ENTRIES)
+    })
+    .await
+    .unwrap();
+    let (files, skipped) = match unpacked {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_tarball",
+                format!("Could not read the repository tarball: {}", e),
+            )
+            .into_response()
+        }
+    };
+    if files.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "no_files",
+            "No Markdown or source files in the repository matched",
+        )
+        .into_response();
+    }
+    if files.len() > MAX_REPO_FILES {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "too_many_files",
+            format!(
+                "{} files matched, more than the {} a repository may add; narrow them with include or exclude globs",
+                files.len(),
+                MAX_REPO_FILES
+            ),
+        )
+        .into_response();
+    }
+
+    let mut queued = Vec::new();
+    let mut progress = Vec::new();
+    for (path, content) in files {
+        let filename = format!("{}/{}", source.name, path);
+        queued.push((progress.len(), filename.clone(), content));
+        progress.push(FileProgress::queued(filename));
+    }
+
+    info!(
+        "Queueing {} files of repository {} ({} skipped)",
+        progress.len(),
+        source.name,
+        skipped
+    );
+    let count = progress.len();
+    let job_id = uuid::Uuid::new_v4().to_string();
+    queue_upload(
+        &state,
+        QueuedUpload {
+            job_id: job_id.clone(),
+            tenant: state.tenant.clone(),
+            files: queued,
+            on_conflict: OnConflict::Replace,
+            collection,
+            date: None,
+            watched: false,
+        },
+        progress,
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "accepted",
+            "job_id": job_id,
+            "repository": source.name,
+            "files": count,
+            "skipped": skipped,
+        })),
+    )
+        .into_response()
+}
+
+/// Downloads a public GitHub repository's tarball at `ref`.
+async fn fetch_github_repo(request: RepoRequest, config: &Config) -> Result<RepoSource, ApiError> {
+    let Some((owner, repo)) = github_repo(&request.url) else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_repo_url",
+            "Only public github.com repositories can be fetched, as https://github.com/<owner>/<repo>; upload other repositories as a tarball",
+        ));
+    };
+    let reference = request.reference.as_deref().unwrap_or("HEAD").trim();
+    let valid_ref = !reference.is_empty()
+        && reference.len() <= 200
+        && !reference.contains("..")
+        && reference
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+    if !valid_ref {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_ref",
+            format!("{} is not a branch, tag or commit name", reference),
+        ));
+    }
+
+    let url = format!(
+        "https://github.com/{}/{}/archive/{}.tar.gz",
+        owner, repo, reference
+    );
+    info!("Fetching {}", url);
+    let fetch_failed = |e: String| {
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "repo_fetch_failed",
+            format!("Could not download {}/{}: {}", owner, repo, e),
+        )
+        .retryable()
+    };
+    let client = reqwest::Client::builder()
+        .timeout(REPO_DOWNLOAD_TIMEOUT)
+        .user_agent("ahtohallan")
+        .build()
+        .map_err(|e| fetch_failed(e.to_string()))?;
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| fetch_failed(e.to_string()))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "repo_not_found",
+            format!(
+                "{}/{} at {} was not found, or is private",
+                owner, repo, reference
+            ),
+        ));
+    }
+    if !response.status().is_success() {
+        return Err(fetch_failed(format!(
+            "GitHub answered {}",
+            response.status()
+        )));
+    }
+
+    let limit = config.max_upload_mb * 1024 * 1024;
+    let mut archive = Vec::new();
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| fetch_failed(e.to_string()))?;
+        if archive.len() + chunk.len() > limit {
+            return Err(upload_too_large(config));
+        }
+        archive.extend_from_slice(&chunk);
+    }
+
+    Ok(RepoSource {
+        name: repo,
+        archive: Bytes::from(archive),
+        include: request.include,
+        exclude: request.exclude,
+        collection: request.collection,
+    })
+}
+
+/// `owner` and `repo` of `https://github.com/<owner>/<repo>`, with or
+/// without the scheme and a `.git` suffix.
+fn github_repo(url: &str) -> Option<(String, String)> {
+    let url = url.trim().trim_end_matches('/');
+    let path = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url)
+        .strip_prefix("github.com/")?;
+    let (owner, repo) = path.split_once('/')?;
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+    let valid = |part: &str| {
+        !part.is_empty()
+            && part != "."
+            && part != ".."
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+    (valid(owner) && valid(repo)).then(|| (owner.to_string(), repo.to_string()))
+}
+
+/// Reads `POST /ingest_repo` as a form: the tarball as `archive`, plus
+/// optional `include` and `exclude` fields (each repeatable) and
+/// `collection`. The repository is named after the tarball.
+async fn read_repo_form(mut multipart: Multipart, config: &Config) -> Result<RepoSource, ApiError> {
+    let mut archive = None;
+    let mut include = Vec::new();
+    let mut exclude: Option<Vec<String>> = None;
+    let mut collection = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(upload_read_failed(config, e)),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let filename = field.file_name().map(str::to_string);
+        let read_failed = |e| upload_read_failed(config, e);
+        match name.as_str() {
+            "archive" => {
+                let bytes = field.bytes().await.map_err(read_failed)?;
+                archive = Some((filename.unwrap_or_default(), bytes));
+            }
+            "include" => include.push(field.text().await.map_err(read_failed)?),
+            "exclude" => exclude
+                .get_or_insert_with(Vec::new)
+                .push(field.text().await.map_err(read_failed)?),
+            "collection" => collection = Some(field.text().await.map_err(read_failed)?),
+            _ => {}
+        }
+    }
+
+    let Some((filename, archive)) = archive else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "no_files",
+            "No tarball in the archive field",
+        ));
+    };
+    let stem = [".tar.gz", ".tgz", ".tar"]
+        .iter()
+        .find_map(|extension| filename.strip_suffix(extension))
+        .unwrap_or(&filename);
+    let name = if stem.is_empty() || stem.contains('/') {
+        "repository".to_string()
+    } else {
+        stem.to_string()
+    };
+    Ok(RepoSource {
+        name,
+        archive,
+        include,
+        exclude,
+        collection,
+    })
+}
+
+/// Compiles include or exclude globs. `*` stays within one path component;
+/// `**` crosses them.
+fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet, ApiError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref().trim();
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_glob",
+                    format!("Invalid glob {}: {}", pattern, e),
+                )
+            })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_glob", e.to_string()))
+}
+
+/// The Markdown and source files of a repository tarball, gzipped or not, as
+/// paths within the repository with their contents, and how many of them
+/// were skipped as too large or not UTF-8. A single top-level directory (as
+/// in GitHub's tarballs) is left out of the paths before they are matched:
+/// against `include` when it has globs, and against `exclude`. Fails once
+/// the tarball unpacks to more than `max_bytes` or `max_entries`, counting
+/// the entries that are skipped too.
+fn read_repo_tarball(
+    archive: &[u8],
+    include: &GlobSet,
+    exclude: &GlobSet,
+    max_bytes: usize,
+    max_entries: usize,
+) -> Result<(Vec<(String, Bytes)>, usize), String> {
+    let reader: Box<dyn std::io::Read + '_> = if archive.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(archive))
+    } else {
+        Box::new(archive)
+    };
+    let mut tarball = tar::Archive::new(UnpackLimit {
+        inner: reader,
+        remaining: max_bytes,
+        max_bytes,
+    });
+
+    let mut files = Vec::new();
+    let mut skipped = 0;
+    // First component of every file's path, "" for files at the top
+    let mut tops = HashSet::new();
+    for (index, entry) in tarball.entries().map_err(|e| e.to_string())?.enumerate() {
+        if index == max_entries {
+            return Err(format!("it has more than {} entries", max_entries));
+        }
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(|e| e.to_string())?;
+        let path = path.to_string_lossy();
+        let path = path.trim_start_matches("./").to_string();
+        tops.insert(path.split_once('/').map_or("", |(top, _)| top).to_string());
+        if !(path.ends_with(".md") || is_source_file(&path)) {
+            continue;
+        }
+        if entry.size() > MAX_REPO_FILE_BYTES {
+            skipped += 1;
+            continue;
+        }
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut content).map_err(|e| e.to_string())?;
+        if std::str::from_utf8(&content).is_err() {
+            skipped += 1;
+            continue;
+        }
+        files.push((path, Bytes::from(content)));
+    }
+
+    if let [top] = Vec::from_iter(tops).as_slice() {
+        if !top.is_empty() {
+            for (path, _) in &mut files {
+                path.drain(..=top.len());
+            }
+        }
+    }
+    files.retain(|(path, _)| {
+        (include.is_empty() || include.is_match(path)) && !exclude.is_match(path)
+    });
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((files, skipped))
+}
+
+/// Reads through `inner` until more than `max_bytes` have come out, then
+/// fails, so a small archive can't unpack without bound.
+struct UnpackLimit<R> {
+    inner: R,
+    remaining: usize,
+    max_bytes: usize,
+}
+
+impl<R: std::io::Read> std::io::Read for UnpackLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.remaining = self.remaining.checked_sub(read).ok_or_else(|| {
+            std::io::Error::other(format!(
+                "it unpacks to more than {} MB",
+                self.max_bytes / (1024 * 1024)
+            ))
+        })?;
+        Ok(read)
+    }
+}
+
+// ============================================================================
+// WATCH FOLDER
+// ============================================================================
+
+/// Quiet time after a change before the watch folder is looked at, so a file
+/// still being written is ingested once rather than once per write.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+/// Watch folder events kept for `GET /watch`.
+const MAX_WATCH_EVENTS: usize = 50;
+
+/// Something the watch folder did to the index.
+#[derive(Clone, Serialize)]
+struct WatchEvent {
+    file: String,
+    action: WatchAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Unix seconds
+    at: u64,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum WatchAction {
+    Indexed,
+    Removed,
+    Failed,
+}
+
+/// `GET /watch`.
+#[derive(Serialize)]
+struct WatchResponse {
+    // `None` while no watch folder is configured for this tenant
+    dir: Option<String>,
+    // Documents that came from the folder
+    documents: Vec<String>,
+    // Newest first
+    events: Vec<WatchEvent>,
+}
+
+/// Whether the watch folder indexes `name`: Markdown and PDF files, minus
+/// hidden files and editor backups.
+fn watchable(name: &str) -> bool {
+    (name.ends_with(".md") || name.ends_with(".pdf"))
+        && !name.starts_with('.')
+        && !name.starts_with('~')
+}
+
+/// Keeps `dir` and the default tenant's index in step until shutdown: syncs
+/// once at startup, then again after every burst of file system events.
+/// Subdirectories aren't watched.
+async fn watch_folder(state: AppState, dir: PathBuf, shutdown: watch::Receiver<bool>) {
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| !event.kind.is_access()) {
+            let _ = tx.send(());
+        }
+    })
+    .and_then(|mut watcher| {
+        notify::Watcher::watch(&mut watcher, &dir, notify::RecursiveMode::NonRecursive)
+            .map(|_| watcher)
+    });
+    let _watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("❌ Failed to watch {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    info!("👁️  Watching {} for documents", dir.display());
+
+    let mut seen = HashMap::new();
+    loop {
+        sync_watch_folder(&state, &dir, &mut seen).await;
+        tokio::select! {
+            biased;
+            _ = shutdown_requested(shutdown.clone()) => break,
+            event = events.recv() => if event.is_none() { break },
+        }
+        // Wait for the folder to settle, swallowing the events meanwhile
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(WATCH_DEBOUNCE) => break,
+                event = events.recv() => if event.is_none() { break },
+            }
+        }
+    }
+}
+
+/// A watched file's modification time and size.
+type FileStamp = (Option<SystemTime>, u64);
+
+/// Queues the folder's new and changed files for ingestion and removes
+/// documents whose files are gone. Files whose stamp matches `seen`, from
+/// the last sync, aren't read again; the rest are skipped by their content
+/// hash when unchanged.
+async fn sync_watch_folder(state: &AppState, dir: &Path, seen: &mut HashMap<String, FileStamp>) {
+    let mut files = Vec::new();
+    match tokio::fs::read_dir(dir).await {
+        Ok(mut entries) => {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !watchable(&name) {
+                    continue;
+                }
+                if let Some(metadata) = entry.metadata().await.ok().filter(|m| m.is_file()) {
+                    files.push((
+                        name,
+                        entry.path(),
+                        (metadata.modified().ok(), metadata.len()),
+                    ));
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Failed to read watch folder {}: {}", dir.display(), e);
+            return;
+        }
+    }
+    files.sort();
+
+    let names: HashSet<&String> = files.iter().map(|(name, _, _)| name).collect();
+    seen.retain(|name, _| names.contains(name));
+    let gone: Vec<String> = {
+        let store = state.vector_store.read().unwrap();
+        let mut gone: Vec<String> = store
+            .documents
+            .iter()
+            .filter(|(name, record)| record.watched && !names.contains(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        gone.sort();
+        gone
+    };
+    if !gone.is_empty() {
+        {
+            let _ingest = state.ingest_lock.read().await;
+            let mut store = state.vector_store.write().unwrap();
+            for name in &gone {
+                store.remove_by_source(name);
+            }
+        }
+        persist_store(state).await;
+    }
+    for name in gone {
+        info!("👁️  {} was deleted from the watch folder; removed it", name);
+        record_watch_event(state, name, WatchAction::Removed, None);
+    }
+
+    let mut contents = Vec::new();
+    for (name, path, stamp) in files {
+        if seen.get(&name) == Some(&stamp) {
+            continue;
+        }
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => contents.push((name, stamp, Bytes::from(bytes))),
+            Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+        }
+    }
+    let changed: Vec<(String, FileStamp, Bytes)> = {
+        let store = state.vector_store.read().unwrap();
+        contents
+            .into_iter()
+            .filter(|(name, stamp, bytes)| {
+                let hash = format!("{:x}", Sha256::digest(bytes));
+                let unchanged = store.documents.get(name).is_some_and(|record| {
+                    record.watched && record.content_hash.as_deref() == Some(hash.as_str())
+                });
+                if unchanged {
+                    seen.insert(name.clone(), *stamp);
+                }
+                !unchanged
+            })
+            .collect()
+    };
+    if changed.is_empty() {
+        return;
+    }
+    // Embedding with a different model than the index was built with would
+    // make the new chunks incomparable to the rest; wait for a reindex
+    let mismatch = state
+        .vector_store
+        .read()
+        .unwrap()
+        .check_embedding_model(&state.config)
+        .err();
+    if let Some(error) = mismatch {
+        warn!(
+            "👁️  Skipping {} changed files in the watch folder: {}",
+            changed.len(),
+            error.message
+        );
+        return;
+    }
+
+    // Ingested by the upload worker like an upload, so the two never work on
+    // the same document at once and progress shows up under `GET /jobs/:id`
+    let mut files = Vec::new();
+    let mut progress = Vec::new();
+    for (name, stamp, bytes) in changed {
+        info!("👁️  Queuing {} from the watch folder", name);
+        seen.insert(name.clone(), stamp);
+        files.push((progress.len(), name.clone(), bytes));
+        progress.push(FileProgress::queued(name));
+    }
+    queue_upload(
+        state,
+        QueuedUpload {
+            job_id: uuid::Uuid::new_v4().to_string(),
+            tenant: state.tenant.clone(),
+            files,
+            on_conflict: OnConflict::Replace,
+            collection: None,
+            date: None,
+            watched: true,
+        },
+        progress,
+    );
+}
+
+/// Records what happened to each file of watch folder job `job_id`.
+fn record_watch_results(state: &AppState, job_id: &str) {
+    let files = match state.jobs.read().unwrap().get(job_id) {
+        Some(job) => job.files.clone(),
+        None => return,
+    };
+    for file in files {
+        match (file.error, file.stage) {
+            (Some(error), _) => {
+                record_watch_event(state, file.filename, WatchAction::Failed, Some(error))
+            }
+            (None, FileStage::Done | FileStage::Unchanged) => {
+                record_watch_event(state, file.filename, WatchAction::Indexed, None)
+            }
+            (None, _) => {}
+        }
+    }
+}
+
+fn record_watch_event(state: &AppState, file: String, action: WatchAction, error: Option<String>) {
+    let mut activity = state.watch_activity.lock().unwrap();
+    activity.push_back(WatchEvent {
+        file,
+        action,
+        error,
+        at: unix_now(),
+    });
+    if activity.len() > MAX_WATCH_EVENTS {
+        activity.pop_front();
+    }
+}
+
+// ============================================================================
+// OLLAMA MODELS
+// ============================================================================
+
+const MAX_MODEL_NAME: usize = 128;
+/// A pull downloads gigabytes, far longer than the client's default timeout.
+const MODEL_PULL_TIMEOUT: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// `GET /api/tags` from Ollama.
+#[derive(Deserialize)]
+struct OllamaTags {
+    #[serde(default)]
+    models: Vec<OllamaTag>,
+}
+
+#[derive(Deserialize)]
+struct OllamaTag {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    modified_at: String,
+    #[serde(default)]
+    details: OllamaTagDetails,
+}
+
+#[derive(Default, Deserialize)]
+struct OllamaTagDetails {
+    #[serde(default)]
+    family: String,
+    #[serde(default)]
+    parameter_size: String,
+    #[serde(default)]
+    quantization_level: String,
+}
+
+/// One line of Ollama's streamed `POST /api/pull` progress.
+#[derive(Deserialize)]
+struct OllamaPullProgress {
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    total: Option<u64>,
+    #[serde(default)]
+    completed: Option<u64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ModelsResponse {
+    // Model answers use when a request names none
+    default: String,
+    models: Vec<LlmModel>,
+}
+
+#[derive(Serialize)]
+struct LlmModel {
+    name: String,
+    // Bytes on disk
+    size: u64,
+    modified_at: String,
+    family: String,
+    parameter_size: String,
+    quantization_level: String,
+}
+
+#[derive(Deserialize)]
+struct PullModelRequest {
+    name: String,
+}
+
+/// Trims a model name and checks it looks like one Ollama accepts
+/// (`llama3`, `phi3:mini`, `library/qwen2:7b`).
+fn model_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_MODEL_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '/'));
+    if !valid {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_model",
+            format!(
+                "Model names are 1-{} letters, digits, dots, dashes, underscores, colons or slashes",
+                MAX_MODEL_NAME
+            ),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Whether `name`, as Ollama lists it, is `model`. An untagged `model`
+/// matches every tag, so `phi3` covers `phi3:latest` and `phi3:mini`.
+fn is_model(name: &str, model: &str) -> bool {
+    name == model || (!model.contains(':') && name.split(':').next() == Some(model))
+}
+
+fn ollama_unreachable(e: impl std::fmt::Display) -> ApiError {
+    ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "llm_unavailable",
+        format!(
+            "❌ Cannot connect to Ollama: {}\n\nMake sure Ollama is running with: `ollama serve`",
+            e
+        ),
+    )
+    .retryable()
+}
+
+/// The models Ollama has pulled, any of which a chat request may pick with
+/// `model`.
+async fn models_handler(TenantState(state): TenantState) -> Response {
+    let tags = async {
+        state
+            .ollama_client
+            .get("http://localhost:11434/api/tags")
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OllamaTags>()
+            .await
+    };
+    let tags = match tags.await {
+        Ok(tags) => tags,
+        Err(e) => return ollama_unreachable(e).into_response(),
+    };
+
+    let mut models: Vec<LlmModel> = tags
+        .models
+        .into_iter()
+        .map(|tag| LlmModel {
+            name: tag.name,
+            size: tag.size,
+            modified_at: tag.modified_at,
+            family: tag.details.family,
+            parameter_size: tag.details.parameter_size,
+            quantization_level: tag.details.quantization_level,
+        })
+        .collect();
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(ModelsResponse {
+        default: state.config.model.clone(),
+        models,
+    })
+    .into_response()
+}
+
+/// Has Ollama pull a model, streaming its progress as `progress` events and
+/// finishing with `done` (or `error`). The pull carries on if the client
+/// goes away, so a closed tab doesn't waste a half-finished download.
+/// Models are shared by every tenant, so this is an admin operation.
+async fn pull_model_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<PullModelRequest>, JsonRejection>,
+) -> Response {
+    if let Err(error) = authorize_admin(&state.config, &headers) {
+        return error.into_response();
+    }
+    let name = match payload {
+        Ok(Json(request)) => match model_name(&request.name) {
+            Ok(name) => name,
+            Err(error) => return error.into_response(),
+        },
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+
+    info!("Pulling model {}", name);
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(pull_model(state, name, tx));
+    Sse::new(ReceiverStream::new(rx))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn pull_model(state: AppState, name: String, tx: mpsc::Sender<Result<Event, Infallible>>) {
+    let response = state
+        .ollama_client
+        .post("http://localhost:11434/api/pull")
+        .json(&serde_json::json!({ "model": name, "name": name, "stream": true }))
+        .timeout(MODEL_PULL_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            let _ = tx.send(sse_error(&ollama_unreachable(e))).await;
+            return;
+        }
+    };
+
+    let failed = |message: String| {
+        warn!("Pulling {} failed: {}", name, message);
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "pull_failed",
+            format!("Pulling {} failed: {}", name, message),
+        )
+        .retryable()
+    };
+
+    // Ollama reports progress many times a second; only changes in status
+    // or whole percent are passed on
+    let mut last = (String::new(), None);
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = tx.send(sse_error(&failed(e.to_string()))).await;
+                return;
+            }
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let Ok(progress) = serde_json::from_slice::<OllamaPullProgress>(&line) else {
+                continue;
+            };
+            if let Some(error) = progress.error {
+                let _ = tx.send(sse_error(&failed(error))).await;
+                return;
+            }
+            if progress.status == "success" {
+                info!("✅ Pulled model {}", name);
+                let _ = tx
+                    .send(sse_event("done", serde_json::json!({ "model": name })))
+                    .await;
+                return;
+            }
+
+            let percent = match (progress.completed, progress.total) {
+                (Some(completed), Some(total)) if total > 0 => Some(completed * 100 / total),
+                _ => None,
+            };
+            if (&progress.status, percent) != (&last.0, last.1) {
+                let _ = tx
+                    .send(sse_event(
+                        "progress",
+                        serde_json::json!({
+                            "status": progress.status,
+                            "completed": progress.completed,
+                            "total": progress.total,
+                            "percent": percent,
+                        }),
+                    ))
+                    .await;
+                last = (progress.status, percent);
+            }
+        }
+    }
+
+    let _ = tx
+        .send(sse_error(&failed(
+            "Ollama ended the pull without reporting success".to_string(),
+        )))
+        .await;
+}
+
+// ============================================================================
+// SHARED CONVERSATIONS
+// ============================================================================
+
+/// Messages one shared conversation may hold.
+const MAX_SHARED_MESSAGES: usize = 200;
+/// Longest a share may be set to last: a year. Longer ones can be made to
+/// never expire instead.
+const MAX_SHARE_HOURS: u64 = 24 * 365;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl SharedConversation {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Reads the published conversations, dropping any that have expired.
+fn load_shares(path: &Path) -> HashMap<String, SharedConversation> {
+    let Ok(bytes) = std::fs::read(path) else {
+        return HashMap::new();
+    };
+    match serde_json::from_slice::<HashMap<String, SharedConversation>>(&bytes) {
+        Ok(mut shares) => {
+            let now = unix_now();
+            shares.retain(|_, share| !share.expired(now));
+            shares
+        }
+        Err(e) => {
+            error!("Failed to parse shares {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes `shares` to disk; the caller holds the shares lock, which keeps
+/// snapshots in order.
+async fn persist_shares(config: &Config, shares: &HashMap<String, SharedConversation>) {
+    write_json_file(config, &config.shares_path(), shares, "shares").await;
+}
+
+/// Serializes `value` to `path` in the data directory through a temporary
+/// file, so a crash never leaves a half-written file behind. `what` names the
+/// data in log messages.
+async fn write_json_file<T: Serialize + ?Sized>(
+    config: &Config,
+    path: &Path,
+    value: &T,
+    what: &str,
+) {
+    let bytes = match serde_json::to_vec(value) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to serialize {}: {}", what, e);
+            return;
+        }
+    };
+
+    let tmp_path = path.with_extension("json.tmp");
+    let result = async {
+        tokio::fs::create_dir_all(&config.data_dir).await?;
+        tokio::fs::write(&tmp_path, &bytes).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!("Failed to persist {} to {}: {}", what, path.display(), e);
+    }
+}
+
+// ============================================================================
+// SESSIONS
+// ============================================================================
+
+/// Characters of the first question used as a session's default title.
+const SESSION_TITLE_CHARS: usize = 60;
+
+impl Session {
+    fn summary(&self, id: &str) -> SessionSummary {
+        SessionSummary {
+            id: id.to_string(),
+            title: self.title.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            messages: self.messages.len(),
+        }
+    }
+
+    /// The session's last turns, as the history for its next question.
+    fn history(&self) -> Vec<HistoryTurn> {
+        self.messages[self.messages.len().saturating_sub(MAX_HISTORY_TURNS)..]
+            .iter()
+            .map(|message| HistoryTurn {
+                role: message.role.clone(),
+                content: message.content.clone(),
+            })
+            .collect()
+    }
+}
+
+fn load_sessions(path: &Path) -> HashMap<String, Session> {
+    let Ok(bytes) = std::fs::read(path) else {
+        return HashMap::new();
+    };
+    serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+        error!("Failed to parse sessions {}: {}", path.display(), e);
+        HashMap::new()
+    })
+}
+
+/// Checks that a chat request's session exists and, when the request brings
+/// no history of its own, fills it in from the session.
+async fn attach_session(state: &AppState, payload: &mut ChatRequest) -> Result<(), ApiError> {
+    let Some(id) = &payload.session_id else {
+        return Ok(());
+    };
+    let sessions = state.sessions.lock().await;
+    let session = sessions.get(id).ok_or_else(|| session_not_found(id))?;
+    if payload.history.is_empty() {
+        payload.history = session.history();
+    }
+    Ok(())
+}
+
+/// Appends a question and its answer to the request's session, if any, and
+/// saves the sessions.
+async fn record_turn(state: &AppState, payload: &ChatRequest, answer: &str, sources: &[String]) {
+    let Some(id) = &payload.session_id else {
+        return;
+    };
+    let mut sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get_mut(id) else {
+        // Deleted from disk by hand while the answer was generated
+        return;
+    };
+
+    let now = unix_now();
+    let question = payload.query.trim();
+    if session.title.is_empty() {
+        session.title = question.chars().take(SESSION_TITLE_CHARS).collect();
+    }
+    session.messages.push(SessionMessage {
+        role: "user".to_string(),
+        content: question.to_string(),
+        sources: Vec::new(),
+        created_at: now,
+    });
+    session.messages.push(SessionMessage {
+        role: "assistant".to_string(),
+        content: answer.trim().to_string(),
+        sources: sources.to_vec(),
+        created_at: now,
+    });
+    session.updated_at = now;
+    write_json_file(
+        &state.config,
+        &state.config.sessions_path(),
+        &*sessions,
+        "sessions",
+    )
+    .await;
+}
+
+fn session_not_found(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "session_not_found",
+        format!("Session {} not found", id),
+    )
+}
+
+// ============================================================================
+// CONTENT SAFETY
+// ============================================================================
+
+/// Replacement for blocked terms masked in answers.
+const MASKED_TERM: &str = "[removed]";
+/// Longest text the LLM judge is shown, in characters.
+const MODERATION_INPUT_CHARS: usize = 4000;
+
+/// What the content-safety filter did to a query or answer.
+#[derive(Clone, Debug, Serialize)]
+struct ModerationDecision {
+    action: ModerationAction,
+    // Blocked terms found, in `blocked_terms` order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    terms: Vec<String>,
+    // Category the LLM judge flagged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ModerationAction {
+    /// The query was answered with `moderation_refusal` instead
+    Refused,
+    /// Blocked terms were masked in the answer
+    Scrubbed,
+    /// The answer was replaced with `moderation_refusal`
+    Withheld,
+}
+
+impl ModerationDecision {
+    fn withheld(&self) -> bool {
+        self.action == ModerationAction::Withheld
+    }
+}
+
+/// Matches any of `terms` as whole words, ignoring case; `None` without terms.
+fn blocked_pattern(terms: &[String]) -> Option<Regex> {
+    if terms.is_empty() {
+        return None;
+    }
+    let alternatives: Vec<String> = terms.iter().map(|term| regex::escape(term)).collect();
+    Regex::new(&format!(r"(?i)\b(?:{})\b", alternatives.join("|"))).ok()
+}
+
+/// The blocked terms `text` contains, in `blocked_terms` order.
+fn blocked_terms_in(config: &Config, text: &str) -> Vec<String> {
+    let Some(pattern) = blocked_pattern(&config.blocked_terms) else {
+        return Vec::new();
+    };
+    let found: HashSet<String> = pattern
+        .find_iter(text)
+        .map(|found| found.as_str().to_lowercase())
+        .collect();
+    config
+        .blocked_terms
+        .iter()
+        .filter(|term| found.contains(*term))
+        .cloned()
+        .collect()
+}
+
+/// Asks the LLM judge whether `text` falls under one of
+/// `moderation_categories`, returning the category it names. A judge that
+/// can't be reached lets the text through rather than failing the request.
+async fn judge_content(state: &AppState, model: &str, text: &str) -> Option<String> {
+    if !state.config.moderation_llm {
+        return None;
+    }
+    let categories = &state.config.moderation_categories;
+    let text: String = text.chars().take(MODERATION_INPUT_CHARS).collect();
+    let prompt = format!(
+        "You are a content-safety filter for a school and workplace assistant. Disallowed categories: {}. Does the text below request or contain content in one of them? Discussing a topic factually, as a textbook or policy would, is allowed. Reply with the one category that applies, or \"none\", and nothing else.\n\nText:\n{}\n\nCategory:",
+        categories.join(", "),
+        text
+    );
+
+    match complete(state, model, &prompt, 0.0, 16, 30).await {
+        Ok(reply) => judged_category(&reply, categories),
+        Err(e) => {
+            warn!("Moderation judge failed, allowing the text: {}", e.message);
+            None
+        }
+    }
+}
+
+/// The category the judge replied with: the whole reply or its first word,
+/// ignoring case, quotes and trailing punctuation. Anything else, including
+/// "none" or a sentence that merely mentions a category, flags nothing.
+fn judged_category(reply: &str, categories: &[String]) -> Option<String> {
+    let clean = |text: &str| {
+        text.trim_matches(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '.' | ','))
+            .to_lowercase()
+    };
+    let reply = clean(reply);
+    let first_word = reply.split_whitespace().next().map(clean);
+    categories
+        .iter()
+        .find(|category| **category == reply || first_word.as_ref() == Some(*category))
+        .cloned()
+}
+
+/// Decides whether a query must be refused: blocked terms refuse it outright,
+/// otherwise the LLM judge gets a say when enabled.
+async fn moderate_query(state: &AppState, model: &str, query: &str) -> Option<ModerationDecision> {
+    let terms = blocked_terms_in(&state.config, query);
+    let category = if terms.is_empty() {
+        judge_content(state, model, query).await
+    } else {
+        None
+    };
+    (!terms.is_empty() || category.is_some()).then_some(ModerationDecision {
+        action: ModerationAction::Refused,
+        terms,
+        category,
+    })
+}
+
+/// Checks a generated answer, returning what to show instead and why: the
+/// refusal when the LLM judge flags it, else the answer with its blocked
+/// terms masked. `None` leaves the answer as it is.
+async fn moderate_answer(
+    state: &AppState,
+    model: &str,
+    answer: &str,
+) -> Option<(String, ModerationDecision)> {
+    let terms = blocked_terms_in(&state.config, answer);
+    if let Some(category) = judge_content(state, model, answer).await {
+        let decision = ModerationDecision {
+            action: ModerationAction::Withheld,
+            terms,
+            category: Some(category),
+        };
+        return Some((state.config.moderation_refusal.clone(), decision));
+    }
+    if terms.is_empty() {
+        return None;
+    }
+    let scrubbed = blocked_pattern(&terms)?
+        .replace_all(answer, MASKED_TERM)
+        .into_owned();
+    let decision = ModerationDecision {
+        action: ModerationAction::Scrubbed,
+        terms,
+        category: None,
+    };
+    Some((scrubbed, decision))
+}
+
+/// One line of `moderation.jsonl`: a query or answer the filter acted on.
+#[derive(Serialize)]
+struct ModerationRecord<'a> {
+    // Unix seconds
+    timestamp: u64,
+    tenant: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<&'a str>,
+    query: &'a str,
+    #[serde(flatten)]
+    decision: &'a ModerationDecision,
+}
+
+/// Logs a moderation decision and appends it to `moderation.jsonl` in the
+/// data directory, which is kept whether or not the audit log is on.
+async fn record_moderation(state: &AppState, payload: &ChatRequest, decision: &ModerationDecision) {
+    warn!(
+        "Moderation {:?} for tenant {} (terms: {:?}, category: {:?})",
+        decision.action, state.tenant, decision.terms, decision.category
+    );
+    let record = ModerationRecord {
+        timestamp: unix_now(),
+        tenant: &state.tenant,
+        session_id: payload.session_id.as_deref(),
+        query: payload.query.trim(),
+        decision,
+    };
+    let result = async {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let _guard = state.audit_lock.lock().await;
+        tokio::fs::create_dir_all(&state.config.data_dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state.config.moderation_path())
+            .await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &line).await
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Failed to write moderation log: {}", e);
+    }
+}
+
+// ============================================================================
+// AUDIT LOG
+// ============================================================================
+
+/// One line of `audit.jsonl`: an answered question and how it was answered.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    // Unix seconds
+    timestamp: u64,
+    tenant: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<&'a str>,
+    query: &'a str,
+    sources: &'a [String],
+    answer: &'a str,
+    latency_ms: u64,
+    // `None` for answers given without calling the LLM
+    model: Option<&'a str>,
+    // Set when the client disconnected before the answer was complete
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    interrupted: bool,
+}
+
+/// Records a finished answer: appends the turn to the request's session, if
+/// any, and to the audit log when it's enabled.
+async fn record_answer(
+    state: &AppState,
+    payload: &ChatRequest,
+    answer: &str,
+    sources: &[String],
+    started: Instant,
+    model: Option<&str>,
+    interrupted: bool,
+) {
+    record_turn(state, payload, answer, sources).await;
+    if !state.config.audit_log {
+        return;
+    }
+    let record = AuditRecord {
+        timestamp: unix_now(),
+        tenant: &state.tenant,
+        session_id: payload.session_id.as_deref(),
+        query: payload.query.trim(),
+        sources,
+        answer: answer.trim(),
+        latency_ms: ms_since(started),
+        model,
+        interrupted,
+    };
+    if let Err(e) = append_audit(state, &record).await {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+/// Appends `record` as one JSON line, first rotating the log if the line
+/// would take it past `audit_log_max_mb`.
+async fn append_audit(state: &AppState, record: &AuditRecord<'_>) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let config = &state.config;
+    let _guard = state.audit_lock.lock().await;
+    tokio::fs::create_dir_all(&config.data_dir).await?;
+    let path = config.audit_path(0);
+    let size = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if size > 0 && size + line.len() as u64 > config.audit_log_max_mb * 1024 * 1024 {
+        // Drop the oldest, then audit.jsonl -> audit.1.jsonl -> audit.2.jsonl ...
+        let oldest = config.audit_path(config.audit_log_files);
+        if tokio::fs::try_exists(&oldest).await? {
+            tokio::fs::remove_file(&oldest).await?;
+        }
+        for generation in (0..config.audit_log_files).rev() {
+            let from = config.audit_path(generation);
+            if tokio::fs::try_exists(&from).await? {
+                tokio::fs::rename(&from, config.audit_path(generation + 1)).await?;
+            }
+        }
+        info!("Rotated audit log {}", path.display());
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, &line).await
+}
+
+// ============================================================================
+// EVALUATION
+// ============================================================================
+
+/// Retrieved chunks an evaluation scores when the run doesn't say.
+const DEFAULT_EVAL_K: usize = 5;
+
+fn load_evals(path: &Path) -> HashMap<String, EvalRun> {
+    let Ok(bytes) = std::fs::read(path) else {
+        return HashMap::new();
+    };
+    serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+        error!("Failed to parse evaluation runs {}: {}", path.display(), e);
+        HashMap::new()
+    })
+}
+
+/// Parses a JSONL evaluation set, skipping blank lines. Errors name the
+/// offending line.
+fn parse_eval_cases(body: &str) -> Result<Vec<EvalCase>, ApiError> {
+    body.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let case: EvalCase = serde_json::from_str(line).map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    format!("Line {}: {}", index + 1, e),
+                )
+            })?;
+            if case.question.trim().is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    format!("Line {}: question must not be empty", index + 1),
+                ));
+            }
+            Ok(case)
+        })
+        .collect()
+}
+
+impl EvalSettings {
+    fn current(state: &AppState) -> Self {
+        let store = state.vector_store.read().unwrap();
+        EvalSettings {
+            chunk_size: state.config.chunk_size,
+            chunk_overlap: state.config.chunk_overlap,
+            min_similarity: state.config.min_similarity,
+            pipeline: state.config.pipeline.clone(),
+            embedding_model: state.config.embedding_model.clone(),
+            model: state.config.model.clone(),
+            documents: store.documents.len(),
+            chunks: store.count(),
+        }
+    }
+}
+
+impl EvalRun {
+    /// The run without its per-question results, for listings.
+    fn summary(&self) -> EvalRun {
+        EvalRun {
+            results: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+impl EvalMetrics {
+    fn from_results(results: &[EvalResult]) -> Self {
+        let count = results.len().max(1) as f32;
+        let hits = results.iter().filter(|r| r.rank.is_some()).count();
+        let reciprocal: f32 = results
+            .iter()
+            .filter_map(|r| r.rank)
+            .map(|rank| 1.0 / rank as f32)
+            .sum();
+        let mean = |values: Vec<f32>| {
+            (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32)
+        };
+
+        EvalMetrics {
+            recall_at_k: hits as f32 / count,
+            mrr: reciprocal / count,
+            answer_similarity: mean(results.iter().filter_map(|r| r.answer_similarity).collect()),
+            source_cited: mean(
+                results
+                    .iter()
+                    .filter_map(|r| r.source_cited)
+                    .map(|cited| if cited { 1.0 } else { 0.0 })
+                    .collect(),
+            ),
+            errors: results.iter().filter(|r| r.error.is_some()).count(),
+        }
+    }
+}
+
+/// Scores one question: where its expected source ranks in the top `k`
+/// chunks and, unless `retrieval_only`, how the generated answer compares.
+async fn evaluate_case(
+    state: &AppState,
+    case: EvalCase,
+    k: usize,
+    retrieval_only: bool,
+) -> EvalResult {
+    let mut result = EvalResult {
+        question: case.question.clone(),
+        expected_source: case.expected_source.clone(),
+        rank: None,
+        retrieved: Vec::new(),
+        answer: None,
+        answer_similarity: None,
+        source_cited: None,
+        error: None,
+    };
+
+    let retrieval = retrieve(
+        state,
+        std::slice::from_ref(&case.question),
+        &SearchOptions::new(&state.config, k),
+        &mut StageTimings::default(),
+    )
+    .await;
+    match retrieval {
+        Ok(retrieval) => {
+            result.retrieved = retrieval
+                .chunks
+                .into_iter()
+                .take(k)
+                .map(|chunk| chunk.source)
+                .collect();
+            result.rank = result
+                .retrieved
+                .iter()
+                .position(|source| *source == case.expected_source)
+                .map(|index| index + 1);
+        }
+        Err(error) => {
+            result.error = Some(error.message);
+            return result;
+        }
+    }
+    if retrieval_only {
+        return result;
+    }
+
+    let request = ChatRequest {
+        query: case.question,
+        ..ChatRequest::default()
+    };
+    let response = match answer_chat(state, request).await {
+        Ok(response) => response,
+        Err(error) => {
+            result.error = Some(error.message);
+            return result;
+        }
+    };
+    result.source_cited = Some(response.sources.contains(&case.expected_source));
+    if let Some(expected) = case.expected_answer.filter(|text| !text.trim().is_empty()) {
+        match embed_texts(state, vec![response.answer.clone(), expected]).await {
+            Ok(vectors) if vectors.len() == 2 => {
+                result.answer_similarity = Some(cosine_similarity(
+                    ArrayView1::from(&vectors[0]),
+                    ArrayView1::from(&vectors[1]),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => result.error = Some(format!("Failed to embed the answer: {}", e)),
+        }
+    }
+    result.answer = Some(response.answer);
+    result
+}
+
+/// Runs an evaluation set `batch_concurrency` questions at a time, keeping
+/// the run's progress current, then stores the finished run.
+async fn run_eval(state: AppState, run_id: String, cases: Vec<EvalCase>) {
+    let (k, retrieval_only) = {
+        let evals = state.evals.lock().await;
+        match evals.get(&run_id) {
+            Some(run) => (run.k, run.retrieval_only),
+            None => return,
+        }
+    };
+    let started = Instant::now();
+
+    let mut results = Vec::with_capacity(cases.len());
+    let mut outcomes = futures_util::stream::iter(cases)
+        .map(|case| evaluate_case(&state, case, k, retrieval_only))
+        .buffered(state.config.batch_concurrency);
+    while let Some(result) = outcomes.next().await {
+        results.push(result);
+        if let Some(run) = state.evals.lock().await.get_mut(&run_id) {
+            run.completed = results.len();
+        }
+    }
+
+    let metrics = EvalMetrics::from_results(&results);
+    info!(
+        "Evaluation {} finished in {} ms: recall@{} {:.2}, MRR {:.2}",
+        run_id,
+        ms_since(started),
+        k,
+        metrics.recall_at_k,
+        metrics.mrr
+    );
+
+    let mut evals = state.evals.lock().await;
+    if let Some(run) = evals.get_mut(&run_id) {
+        run.status = if metrics.errors == results.len() {
+            JobStatus::Failed
+        } else {
+            JobStatus::Completed
+        };
+        run.finished_at = Some(unix_now());
+        run.metrics = Some(metrics);
+        run.results = results;
+    }
+    let finished: HashMap<&String, &EvalRun> = evals
+        .iter()
+        .filter(|(_, run)| run.finished_at.is_some())
+        .collect();
+    write_json_file(
+        &state.config,
+        &state.config.evals_path(),
+        &finished,
+        "evaluation runs",
+    )
+    .await;
+}
+
+// ============================================================================
+// RATE LIMITING
+// ============================================================================
+
+/// Idle clients forgotten once this many are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Token bucket per client: each holds up to `per_minute` requests and
+/// refills at `per_minute` a minute.
+struct RateLimiter {
+    per_minute: u32,
+    // Client -> (requests left, when that was computed)
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one request from `client`'s bucket, or returns how many seconds
+    /// until one is available.
+    fn check(&self, client: &str) -> Result<(), u64> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = self.per_minute as f64;
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A bucket idle long enough to refill is the same as a new one
+            buckets.retain(|_, (_, updated)| now.duration_since(*updated).as_secs() < 60);
+        }
+        let (tokens, updated) = buckets.entry(client.to_string()).or_insert((capacity, now));
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * per_second).min(capacity);
+        *updated = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - *tokens) / per_second).ceil() as u64)
+        }
+    }
+}
+
+/// Middleware limiting each client to `rate_limit_per_minute` requests,
+/// answering `429` with `Retry-After` beyond that. Clients are told apart by
+/// API key when they send one, else by IP address.
+async fn rate_limit(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Configured keys get a bucket each, kept only as a digest; any other
+    // token counts against the client's address, so inventing a new one on
+    // every request doesn't escape the limit
+    let client = match bearer_token(request.headers()) {
+        Some(token) if is_configured_key(&state.config, token) => {
+            format!("key:{:x}", Sha256::digest(token.as_bytes()))
+        }
+        _ => format!("ip:{}", addr.ip()),
+    };
+    if let Err(retry_after) = state.rate_limiter.check(&client) {
+        warn!("Rate limited {} on {}", client, request.uri().path());
+        let mut response = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            format!(
+                "Too many requests (limit {} a minute), try again in {} s",
+                state.config.rate_limit_per_minute, retry_after
+            ),
+        )
+        .retryable()
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        return response;
+    }
+    next.run(request).await
+}
+
+/// Whether `token` is one of the tenant, embedding or admin API keys.
+fn is_configured_key(config: &Config, token: &str) -> bool {
+    config
+        .api_keys
+        .keys()
+        .chain(&config.embed_api_keys)
+        .chain(&config.admin_api_keys)
+        .any(|key| key_matches(key, token))
+}
+
+fn upload_too_large(config: &Config) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        format!(
+            "Uploads are limited to {} MB per request",
+            config.max_upload_mb
+        ),
+    )
+}
+
+/// The error for a multipart body that couldn't be read: over the upload
+/// limit, or malformed.
+fn upload_read_failed(config: &Config, e: axum::extract::multipart::MultipartError) -> ApiError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        upload_too_large(config)
+    } else {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request", e.body_text())
+    }
+}
+
+// ============================================================================
+// TENANTS
+// ============================================================================
+
+/// Tenant whose data lives directly in `data_dir`; every request's tenant
+/// while `api_keys` is empty.
+const DEFAULT_TENANT: &str = "default";
+
+/// A tenant's own documents, sessions and overview cache.
+#[derive(Clone)]
+struct TenantData {
+    config: Arc<Config>,
+    vector_store: Arc<RwLock<VectorStore>>,
+    sessions: Arc<tokio::sync::Mutex<HashMap<String, Session>>>,
+    evals: Arc<tokio::sync::Mutex<HashMap<String, EvalRun>>>,
+    overview: Arc<tokio::sync::Mutex<Option<(u64, OverviewResponse)>>>,
+}
+
+impl TenantData {
+    fn load(config: Config) -> Self {
+        let vector_store = VectorStore::load(&config);
+        let sessions = load_sessions(&config.sessions_path());
+        let evals = load_evals(&config.evals_path());
+        TenantData {
+            config: Arc::new(config),
+            vector_store: Arc::new(RwLock::new(vector_store)),
+            sessions: Arc::new(tokio::sync::Mutex::new(sessions)),
+            evals: Arc::new(tokio::sync::Mutex::new(evals)),
+            overview: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+}
+
+impl AppState {
+    /// The state `tenant`'s requests run with: its documents, sessions and
+    /// data directory, everything else shared. Call on the root state.
+    fn for_tenant(&self, tenant: &str) -> AppState {
+        if tenant == self.tenant {
+            return self.clone();
+        }
+        let data = self
+            .tenants
+            .write()
+            .unwrap()
+            .entry(tenant.to_string())
+            .or_insert_with(|| {
+                info!("Loading data for tenant {}", tenant);
+                TenantData::load(self.config.for_tenant(tenant))
+            })
+            .clone();
+        AppState {
+            config: data.config,
+            vector_store: data.vector_store,
+            sessions: data.sessions,
+            evals: data.evals,
+            overview: data.overview,
+            tenant: tenant.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Whether `name` can name a tenant (and its directory under `data_dir`).
+fn tenant_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Compares digests so the time taken doesn't reveal how much of a key matched.
+fn key_matches(key: &str, token: &str) -> bool {
+    Sha256::digest(key.as_bytes()) == Sha256::digest(token.as_bytes())
+}
+
+fn unauthorized() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "Missing or invalid API key",
+    )
+}
+
+/// The state of the tenant whose API key the request carries. While
+/// `api_keys` is empty no key is needed and every request is the default
+/// tenant's.
+struct TenantState(AppState);
+
+#[async_trait]
+impl FromRequestParts<AppState> for TenantState {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        if state.config.api_keys.is_empty() {
+            return Ok(TenantState(state.clone()));
+        }
+        let token = bearer_token(&parts.headers).ok_or_else(unauthorized)?;
+        let tenant = state
+            .config
+            .api_keys
+            .iter()
+            .find(|(key, _)| key_matches(key, token))
+            .map(|(_, tenant)| tenant)
+            .ok_or_else(unauthorized)?;
+        Ok(TenantState(state.for_tenant(tenant)))
+    }
+}
+
+// ============================================================================
+// SPEECH SYNTHESIS
+// ============================================================================
+
+/// Reads text aloud for `POST /tts`; one implementation per `tts` provider.
+#[async_trait]
+trait SpeechSynthesizer: Send + Sync {
+    /// `text` read aloud at `speed`, as audio and its MIME type.
+    async fn synthesize(&self, text: &str, speed: f32) -> Result<(Vec<u8>, &'static str), String>;
+}
+
+fn speech_synthesizer(config: &TtsConfig) -> Arc<dyn SpeechSynthesizer> {
+    match config.clone() {
+        TtsConfig::Piper { piper, model } => Arc::new(PiperSpeech { piper, model }),
+        TtsConfig::Openai {
+            url,
+            api_key,
+            model,
+            voice,
+        } => Arc::new(OpenAiSpeech {
+            client: reqwest::Client::builder()
+                .timeout(TTS_TIMEOUT)
+                .build()
+                .expect("Failed to create HTTP client"),
+            url,
+            api_key,
+            model,
+            voice,
+        }),
+    }
+}
+
+/// Speech from a local piper voice.
+struct PiperSpeech {
+    piper: PathBuf,
+    model: PathBuf,
+}
+
+/// A temporary file that is deleted when dropped, however the work using it
+/// ends.
+struct ScratchFile(PathBuf);
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[async_trait]
+impl SpeechSynthesizer for PiperSpeech {
+    async fn synthesize(&self, text: &str, speed: f32) -> Result<(Vec<u8>, &'static str), String> {
+        use tokio::io::AsyncWriteExt;
+
+        let wav = ScratchFile(
+            std::env::temp_dir().join(format!("ahtohallan-{}.wav", uuid::Uuid::new_v4())),
+        );
+        let mut child = tokio::process::Command::new(&self.piper)
+            .arg("--model")
+            .arg(&self.model)
+            // Piper stretches time rather than speeding up
+            .args([
+                "--length_scale",
+                &(1.0 / speed).to_string(),
+                "--output_file",
+            ])
+            .arg(&wav.0)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", self.piper.display(), e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // One line is one utterance to piper
+            let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            stdin
+                .write_all(line.as_bytes())
+                .await
+                .map_err(|e| format!("Failed to pass text to piper: {}", e))?;
+        }
+        let output = tokio::time::timeout(TTS_TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| format!("piper took longer than {}s", TTS_TIMEOUT.as_secs()))?
+            .map_err(|e| format!("piper failed: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "piper failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let audio = tokio::fs::read(&wav.0)
+            .await
+            .map_err(|e| format!("piper wrote no audio: {}", e))?;
+        Ok((audio, "audio/wav"))
+    }
+}
+
+/// Speech from an OpenAI-compatible `/v1/audio/speech` endpoint.
+struct OpenAiSpeech {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+    voice: String,
+}
+
+#[async_trait]
+impl SpeechSynthesizer for OpenAiSpeech {
+    async fn synthesize(&self, text: &str, speed: f32) -> Result<(Vec<u8>, &'static str), String> {
+        let mut request = self.client.post(&self.url).json(&serde_json::json!({
+            "model": self.model,
+            "voice": self.voice,
+            "input": text,
+            "response_format": "mp3",
+            "speed": speed.clamp(0.25, 4.0),
+        }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Speech API unreachable: {}", e))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Speech API returned {}: {}", status, body.trim()));
+        }
+        let audio = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Speech API response cut off: {}", e))?;
+        Ok((audio.to_vec(), "audio/mpeg"))
+    }
+}
+
+// ============================================================================
+// HANDLERS
+// ============================================================================
+
+/// What an upload may be, so clients can refuse oversized or unsupported
+/// files up front instead of sending them.
+async fn config_handler(TenantState(state): TenantState) -> Json<PublicConfig> {
+    let upload_extensions = [".md", ".pdf"]
+        .into_iter()
+        .map(str::to_string)
+        .chain(
+            SOURCE_EXTENSIONS
+                .iter()
+                .chain(IMAGE_EXTENSIONS)
+                .map(|extension| format!(".{}", extension)),
+        )
+        .collect();
+    Json(PublicConfig {
+        max_upload_mb: state.config.max_upload_mb,
+        upload_extensions,
+        transcription: state.config.whisper_cli.is_some() && state.config.whisper_model.is_some(),
+        speech: state.speech.is_some(),
+    })
+}
+
+/// Readiness: `200` when questions can be answered, `503` (with the same
+/// body) when a component is down.
+async fn health_check(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    let (pulled, loaded, embedding_ready) = tokio::join!(
+        ollama_models(&state.ollama_client, "/api/tags"),
+        ollama_models(&state.ollama_client, "/api/ps"),
+        embedding_ready(&state),
+    );
+    let has_model = |models: &Option<Vec<String>>| {
+        models.as_ref().is_some_and(|models| {
+            models
+                .iter()
+                .any(|name| is_model(name, &state.config.model))
+        })
+    };
+
+    let ollama = pulled.is_some();
+    let model_available = has_model(&pulled);
+    let (documents, chunks) = {
+        let store = state.vector_store.read().unwrap();
+        (store.documents.len(), store.count())
+    };
+
+    let ready = model_available && embedding_ready;
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(HealthResponse {
+            status: if ready { "ok" } else { "degraded" },
+            embedding_model: state.config.embedding_model.clone(),
+            embedding_ready,
+            ollama,
+            model: state.config.model.clone(),
+            model_available,
+            model_loaded: has_model(&loaded),
+            documents,
+            chunks,
+            uptime_secs: state.started_at.elapsed().as_secs(),
+        }),
+    )
+}
+
+/// How long a successful embedding probe vouches for the embedder, so
+/// polling `/health` doesn't keep the embedding pool busy.
+const EMBEDDING_CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// Whether a probe sentence can be embedded. A success is remembered for
+/// `EMBEDDING_CHECK_TTL`; a failure is checked again on the next call.
+async fn embedding_ready(state: &AppState) -> bool {
+    let fresh = state
+        .embedding_checked
+        .lock()
+        .unwrap()
+        .is_some_and(|checked| checked.elapsed() < EMBEDDING_CHECK_TTL);
+    if fresh {
+        return true;
+    }
+    let ready = embed_texts(state, vec!["health check".to_string()])
+        .await
+        .is_ok_and(|embeddings| !embeddings.is_empty());
+    *state.embedding_checked.lock().unwrap() = ready.then(Instant::now);
+    ready
+}
+
+/// Liveness: answers as long as the server is running, whatever the state
+/// of Ollama.
+async fn liveness_check() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Checks for `Authorization: Bearer <key>` with a key from
+/// `admin_api_keys`; every admin operation is off while there are none.
+fn authorize_admin(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    if config.admin_api_keys.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin_disabled",
+            "The admin endpoints are disabled; set admin_api_keys in ahtohallan.toml to enable them",
+        ));
+    }
+    let authorized = bearer_token(headers).is_some_and(|token| {
+        config
+            .admin_api_keys
+            .iter()
+            .any(|key| key_matches(key, token))
+    });
+    if !authorized {
+        return Err(unauthorized());
+    }
+    Ok(())
+}
+
+/// Every tenant with an API key, plus the default one, and what each stores.
+/// Needs `Authorization: Bearer <key>` with a key from `admin_api_keys`.
+async fn tenants_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(error) = authorize_admin(&state.config, &headers) {
+        return error.into_response();
+    }
+
+    // Tenant name -> how many API keys it has
+    let mut names = BTreeMap::from([(DEFAULT_TENANT, 0)]);
+    for tenant in state.config.api_keys.values() {
+        *names.entry(tenant.as_str()).or_default() += 1;
+    }
+    // Only tenants already in memory are counted; loading every tenant's
+    // store just to list it would cost more than the listing is worth
+    let loaded = state.tenants.read().unwrap().clone();
+    let mut tenants = Vec::with_capacity(names.len());
+    for (name, api_keys) in names {
+        let (data, store_path) = if name == state.tenant {
+            let data = (state.vector_store.clone(), state.sessions.clone());
+            (Some(data), state.config.store_path())
+        } else {
+            let data = loaded
+                .get(name)
+                .map(|data| (data.vector_store.clone(), data.sessions.clone()));
+            (data, state.config.for_tenant(name).store_path())
+        };
+        let (documents, chunks, sessions) = match data {
+            Some((vector_store, sessions)) => {
+                let (documents, chunks) = {
+                    let store = vector_store.read().unwrap();
+                    (store.documents.len(), store.count())
+                };
+                (
+                    Some(documents),
+                    Some(chunks),
+                    Some(sessions.lock().await.len()),
+                )
+            }
+            None => (None, None, None),
+        };
+        tenants.push(TenantUsage {
+            name: name.to_string(),
+            api_keys,
+            loaded: documents.is_some(),
+            documents,
+            chunks,
+            sessions,
+            store_bytes: tokio::fs::metadata(store_path)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+        });
+    }
+
+    Json(TenantsResponse { tenants }).into_response()
+}
+
+/// Names of the models Ollama lists at `path` (`/api/tags`: pulled,
+/// `/api/ps`: loaded in memory), or `None` when Ollama is unreachable.
+async fn ollama_models(client: &reqwest::Client, path: &str) -> Option<Vec<String>> {
+    let response = client
+        .get(format!("http://localhost:11434{}", path))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await
+        .ok()?;
+    let body: serde_json::Value = response.error_for_status().ok()?.json().await.ok()?;
+
+    Some(
+        body.get("models")
+            .and_then(|m| m.as_array())
+            .map(|models| {
+                models
+                    .iter()
+                    .filter_map(|m| m.get("name").and_then(|n| n.as_str()))
+                    .map(|name| name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    )
+}
+
+async fn documents_handler(TenantState(state): TenantState) -> Json<DocumentsResponse> {
+    let store = state.vector_store.read().unwrap();
+    // One pass over the chunks for every document's count
+    let mut chunks: HashMap<&str, usize> = HashMap::new();
+    for chunk in &store.chunks {
+        for name in std::iter::once(&chunk.source).chain(&chunk.shared_with) {
+            *chunks.entry(name.as_str()).or_default() += 1;
+        }
+    }
+    let sizes = store
+        .documents
+        .iter()
+        .map(|(name, record)| {
+            let size = DocumentSize {
+                chunks: chunks.get(name.as_str()).copied().unwrap_or(0),
+                words: record.text.split_whitespace().count(),
+            };
+            (name.clone(), size)
+        })
+        .collect();
+    Json(DocumentsResponse {
+        documents: store.document_names(),
+        sizes,
+    })
+}
+
+async fn tags_handler(TenantState(state): TenantState) -> Json<TagsResponse> {
+    let store = state.vector_store.read().unwrap();
+    Json(TagsResponse { tags: store.tags() })
+}
+
+async fn collections_handler(TenantState(state): TenantState) -> Json<CollectionsResponse> {
+    let store = state.vector_store.read().unwrap();
+    Json(CollectionsResponse {
+        collections: store.collections(),
+    })
+}
+
+/// Adds documents to a collection, creating it if it has none yet.
+async fn add_to_collection_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+    payload: Result<Json<CollectionDocumentsRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let name = match collection_name(&name) {
+        Ok(name) => name,
+        Err(error) => return error.into_response(),
+    };
+    let _ingest = state.ingest_lock.read().await;
+
+    let changed = {
+        let mut store = state.vector_store.write().unwrap();
+        if let Some(missing) = request
+            .documents
+            .iter()
+            .find(|document| !store.documents.contains_key(*document))
+        {
+            return ApiError::new(
+                StatusCode::NOT_FOUND,
+                "document_not_found",
+                format!("Document {} not found", missing),
+            )
+            .into_response();
+        }
+        let added = request
+            .documents
+            .iter()
+            .filter(|document| store.add_to_collection(document, &name))
+            .count();
+        added > 0
+    };
+    if changed {
+        persist_store(&state).await;
+    }
+
+    collection_response(&state, name)
+}
+
+/// Takes one document out of a collection; the document stays indexed.
+async fn remove_from_collection_handler(
+    TenantState(state): TenantState,
+    UrlPath((name, document)): UrlPath<(String, String)>,
+) -> Response {
+    let _ingest = state.ingest_lock.read().await;
+    let removed = state
+        .vector_store
+        .write()
+        .unwrap()
+        .remove_from_collection(&document, &name);
+    if !removed {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} is not in collection {}", document, name),
+        )
+        .into_response();
+    }
+    persist_store(&state).await;
+
+    collection_response(&state, name)
+}
+
+/// Dissolves a collection; its documents stay indexed.
+async fn delete_collection_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    let _ingest = state.ingest_lock.read().await;
+    let removed = {
+        let mut store = state.vector_store.write().unwrap();
+        let members = store.collection_documents(&name);
+        for document in &members {
+            store.remove_from_collection(document, &name);
+        }
+        members.len()
+    };
+    if removed == 0 {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "collection_not_found",
+            format!("Collection {} not found", name),
+        )
+        .into_response();
+    }
+    persist_store(&state).await;
+
+    Json(DeleteResponse {
+        status: "success".to_string(),
+        message: format!("Removed collection {} from {} documents", name, removed),
+    })
+    .into_response()
+}
+
+/// A collection's current documents, as returned after changing it.
+fn collection_response(state: &AppState, name: String) -> Response {
+    let store = state.vector_store.read().unwrap();
+    let mut documents: Vec<String> = store.collection_documents(&name).into_iter().collect();
+    documents.sort();
+    Json(CollectionInfo { name, documents }).into_response()
+}
+
+/// Embeds `texts` with the model the index uses, so other tools can work in
+/// the same embedding space. Needs `Authorization: Bearer <key>` with a key
+/// from `embed_api_keys`, and runs on the upload embedding pool.
+async fn embed_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<EmbedRequest>, JsonRejection>,
+) -> Response {
+    if state.config.embed_api_keys.is_empty() {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "embed_disabled",
+            "POST /embed is disabled; set embed_api_keys in ahtohallan.toml to enable it",
+        )
+        .into_response();
+    }
+
+    let authorized = bearer_token(&headers).is_some_and(|token| {
+        state
+            .config
+            .embed_api_keys
+            .iter()
+            .any(|key| key_matches(key, token))
+    });
+    if !authorized {
+        return unauthorized().into_response();
+    }
+
+    let texts = match payload {
+        Ok(Json(payload)) => payload.texts,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if texts.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "texts must not be empty",
+        )
+        .into_response();
+    }
+    if texts.len() > state.config.max_embed_batch {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!(
+                "{} texts sent, at most {} are allowed per request",
+                texts.len(),
+                state.config.max_embed_batch
+            ),
+        )
+        .into_response();
+    }
+
+    match embed_batches_collect(&state, &texts).await {
+        Ok(embeddings) => Json(EmbedResponse {
+            model: state.config.embedding_model.clone(),
+            dimensions: embeddings.first().map_or(0, Vec::len),
+            embeddings,
+        })
+        .into_response(),
+        Err(e) => ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "embedding_failed",
+            format!("Failed to generate embeddings: {}", e),
+        )
+        .retryable()
+        .into_response(),
+    }
+}
+
+/// Speech to text with whisper.cpp, for browsers without (or users who'd
+/// rather not use the cloud-backed) Web Speech recognition. The body is a
+/// recording in any format ffmpeg reads, such as a `MediaRecorder` blob.
+async fn transcribe_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<TranscribeParams>,
+    body: Result<Bytes, BytesRejection>,
+) -> Response {
+    let (Some(whisper_cli), Some(whisper_model)) =
+        (&state.config.whisper_cli, &state.config.whisper_model)
+    else {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "transcription_disabled",
+            "POST /transcribe is disabled; set whisper_cli and whisper_model in ahtohallan.toml to enable it",
+        )
+        .into_response();
+    };
+    let audio = match body {
+        Ok(audio) => audio,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                format!("Recordings are limited to {} MB", MAX_AUDIO_MB),
+            )
+            .into_response()
+        }
+        Err(rejection) => {
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                rejection.body_text(),
+            )
+            .into_response()
+        }
+    };
+    if audio.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "The request body must be the recorded audio",
+        )
+        .into_response();
+    }
+    // whisper.cpp takes the bare language code
+    let language = match params.language.as_deref().map(str::trim) {
+        None | Some("") => "auto".to_string(),
+        Some(tag) => {
+            let code = tag.split(['-', '_']).next().unwrap_or_default();
+            if !(2..=3).contains(&code.len()) || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                return ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    format!("Unknown language {}", tag),
+                )
+                .into_response();
+            }
+            code.to_lowercase()
+        }
+    };
+
+    let started = Instant::now();
+    let stem = std::env::temp_dir().join(format!("ahtohallan-{}", uuid::Uuid::new_v4()));
+    let recording = stem.with_extension("audio");
+    let wav = stem.with_extension("wav");
+    let result = transcribe(
+        &state.config.ffmpeg,
+        whisper_cli,
+        whisper_model,
+        &language,
+        &audio,
+        &recording,
+        &wav,
+    )
+    .await;
+    for path in [&recording, &wav] {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    match result {
+        Ok(text) => {
+            debug!(
+                "🎙️ Transcribed {} bytes of audio in {:?}",
+                audio.len(),
+                started.elapsed()
+            );
+            Json(TranscribeResponse { text }).into_response()
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+/// The ffmpeg demuxers recordings are read with: what browsers record
+/// (WebM, Ogg, MP4) and common audio files.
+const AUDIO_FORMATS: &str = "matroska,webm,ogg,mov,mp4,m4a,wav,mp3,flac,aac";
+
+/// Converts `audio` to WAV with ffmpeg and runs whisper.cpp over it, using
+/// `recording` and `wav` as scratch files.
+async fn transcribe(
+    ffmpeg: &Path,
+    whisper_cli: &Path,
+    whisper_model: &Path,
+    language: &str,
+    audio: &[u8],
+    recording: &Path,
+    wav: &Path,
+) -> Result<String, ApiError> {
+    let failed = |message: String| {
+        error!("❌ {}", message);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "transcription_failed",
+            message,
+        )
+    };
+    tokio::fs::write(recording, audio)
+        .await
+        .map_err(|e| failed(format!("Failed to store the recording: {}", e)))?;
+
+    // Only plain audio containers, read from the one file: playlists and
+    // concat lists could otherwise point ffmpeg at other files or URLs
+    let conversion = tokio::process::Command::new(ffmpeg)
+        .args(["-nostdin", "-loglevel", "error", "-y"])
+        .args(["-protocol_whitelist", "file"])
+        .args(["-format_whitelist", AUDIO_FORMATS, "-i"])
+        .arg(recording)
+        .args(["-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"])
+        .arg(wav)
+        .kill_on_drop(true)
+        .output();
+    let converted = tokio::time::timeout(TRANSCRIBE_TIMEOUT, conversion)
+        .await
+        .map_err(|_| {
+            failed(format!(
+                "ffmpeg took longer than {}s",
+                TRANSCRIBE_TIMEOUT.as_secs()
+            ))
+        })?
+        .map_err(|e| failed(format!("Failed to run {}: {}", ffmpeg.display(), e)))?;
+    if !converted.status.success() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_audio",
+            format!(
+                "The recording could not be decoded: {}",
+                String::from_utf8_lossy(&converted.stderr).trim()
+            ),
+        ));
+    }
+
+    let whisper = tokio::process::Command::new(whisper_cli)
+        .arg("-m")
+        .arg(whisper_model)
+        .args(["-l", language, "--no-timestamps", "--no-prints", "-f"])
+        .arg(wav)
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(TRANSCRIBE_TIMEOUT, whisper)
+        .await
+        .map_err(|_| {
+            failed(format!(
+                "whisper.cpp took longer than {}s",
+                TRANSCRIBE_TIMEOUT.as_secs()
+            ))
+        })?
+        .map_err(|e| failed(format!("Failed to run {}: {}", whisper_cli.display(), e)))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "whisper.cpp failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // One line per segment
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Text to speech with the configured provider, for voices that sound the
+/// same on every machine. Returns the audio itself.
+async fn tts_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<TtsRequest>, JsonRejection>,
+) -> Response {
+    let Some(speech) = state.speech.clone() else {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "tts_disabled",
+            "POST /tts is disabled; configure [tts] in ahtohallan.toml to enable it",
+        )
+        .into_response();
+    };
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let text = request.text.trim();
+    if text.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "text must not be empty",
+        )
+        .into_response();
+    }
+    if text.chars().count() > MAX_TTS_CHARS {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "text_too_long",
+            format!("text is limited to {} characters", MAX_TTS_CHARS),
+        )
+        .into_response();
+    }
+    let speed = request
+        .speed
+        .filter(|speed| speed.is_finite())
+        .unwrap_or(1.0)
+        .clamp(0.5, 2.0);
+
+    let started = Instant::now();
+    match speech.synthesize(text, speed).await {
+        Ok((audio, mime)) => {
+            debug!(
+                "🔊 Synthesized {} characters in {:?}",
+                text.len(),
+                started.elapsed()
+            );
+            ([(header::CONTENT_TYPE, mime)], audio).into_response()
+        }
+        Err(message) => {
+            error!("❌ {}", message);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "tts_failed", message).into_response()
+        }
+    }
+}
+
+/// Starts an empty server-side conversation.
+async fn create_session_handler(TenantState(state): TenantState, body: Bytes) -> Response {
+    // The body is optional, but one that is sent has to be valid
+    let request = if body.is_empty() {
+        CreateSessionRequest::default()
+    } else {
+        match Json::<CreateSessionRequest>::from_bytes(&body) {
+            Ok(Json(request)) => request,
+            Err(rejection) => return ApiError::from(rejection).into_response(),
+        }
+    };
+    let now = unix_now();
+    let id = uuid::Uuid::new_v4().to_string();
+    let session = Session {
+        title: request
+            .title
+            .map(|title| title.trim().to_string())
+            .unwrap_or_default(),
+        created_at: now,
+        updated_at: now,
+        messages: Vec::new(),
+    };
+    let summary = session.summary(&id);
+
+    let mut sessions = state.sessions.lock().await;
+    sessions.insert(id, session);
+    write_json_file(
+        &state.config,
+        &state.config.sessions_path(),
+        &*sessions,
+        "sessions",
+    )
+    .await;
+
+    (StatusCode::CREATED, Json(summary)).into_response()
+}
+
+/// Every session, most recently used first.
+async fn sessions_handler(TenantState(state): TenantState) -> Json<SessionsResponse> {
+    let sessions = state.sessions.lock().await;
+    let mut summaries: Vec<SessionSummary> = sessions
+        .iter()
+        .map(|(id, session)| session.summary(id))
+        .collect();
+    summaries.sort_by(|a, b| {
+        b.updated_at
+            .cmp(&a.updated_at)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Json(SessionsResponse {
+        sessions: summaries,
+    })
+}
+
+/// A session's messages in order, for replaying the conversation.
+async fn session_messages_handler(
+    TenantState(state): TenantState,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    let sessions = state.sessions.lock().await;
+    match sessions.get(&id) {
+        Some(session) => Json(SessionMessagesResponse {
+            title: session.title.clone(),
+            messages: session.messages.clone(),
+            id,
+        })
+        .into_response(),
+        None => session_not_found(&id).into_response(),
+    }
+}
+
+/// Retitles a session.
+async fn rename_session_handler(
+    TenantState(state): TenantState,
+    UrlPath(id): UrlPath<String>,
+    payload: Result<Json<RenameSessionRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let mut sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get_mut(&id) else {
+        return session_not_found(&id).into_response();
+    };
+    session.title = request.title.trim().to_string();
+    let summary = session.summary(&id);
+    write_json_file(
+        &state.config,
+        &state.config.sessions_path(),
+        &*sessions,
+        "sessions",
+    )
+    .await;
+
+    Json(summary).into_response()
+}
+
+/// Removes a session's messages from `from` on, so an edited or regenerated
+/// question replaces them.
+async fn truncate_session_handler(
+    TenantState(state): TenantState,
+    UrlPath(id): UrlPath<String>,
+    Query(params): Query<TruncateSessionParams>,
+) -> Response {
+    let mut sessions = state.sessions.lock().await;
+    let Some(session) = sessions.get_mut(&id) else {
+        return session_not_found(&id).into_response();
+    };
+    if params.from >= session.messages.len() {
+        return Json(session.summary(&id)).into_response();
+    }
+    session.messages.truncate(params.from);
+    session.updated_at = unix_now();
+    let summary = session.summary(&id);
+    write_json_file(
+        &state.config,
+        &state.config.sessions_path(),
+        &*sessions,
+        "sessions",
+    )
+    .await;
+
+    Json(summary).into_response()
+}
+
+/// Starts an evaluation run over a JSONL body of
+/// `{"question", "expected_source", "expected_answer"?}` lines and returns
+/// its id; poll `GET /evals/:id` for progress and results.
+async fn create_eval_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<EvalParams>,
+    body: String,
+) -> Response {
+    let cases = match parse_eval_cases(&body) {
+        Ok(cases) => cases,
+        Err(error) => return error.into_response(),
+    };
+    if cases.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "The evaluation set has no questions",
+        )
+        .into_response();
+    }
+    if cases.len() > state.config.max_eval_questions {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!(
+                "{} questions sent, at most {} are allowed per evaluation",
+                cases.len(),
+                state.config.max_eval_questions
+            ),
+        )
+        .into_response();
+    }
+
+    let run = EvalRun {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: params.name,
+        status: JobStatus::Running,
+        k: params
+            .k
+            .unwrap_or(DEFAULT_EVAL_K)
+            .clamp(1, MAX_SEARCH_RESULTS),
+        retrieval_only: params.retrieval_only,
+        questions: cases.len(),
+        completed: 0,
+        started_at: unix_now(),
+        finished_at: None,
+        settings: EvalSettings::current(&state),
+        metrics: None,
+        results: Vec::new(),
+    };
+    info!(
+        "Starting evaluation {} with {} questions (k = {})",
+        run.id, run.questions, run.k
+    );
+    let summary = run.summary();
+    state.evals.lock().await.insert(run.id.clone(), run);
+    tokio::spawn(run_eval(state, summary.id.clone(), cases));
+
+    (StatusCode::ACCEPTED, Json(summary)).into_response()
+}
+
+/// Every evaluation run's settings and metrics, newest first, for comparing
+/// runs without their per-question results.
+async fn evals_handler(TenantState(state): TenantState) -> Response {
+    let mut runs: Vec<EvalRun> = state
+        .evals
+        .lock()
+        .await
+        .values()
+        .map(EvalRun::summary)
+        .collect();
+    runs.sort_by(|a, b| {
+        b.started_at
+            .cmp(&a.started_at)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Json(EvalsResponse { runs }).into_response()
+}
+
+/// One evaluation run with its per-question results once finished.
+async fn eval_handler(TenantState(state): TenantState, UrlPath(id): UrlPath<String>) -> Response {
+    match state.evals.lock().await.get(&id) {
+        Some(run) => Json(run.clone()).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "eval_not_found",
+            format!("Evaluation run {} not found", id),
+        )
+        .into_response(),
+    }
+}
+
+/// Deletes a session and its messages.
+async fn delete_session_handler(
+    TenantState(state): TenantState,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    let mut sessions = state.sessions.lock().await;
+    let Some(session) = sessions.remove(&id) else {
+        return session_not_found(&id).into_response();
+    };
+    write_json_file(
+        &state.config,
+        &state.config.sessions_path(),
+        &*sessions,
+        "sessions",
+    )
+    .await;
+
+    Json(DeleteResponse {
+        status: "success".to_string(),
+        message: format!(
+            "Deleted session {} ({} messages)",
+            id,
+            session.messages.len()
+        ),
+    })
+    .into_response()
+}
+
+/// Longest feedback comment kept; longer ones are cut off.
+const MAX_FEEDBACK_COMMENT_CHARS: usize = 2000;
+
+/// Appends a rating of an answer to `feedback.jsonl`. The file is only ever
+/// appended to, one JSON object per line, so earlier feedback can't be lost
+/// to a rewrite.
+async fn feedback_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<FeedbackRequest>, JsonRejection>,
+) -> Response {
+    let feedback = match payload {
+        Ok(Json(feedback)) => feedback,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if feedback.query.trim().is_empty() || feedback.answer.trim().is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "query and answer must not be empty",
+        )
+        .into_response();
+    }
+
+    let record = FeedbackRecord {
+        id: uuid::Uuid::new_v4().to_string(),
+        created_at: unix_now(),
+        rating: feedback.rating,
+        query: feedback.query,
+        answer: feedback.answer,
+        sources: feedback.sources,
+        comment: feedback
+            .comment
+            .map(|comment| {
+                comment
+                    .trim()
+                    .chars()
+                    .take(MAX_FEEDBACK_COMMENT_CHARS)
+                    .collect::<String>()
+            })
+            .filter(|comment| !comment.is_empty()),
+        session_id: feedback.session_id,
+    };
+    let mut line = match serde_json::to_vec(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "feedback_failed",
+                format!("Failed to serialize feedback: {}", e),
+            )
+            .into_response()
+        }
+    };
+    line.push(b'\n');
+
+    // One write per record on an append-mode file, so concurrent ratings
+    // land on separate lines
+    let path = state.config.feedback_path();
+    let written = async {
+        tokio::fs::create_dir_all(&state.config.data_dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &line).await
+    }
+    .await;
+    if let Err(e) = written {
+        error!("Failed to record feedback in {}: {}", path.display(), e);
+        return ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "feedback_failed",
+            "Failed to record feedback",
+        )
+        .retryable()
+        .into_response();
+    }
+
+    info!(
+        "Recorded {} feedback {}",
+        if record.rating == Rating::Up {
+            "👍"
+        } else {
+            "👎"
+        },
+        record.id
+    );
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "id": record.id })),
+    )
+        .into_response()
+}
+
+/// Exports the recorded feedback as JSON lines, oldest first, optionally
+/// only one `rating`.
+async fn export_feedback_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<FeedbackParams>,
+) -> Response {
+    let contents = match tokio::fs::read_to_string(state.config.feedback_path()).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "feedback_failed",
+                format!("Failed to read feedback: {}", e),
+            )
+            .into_response()
+        }
+    };
+
+    let body: String = contents
+        .lines()
+        .filter(|line| match params.rating {
+            Some(rating) => serde_json::from_str::<FeedbackRecord>(line)
+                .is_ok_and(|record| record.rating == rating),
+            None => !line.trim().is_empty(),
+        })
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+/// Publishes a snapshot of a conversation under an unguessable token, for
+/// the read-only share view.
+async fn create_share_handler(
+    // Shares are public links, so they live outside tenants; creating one
+    // still needs a key
+    _tenant: TenantState,
+    State(state): State<AppState>,
+    payload: Result<Json<ShareRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if request.messages.is_empty() || request.messages.len() > MAX_SHARED_MESSAGES {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            format!(
+                "A shared conversation needs 1 to {} messages",
+                MAX_SHARED_MESSAGES
+            ),
+        )
+        .into_response();
+    }
+    if let Some(message) = request
+        .messages
+        .iter()
+        .find(|message| message.role != "user" && message.role != "assistant")
+    {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            format!("Unknown message role {:?}", message.role),
+        )
+        .into_response();
+    }
+
+    if request
+        .expires_in_hours
+        .is_some_and(|hours| hours > MAX_SHARE_HOURS)
+    {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            format!(
+                "expires_in_hours may be at most {}; omit it for a share that never expires",
+                MAX_SHARE_HOURS
+            ),
+        )
+        .into_response();
+    }
+
+    let now = unix_now();
+    let expires_at = request.expires_in_hours.map(|hours| now + hours * 3600);
+    // Two v4 UUIDs: 244 random bits
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+
+    let mut shares = state.shares.lock().await;
+    shares.retain(|_, share| !share.expired(now));
+    shares.insert(
+        token.clone(),
+        SharedConversation {
+            messages: request.messages,
+            created_at: now,
+            expires_at,
+        },
+    );
+    persist_shares(&state.config, &shares).await;
+    info!("Published shared conversation ({} active)", shares.len());
+
+    (
+        StatusCode::CREATED,
+        Json(ShareResponse { token, expires_at }),
+    )
+        .into_response()
+}
+
+async fn get_share_handler(
+    State(state): State<AppState>,
+    UrlPath(token): UrlPath<String>,
+) -> Response {
+    let shares = state.shares.lock().await;
+    match shares.get(&token) {
+        Some(share) if share.expired(unix_now()) => ApiError::new(
+            StatusCode::GONE,
+            "share_expired",
+            "This shared conversation has expired",
+        )
+        .into_response(),
+        Some(share) => Json(share.clone()).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "share_not_found",
+            "No shared conversation with this link",
+        )
+        .into_response(),
+    }
+}
+
+/// Results returned by the similarity endpoints unless `limit` says otherwise.
+const DEFAULT_SIMILAR_RESULTS: usize = 5;
+const MAX_SIMILAR_RESULTS: usize = 50;
+
+/// Results `POST /search` returns unless `top_k` says otherwise.
+const DEFAULT_SEARCH_RESULTS: usize = 5;
+const MAX_SEARCH_RESULTS: usize = 50;
+
+/// Runs retrieval for a query and returns the passages found, without
+/// generating an answer.
+async fn search_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<SearchRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let query = request.query.trim().to_string();
+    if query.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "query must not be empty",
+        )
+        .into_response();
+    }
+
+    let top_k = request
+        .top_k
+        .unwrap_or(DEFAULT_SEARCH_RESULTS)
+        .clamp(1, MAX_SEARCH_RESULTS);
+    let min_similarity = request
+        .min_similarity
+        .unwrap_or(state.config.min_similarity);
+    let scope = match source_scope(
+        &state.vector_store.read().unwrap(),
+        &request.sources,
+        request.collection.as_deref(),
+        &request.tags,
+    ) {
+        Ok(scope) => scope,
+        Err(error) => return error.into_response(),
+    };
+    let options = SearchOptions {
+        min_similarity,
+        top_k,
+        scope: scope.as_ref(),
+        recency_weight: request
+            .recency_weight
+            .map(|weight| weight.clamp(0.0, 1.0))
+            .unwrap_or(state.config.recency_weight),
+        keywords: &request.keywords,
+    };
+    let retrieval = match retrieve(
+        &state,
+        std::slice::from_ref(&query),
+        &options,
+        &mut StageTimings::default(),
+    )
+    .await
+    {
+        Ok(retrieval) => retrieval,
+        Err(error) => return error.into_response(),
+    };
+
+    let store = state.vector_store.read().unwrap();
+    let results = retrieval
+        .chunks
+        .into_iter()
+        .map(|chunk| {
+            let document = store.documents.get(&chunk.source);
+            let chunk_index =
+                document.and_then(|doc| doc.chunk_ids.iter().position(|id| *id == chunk.id));
+            let range = document.and_then(|doc| locate_passage(&doc.text, &chunk.text));
+            SearchResult {
+                id: chunk.id,
+                source: chunk.source,
+                score: chunk.score,
+                text: chunk.text,
+                chunk_index,
+                start: range.map(|(start, _)| start),
+                end: range.map(|(_, end)| end),
+                keywords: chunk.keywords,
+            }
+        })
+        .collect();
+
+    Json(SearchResponse { query, results }).into_response()
+}
+
+/// Names of the prompt templates a chat request may pick.
+async fn templates_handler(TenantState(state): TenantState) -> Json<TemplatesResponse> {
+    let mut templates: Vec<String> = state.config.prompts.keys().cloned().collect();
+    templates.sort();
+    Json(TemplatesResponse { templates })
+}
+
+/// Documents whose content overlaps most with `name`, by embedding
+/// similarity, to surface related or duplicated material.
+async fn similar_documents_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+    Query(params): Query<SimilarParams>,
+) -> Response {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SIMILAR_RESULTS)
+        .clamp(1, MAX_SIMILAR_RESULTS);
+    let store = state.vector_store.read().unwrap();
+    if !store.documents.contains_key(&name) {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", name),
+        )
+        .into_response();
+    }
+
+    let similar = store
+        .similar_documents(&name, limit)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, score, shared_chunks)| SimilarDocument {
+            name,
+            score,
+            shared_chunks,
+        })
+        .collect();
+    Json(SimilarDocumentsResponse {
+        document: name,
+        similar,
+    })
+    .into_response()
+}
+
+/// Chunks from anywhere in the corpus closest to chunk `id`.
+async fn related_chunks_handler(
+    TenantState(state): TenantState,
+    UrlPath(id): UrlPath<u64>,
+    Query(params): Query<SimilarParams>,
+) -> Response {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SIMILAR_RESULTS)
+        .clamp(1, MAX_SIMILAR_RESULTS);
+    let store = state.vector_store.read().unwrap();
+    match store.related_chunks(id, limit) {
+        Some(related) => Json(RelatedChunksResponse {
+            chunk_id: id,
+            related: related
+                .into_iter()
+                .map(|(chunk, score)| RelatedChunk {
+                    id: chunk.id,
+                    source: chunk.source.clone(),
+                    score,
+                    text: chunk.text.clone(),
+                })
+                .collect(),
+        })
+        .into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "chunk_not_found",
+            format!("Chunk {} not found", id),
+        )
+        .into_response(),
+    }
+}
+
+/// The ingestion report kept for a document.
+async fn report_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    let store = state.vector_store.read().unwrap();
+    match store.documents.get(&name) {
+        Some(DocumentRecord {
+            report: Some(report),
+            ..
+        }) => Json(report.clone()).into_response(),
+        Some(_) => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "report_not_found",
+            format!(
+                "{} was ingested before reports were kept; re-upload it to get one",
+                name
+            ),
+        )
+        .into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", name),
+        )
+        .into_response(),
+    }
+}
+
+/// Largest recording `POST /transcribe` accepts, in megabytes.
+const MAX_AUDIO_MB: usize = 25;
+/// Longest ffmpeg, and then whisper.cpp, may each take over one recording.
+const TRANSCRIBE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Longest text one `POST /tts` request may read aloud, in characters.
+const MAX_TTS_CHARS: usize = 4_000;
+/// Longest a speech provider may take over one request.
+const TTS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest extracted text `GET /documents/:name/preview` returns, in characters.
+const MAX_PREVIEW_CHARS: usize = 200_000;
+
+/// The text a document was indexed from, as it is after redaction: PDFs are
+/// split back into pages, documents indexed before pages were kept come back
+/// as a single page.
+async fn preview_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    let store = state.vector_store.read().unwrap();
+    let Some(record) = store.documents.get(&name) else {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", name),
+        )
+        .into_response();
+    };
+
+    let lower = name.to_lowercase();
+    let format = if lower.ends_with(".md") {
+        "markdown"
+    } else if lower.ends_with(".pdf") {
+        "pdf"
+    } else {
+        "text"
+    };
+    let (text, truncated) = match record.text.char_indices().nth(MAX_PREVIEW_CHARS) {
+        Some((end, _)) => (&record.text[..end], true),
+        None => (record.text.as_str(), false),
+    };
+    let pages = if format == "pdf" {
+        let mut pages: Vec<String> = text
+            .split(PAGE_BREAK)
+            .map(|page| page.trim_matches('\n').to_string())
+            .collect();
+        // Each table and figure description after the text of its page
+        for table in &record.tables {
+            if let Some(page) = pages.get_mut(table.page.saturating_sub(1)) {
+                page.push_str(&format!("\n\n{}", table.markdown));
+            }
+        }
+        for figure in &record.figures {
+            if let Some(page) = pages.get_mut(figure.page.saturating_sub(1)) {
+                page.push_str(&format!("\n\n[Figure] {}", figure.description));
+            }
+        }
+        pages
+    } else {
+        vec![text.to_string()]
+    };
+
+    Json(DocumentPreview {
+        name,
+        format,
+        pages,
+        truncated,
+    })
+    .into_response()
+}
+
+async fn document_stats_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    let store = state.vector_store.read().unwrap();
+    let Some(record) = store.documents.get(&name) else {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", name),
+        )
+        .into_response();
+    };
+    let report = record.report.as_ref();
+    let embedding_model = if store.embedding_model.is_empty() {
+        state.config.embedding_model.clone()
+    } else {
+        store.embedding_model.clone()
+    };
+
+    // Stores indexed before chunk order was kept have to be counted
+    let chunks = if record.chunk_ids.is_empty() {
+        store
+            .chunks
+            .iter()
+            .filter(|chunk| chunk_of(chunk, &name))
+            .count()
+    } else {
+        record.chunk_ids.len()
+    };
+
+    Json(DocumentStats {
+        chunks,
+        words: record.text.split_whitespace().count(),
+        characters: record.text.chars().count(),
+        pages: report.and_then(|report| report.pages),
+        duration_ms: report.map(|report| report.duration_ms),
+        ingested_at: report.map(|report| report.ingested_at),
+        language: record.language.clone(),
+        embedding_model,
+        embedding_dimensions: store.embedding_dimensions,
+        name,
+    })
+    .into_response()
+}
+
+/// Sets or clears a document's date, which the recency stage ranks by.
+async fn update_document_handler(
+    TenantState(state): TenantState,
+    UrlPath(name): UrlPath<String>,
+    payload: Result<Json<UpdateDocumentRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let date = match request.date.as_deref().map(document_date) {
+        Some(Ok(date)) => Some(date),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+    let _ingest = state.ingest_lock.read().await;
+
+    let changed = {
+        let mut store = state.vector_store.write().unwrap();
+        if !store.documents.contains_key(&name) {
+            return ApiError::new(
+                StatusCode::NOT_FOUND,
+                "document_not_found",
+                format!("Document {} not found", name),
+            )
+            .into_response();
+        }
+        store.set_date(&name, date.clone())
+    };
+    if changed {
+        persist_store(&state).await;
+    }
+
+    Json(serde_json::json!({
+        "document": name,
+        "date": date,
+    }))
+    .into_response()
+}
+
+/// The watch folder and what it did lately. Only the default tenant has one.
+async fn watch_handler(TenantState(state): TenantState) -> Json<WatchResponse> {
+    let dir = state
+        .config
+        .watch_dir
+        .as_ref()
+        .filter(|_| state.tenant == DEFAULT_TENANT);
+    let Some(dir) = dir else {
+        return Json(WatchResponse {
+            dir: None,
+            documents: Vec::new(),
+            events: Vec::new(),
+        });
+    };
+
+    let mut documents: Vec<String> = {
+        let store = state.vector_store.read().unwrap();
+        store
+            .documents
+            .iter()
+            .filter(|(_, record)| record.watched)
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    documents.sort();
+    Json(WatchResponse {
+        dir: Some(dir.display().to_string()),
+        documents,
+        events: state
+            .watch_activity
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect(),
+    })
+}
+
+async fn stats_handler(TenantState(state): TenantState) -> Json<StatsResponse> {
+    let store = state.vector_store.read().unwrap();
+    Json(StatsResponse {
+        documents: store.documents.len(),
+        chunks: store.count(),
+        languages: store
+            .language_counts()
+            .into_iter()
+            .map(|(code, documents)| LanguageCount {
+                name: language_name(&code),
+                code,
+                documents,
+            })
+            .collect(),
+    })
+}
+
+/// Corpus statistics with an LLM-written overview and each document's
+/// topics. The overview is cached until the set of documents changes.
+async fn overview_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<OverviewParams>,
+) -> Json<OverviewResponse> {
+    let (fingerprint, mut response) = {
+        let store = state.vector_store.read().unwrap();
+        let mut chunk_counts: HashMap<&str, usize> = HashMap::new();
+        for chunk in &store.chunks {
+            for source in std::iter::once(&chunk.source).chain(&chunk.shared_with) {
+                *chunk_counts.entry(source.as_str()).or_default() += 1;
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        let topics: Vec<DocumentTopics> = store
+            .document_names()
+            .into_iter()
+            .map(|name| {
+                let record = &store.documents[&name];
+                (&name, &record.content_hash, &record.summary).hash(&mut hasher);
+                DocumentTopics {
+                    chunks: chunk_counts.get(name.as_str()).copied().unwrap_or(0),
+                    words: record.text.split_whitespace().count(),
+                    summary: record.summary.clone(),
+                    name,
+                }
+            })
+            .collect();
+
+        let response = OverviewResponse {
+            overview: None,
+            documents: topics.len(),
+            chunks: store.count(),
+            words: topics.iter().map(|topic| topic.words).sum(),
+            topics,
+            warning: None,
+            generated_at: unix_now(),
+        };
+        (hasher.finish(), response)
+    };
+
+    let mut cached = state.overview.lock().await;
+    if let Some((cached_fingerprint, overview)) = cached.as_ref() {
+        if *cached_fingerprint == fingerprint && !params.refresh {
+            return Json(overview.clone());
+        }
+    }
+    if response.topics.is_empty() {
+        return Json(response);
+    }
+
+    match corpus_overview(&state, &response.topics).await {
+        Ok(overview) if !overview.is_empty() => {
+            response.overview = Some(overview);
+            *cached = Some((fingerprint, response.clone()));
+        }
+        Ok(_) => response.warning = Some("The model returned an empty overview".to_string()),
+        Err(e) => {
+            warn!("Corpus overview failed: {}", e.message);
+            response.warning = Some(format!("No overview could be written: {}", e.message));
+        }
+    }
+    Json(response)
 }
 
 async fn delete_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<DeleteRequest>,
-) -> impl IntoResponse {
-    let filename = payload.filename;
+    TenantState(state): TenantState,
+    payload: Result<Json<DeleteRequest>, JsonRejection>,
+) -> Response {
+    let filename = match payload {
+        Ok(Json(payload)) => payload.filename,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
     info!("Delete request for: {}", filename);
 
-    let mut store = state.vector_store.write().unwrap();
-    let removed_count = store.remove_by_source(&filename);
-    drop(store);
+    let removed_count = {
+        let mut store = state.vector_store.write().unwrap();
+        store.remove_by_source(&filename)
+    };
+
+    if removed_count > 0 {
+        persist_store(&state).await;
+
+        Json(DeleteResponse {
+            status: "success".to_string(),
+            message: format!("Removed {} chunks from {}", removed_count, filename),
+        })
+        .into_response()
+    } else {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "document_not_found",
+            format!("Document {} not found", filename),
+        )
+        .into_response()
+    }
+}
+
+/// Removes every document (or every document in `collection`) with its
+/// chunks, drops the cached overview and saves the emptied index. Waits for
+/// ingests in progress to finish first; uploads still queued are indexed
+/// afterwards.
+async fn reset_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<ResetRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if !request.confirm {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "confirmation_required",
+            "Resetting removes documents for good; send \"confirm\": true to go ahead",
+        )
+        .into_response();
+    }
+
+    let _ingest = state.ingest_lock.write().await;
+    let (documents, chunks) = {
+        let mut store = state.vector_store.write().unwrap();
+        let (documents, chunks) = match &request.collection {
+            Some(collection) => {
+                let members = store.collection_documents(collection);
+                let chunks: usize = members
+                    .iter()
+                    .map(|document| store.remove_by_source(document))
+                    .sum();
+                (members.len(), chunks)
+            }
+            None => {
+                let counts = (store.documents.len(), store.count());
+                *store = store.empty_like(&state.config);
+                counts
+            }
+        };
+        // Compacts the embedding file the removed chunks leave behind
+        store.prepare_embeddings(&state.config);
+        (documents, chunks)
+    };
+    if let Some(collection) = request.collection.filter(|_| documents == 0) {
+        return ApiError::new(
+            StatusCode::NOT_FOUND,
+            "collection_not_found",
+            format!("Collection {} not found", collection),
+        )
+        .into_response();
+    }
+    *state.overview.lock().await = None;
+    persist_store(&state).await;
+    info!("Reset removed {} documents ({} chunks)", documents, chunks);
+
+    Json(DeleteResponse {
+        status: "success".to_string(),
+        message: format!("Removed {} documents ({} chunks)", documents, chunks),
+    })
+    .into_response()
+}
+
+const ARCHIVE_FORMAT: &str = "ahtohallan-index";
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Writes the index out as an `IndexArchive` download.
+async fn export_handler(TenantState(state): TenantState) -> Response {
+    let bytes = {
+        let store = state.vector_store.read().unwrap();
+        let archive = IndexArchive {
+            format: ARCHIVE_FORMAT.to_string(),
+            version: ARCHIVE_VERSION,
+            exported_at: unix_now(),
+            embedding_model: store.embedding_model.clone(),
+            embedding_dimensions: store.embedding_dimensions,
+            documents: store.documents.clone(),
+            chunks: store
+                .chunks
+                .iter()
+                .map(|chunk| ChunkData {
+                    embedding: StoredEmbedding::F32(store.embedding(chunk)),
+                    ..chunk.clone()
+                })
+                .collect(),
+            next_chunk_id: store.next_chunk_id,
+        };
+        serde_json::to_vec(&archive)
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "export_failed",
+                format!("Failed to serialize the index: {}", e),
+            )
+            .into_response()
+        }
+    };
+    info!("Exported the index ({} bytes)", bytes.len());
+
+    let disposition = format!(
+        "attachment; filename=\"ahtohallan-{}-{}.json\"",
+        state.tenant,
+        unix_now()
+    );
+    (
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+/// Replaces the index with an `IndexArchive` from `GET /export`. The
+/// archive must have been embedded with the configured model; an index
+/// that already holds documents is only replaced with `?replace=true`.
+async fn import_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<ImportParams>,
+    payload: Result<Json<IndexArchive>, JsonRejection>,
+) -> Response {
+    let archive = match payload {
+        Ok(Json(archive)) => archive,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return upload_too_large(&state.config).into_response()
+        }
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if let Err(error) = check_archive(&archive, &state.config) {
+        return error.into_response();
+    }
+
+    let Ok(_reindex) = state.reindex_lock.try_lock() else {
+        return ApiError::new(
+            StatusCode::CONFLICT,
+            "reindex_in_progress",
+            "A reindex is running; import once it has finished",
+        )
+        .retryable()
+        .into_response();
+    };
+    let _ingest = state.ingest_lock.write().await;
+    let (documents, chunks) = {
+        let mut store = state.vector_store.write().unwrap();
+        if !store.documents.is_empty() && !params.replace {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "index_not_empty",
+                format!(
+                    "The index already holds {} documents; pass ?replace=true to replace them",
+                    store.documents.len()
+                ),
+            )
+            .into_response();
+        }
+
+        let max_id = archive.chunks.iter().map(|chunk| chunk.id + 1).max();
+        let mut imported = VectorStore {
+            documents: archive.documents,
+            chunks: archive.chunks,
+            next_chunk_id: archive.next_chunk_id.max(max_id.unwrap_or(0)),
+            ..store.empty_like(&state.config)
+        };
+        imported.rebuild_content_index();
+        *store = imported;
+        // Moves the embeddings into the configured storage, compacting away
+        // the replaced index's embedding file
+        store.prepare_embeddings(&state.config);
+        (store.documents.len(), store.count())
+    };
+    *state.overview.lock().await = None;
+    persist_store(&state).await;
+    info!("Imported {} documents ({} chunks)", documents, chunks);
+
+    Json(DeleteResponse {
+        status: "success".to_string(),
+        message: format!("Imported {} documents ({} chunks)", documents, chunks),
+    })
+    .into_response()
+}
+
+/// Rejects archives of another format or embedding model, and ones whose
+/// chunks don't fit their documents or dimensions.
+fn check_archive(archive: &IndexArchive, config: &Config) -> Result<(), ApiError> {
+    let invalid =
+        |message: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_archive", message);
+    if archive.format != ARCHIVE_FORMAT || archive.version > ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "Not an index archive this backend can read (format {:?}, version {})",
+            archive.format, archive.version
+        )));
+    }
+    if archive.embedding_model != config.embedding_model {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "embedding_model_mismatch",
+            format!(
+                "The archive was embedded with {} but embedding_model is {}; set embedding_model = \"{}\" to import it",
+                archive.embedding_model, config.embedding_model, archive.embedding_model
+            ),
+        ));
+    }
+    for chunk in &archive.chunks {
+        let StoredEmbedding::F32(embedding) = &chunk.embedding else {
+            return Err(invalid(format!(
+                "Chunk {} has no full-precision embedding",
+                chunk.id
+            )));
+        };
+        if embedding.len() != archive.embedding_dimensions {
+            return Err(invalid(format!(
+                "Chunk {} has {} dimensions, not {}",
+                chunk.id,
+                embedding.len(),
+                archive.embedding_dimensions
+            )));
+        }
+        let orphaned = std::iter::once(&chunk.source)
+            .chain(&chunk.shared_with)
+            .find(|source| !archive.documents.contains_key(*source));
+        if let Some(source) = orphaned {
+            return Err(invalid(format!(
+                "Chunk {} belongs to {}, which the archive doesn't contain",
+                chunk.id, source
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Registers `upload`'s job, with `progress` for each of its files, and
+/// hands it to the upload worker.
+fn queue_upload(state: &AppState, upload: QueuedUpload, progress: Vec<FileProgress>) {
+    let mut job = UploadJob {
+        id: upload.job_id.clone(),
+        status: JobStatus::Queued,
+        percent: 0,
+        files: progress,
+        total_chunks: 0,
+        duplicates_skipped: 0,
+        finished_at: None,
+        tenant: state.tenant.clone(),
+    };
+    job.update_percent();
+
+    info!(
+        "Queued upload job {} with {} files",
+        job.id,
+        job.files.len()
+    );
+    insert_job(state, job);
+
+    let _ = state.upload_queue.send(upload);
+}
+
+/// Longest title accepted for pasted text, in characters.
+const MAX_TITLE_CHARS: usize = 200;
+
+/// `POST /ingest_text`: pasted text to index like an uploaded Markdown file.
+#[derive(Deserialize)]
+struct IngestTextRequest {
+    title: String,
+    text: String,
+    #[serde(default)]
+    on_conflict: OnConflict,
+    #[serde(default)]
+    collection: Option<String>,
+    #[serde(default)]
+    date: Option<String>,
+}
+
+/// The document name pasted text titled `title` is stored under: the
+/// trimmed title, with `.md` added unless it already ends in it.
+fn pasted_document_name(title: &str) -> Result<String, ApiError> {
+    let title = title.trim();
+    let valid = !title.is_empty()
+        && title.chars().count() <= MAX_TITLE_CHARS
+        && !title
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '/' | '\\'));
+    if !valid {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_title",
+            format!(
+                "Titles are 1-{} characters, without slashes",
+                MAX_TITLE_CHARS
+            ),
+        ));
+    }
+    if title.ends_with(".md") {
+        Ok(title.to_string())
+    } else {
+        Ok(format!("{}.md", title))
+    }
+}
+
+/// Queues pasted text for ingestion as a Markdown document named after its
+/// title, through the same worker and job tracking as `/upload`.
+async fn ingest_text_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<IngestTextRequest>, JsonRejection>,
+) -> Response {
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return upload_too_large(&state.config).into_response()
+        }
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if let Err(error) = state
+        .vector_store
+        .read()
+        .unwrap()
+        .check_embedding_model(&state.config)
+    {
+        return error.into_response();
+    }
+    if let Some(response) = upload_queue_full(&state) {
+        return response;
+    }
+
+    let filename = match pasted_document_name(&request.title) {
+        Ok(filename) => filename,
+        Err(error) => return error.into_response(),
+    };
+    if request.text.trim().is_empty() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "empty_text", "No text to index")
+            .into_response();
+    }
+    let collection = match request.collection.as_deref().map(collection_name) {
+        Some(Ok(name)) => Some(name),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+    let date = match request.date.as_deref().map(document_date) {
+        Some(Ok(date)) => Some(date),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+
+    let job_id = uuid::Uuid::new_v4().to_string();
+    queue_upload(
+        &state,
+        QueuedUpload {
+            job_id: job_id.clone(),
+            tenant: state.tenant.clone(),
+            files: vec![(0, filename.clone(), Bytes::from(request.text))],
+            on_conflict: request.on_conflict,
+            collection,
+            date,
+            watched: false,
+        },
+        vec![FileProgress::queued(filename.clone())],
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "accepted",
+            "job_id": job_id,
+            "filename": filename,
+        })),
+    )
+        .into_response()
+}
+
+/// The `503` to turn uploads away with while `max_pending_uploads` jobs are
+/// waiting or running.
+fn upload_queue_full(state: &AppState) -> Option<Response> {
+    let pending = {
+        let jobs = state.jobs.read().unwrap();
+        jobs.values()
+            .filter(|job| job.finished_at.is_none())
+            .count()
+    };
+    if pending >= state.config.max_pending_uploads {
+        let mut response = ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "upload_queue_full",
+            format!(
+                "{} uploads are already being processed, try again shortly",
+                pending
+            ),
+        )
+        .retryable()
+        .into_response();
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(UPLOAD_RETRY_AFTER_SECS),
+        );
+        return Some(response);
+    }
+    None
+}
+
+/// Accepts the files and queues them for background ingestion, returning a
+/// job id that can be polled via `GET /jobs/:id`. When too many jobs are
+/// already pending, answers 503 with `Retry-After` before reading the body.
+async fn upload_handler(
+    TenantState(state): TenantState,
+    Query(params): Query<UploadParams>,
+    mut multipart: Multipart,
+) -> Response {
+    if let Err(error) = state
+        .vector_store
+        .read()
+        .unwrap()
+        .check_embedding_model(&state.config)
+    {
+        return error.into_response();
+    }
+
+    if let Some(response) = upload_queue_full(&state) {
+        return response;
+    }
+
+    let collection = match params.collection.as_deref().map(collection_name) {
+        Some(Ok(name)) => Some(name),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+    let date = match params.date.as_deref().map(document_date) {
+        Some(Ok(date)) => Some(date),
+        Some(Err(error)) => return error.into_response(),
+        None => None,
+    };
+
+    let mut files = Vec::new();
+    let mut progress = Vec::new();
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return upload_read_failed(&state.config, e).into_response(),
+        };
+        let filename = match field.file_name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        match field.bytes().await {
+            Ok(bytes) => {
+                files.push((progress.len(), filename.clone(), bytes));
+                progress.push(FileProgress::queued(filename));
+            }
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return upload_too_large(&state.config).into_response();
+            }
+            Err(e) => {
+                let error = format!("Failed to read {}: {}", filename, e);
+                progress.push(FileProgress::failed(filename, error));
+            }
+        }
+    }
+
+    if progress.is_empty() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "no_files", "No files in upload")
+            .into_response();
+    }
+
+    let job_id = uuid::Uuid::new_v4().to_string();
+    queue_upload(
+        &state,
+        QueuedUpload {
+            job_id: job_id.clone(),
+            tenant: state.tenant.clone(),
+            files,
+            on_conflict: params.on_conflict,
+            collection,
+            date,
+            watched: false,
+        },
+        progress,
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "accepted",
+            "job_id": job_id,
+        })),
+    )
+        .into_response()
+}
+
+async fn job_status_handler(
+    TenantState(state): TenantState,
+    UrlPath(job_id): UrlPath<String>,
+) -> Response {
+    let job = state
+        .jobs
+        .read()
+        .unwrap()
+        .get(&job_id)
+        .filter(|job| job.tenant == state.tenant)
+        .cloned();
+
+    match job {
+        Some(job) => Json(job).into_response(),
+        None => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "job_not_found",
+            format!("Job {} not found", job_id),
+        )
+        .into_response(),
+    }
+}
+
+/// Starts rebuilding the index from every document's stored text with the
+/// current chunking settings, returning a job id to poll via `GET /jobs/:id`.
+/// Queries keep using the old index until the new one is swapped in whole.
+async fn reindex_handler(TenantState(state): TenantState) -> Response {
+    let Ok(guard) = state.reindex_lock.clone().try_lock_owned() else {
+        return ApiError::new(
+            StatusCode::CONFLICT,
+            "reindex_in_progress",
+            "A reindex is already running",
+        )
+        .retryable()
+        .into_response();
+    };
+
+    let documents: Vec<(String, DocumentRecord)> = {
+        let store = state.vector_store.read().unwrap();
+        let mut documents: Vec<(String, DocumentRecord)> = store
+            .documents
+            .iter()
+            .map(|(name, doc)| (name.clone(), doc.clone()))
+            .collect();
+        documents.sort_by(|a, b| a.0.cmp(&b.0));
+        documents
+    };
+
+    let job_id = uuid::Uuid::new_v4().to_string();
+    insert_job(
+        &state,
+        UploadJob {
+            id: job_id.clone(),
+            status: JobStatus::Running,
+            percent: 0,
+            files: documents
+                .iter()
+                .map(|(name, _)| FileProgress::queued(name.clone()))
+                .collect(),
+            total_chunks: 0,
+            duplicates_skipped: 0,
+            finished_at: None,
+            tenant: state.tenant.clone(),
+        },
+    );
+
+    info!(
+        "Reindexing {} documents in job {} (chunk size {}, overlap {})",
+        documents.len(),
+        job_id,
+        state.config.chunk_size,
+        state.config.chunk_overlap
+    );
+    tokio::spawn({
+        let job_id = job_id.clone();
+        async move {
+            rebuild_index(&state, &job_id, documents).await;
+            drop(guard);
+        }
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "accepted",
+            "job_id": job_id,
+        })),
+    )
+        .into_response()
+}
+
+/// Re-chunks and re-embeds `documents` into a fresh store off to the side,
+/// then swaps it in for the live one in a single step. Documents uploaded,
+/// replaced or deleted meanwhile keep their live state, and documents that
+/// fail to re-embed keep their old chunks.
+async fn rebuild_index(state: &AppState, job_id: &str, documents: Vec<(String, DocumentRecord)>) {
+    // Fresh ids continue from the live store's so none is ever reused
+    let mut rebuilt = state.vector_store.read().unwrap().empty_like(&state.config);
+    let mut failed = HashSet::new();
+    let total = documents.len();
+
+    for (index, (filename, record)) in documents.iter().enumerate() {
+        info!("Reindexing [{}/{}] {}", index + 1, total, filename);
+        update_file_progress(state, job_id, index, |file| {
+            file.stage = FileStage::Embedding;
+            file.percent = 10;
+        });
+
+        // Documents ingested before redaction was turned on are masked now
+        let (text, mut redactions) = redact_pii(&record.text, &state.config.redact_pii);
+        let summary = record
+            .summary
+            .as_deref()
+            .map(|summary| redact_pii(summary, &state.config.redact_pii).0);
+        let tables: Vec<Table> = record
+            .tables
+            .iter()
+            .map(|table| Table {
+                page: table.page,
+                markdown: redact_pii_counted(
+                    &table.markdown,
+                    &state.config.redact_pii,
+                    &mut redactions,
+                ),
+            })
+            .collect();
+        let figures: Vec<Figure> = record
+            .figures
+            .iter()
+            .map(|figure| Figure {
+                page: figure.page,
+                description: redact_pii_counted(
+                    &figure.description,
+                    &state.config.redact_pii,
+                    &mut redactions,
+                ),
+            })
+            .collect();
+        let mut report = record.report.clone();
+        if let Some(report) = &mut report {
+            for (kind, count) in redactions {
+                *report.redactions.entry(kind).or_default() += count;
+            }
+        }
+
+        let mut chunks = chunk_text(&text, state.config.chunk_size, state.config.chunk_overlap);
+        let attached_chunks = attached_chunks(
+            &tables,
+            &figures,
+            state.config.chunk_size,
+            state.config.chunk_overlap,
+        );
+        chunks.extend(attached_chunks.iter().cloned());
+        let embeddings = match embed_batches_collect(state, &chunks).await {
+            Ok(embeddings) => embeddings,
+            Err(e) => {
+                failed.insert(filename.clone());
+                fail_file(
+                    state,
+                    job_id,
+                    index,
+                    format!("Failed to generate embeddings for {}: {}", filename, e),
+                );
+                continue;
+            }
+        };
+
+        let summary_embedding = match &summary {
+            Some(summary) => embed_batches_collect(state, std::slice::from_ref(summary))
+                .await
+                .map(|mut embeddings| embeddings.remove(0))
+                .unwrap_or_else(|e| {
+                    warn!("Failed to embed summary of {}: {}", filename, e);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        // Documents ingested before tagging get their tags now
+        let tags = if record.tags.is_empty() {
+            classify_document(state, &text).await
+        } else {
+            record.tags.clone()
+        };
+
+        let added = chunks
+            .iter()
+            .cloned()
+            .zip(embeddings)
+            .filter_map(|(chunk, embedding)| rebuilt.add(chunk, embedding, filename.clone()))
+            .count();
+        rebuilt.documents.insert(
+            filename.clone(),
+            DocumentRecord {
+                summary,
+                summary_embedding,
+                chunk_ids: Vec::new(),
+                tags,
+                report,
+                text: text.clone(),
+                tables,
+                figures,
+                ..record.clone()
+            },
+        );
+        rebuilt.set_chunk_order(filename, &chunks);
+        let (mut sections, mut chunk_sections) = split_sections(
+            &text,
+            state.config.parent_size,
+            state.config.chunk_size,
+            state.config.chunk_overlap,
+        );
+        add_attached_sections(&mut sections, &mut chunk_sections, &attached_chunks);
+        rebuilt.set_sections(filename, sections, chunk_sections);
+
+        update_file_progress(state, job_id, index, |file| {
+            file.stage = FileStage::Done;
+            file.percent = 100;
+            file.chunks = added;
+            file.duplicates_skipped = chunks.len() - added;
+        });
+    }
+
+    // Let an in-flight upload finish first so the swap never splits a document
+    let _ingest = state.ingest_lock.write().await;
+    {
+        let mut live = state.vector_store.write().unwrap();
+        let snapshot: HashMap<&String, &DocumentRecord> = documents
+            .iter()
+            .map(|(name, record)| (name, record))
+            .collect();
+
+        let carried: Vec<String> = live
+            .documents
+            .iter()
+            .filter(|(name, record)| {
+                failed.contains(*name)
+                    || snapshot
+                        .get(name)
+                        .is_none_or(|old| old.content_hash != record.content_hash)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        let same_model = live.embedding_model == rebuilt.embedding_model;
+        for name in &carried {
+            rebuilt.remove_by_source(name);
+            if same_model {
+                rebuilt.copy_document(&live, name);
+            } else if let Some(record) = live.documents.get(name) {
+                // Its chunks came from the previous model; keep only the
+                // text, so the next reindex can embed it again
+                rebuilt.documents.insert(
+                    name.clone(),
+                    DocumentRecord {
+                        chunk_ids: Vec::new(),
+                        summary_embedding: Vec::new(),
+                        ..record.clone()
+                    },
+                );
+            }
+        }
+
+        let deleted: Vec<String> = rebuilt
+            .documents
+            .keys()
+            .filter(|name| !live.documents.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in &deleted {
+            rebuilt.remove_by_source(name);
+        }
+
+        rebuilt.next_chunk_id = rebuilt.next_chunk_id.max(live.next_chunk_id);
+        *live = rebuilt;
+        info!(
+            "Swapped in rebuilt index ({} chunks, {} documents kept as they were)",
+            live.count(),
+            carried.len()
+        );
+    }
+
+    persist_store(state).await;
+    finish_job(state, job_id);
+}
+
+/// A reply decided before generation (empty query, no documents, ...) that is
+/// sent as-is instead of calling Ollama.
+struct EarlyAnswer {
+    answer: String,
+    scores: Vec<ChunkScore>,
+    moderation: Option<ModerationDecision>,
+}
+
+/// Why `prepare_chat` stopped short of a prompt.
+enum ChatShortcut {
+    Answer(EarlyAnswer),
+    Failed(ApiError),
+}
+
+impl From<EarlyAnswer> for ChatShortcut {
+    fn from(answer: EarlyAnswer) -> Self {
+        ChatShortcut::Answer(answer)
+    }
+}
 
-    if removed_count > 0 {
-        (
-            StatusCode::OK,
-            Json(DeleteResponse {
-                status: "success".to_string(),
-                message: format!("Removed {} chunks from {}", removed_count, filename),
-            }),
+impl From<ApiError> for ChatShortcut {
+    fn from(error: ApiError) -> Self {
+        ChatShortcut::Failed(error)
+    }
+}
+
+/// A grounded conversation plus generation settings, ready to send to Ollama.
+struct PreparedChat {
+    model: String,
+    // System instructions and context, earlier turns, then the question
+    messages: Vec<OllamaMessage>,
+    sources: Vec<String>,
+    warning: Option<String>,
+    scores: Vec<ChunkScore>,
+    rewrites: Vec<String>,
+    steps: Vec<ThinkStep>,
+    citations: Vec<Citation>,
+    temperature: f64,
+    num_ctx: u32,
+    num_predict: u32,
+    timeout_secs: u64,
+    // Stages up to and including the prompt
+    timings: StageTimings,
+}
+
+impl AnswerLength {
+    /// Adjusts the mode's (num_ctx, num_predict, timeout) for this preset.
+    /// Longer answers need room in the context window and more time.
+    fn scale(self, num_ctx: u32, num_predict: u32, timeout_secs: u64) -> (u32, u32, u64) {
+        match self {
+            AnswerLength::Short => (num_ctx, num_predict / 2, timeout_secs),
+            AnswerLength::Normal => (num_ctx, num_predict, timeout_secs),
+            AnswerLength::Detailed => (num_ctx + num_predict, num_predict * 2, timeout_secs * 2),
+        }
+    }
+
+    /// Extra sentence appended to the grounding instruction (leading space included).
+    fn instruction(self) -> &'static str {
+        match self {
+            AnswerLength::Short => " Reply in one or two sentences.",
+            AnswerLength::Normal => "",
+            AnswerLength::Detailed => {
+                " Give a thorough, detailed answer, using lists where they help."
+            }
+        }
+    }
+}
+
+impl EarlyAnswer {
+    fn new(answer: impl Into<String>) -> Self {
+        Self {
+            answer: answer.into(),
+            scores: Vec::new(),
+            moderation: None,
+        }
+    }
+
+    fn with_scores(mut self, scores: Vec<ChunkScore>) -> Self {
+        self.scores = scores;
+        self
+    }
+}
+
+impl PreparedChat {
+    fn ollama_request(&self, stream: bool) -> serde_json::Value {
+        serde_json::json!({
+            "model": self.model,
+            "messages": self.messages,
+            "stream": stream,
+            "options": {
+                "temperature": self.temperature,
+                "num_ctx": self.num_ctx,
+                "num_predict": self.num_predict,  // Optimized: 192-384 instead of 512
+                "num_gpu": 1, // Enable GPU usage if available
+            }
+        })
+    }
+}
+
+fn chat_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<ChatRequest>, JsonRejection>,
+) -> Pin<Box<dyn Future<Output = Response> + Send>> {
+    Box::pin(async move {
+        match payload {
+            Ok(Json(payload)) => chat_handler_impl(state, payload).await,
+            Err(rejection) => ApiError::from(rejection).into_response(),
+        }
+    })
+}
+
+async fn chat_handler_impl(state: AppState, payload: ChatRequest) -> Response {
+    match answer_chat(&state, payload).await {
+        Ok(response) => Json(response).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Answers a list of questions independently, `batch_concurrency` at a time,
+/// for FAQ generation or evaluation runs. A failed query gets an `error` in
+/// its result instead of failing the batch. Batch answers aren't recorded in
+/// sessions, so `session_id` is ignored.
+async fn chat_batch_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<BatchChatRequest>, JsonRejection>,
+) -> Response {
+    let queries = match payload {
+        Ok(Json(payload)) => payload.queries,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if queries.is_empty() {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "queries must not be empty",
         )
-    } else {
-        (
-            StatusCode::NOT_FOUND,
-            Json(DeleteResponse {
-                status: "error".to_string(),
-                message: format!("Document {} not found", filename),
-            }),
+        .into_response();
+    }
+    if queries.len() > state.config.max_batch_queries {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            format!(
+                "{} queries sent, at most {} are allowed per request",
+                queries.len(),
+                state.config.max_batch_queries
+            ),
         )
+        .into_response();
     }
+
+    let started = Instant::now();
+    info!("Answering a batch of {} queries", queries.len());
+    let requests = queries.into_iter().map(|query| {
+        let mut request = match query {
+            BatchQuery::Text(query) => ChatRequest {
+                query,
+                ..ChatRequest::default()
+            },
+            BatchQuery::Request(request) => *request,
+        };
+        request.session_id = None;
+        request
+    });
+
+    let results: Vec<BatchChatResult> = futures_util::stream::iter(requests)
+        .map(|request| {
+            let state = &state;
+            async move {
+                let query = request.query.clone();
+                match answer_chat(state, request).await {
+                    Ok(response) => BatchChatResult {
+                        query,
+                        response: Some(response),
+                        error: None,
+                    },
+                    Err(error) => BatchChatResult {
+                        query,
+                        response: None,
+                        error: Some(error),
+                    },
+                }
+            }
+        })
+        .buffered(state.config.batch_concurrency)
+        .collect()
+        .await;
+
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    info!(
+        "Answered batch: {} ok, {} failed in {} ms",
+        results.len() - failed,
+        failed,
+        ms_since(started)
+    );
+    Json(BatchChatResponse {
+        answered: results.len() - failed,
+        failed,
+        results,
+        total_ms: ms_since(started),
+    })
+    .into_response()
 }
 
-async fn upload_handler(
-    State(state): State<AppState>,
-    mut multipart: Multipart,
-) -> impl IntoResponse {
-    let mut processed_files = Vec::new();
-    let mut errors = Vec::new();
+/// Retrieves, generates and (when asked) verifies one answer, recording the
+/// turn in the request's session.
+async fn answer_chat(state: &AppState, mut payload: ChatRequest) -> Result<ChatResponse, ApiError> {
+    let started = Instant::now();
+    attach_session(state, &mut payload).await?;
+    let prepared = match prepare_chat(state, &payload)
+        .instrument(info_span!("prepare", deep_think = payload.deep_think))
+        .await
+    {
+        Ok(prepared) => prepared,
+        Err(ChatShortcut::Answer(early)) => {
+            record_answer(state, &payload, &early.answer, &[], started, None, false).await;
+            return Ok(ChatResponse {
+                answer: early.answer,
+                sources: vec![],
+                warning: None,
+                scores: early.scores,
+                rewrites: vec![],
+                steps: vec![],
+                citations: vec![],
+                grounding: None,
+                timings: None,
+                moderation: early.moderation,
+            });
+        }
+        Err(ChatShortcut::Failed(error)) => return Err(error),
+    };
 
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        let filename = match field.file_name() {
-            Some(name) => name.to_string(),
-            None => continue,
-        };
+    let ollama_request = prepared.ollama_request(false);
+    info!(
+        "Sending request to Ollama (timeout: {}s)...",
+        prepared.timeout_secs
+    );
+
+    let mut timings = prepared.timings.clone();
+    let generating = Instant::now();
+    let response = async {
+        send_to_ollama(state, &ollama_request, prepared.timeout_secs)
+            .await?
+            .json::<OllamaResponse>()
+            .await
+            .map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "invalid_llm_response",
+                    format!("Failed to parse Ollama response: {}", e),
+                )
+                .retryable()
+            })
+    }
+    .instrument(info_span!("generate"))
+    .await;
+    timings.generate_ms = ms_since(generating);
+
+    let mut answer = response?.message.content.trim().to_string();
+    info!("Successfully generated answer: {} chars", answer.len());
+    let model = ollama_request["model"].as_str();
+    let moderation = moderate_answer(state, &prepared.model, &answer).await;
+    if let Some((replacement, decision)) = &moderation {
+        record_moderation(state, &payload, decision).await;
+        answer = replacement.clone();
+    }
+    let moderation = moderation.map(|(_, decision)| decision);
+    let verifying = Instant::now();
+    let grounding = if payload.verify && !moderation.as_ref().is_some_and(|m| m.withheld()) {
+        verify_answer(state, &answer, &prepared.citations)
+            .instrument(info_span!("verify"))
+            .await
+    } else {
+        None
+    };
+    timings.verify_ms = ms_since(verifying);
+    timings.total_ms = ms_since(started);
+    timings.log();
+    record_answer(
+        state,
+        &payload,
+        &answer,
+        &prepared.sources,
+        started,
+        model,
+        false,
+    )
+    .await;
+    Ok(ChatResponse {
+        answer,
+        sources: prepared.sources,
+        warning: prepared.warning,
+        scores: prepared.scores,
+        rewrites: prepared.rewrites,
+        steps: prepared.steps,
+        citations: prepared.citations,
+        grounding,
+        timings: payload.debug.then_some(timings),
+        moderation,
+    })
+}
+
+/// Streams the answer as server-sent events: optional `warning`, `rewrites`
+/// and `steps` events, one `sources`, `scores` and `citations` event each,
+/// then `token` events as Ollama generates, a `grounding` event when `verify`
+/// is set, a `timings` event when `debug` is set, then `done` (or `error`).
+async fn chat_stream_handler(
+    TenantState(state): TenantState,
+    payload: Result<Json<ChatRequest>, JsonRejection>,
+) -> Response {
+    let payload = match payload {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(stream_chat(state, payload, tx));
+    Sse::new(ReceiverStream::new(rx))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn sse_event(name: &str, data: serde_json::Value) -> Result<Event, Infallible> {
+    Ok(Event::default().event(name).data(data.to_string()))
+}
+
+/// An `error` event carrying the same fields as an error response body.
+fn sse_error(error: &ApiError) -> Result<Event, Infallible> {
+    sse_event("error", serde_json::json!(error))
+}
+
+async fn stream_chat(
+    state: AppState,
+    mut payload: ChatRequest,
+    tx: mpsc::Sender<Result<Event, Infallible>>,
+) {
+    let started = Instant::now();
+    if let Err(error) = attach_session(&state, &mut payload).await {
+        let _ = tx.send(sse_error(&error)).await;
+        return;
+    }
+    let preparing = prepare_chat(&state, &payload)
+        .instrument(info_span!("prepare", deep_think = payload.deep_think));
+    let Some(prepared) = unless_disconnected(&tx, preparing).await else {
+        return;
+    };
+    let prepared = match prepared {
+        Ok(prepared) => prepared,
+        Err(ChatShortcut::Answer(early)) => {
+            record_answer(&state, &payload, &early.answer, &[], started, None, false).await;
+            if let Some(decision) = &early.moderation {
+                let _ = tx
+                    .send(sse_event(
+                        "moderation",
+                        serde_json::json!({ "answer": early.answer, "decision": decision }),
+                    ))
+                    .await;
+            }
+            // Early answers arrive as a single token so clients handle them uniformly
+            let _ = tx.send(sse_event("sources", serde_json::json!([]))).await;
+            let _ = tx
+                .send(sse_event("scores", serde_json::json!(early.scores)))
+                .await;
+            let _ = tx
+                .send(sse_event(
+                    "token",
+                    serde_json::json!({ "content": early.answer }),
+                ))
+                .await;
+            let _ = tx.send(sse_event("done", serde_json::json!({}))).await;
+            return;
+        }
+        Err(ChatShortcut::Failed(error)) => {
+            let _ = tx.send(sse_error(&error)).await;
+            return;
+        }
+    };
+
+    if let Some(warning) = &prepared.warning {
+        let _ = tx
+            .send(sse_event(
+                "warning",
+                serde_json::json!({ "message": warning }),
+            ))
+            .await;
+    }
+    if !prepared.rewrites.is_empty() {
+        let _ = tx
+            .send(sse_event("rewrites", serde_json::json!(prepared.rewrites)))
+            .await;
+    }
+    if !prepared.steps.is_empty() {
+        let _ = tx
+            .send(sse_event("steps", serde_json::json!(prepared.steps)))
+            .await;
+    }
+    let _ = tx
+        .send(sse_event("sources", serde_json::json!(prepared.sources)))
+        .await;
+    let _ = tx
+        .send(sse_event("scores", serde_json::json!(prepared.scores)))
+        .await;
+    let _ = tx
+        .send(sse_event(
+            "citations",
+            serde_json::json!(prepared.citations),
+        ))
+        .await;
+
+    let ollama_request = prepared.ollama_request(true);
+    let model = ollama_request["model"].as_str();
+    info!(
+        "Streaming request to Ollama (timeout: {}s)...",
+        prepared.timeout_secs
+    );
+
+    let mut timings = prepared.timings.clone();
+    let generating = Instant::now();
+    let response = unless_disconnected(
+        &tx,
+        send_to_ollama(&state, &ollama_request, prepared.timeout_secs)
+            .instrument(info_span!("generate")),
+    )
+    .await;
+    let response = match response {
+        Some(Ok(response)) => response,
+        Some(Err(error)) => {
+            let _ = tx.send(sse_error(&error)).await;
+            return;
+        }
+        None => return,
+    };
 
-        let content = match field.bytes().await {
-            Ok(bytes) => bytes,
+    // Ollama streams one JSON object per line; a line may span several chunks
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    let mut answer = String::new();
+
+    while let Some(chunk) = unless_disconnected(&tx, body.next()).await {
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
             Err(e) => {
-                errors.push(format!("Failed to read {}: {}", filename, e));
-                continue;
+                let error = ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "stream_interrupted",
+                    format!("Ollama stream interrupted: {}", e),
+                )
+                .retryable();
+                let _ = tx.send(sse_error(&error)).await;
+                return;
             }
         };
+        buffer.extend_from_slice(&chunk);
 
-        info!("Processing file: {} ({} bytes)", filename, content.len());
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
 
-        // Extract text based on file type
-        let text = if filename.ends_with(".md") {
-            match extract_text_from_md(&content) {
-                Ok(t) => t,
+            let part = match serde_json::from_slice::<OllamaStreamResponse>(&line) {
+                Ok(part) => part,
                 Err(e) => {
-                    errors.push(format!("Failed to parse {}: {}", filename, e));
+                    warn!("Skipping malformed Ollama stream line: {}", e);
                     continue;
                 }
+            };
+
+            let content = part.message.map(|message| message.content);
+            if let Some(content) = content.filter(|content| !content.is_empty()) {
+                answer.push_str(&content);
+                let token = sse_event("token", serde_json::json!({ "content": content }));
+                if tx.send(token).await.is_err() {
+                    // Dropping the response body aborts the Ollama request
+                    info!("Client disconnected, cancelling generation");
+                    record_answer(
+                        &state,
+                        &payload,
+                        &answer,
+                        &prepared.sources,
+                        started,
+                        model,
+                        true,
+                    )
+                    .await;
+                    return;
+                }
             }
-        } else if filename.ends_with(".pdf") {
-            match extract_text_from_pdf(&content) {
-                Ok(t) => t,
-                Err(e) => {
-                    errors.push(format!("Failed to parse {}: {}", filename, e));
-                    continue;
+
+            if part.done {
+                timings.generate_ms = ms_since(generating);
+                info!("Successfully streamed answer: {} chars", answer.len());
+                let mut withheld = false;
+                if let Some((replacement, decision)) =
+                    moderate_answer(&state, &prepared.model, &answer).await
+                {
+                    // Tokens already shown can't be taken back, so the client
+                    // is told to replace the answer instead
+                    record_moderation(&state, &payload, &decision).await;
+                    withheld = decision.withheld();
+                    let _ = tx
+                        .send(sse_event(
+                            "moderation",
+                            serde_json::json!({ "answer": replacement, "decision": decision }),
+                        ))
+                        .await;
+                    answer = replacement;
+                }
+                record_answer(
+                    &state,
+                    &payload,
+                    &answer,
+                    &prepared.sources,
+                    started,
+                    model,
+                    false,
+                )
+                .await;
+                if payload.verify && !withheld {
+                    let verifying = Instant::now();
+                    let grounding = verify_answer(&state, &answer, &prepared.citations)
+                        .instrument(info_span!("verify"))
+                        .await;
+                    timings.verify_ms = ms_since(verifying);
+                    if let Some(grounding) = grounding {
+                        let _ = tx
+                            .send(sse_event("grounding", serde_json::json!(grounding)))
+                            .await;
+                    }
                 }
+                timings.total_ms = ms_since(started);
+                timings.log();
+                if payload.debug {
+                    let _ = tx
+                        .send(sse_event("timings", serde_json::json!(timings)))
+                        .await;
+                }
+                let _ = tx.send(sse_event("done", serde_json::json!({}))).await;
+                return;
             }
-        } else {
-            errors.push(format!("Unsupported file type: {}", filename));
-            continue;
-        };
+        }
+    }
 
-        if text.trim().is_empty() {
-            errors.push(format!("No text extracted from {}", filename));
-            continue;
+    if tx.is_closed() {
+        // The client stopped reading; keep what it was shown
+        if !answer.is_empty() {
+            record_answer(
+                &state,
+                &payload,
+                &answer,
+                &prepared.sources,
+                started,
+                model,
+                true,
+            )
+            .await;
         }
+        return;
+    }
+    warn!("Ollama stream ended without a done marker");
+    let _ = tx.send(sse_event("done", serde_json::json!({}))).await;
+}
+
+/// Runs `work` unless the client stops listening first, in which case `work`
+/// is dropped (cancelling any Ollama request in it) and `None` is returned.
+async fn unless_disconnected<T>(
+    tx: &mpsc::Sender<Result<Event, Infallible>>,
+    work: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        result = work => Some(result),
+        _ = tx.closed() => {
+            info!("Client disconnected, cancelling generation");
+            None
+        }
+    }
+}
 
-        info!("Extracted {} characters from {}", text.len(), filename);
+/// Explains likely poor retrieval when the query is in a language none of the
+/// documents are written in, unless the embedding model is multilingual.
+fn language_mismatch_warning(store: &VectorStore, config: &Config, query: &str) -> Option<String> {
+    if config.multilingual_embeddings() {
+        return None;
+    }
+    let query_language = detect_language(query)?;
+    let corpus = store.language_counts();
+    if corpus.is_empty() || corpus.iter().any(|(code, _)| *code == query_language) {
+        return None;
+    }
 
-        // Chunk text (256 words with 50 word overlap for better retrieval precision)
-        let chunks = chunk_text(&text, 256, 50);
-        info!("Created {} chunks from {}", chunks.len(), filename);
+    let corpus_names: Vec<String> = corpus.iter().map(|(code, _)| language_name(code)).collect();
+    Some(format!(
+        "Your question looks like {}, but the documents are in {}. Retrieval may be poor; try asking in {}.",
+        language_name(&query_language),
+        corpus_names.join(", "),
+        corpus_names[0]
+    ))
+}
 
-        // Generate embeddings and store
-        let texts_for_embedding: Vec<String> = chunks.clone();
+/// Embeds the query, retrieves context and builds the grounded prompt.
+async fn prepare_chat(
+    state: &AppState,
+    payload: &ChatRequest,
+) -> Result<PreparedChat, ChatShortcut> {
+    let query = payload.query.trim();
+    info!("Received chat query: {}", query);
 
-        let embedding_model = state.embedding_model.clone();
-        let embeddings_result = tokio::task::spawn_blocking(move || {
-            let model = embedding_model.lock().unwrap();
-            model.embed(texts_for_embedding, None)
-        })
-        .await
-        .unwrap();
+    if query.is_empty() {
+        return Err(EarlyAnswer::new("Ask something!").into());
+    }
+    let model = match &payload.model {
+        Some(name) => model_name(name)?,
+        None => state.config.model.clone(),
+    };
+    if let Some(decision) = moderate_query(state, &model, query).await {
+        record_moderation(state, payload, &decision).await;
+        return Err(EarlyAnswer {
+            moderation: Some(decision),
+            ..EarlyAnswer::new(state.config.moderation_refusal.clone())
+        }
+        .into());
+    }
 
-        match embeddings_result {
-            Ok(embeddings) => {
-                let mut store = state.vector_store.write().unwrap();
+    // Check if documents are uploaded
+    let (has_docs, warning, scope) = {
+        let store = state.vector_store.read().unwrap();
+        (
+            store.count() > 0,
+            language_mismatch_warning(&store, &state.config, query),
+            source_scope(
+                &store,
+                &payload.sources,
+                payload.collection.as_deref(),
+                &payload.tags,
+            )?,
+        )
+    };
 
-                for (chunk_text, embedding) in chunks.into_iter().zip(embeddings.into_iter()) {
-                    store.add(chunk_text, embedding, filename.clone());
-                }
+    if !has_docs {
+        return Err(EarlyAnswer::new(
+            "⚠️ Please upload some documents first! Use the upload section to add PDF or Markdown files.",
+        )
+        .into());
+    }
 
-                processed_files.push(filename.clone());
-                info!("Successfully processed {}", filename);
-            }
-            Err(e) => {
-                errors.push(format!(
-                    "Failed to generate embeddings for {}: {}",
-                    filename, e
-                ));
-            }
+    let template_name = payload
+        .template
+        .as_deref()
+        .unwrap_or(if payload.deep_think {
+            "deep_think"
+        } else {
+            "grounded"
+        });
+    let Some(template) = state.config.prompts.get(template_name) else {
+        let mut names: Vec<&String> = state.config.prompts.keys().collect();
+        names.sort();
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_template",
+            format!(
+                "No prompt template named {}; available: {}",
+                template_name,
+                names
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+        .into());
+    };
+
+    // Run the configured retrieval pipeline (search, fusion, diversification,
+    // overlap removal, threshold): per sub-question in deep-think mode, else
+    // for each phrasing of the query, merging the results
+    let min_similarity = payload
+        .min_similarity
+        .unwrap_or(state.config.min_similarity);
+    let mut rewrites = Vec::new();
+    let mut steps = Vec::new();
+    let mut timings = StageTimings::default();
+    let options = SearchOptions {
+        min_similarity,
+        top_k: payload.top_k.unwrap_or(TOP_K).clamp(1, MAX_TOP_K),
+        scope: scope.as_ref(),
+        recency_weight: payload
+            .recency_weight
+            .map(|weight| weight.clamp(0.0, 1.0))
+            .unwrap_or(state.config.recency_weight),
+        keywords: &payload.keywords,
+    };
+    let retrieval = if payload.deep_think {
+        let (retrieval, think_steps) =
+            think_through(state, &model, query, &options, &mut timings).await?;
+        steps = think_steps;
+        retrieval
+    } else {
+        let mut queries = vec![query.to_string()];
+        if payload.rewrite_query {
+            let rewriting = Instant::now();
+            queries = rewrite_query(state, &model, query)
+                .instrument(info_span!("rewrite"))
+                .await;
+            timings.rewrite_ms = ms_since(rewriting);
+            info!("Searching with {} phrasings: {:?}", queries.len(), queries);
+            rewrites = queries[1..].to_vec();
         }
+        retrieve(state, &queries, &options, &mut timings).await?
+    };
+    let Retrieval {
+        chunks: mut results,
+        rejected,
+    } = retrieval;
+    info!(
+        "Found {} results ({} below the similarity threshold)",
+        results.len(),
+        rejected.len()
+    );
+    if let RetrievalStrategy::Parent = payload.strategy {
+        results = state
+            .vector_store
+            .read()
+            .unwrap()
+            .parent_sections(results, state.config.chunk_size);
+        info!("Using {} parent sections as context", results.len());
     }
 
-    let store = state.vector_store.read().unwrap();
-    let total_chunks = store.count();
-    drop(store);
+    let scores = |results: &[RetrievedChunk]| -> Vec<ChunkScore> {
+        results
+            .iter()
+            .map(|chunk| ChunkScore {
+                source: chunk.source.clone(),
+                score: chunk.score,
+            })
+            .collect()
+    };
 
-    if processed_files.is_empty() && !errors.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "status": "error",
-                "errors": errors,
-            })),
+    if results.is_empty() {
+        // Refuse rather than let the model improvise on unrelated context
+        let Some(best_score) = rejected.iter().map(|chunk| chunk.score).reduce(f32::max) else {
+            return Err(EarlyAnswer::new("No relevant information found in the documents.").into());
+        };
+        info!(
+            "Best match scored {:.2}, below threshold {:.2}; not calling Ollama",
+            best_score, min_similarity
         );
+        return Err(EarlyAnswer::new(format!(
+            "No relevant information found in the documents (best match scored {:.2}, below the {:.2} threshold).",
+            best_score, min_similarity
+        ))
+        .with_scores(scores(&rejected))
+        .into());
     }
 
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({
-            "status": "success",
-            "processed_files": processed_files,
-            "total_chunks": total_chunks,
-            "errors": errors,
-        })),
-    )
-}
+    // Call Ollama with optimized settings for faster responses
+    let prompting = Instant::now();
+    let _prompt_span = info_span!("prompt").entered();
+    let (temperature, num_ctx, num_predict, timeout_secs) = if payload.deep_think {
+        (0.1, 2048, 384, 120) // Deep think: reduced context window, moderate generation
+    } else {
+        (0.7, 1024, 192, 60) // Quick mode: small context window, concise answers
+    };
+    let (num_ctx, num_predict, timeout_secs) =
+        payload.length.scale(num_ctx, num_predict, timeout_secs);
 
-fn chat_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<ChatRequest>,
-) -> Pin<Box<dyn Future<Output = Response> + Send>> {
-    Box::pin(async move { chat_handler_impl(state, payload).await })
+    // Build prompt with strict grounding (simplified for faster processing);
+    // deep think also hands over what it found for each sub-question
+    let findings: Vec<String> = steps
+        .iter()
+        .map(|step| format!("- {}\n  {}", step.question, step.answer))
+        .collect();
+    let findings = findings.join("\n");
+    // Templates that place the history themselves get it as text; otherwise
+    // earlier turns go to the model as messages of their own
+    let uses_history = template.contains("{history}");
+    let history = if uses_history {
+        format_history(&payload.history)
+    } else {
+        String::new()
+    };
+    let mut messages = vec![OllamaMessage::new("system", String::new())];
+    if !uses_history {
+        messages.extend(history_messages(&payload.history));
+    }
+    messages.push(OllamaMessage::new("user", query));
+    let language = detect_language(query)
+        .map(|code| language_name(&code))
+        .unwrap_or_else(|| "the language of the question".to_string());
+    let render = |context: &str| {
+        fill_template(
+            template,
+            &[
+                ("context", context),
+                ("question", query),
+                ("history", &history),
+                ("findings", &findings),
+                ("instruction", payload.length.instruction()),
+                ("language", &language),
+            ],
+        )
+    };
+
+    // Context gets whatever the window leaves after the instructions, the
+    // other messages and the room reserved for the answer
+    let message_tokens = |messages: &[OllamaMessage]| -> usize {
+        messages
+            .iter()
+            .map(|message| state.tokens.count(&message.content))
+            .sum()
+    };
+    let budget = (num_ctx as usize).saturating_sub(
+        state.tokens.count(&render(""))
+            + message_tokens(&messages)
+            + num_predict as usize
+            + PROMPT_TOKEN_MARGIN,
+    );
+    let (context, citations) = build_context(&state.tokens, &results, budget);
+    messages[0].content = render(&context);
+    info!(
+        "Prompt is {} tokens in {} messages of {} ({} reserved for the answer)",
+        message_tokens(&messages),
+        messages.len(),
+        num_ctx,
+        num_predict
+    );
+    timings.prompt_ms = ms_since(prompting);
+
+    Ok(PreparedChat {
+        model,
+        messages,
+        sources: source_names(&results),
+        warning,
+        scores: scores(&results),
+        rewrites,
+        steps,
+        citations,
+        temperature,
+        num_ctx,
+        num_predict,
+        timeout_secs,
+        timings,
+    })
 }
 
-async fn chat_handler_impl(state: AppState, payload: ChatRequest) -> Response {
-    let query = payload.query.trim();
-    info!("Received chat query: {}", query);
+/// Embeds each phrasing of a query, runs the retrieval pipeline for it and
+/// merges the results into the best `top_k`.
+async fn retrieve(
+    state: &AppState,
+    queries: &[String],
+    options: &SearchOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<Retrieval, ApiError> {
+    state
+        .vector_store
+        .read()
+        .unwrap()
+        .check_embedding_model(&state.config)?;
 
-    if query.is_empty() {
-        return (
-            StatusCode::OK,
-            Json(ChatResponse {
-                answer: "Ask something!".to_string(),
-                sources: vec![],
-            }),
+    info!("Generating query embedding...");
+    let embedding = Instant::now();
+    let embeddings = embed_texts(state, queries.to_vec())
+        .instrument(info_span!("embed", queries = queries.len()))
+        .await;
+    timings.embed_ms += ms_since(embedding);
+    let embeddings = embeddings.map_err(|e| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "embedding_failed",
+            format!("Failed to generate query embedding: {}", e),
         )
-            .into_response();
+        .retryable()
+    })?;
+    if embeddings.len() != queries.len() {
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "embedding_failed",
+            "Failed to generate query embedding",
+        ));
+    }
+    info!("Query embedding generated");
+
+    info!("Searching vector store...");
+    let searching = Instant::now();
+    let retrieval = info_span!("search").in_scope(|| {
+        let store = state.vector_store.read().unwrap();
+        let retrievals = queries
+            .iter()
+            .zip(&embeddings)
+            .map(|(query, embedding)| {
+                run_pipeline(&store, &state.config, query, embedding, options)
+            })
+            .collect();
+        merge_retrievals(retrievals, options.top_k)
+    });
+    timings.search_ms += ms_since(searching);
+    Ok(retrieval)
+}
+
+/// Deep-think retrieval: splits `query` into sub-questions, retrieves for and
+/// answers each one, and returns those steps with the merged chunks for the
+/// final synthesis.
+async fn think_through(
+    state: &AppState,
+    model: &str,
+    query: &str,
+    options: &SearchOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<(Retrieval, Vec<ThinkStep>), ApiError> {
+    let thinking = Instant::now();
+    let sub_questions = decompose_question(state, model, query)
+        .instrument(info_span!("think"))
+        .await;
+    timings.think_ms += ms_since(thinking);
+    info!(
+        "Deep think: {} sub-questions: {:?}",
+        sub_questions.len(),
+        sub_questions
+    );
+
+    let mut steps = Vec::with_capacity(sub_questions.len());
+    let mut retrievals = Vec::with_capacity(sub_questions.len());
+    for question in sub_questions {
+        let retrieval = retrieve(state, std::slice::from_ref(&question), options, timings).await?;
+        let thinking = Instant::now();
+        let answer = if retrieval.chunks.is_empty() {
+            "No relevant information found in the documents.".to_string()
+        } else {
+            let prompt = format!(
+                r#"Answer using ONLY this context, in one to three sentences. If not found, say "Not found in the documents."
+
+Context:
+{}
+
+Question: {}
+
+Answer:"#,
+                build_context(
+                    &state.tokens,
+                    &retrieval.chunks,
+                    SUB_QUESTION_CONTEXT_TOKENS
+                )
+                .0,
+                question
+            );
+            complete(state, model, &prompt, 0.1, 160, 60)
+                .instrument(info_span!("think"))
+                .await?
+        };
+        timings.think_ms += ms_since(thinking);
+
+        steps.push(ThinkStep {
+            question,
+            answer,
+            sources: source_names(&retrieval.chunks),
+        });
+        retrievals.push(retrieval);
     }
 
-    // Check if documents are uploaded
-    let has_docs = {
-        let store = state.vector_store.read().unwrap();
-        store.count() > 0
-    };
+    Ok((merge_retrievals(retrievals, options.top_k), steps))
+}
 
-    if !has_docs {
-        return (
-            StatusCode::OK,
-            Json(ChatResponse {
-                answer: "⚠️ Please upload some documents first! Use the upload section to add PDF or Markdown files.".to_string(),
-                sources: vec![],
-            }),
-        )
-            .into_response();
+/// Earlier turns passed to the model, most recent last.
+const MAX_HISTORY_TURNS: usize = 6;
+
+/// Replaces each `{name}` in `template` with its value in one pass, so
+/// placeholder-like text inside values is left alone. Unknown names stay as
+/// written.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let value = rest[start + 1..].find('}').and_then(|len| {
+            let name = &rest[start + 1..start + 1 + len];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, len))
+        });
+        match value {
+            Some((value, len)) => {
+                filled.push_str(value);
+                rest = &rest[start + len + 2..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
     }
+    filled.push_str(rest);
+    filled
+}
 
-    // Generate query embedding
-    info!("Generating query embedding...");
-    let query_string = query.to_string();
-    let embedding_model = state.embedding_model.clone();
-    let embedding_result = tokio::task::spawn_blocking(move || {
-        let model = embedding_model.lock().unwrap();
-        model.embed(vec![query_string], None)
+/// The last `MAX_HISTORY_TURNS` turns.
+fn recent_turns(history: &[HistoryTurn]) -> &[HistoryTurn] {
+    &history[history.len().saturating_sub(MAX_HISTORY_TURNS)..]
+}
+
+/// The last `MAX_HISTORY_TURNS` turns as `User:` / `Assistant:` lines.
+fn format_history(history: &[HistoryTurn]) -> String {
+    recent_turns(history)
+        .iter()
+        .map(|turn| {
+            let speaker = if turn.role == "user" {
+                "User"
+            } else {
+                "Assistant"
+            };
+            format!("{}: {}", speaker, turn.content.trim())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The last `MAX_HISTORY_TURNS` turns as user / assistant chat messages.
+fn history_messages(history: &[HistoryTurn]) -> impl Iterator<Item = OllamaMessage> + '_ {
+    recent_turns(history).iter().map(|turn| {
+        let role = if turn.role == "user" {
+            "user"
+        } else {
+            "assistant"
+        };
+        OllamaMessage::new(role, turn.content.trim())
     })
-    .await
-    .unwrap();
-    info!("Query embedding generated");
+}
 
-    let query_embedding = match embedding_result {
-        Ok(mut embeddings) => {
-            if embeddings.is_empty() {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ChatResponse {
-                        answer: "Failed to generate query embedding".to_string(),
-                        sources: vec![],
-                    }),
-                )
-                    .into_response();
-            }
-            embeddings.remove(0)
-        }
-        Err(e) => {
-            error!("Embedding generation failed: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ChatResponse {
-                    answer: format!("Failed to generate query embedding: {}", e),
-                    sources: vec![],
-                }),
-            )
-                .into_response();
+/// Tokens one context block may take, per chunk stitched into it.
+const MAX_CHUNK_TOKENS: usize = 200;
+/// Blocks that would get less room than this are left out rather than cut
+/// to a stub.
+const MIN_BLOCK_TOKENS: usize = 32;
+/// Window space kept free in case Ollama's prompt template adds tokens.
+const PROMPT_TOKEN_MARGIN: usize = 32;
+/// Context budget for answering one deep-think sub-question.
+const SUB_QUESTION_CONTEXT_TOKENS: usize = 700;
+
+/// Numbered context blocks for the prompt, filling at most `budget` tokens,
+/// and the citation each number stands for.
+fn build_context(
+    tokens: &TokenCounter,
+    results: &[RetrievedChunk],
+    budget: usize,
+) -> (String, Vec<Citation>) {
+    let mut remaining = budget;
+    let mut blocks = Vec::new();
+    let mut citations = Vec::new();
+
+    for chunk in results {
+        let number = citations.len() + 1;
+        let header = format!("[{}] (score: {:.2})\n", number, chunk.score);
+        // The header plus the blank line separating blocks
+        let overhead = tokens.count(&header) + 2;
+        let room = remaining
+            .saturating_sub(overhead)
+            .min(MAX_CHUNK_TOKENS * chunk.parts);
+        if room < MIN_BLOCK_TOKENS {
+            break;
         }
-    };
 
-    // Search vector store (increased top-k for better coverage)
-    info!("Searching vector store...");
-    let results = {
-        let store = state.vector_store.read().unwrap();
-        store.search(&query_embedding, 5)
-    };
-    info!("Found {} results", results.len());
+        let text = chunk.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let truncated = tokens.truncate(&text, room).to_string();
+        remaining = remaining.saturating_sub(overhead + tokens.count(&truncated));
 
-    if results.is_empty() {
-        return (
-            StatusCode::OK,
-            Json(ChatResponse {
-                answer: "No relevant information found in the documents.".to_string(),
-                sources: vec![],
-            }),
-        )
-            .into_response();
+        blocks.push(format!("{}{}", header, truncated));
+        citations.push(Citation {
+            number,
+            source: chunk.source.clone(),
+            chunk_id: chunk.id,
+            score: chunk.score,
+            text: truncated,
+        });
     }
 
-    // Build context from top results with aggressive truncation
-    const MAX_CHUNK_WORDS: usize = 150; // Limit per chunk
-    const MAX_TOTAL_CONTEXT_WORDS: usize = 500; // Total budget
+    info!(
+        "Context built with {} of {} tokens from {} chunks",
+        budget - remaining,
+        budget,
+        citations.len()
+    );
+    (blocks.join("\n\n"), citations)
+}
 
-    fn truncate_text(text: &str, max_words: usize) -> String {
-        let words: Vec<&str> = text.split_whitespace().take(max_words).collect();
-        words.join(" ")
+/// Distinct documents the chunks come from, in order of first appearance.
+fn source_names(chunks: &[RetrievedChunk]) -> Vec<String> {
+    let mut sources: Vec<String> = Vec::new();
+    for chunk in chunks {
+        if !sources.contains(&chunk.source) {
+            sources.push(chunk.source.clone());
+        }
     }
+    sources
+}
 
-    let mut total_words = 0;
-    let context: String = results
-        .iter()
-        .enumerate()
-        .filter_map(|(i, (text, _source, score))| {
-            if total_words >= MAX_TOTAL_CONTEXT_WORDS {
-                return None;
-            }
-            let truncated = truncate_text(text, MAX_CHUNK_WORDS);
-            let words_count = truncated.split_whitespace().count();
-            total_words += words_count;
+/// Answer sentences less similar than this to every context passage count as
+/// unsupported.
+const SUPPORT_SIMILARITY: f32 = 0.55;
+/// Answers with a smaller share of supported sentences carry a warning.
+const MIN_GROUNDEDNESS: f32 = 0.7;
+/// Sentences shorter than this (in words) are not checked; "Yes." proves nothing.
+const MIN_CHECKED_WORDS: usize = 4;
 
-            Some(format!("[{}] (score: {:.2})\n{}", i + 1, score, truncated))
+/// Checks each answer sentence against the context blocks it was generated
+/// from by embedding similarity. `None` when there is nothing to check (e.g.
+/// a refusal) or embedding fails.
+async fn verify_answer(
+    state: &AppState,
+    answer: &str,
+    citations: &[Citation],
+) -> Option<Grounding> {
+    let sentences: Vec<String> = split_sentences(&strip_citation_markers(answer))
+        .into_iter()
+        .filter(|sentence| {
+            sentence.split_whitespace().count() >= MIN_CHECKED_WORDS
+                && !sentence.starts_with("I don't know")
         })
-        .collect::<Vec<_>>()
-        .join("\n\n");
+        .collect();
+    if sentences.is_empty() || citations.is_empty() {
+        return None;
+    }
 
-    info!(
-        "Context built with {} words from {} chunks",
-        total_words,
-        results.len()
-    );
+    // Whole blocks and their single sentences, so both summarizing and
+    // specific claims have something to match
+    let mut texts = sentences.clone();
+    for citation in citations {
+        texts.push(citation.text.clone());
+        texts.extend(split_sentences(&citation.text));
+    }
+    let embeddings = match embed_texts(state, texts).await {
+        Ok(embeddings) => embeddings,
+        Err(e) => {
+            warn!("Answer verification failed: {}", e);
+            return None;
+        }
+    };
+    let (sentence_embeddings, passage_embeddings) = embeddings.split_at(sentences.len());
 
-    let sources: Vec<String> = results
-        .iter()
-        .map(|(_, source, _)| source.clone())
-        .collect::<HashSet<_>>()
+    let checked: Vec<SentenceSupport> = sentences
         .into_iter()
+        .zip(sentence_embeddings)
+        .map(|(text, embedding)| {
+            let sentence = ArrayView1::from(embedding.as_slice());
+            let support = passage_embeddings
+                .iter()
+                .map(|passage| cosine_similarity(sentence, ArrayView1::from(passage.as_slice())))
+                .fold(0.0, f32::max);
+            SentenceSupport {
+                text,
+                support,
+                supported: support >= SUPPORT_SIMILARITY,
+            }
+        })
         .collect();
 
-    // Build prompt with strict grounding (simplified for faster processing)
-    let prompt = format!(
-        r#"Answer using ONLY this context. If not found, say "I don't know based on the provided documents."
-
-Context:
-{}
-
-Question: {}
-
-Answer:"#,
-        context, query
+    let supported = checked.iter().filter(|s| s.supported).count();
+    let score = supported as f32 / checked.len() as f32;
+    info!(
+        "Answer verification: {}/{} sentences supported",
+        supported,
+        checked.len()
     );
-
-    // Call Ollama with optimized settings for faster responses
-    let (temperature, num_ctx, num_predict, timeout_secs) = if payload.deep_think {
-        (0.1, 2048, 384, 120) // Deep think: reduced context window, moderate generation
-    } else {
-        (0.7, 1024, 192, 60) // Quick mode: small context window, concise answers
-    };
-
-    let ollama_request = serde_json::json!({
-        "model": "phi3",
-        "prompt": prompt,
-        "stream": false,
-        "options": {
-            "temperature": temperature,
-            "num_ctx": num_ctx,
-            "num_predict": num_predict,  // Optimized: 192-384 instead of 512
-            "num_gpu": 1, // Enable GPU usage if available
-        }
+    let warning = (score < MIN_GROUNDEDNESS).then(|| {
+        format!(
+            "Only {} of {} sentences in this answer are clearly supported by your documents. Check it against the sources.",
+            supported,
+            checked.len()
+        )
     });
 
-    info!("Sending request to Ollama (timeout: {}s)...", timeout_secs);
+    Some(Grounding {
+        score,
+        sentences: checked,
+        warning,
+    })
+}
 
+/// Posts a chat request to Ollama, retrying transient connection errors.
+/// Failures come back with user-facing messages.
+async fn send_to_ollama(
+    state: &AppState,
+    request: &serde_json::Value,
+    timeout_secs: u64,
+) -> Result<reqwest::Response, ApiError> {
     // Retry logic for transient errors
     let mut attempts = 0;
     let max_attempts = 3;
@@ -565,8 +11383,8 @@ Answer:"#,
 
         match state
             .ollama_client
-            .post("http://localhost:11434/api/generate")
-            .json(&ollama_request)
+            .post("http://localhost:11434/api/chat")
+            .json(request)
             .timeout(Duration::from_secs(timeout_secs))
             .send()
             .await
@@ -574,52 +11392,36 @@ Answer:"#,
             Ok(response) => {
                 info!("Received response from Ollama: {}", response.status());
                 if response.status().is_success() {
-                    match response.json::<OllamaResponse>().await {
-                        Ok(ollama_resp) => {
-                            let answer = ollama_resp.response.trim().to_string();
-                            info!("Successfully generated answer: {} chars", answer.len());
-                            return (StatusCode::OK, Json(ChatResponse { answer, sources }))
-                                .into_response();
-                        }
-                        Err(e) => {
-                            error!("Failed to parse Ollama response: {}", e);
-                            return (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                Json(ChatResponse {
-                                    answer: format!("Failed to parse Ollama response: {}", e),
-                                    sources: vec![],
-                                }),
-                            )
-                                .into_response();
-                        }
-                    }
-                } else {
-                    let status = response.status();
-                    let error_text = response.text().await.unwrap_or_default();
-                    error!("Ollama returned error {}: {}", status, error_text);
-
-                    // Check if model is not found
-                    if error_text.contains("not found") || error_text.contains("does not exist") {
-                        return (
-                            StatusCode::BAD_GATEWAY,
-                            Json(ChatResponse {
-                                answer: "❌ Model 'phi3' not found. Please run: `ollama pull phi3`"
-                                    .to_string(),
-                                sources: vec![],
-                            }),
-                        )
-                            .into_response();
-                    }
+                    return Ok(response);
+                }
+
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_default();
+                error!("Ollama returned error {}: {}", status, error_text);
 
-                    return (
+                // Check if model is not found
+                if error_text.contains("not found") || error_text.contains("does not exist") {
+                    let model = request["model"].as_str().unwrap_or_default();
+                    return Err(ApiError::new(
                         StatusCode::BAD_GATEWAY,
-                        Json(ChatResponse {
-                            answer: format!("Ollama error: {} - {}", status, error_text),
-                            sources: vec![],
-                        }),
-                    )
-                        .into_response();
+                        "model_not_found",
+                        format!(
+                            "❌ Model '{}' not found. Please run: `ollama pull {}`",
+                            model, model
+                        ),
+                    ));
                 }
+
+                let error = ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "llm_error",
+                    format!("Ollama error: {} - {}", status, error_text),
+                );
+                return Err(if status.is_server_error() {
+                    error.retryable()
+                } else {
+                    error
+                });
             }
             Err(e) => {
                 let error_msg = e.to_string();
@@ -638,25 +11440,186 @@ Answer:"#,
                 }
 
                 // Check error type for better messages
-                let user_message = if error_msg.contains("connection refused") {
-                    "❌ Cannot connect to Ollama. Please start Ollama:\n\n1. Run: `ollama serve`\n2. In another terminal: `ollama pull phi3`\n3. Try your question again"
+                let error = if error_msg.contains("connection refused") {
+                    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "llm_unavailable", "❌ Cannot connect to Ollama. Please start Ollama:\n\n1. Run: `ollama serve`\n2. In another terminal: `ollama pull phi3`\n3. Try your question again")
                 } else if error_msg.contains("timeout") {
-                    "⏱️ Ollama took too long to respond. The model might be loading for the first time, or the query is too complex. Try again or use a simpler question."
+                    ApiError::new(StatusCode::GATEWAY_TIMEOUT, "llm_timeout", "⏱️ Ollama took too long to respond. The model might be loading for the first time, or the query is too complex. Try again or use a simpler question.")
                 } else {
-                    &format!("❌ Ollama connection error: {}\n\nMake sure Ollama is running with: `ollama serve`", error_msg)
+                    ApiError::new(StatusCode::BAD_GATEWAY, "llm_unavailable", format!("❌ Ollama connection error: {}\n\nMake sure Ollama is running with: `ollama serve`", error_msg))
                 };
 
-                return (
-                    StatusCode::BAD_GATEWAY,
-                    Json(ChatResponse {
-                        answer: user_message.to_string(),
-                        sources: vec![],
-                    }),
-                )
-                    .into_response();
+                return Err(error.retryable());
+            }
+        }
+    }
+}
+
+// COMPRESSION
+
+/// Responses smaller than this are sent as they are.
+const COMPRESS_MIN_BYTES: u16 = 1024;
+
+/// Gzip or brotli for responses, whichever the client prefers. Small bodies
+/// aren't worth it, images are compressed already, and event streams would
+/// hold answer tokens back in the encoder's buffer.
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(
+        SizeAbove::new(COMPRESS_MIN_BYTES)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE)
+            .and(NotForContentType::GRPC),
+    )
+}
+
+// HTTPS
+
+/// Serves `app` over TLS. Each connection is handshaken before hyper sees
+/// it, and the peer address is passed on so `ConnectInfo` (and with it rate
+/// limiting) works as it does over plain HTTP.
+async fn serve_tls(
+    listener: TcpListener,
+    tls: Arc<ServerConfig>,
+    app: Router,
+    shutdown: watch::Receiver<bool>,
+) {
+    let acceptor = TlsAcceptor::from(tls);
+    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown_requested(shutdown.clone()) => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Usually out of file descriptors; give connections time to close
+                warn!("⚠️  Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let Ok(service) = make_service.call(peer).await;
+        let acceptor = acceptor.clone();
+        let watcher = graceful.watcher();
+
+        tokio::spawn(async move {
+            // Failed handshakes are mostly scanners and plain-HTTP clients
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(service),
+            );
+            if let Err(e) = watcher.watch(connection).await {
+                debug!("Connection with {} ended: {}", peer, e);
+            }
+        });
+    }
+
+    // Idle connections close now; busy ones once their response is sent
+    graceful.shutdown().await;
+}
+
+/// Answers plain HTTP on `addr` with a permanent redirect to the same path
+/// on `host`'s HTTPS port. The client's `Host` header is never used, so the
+/// redirect can't be pointed elsewhere. Runs alongside the HTTPS server,
+/// which keeps going if this port can't be bound.
+async fn redirect_to_https(addr: SocketAddr, host: String, https_port: u16) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("❌ Failed to bind HTTP redirect on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    let origin = if https_port == 443 {
+        format!("https://{}", host)
+    } else {
+        format!("https://{}:{}", host, https_port)
+    };
+    info!("↪️  Redirecting http://{} to {}", addr, origin);
+    let redirect = move |uri: Uri| {
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let location = format!("{}{}", origin, path);
+        async move { Redirect::permanent(&location) }
+    };
+
+    if let Err(e) = axum::serve(listener, Router::new().fallback(redirect)).await {
+        error!("❌ HTTP redirect stopped: {}", e);
+    }
+}
+
+// SHUTDOWN
+
+/// How long in-flight requests, and then the upload being ingested, get to
+/// finish after a shutdown signal before the state is flushed regardless.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Resolves on Ctrl-C, or on SIGTERM (what `docker stop` and systemd send).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("⚠️  Can't listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("⚠️  Can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Resolves once `shutdown` turns true.
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    // The sender lives until exit, so an error can't cut this short early
+    let _ = shutdown.wait_for(|&down| down).await;
+}
+
+/// Writes the store and sessions of every loaded tenant, and the shares,
+/// once more on the way out. Everything is already saved as it changes, so
+/// this only matters for whatever was cut off mid-way.
+async fn flush_state(root: &AppState) {
+    let tenants: Vec<String> = root.tenants.read().unwrap().keys().cloned().collect();
+    let states = std::iter::once(root.clone()).chain(tenants.iter().map(|t| root.for_tenant(t)));
+    for state in states {
+        persist_store(&state).await;
+        let sessions = state.sessions.lock().await;
+        write_json_file(
+            &state.config,
+            &state.config.sessions_path(),
+            &*sessions,
+            "sessions",
+        )
+        .await;
     }
+
+    let shares = root.shares.lock().await;
+    persist_shares(&root.config, &shares).await;
 }
 
 // MAIN
@@ -670,12 +11633,22 @@ async fn main() {
 
     info!("🚀 Starting RAG Chatbot Backend");
 
-    // Initialize embedding model
-    info!("📚 Loading embedding model (all-MiniLM-L6-v2)...");
-    let model = TextEmbedding::try_new(
-        InitOptions::new(EmbeddingModel::AllMiniLML6V2).with_show_download_progress(true),
-    )
-    .expect("Failed to load embedding model");
+    let config = Config::load();
+
+    // Initialize embedding models: one for queries, one for ingestion
+    let embedding_model = config.embedding_model_info();
+    info!(
+        "📚 Loading embedding model ({}, {} dimensions)...",
+        embedding_model.model_code, embedding_model.dim
+    );
+    let load_model = || {
+        TextEmbedding::try_new(
+            InitOptions::new(embedding_model.model.clone()).with_show_download_progress(true),
+        )
+        .expect("Failed to load embedding model")
+    };
+    let model = load_model();
+    let ingest_model = load_model();
     info!("✅ Embedding model loaded");
 
     // Initialize HTTP client for Ollama with better settings
@@ -699,20 +11672,22 @@ async fn main() {
             if response.status().is_success() {
                 info!("✅ Ollama is running");
 
-                // Check if phi3 model is available
+                // Check if the answering model is available
                 if let Ok(body) = response.json::<serde_json::Value>().await {
                     if let Some(models) = body.get("models").and_then(|m| m.as_array()) {
-                        let has_phi3 = models.iter().any(|m| {
+                        let has_model = models.iter().any(|m| {
                             m.get("name")
                                 .and_then(|n| n.as_str())
-                                .map(|s| s.contains("phi3"))
-                                .unwrap_or(false)
+                                .is_some_and(|name| is_model(name, &config.model))
                         });
 
-                        if has_phi3 {
-                            info!("✅ Model 'phi3' is available");
+                        if has_model {
+                            info!("✅ Model '{}' is available", config.model);
                         } else {
-                            warn!("⚠️  Model 'phi3' not found. Run: ollama pull phi3");
+                            warn!(
+                                "⚠️  Model '{}' not found. Run: ollama pull {}",
+                                config.model, config.model
+                            );
                         }
                     }
                 }
@@ -723,44 +11698,415 @@ async fn main() {
         Err(e) => {
             warn!("⚠️  Ollama is not running: {}", e);
             warn!("⚠️  Start it with: ollama serve");
-            warn!("⚠️  Then run: ollama pull phi3");
+            warn!("⚠️  Then run: ollama pull {}", config.model);
             warn!("⚠️  For GPU support, check: https://github.com/ollama/ollama/blob/main/docs/gpu.md");
         }
     }
 
     // Warm up Ollama connection to avoid cold-start latency
-    warm_up_ollama(&ollama_client).await;
+    warm_up_ollama(&ollama_client, &config.model).await;
+
+    // Restore previously indexed documents
+    let vector_store = VectorStore::load(&config);
+    info!(
+        "📦 Restored {} documents ({} chunks) from {}",
+        vector_store.documents.len(),
+        vector_store.count(),
+        config.store_path().display()
+    );
+
+    let tokens = TokenCounter::load(&config);
+    let shares = load_shares(&config.shares_path());
+    let sessions = load_sessions(&config.sessions_path());
+    let evals = load_evals(&config.evals_path());
+
+    let (upload_queue, upload_rx) = mpsc::unbounded_channel();
+    let embedding_pool = Arc::new(Semaphore::new(config.embedding_workers));
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit_per_minute));
+
+    let config_tts = config.tts.clone();
 
     // Create app state
     let state = AppState {
-        vector_store: Arc::new(RwLock::new(VectorStore::new())),
-        embedding_model: Arc::new(Mutex::new(model)),
+        config: Arc::new(config),
+        vector_store: Arc::new(RwLock::new(vector_store)),
+        embedding_model: Arc::new(model),
+        ingest_embedding_model: Arc::new(ingest_model),
+        embedding_pool,
         ollama_client,
+        persist_lock: Arc::new(tokio::sync::Mutex::new(())),
+        jobs: Arc::new(RwLock::new(HashMap::new())),
+        upload_queue,
+        reindex_lock: Arc::new(tokio::sync::Mutex::new(())),
+        ingest_lock: Arc::new(tokio::sync::RwLock::new(())),
+        tokens,
+        shares: Arc::new(tokio::sync::Mutex::new(shares)),
+        sessions: Arc::new(tokio::sync::Mutex::new(sessions)),
+        evals: Arc::new(tokio::sync::Mutex::new(evals)),
+        audit_lock: Arc::new(tokio::sync::Mutex::new(())),
+        overview: Arc::new(tokio::sync::Mutex::new(None)),
+        watch_activity: Arc::new(Mutex::new(VecDeque::new())),
+        embedding_checked: Arc::new(Mutex::new(None)),
+        started_at: Instant::now(),
+        tenant: DEFAULT_TENANT.to_string(),
+        tenants: Arc::new(RwLock::new(HashMap::new())),
+        rate_limiter,
+        speech: config_tts.as_ref().map(speech_synthesizer),
     };
 
-    // Build router
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let worker = tokio::spawn(upload_worker(state.clone(), upload_rx, shutdown.clone()));
+    if let Some(dir) = state.config.watch_dir.clone() {
+        tokio::spawn(watch_folder(state.clone(), dir, shutdown.clone()));
+    }
+
+    if !state.config.api_keys.is_empty() {
+        let tenants: HashSet<&String> = state.config.api_keys.values().collect();
+        info!(
+            "🔑 Multi-tenant mode: {} API keys for {} tenants; requests need a key",
+            state.config.api_keys.len(),
+            tenants.len()
+        );
+    }
+
+    let allow_any_origin = std::env::args().any(|arg| arg == "--allow-any-origin");
+    if allow_any_origin {
+        warn!("⚠️  --allow-any-origin: any website can call this backend");
+    } else {
+        info!(
+            "🌐 Allowing browser requests from {}",
+            state.config.cors_origins.join(", ")
+        );
+    }
+    let cors = state.config.cors_layer(allow_any_origin);
+
+    let addr = state.config.listen_addr;
+    let redirect = state
+        .config
+        .http_redirect_port
+        .zip(state.config.public_host.clone());
+    let tls = state
+        .config
+        .tls_config()
+        .unwrap_or_else(|e| panic!("Failed to load TLS settings: {}", e));
+
+    // Build router; answering and ingesting are the expensive endpoints, so
+    // only they are rate limited
+    let upload_limit = state.config.max_upload_mb * 1024 * 1024;
+    let limited = Router::new()
+        .route(
+            "/upload",
+            post(upload_handler).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route(
+            "/ingest_repo",
+            post(ingest_repo_handler).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route(
+            "/ingest_text",
+            post(ingest_text_handler).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route(
+            "/transcribe",
+            post(transcribe_handler).layer(DefaultBodyLimit::max(MAX_AUDIO_MB * 1024 * 1024)),
+        )
+        .route("/tts", post(tts_handler))
+        .route("/chat", post(chat_handler))
+        .route("/chat/stream", post(chat_stream_handler))
+        .route("/chat/batch", post(chat_batch_handler))
+        .route("/models/pull", post(pull_model_handler))
+        .route("/evals", post(create_eval_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit));
     let app = Router::new()
         .route("/health", get(health_check))
-        .route("/upload", post(upload_handler))
-        .route("/chat", post(chat_handler))
+        .route("/health/live", get(liveness_check))
+        .route("/admin/tenants", get(tenants_handler))
+        .merge(limited)
+        .route("/jobs/:id", get(job_status_handler))
+        .route("/search", post(search_handler))
         .route("/delete", post(delete_handler))
-        .with_state(state)
-        .layer(CorsLayer::permissive());
+        .route("/reset", post(reset_handler))
+        .route("/export", get(export_handler))
+        .route(
+            "/import",
+            post(import_handler).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/documents", get(documents_handler))
+        .route("/collections", get(collections_handler))
+        .route("/tags", get(tags_handler))
+        .route("/collections/:name", delete(delete_collection_handler))
+        .route(
+            "/collections/:name/documents",
+            post(add_to_collection_handler),
+        )
+        .route(
+            "/collections/:name/documents/:document",
+            delete(remove_from_collection_handler),
+        )
+        .route("/documents/:name", patch(update_document_handler))
+        .route("/documents/:name/report", get(report_handler))
+        .route("/documents/:name/preview", get(preview_handler))
+        .route("/documents/:name/stats", get(document_stats_handler))
+        .route("/documents/:name/similar", get(similar_documents_handler))
+        .route("/chunks/:id/related", get(related_chunks_handler))
+        .route("/embed", post(embed_handler))
+        .route("/templates", get(templates_handler))
+        .route("/models", get(models_handler))
+        .route(
+            "/sessions",
+            get(sessions_handler).post(create_session_handler),
+        )
+        .route(
+            "/sessions/:id",
+            patch(rename_session_handler).delete(delete_session_handler),
+        )
+        .route(
+            "/sessions/:id/messages",
+            get(session_messages_handler).delete(truncate_session_handler),
+        )
+        .route("/evals", get(evals_handler))
+        .route(
+            "/feedback",
+            get(export_feedback_handler).post(feedback_handler),
+        )
+        .route("/evals/:id", get(eval_handler))
+        .route("/shares", post(create_share_handler))
+        .route("/shares/:token", get(get_share_handler))
+        .route("/stats", get(stats_handler))
+        .route("/config", get(config_handler))
+        .route("/watch", get(watch_handler))
+        .route("/overview", get(overview_handler))
+        .route("/reindex", post(reindex_handler))
+        .with_state(state.clone())
+        // Request bodies are inflated before the upload size limit counts them
+        .layer(RequestDecompressionLayer::new())
+        .layer(compression_layer())
+        .layer(cors);
 
     // Start server
-    let addr = "127.0.0.1:3000";
-    let listener = tokio::net::TcpListener::bind(addr)
+    let listener = TcpListener::bind(addr)
         .await
         .expect("Failed to bind to address");
 
-    info!("🎯 Backend running at http://{}", addr);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("🎯 Backend running at {}://{}", scheme, addr);
     info!("📖 Endpoints:");
-    info!("   - GET  /health");
-    info!("   - POST /upload (multipart/form-data)");
+    info!("   - GET  /health, GET /health/live");
+    info!("   - POST /upload?on_conflict=replace|version&collection=<name>&date=<YYYY-MM-DD> (multipart/form-data)");
+    info!("   - POST /ingest_text (JSON with a title and text)");
+    info!("   - POST /ingest_repo (JSON with a GitHub url, or a tarball as multipart/form-data)");
+    info!("   - GET  /jobs/:id");
     info!("   - POST /chat (JSON)");
-    info!("   - POST /delete (JSON)");
+    info!("   - POST /chat/stream (JSON → SSE)");
+    info!("   - POST /chat/batch (JSON)");
+    info!("   - GET  /models, POST /models/pull (JSON → SSE)");
+    info!("   - POST /evals (JSONL), GET /evals, GET /evals/:id");
+    info!("   - POST /feedback (JSON), GET /feedback (JSONL)");
+    info!("   - POST /delete (JSON), POST /reset (JSON)");
+    info!("   - GET  /export, POST /import?replace=true (JSON)");
+    info!("   - GET  /documents, PATCH /documents/:name (JSON)");
+    info!("   - GET  /collections, GET /tags");
+    info!("   - GET  /stats, GET /watch, GET /config");
+    info!("   - GET  /overview");
+    info!("   - GET  /sessions, POST /sessions, PATCH|DELETE /sessions/:id, GET|DELETE /sessions/:id/messages");
+    info!("   - POST /reindex");
+    info!("   - GET  /admin/tenants");
 
-    axum::serve(listener, app)
-        .await
-        .expect("Server failed to start");
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("🛑 Shutting down: finishing in-flight requests...");
+        shutdown_tx.send_replace(true);
+        // Keep the sender alive so no receiver sees it closed
+        std::future::pending::<()>().await;
+    });
+
+    let server = async {
+        match tls {
+            Some(tls) => {
+                if let Some((port, host)) = redirect {
+                    tokio::spawn(redirect_to_https(
+                        SocketAddr::new(addr.ip(), port),
+                        host,
+                        addr.port(),
+                    ));
+                }
+                serve_tls(listener, tls, app, shutdown.clone()).await;
+            }
+            None => axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_requested(shutdown.clone()))
+            .await
+            .expect("Server failed to start"),
+        }
+    };
+    let deadline = async {
+        shutdown_requested(shutdown.clone()).await;
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+    };
+    tokio::select! {
+        _ = server => {}
+        _ = deadline => warn!(
+            "⚠️  Requests still running after {}s, closing them",
+            SHUTDOWN_GRACE.as_secs()
+        ),
+    }
+
+    if tokio::time::timeout(SHUTDOWN_GRACE, worker).await.is_err() {
+        warn!(
+            "⚠️  Upload still ingesting after {}s, saving what is done",
+            SHUTDOWN_GRACE.as_secs()
+        );
+    }
+    flush_state(&state).await;
+    info!("👋 Backend stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(texts: &[&str]) -> Vec<(String, Vec<f32>)> {
+        texts
+            .iter()
+            .map(|text| (text.to_string(), vec![1.0, 0.0]))
+            .collect()
+    }
+
+    #[test]
+    fn replacing_a_changed_document_counts_only_real_duplicates() {
+        let mut store = VectorStore::default();
+        for (chunk, embedding) in chunks(&["alpha", "beta", "gamma"]) {
+            store.add(chunk, embedding, "notes.md".to_string());
+        }
+
+        let replacement = chunks(&["alpha", "beta", "delta", "delta"]);
+        let chunk_count = replacement.len();
+        let added = store.replace_chunks("notes.md", replacement);
+        assert_eq!(added.len(), 3);
+        assert_eq!(chunk_count - added.len(), 1);
+
+        let mut texts: Vec<&str> = store.chunks.iter().map(|c| c.text.as_str()).collect();
+        texts.sort();
+        assert_eq!(texts, ["alpha", "beta", "delta"]);
+    }
+
+    #[test]
+    fn github_repo_accepts_repository_urls_only() {
+        let repo = |owner: &str, name: &str| Some((owner.to_string(), name.to_string()));
+        assert_eq!(
+            github_repo("https://github.com/tokio-rs/tokio"),
+            repo("tokio-rs", "tokio")
+        );
+        assert_eq!(
+            github_repo("http://github.com/tokio-rs/tokio/"),
+            repo("tokio-rs", "tokio")
+        );
+        assert_eq!(
+            github_repo(" github.com/serde-rs/serde.git "),
+            repo("serde-rs", "serde")
+        );
+        assert_eq!(github_repo("https://gitlab.com/tokio-rs/tokio"), None);
+        assert_eq!(github_repo("https://github.com/tokio-rs"), None);
+        assert_eq!(
+            github_repo("https://github.com/tokio-rs/tokio/tree/master"),
+            None
+        );
+        assert_eq!(github_repo("https://github.com/../tokio"), None);
+        assert_eq!(github_repo("https://github.com/tokio-rs/to%6Bio"), None);
+    }
+
+    #[test]
+    fn glob_set_keeps_single_stars_within_a_directory() {
+        let include = glob_set(&["docs/**", "*.md"]).unwrap();
+        assert!(include.is_match("docs/guide/intro.md"));
+        assert!(include.is_match("README.md"));
+        assert!(!include.is_match("src/notes.md"));
+
+        let exclude = glob_set(REPO_DEFAULT_EXCLUDES).unwrap();
+        assert!(exclude.is_match("web/node_modules/left-pad/index.js"));
+        assert!(exclude.is_match(".github/workflows/ci.yml"));
+        assert!(exclude.is_match("Cargo.lock"));
+        assert!(!exclude.is_match("src/lib.rs"));
+
+        assert_eq!(glob_set(&["src/[lib.rs"]).unwrap_err().code, "invalid_glob");
+    }
+
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzipped(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn repo_tarball_drops_the_top_directory_and_applies_globs() {
+        let archive = gzipped(&tarball(&[
+            ("tokio-master/README.md", b"# Tokio"),
+            ("tokio-master/src/lib.rs", b"pub fn spawn() {}"),
+            (
+                "tokio-master/node_modules/x/index.js",
+                b"module.exports = 1",
+            ),
+            ("tokio-master/logo.png", b"\x89PNG"),
+            ("tokio-master/src/bad.rs", b"\xff\xfe"),
+        ]));
+        let include = glob_set::<&str>(&[]).unwrap();
+        let exclude = glob_set(REPO_DEFAULT_EXCLUDES).unwrap();
+        let (files, skipped) =
+            read_repo_tarball(&archive, &include, &exclude, 1024 * 1024, 100).unwrap();
+
+        let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["README.md", "src/lib.rs"]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn repo_tarball_limits_count_skipped_entries() {
+        let none = glob_set::<&str>(&[]).unwrap();
+        // Zeros compress to almost nothing, and a .bin file is never indexed
+        let zeros = vec![0; 1024 * 1024];
+        let bomb = gzipped(&tarball(&[
+            ("repo/README.md", b"# Hi"),
+            ("repo/data.bin", &zeros),
+        ]));
+        assert!(bomb.len() < 16 * 1024);
+        let error = read_repo_tarball(&bomb, &none, &none, 64 * 1024, 100).unwrap_err();
+        assert!(error.contains("unpacks to more than"), "{}", error);
+
+        let many = tarball(&[("a.txt", b"a"), ("b.bin", b"b"), ("c.bin", b"c")]);
+        let error = read_repo_tarball(&many, &none, &none, 1024 * 1024, 2).unwrap_err();
+        assert!(error.contains("more than 2 entries"), "{}", error);
+        assert!(read_repo_tarball(&many, &none, &none, 1024 * 1024, 3).is_ok());
+    }
+
+    #[test]
+    fn judged_category_needs_the_reply_to_be_the_category() {
+        let categories = Config::default().moderation_categories;
+        let judged = |reply| judged_category(reply, &categories);
+        assert_eq!(judged("Violence"), Some("violence".to_string()));
+        assert_eq!(
+            judged(" \"Sexual content.\"\n"),
+            Some("sexual content".to_string())
+        );
+        assert_eq!(
+            judged("weapons, since it asks how to build one"),
+            Some("weapons".to_string())
+        );
+        assert_eq!(judged("none"), None);
+        assert_eq!(judged("None of these; it is not about violence"), None);
+        assert_eq!(judged("nonviolence"), None);
+    }
 }
@@ -0,0 +1,294 @@
+//! Terminal chat client for the backend, for machines without a browser.
+//!
+//! ```text
+//! cargo run --release --features cli --bin cli -- --url http://localhost:3000
+//! ```
+
+use ahtohallan_client::{
+    ChatRequest, Client, Error, FileStage, HistoryTurn, JobStatus, ModerationAction, StreamEvent,
+    UploadFile, UploadOptions,
+};
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+    time::Duration,
+};
+
+const DEFAULT_URL: &str = "http://localhost:3000";
+
+/// Earlier turns sent along with each question, for templates that use them.
+const HISTORY_TURNS: usize = 6;
+
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const HELP: &str = "\
+Type a question to ask it, or:
+  /upload <path>...   index .md and .pdf files
+  /docs               list indexed documents
+  /delete <name>      remove a document
+  /help               show this help
+  /quit               exit (Ctrl-D works too)";
+
+struct Options {
+    url: String,
+    api_key: Option<String>,
+}
+
+impl Options {
+    /// `--url` and `--api-key`, falling back to `AHTOHALLAN_URL` and
+    /// `AHTOHALLAN_API_KEY`.
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            url: std::env::var("AHTOHALLAN_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+            api_key: std::env::var("AHTOHALLAN_API_KEY").ok(),
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--url" => options.url = args.next().ok_or("--url needs a value")?,
+                "--api-key" => {
+                    options.api_key = Some(args.next().ok_or("--api-key needs a value")?)
+                }
+                "-h" | "--help" => {
+                    println!("Usage: cli [--url <backend>] [--api-key <key>]\n\n{}", HELP);
+                    std::process::exit(0);
+                }
+                other => return Err(format!("Unknown argument {:?}", other)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut client = Client::new(&options.url);
+    if let Some(key) = options.api_key {
+        client = client.with_api_key(key);
+    }
+
+    match client.health().await {
+        Ok(health) if health.is_ok() => {
+            println!(
+                "✅ Connected to {} ({} documents)",
+                options.url, health.documents
+            )
+        }
+        Ok(health) => println!(
+            "⚠️  {} is degraded: Ollama {}, model {} {}, embeddings {}",
+            options.url,
+            if health.ollama { "up" } else { "down" },
+            health.model,
+            if health.model_available {
+                "pulled"
+            } else {
+                "missing"
+            },
+            if health.embedding_ready {
+                "ready"
+            } else {
+                "not ready"
+            },
+        ),
+        Err(e) => {
+            eprintln!("❌ Can't reach {}: {}", options.url, e);
+            eprintln!("   Start it with: cargo run --release --features backend --bin backend");
+            std::process::exit(1);
+        }
+    }
+    println!("{}\n", HELP);
+
+    let mut history: Vec<HistoryTurn> = Vec::new();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                break;
+            }
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let result = match command {
+            "/quit" | "/exit" => break,
+            "/help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            "/docs" => list_documents(&client).await,
+            "/upload" if !rest.is_empty() => upload(&client, rest).await,
+            "/delete" if !rest.is_empty() => delete(&client, rest).await,
+            "/upload" | "/delete" => {
+                println!(
+                    "Usage: {} <{}>",
+                    command,
+                    if command == "/upload" { "path" } else { "name" }
+                );
+                Ok(())
+            }
+            _ if command.starts_with('/') => {
+                println!("Unknown command {}; /help lists them", command);
+                Ok(())
+            }
+            _ => ask(&client, line, &mut history).await,
+        };
+
+        if let Err(e) = result {
+            report(&e);
+        }
+    }
+}
+
+fn report(error: &Error) {
+    eprintln!("❌ {}", error);
+    if error.is_retryable() {
+        eprintln!("   This may work if you try again in a moment.");
+    }
+}
+
+/// Streams the answer to stdout, then lists its sources.
+async fn ask(client: &Client, query: &str, history: &mut Vec<HistoryTurn>) -> Result<(), Error> {
+    let request = ChatRequest {
+        history: history.clone(),
+        ..ChatRequest::new(query)
+    };
+
+    let mut answer = String::new();
+    let mut sources = Vec::new();
+    let mut stdout = io::stdout();
+    let result = client
+        .chat_stream(&request, |event| match event {
+            StreamEvent::Token(token) => {
+                answer.push_str(&token);
+                print!("{}", token);
+                let _ = stdout.flush();
+            }
+            StreamEvent::Sources(names) => sources = names,
+            StreamEvent::Warning(message) => println!("⚠️  {}", message),
+            // A refused query's reply follows as a token; anything else
+            // replaces what was already printed
+            StreamEvent::Moderation(moderation)
+                if moderation.decision.action != ModerationAction::Refused =>
+            {
+                println!(
+                    "\n🛡️  Replaced by the content filter:\n{}",
+                    moderation.answer
+                );
+                answer = moderation.answer;
+            }
+            _ => {}
+        })
+        .await;
+    println!();
+    result?;
+
+    if !sources.is_empty() {
+        println!("\n📚 Sources:");
+        for source in &sources {
+            println!("   - {}", source);
+        }
+    }
+    println!();
+
+    history.push(HistoryTurn {
+        role: "user".to_string(),
+        content: query.to_string(),
+    });
+    history.push(HistoryTurn {
+        role: "assistant".to_string(),
+        content: answer.trim().to_string(),
+    });
+    let excess = history.len().saturating_sub(HISTORY_TURNS);
+    history.drain(..excess);
+    Ok(())
+}
+
+async fn list_documents(client: &Client) -> Result<(), Error> {
+    let documents = client.documents().await?;
+    if documents.is_empty() {
+        println!("No documents yet; add some with /upload <path>");
+    }
+    for name in documents {
+        println!("   - {}", name);
+    }
+    Ok(())
+}
+
+/// Uploads the space-separated `paths` as one job and waits for it,
+/// printing each file's outcome.
+async fn upload(client: &Client, paths: &str) -> Result<(), Error> {
+    let mut files = Vec::new();
+    for path in paths.split_whitespace().map(Path::new) {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        match std::fs::read(path) {
+            Ok(content) => files.push(UploadFile::new(name, content)),
+            Err(e) => println!("⚠️  Skipping {}: {}", path.display(), e),
+        }
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let accepted = client.upload(files, &UploadOptions::default()).await?;
+    let mut last_percent = None;
+    let job = loop {
+        let job = client.job(&accepted.job_id).await?;
+        if job.is_finished() {
+            break job;
+        }
+        if last_percent != Some(job.percent) {
+            print!("\r⏳ Indexing... {}%", job.percent);
+            let _ = io::stdout().flush();
+            last_percent = Some(job.percent);
+        }
+        tokio::time::sleep(JOB_POLL_INTERVAL).await;
+    };
+    if last_percent.is_some() {
+        println!();
+    }
+
+    for file in &job.files {
+        match (file.stage, &file.error) {
+            (FileStage::Failed, error) => println!(
+                "❌ {}: {}",
+                file.filename,
+                error.as_deref().unwrap_or("failed")
+            ),
+            (FileStage::Unchanged, _) => println!("✅ {} is already indexed", file.filename),
+            _ => println!("✅ {} ({} chunks)", file.filename, file.chunks),
+        }
+    }
+    if job.status == JobStatus::Completed {
+        println!("📦 {} chunks indexed in total", job.total_chunks);
+    }
+    Ok(())
+}
+
+async fn delete(client: &Client, name: &str) -> Result<(), Error> {
+    client.delete(name).await?;
+    println!("🗑️  Deleted {}", name);
+    Ok(())
+}
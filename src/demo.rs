@@ -0,0 +1,172 @@
+//! Offline demo mode (`--features demo`): a bundled sample corpus searched in
+//! the browser with canned answers, so the UI can be tried without a backend
+//! (e.g. on GitHub Pages). Search uses keyword hashing rather than
+//! embeddings, since no embedding model runs in the browser.
+
+use crate::{CorpusStats, DocumentInfo, LanguageCount};
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+const CORPUS_JSON: &str = include_str!("../assets/demo_corpus.json");
+
+/// Size of the hashed bag-of-words vectors standing in for real embeddings.
+const DIMENSIONS: usize = 256;
+/// Chunks scoring below this cosine similarity are not used to answer.
+const MIN_SIMILARITY: f32 = 0.15;
+/// Chunks an answer may cite as sources.
+const TOP_K: usize = 2;
+/// Words too common to say anything about what a chunk is about.
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "are", "with", "that", "this", "what", "how", "does", "you", "your",
+    "can", "from", "its", "into", "each", "then", "only", "about",
+];
+
+#[derive(Deserialize)]
+struct DemoCorpus {
+    documents: Vec<DemoDocument>,
+}
+
+#[derive(Deserialize)]
+struct DemoDocument {
+    filename: String,
+    chunks: Vec<DemoChunk>,
+}
+
+#[derive(Deserialize)]
+struct DemoChunk {
+    text: String,
+    answer: String,
+}
+
+/// The corpus with each chunk's vector, computed once on first use.
+struct Index {
+    corpus: DemoCorpus,
+    vectors: Vec<(usize, usize, Vec<f32>)>,
+}
+
+pub struct DemoAnswer {
+    pub answer: String,
+    pub sources: Vec<String>,
+}
+
+fn index() -> &'static Index {
+    static INDEX: OnceLock<Index> = OnceLock::new();
+    INDEX.get_or_init(|| {
+        let corpus: DemoCorpus =
+            serde_json::from_str(CORPUS_JSON).expect("demo corpus is valid JSON");
+        let vectors = corpus
+            .documents
+            .iter()
+            .enumerate()
+            .flat_map(|(doc, document)| {
+                document
+                    .chunks
+                    .iter()
+                    .enumerate()
+                    .map(move |(chunk, data)| (doc, chunk, embed(&data.text)))
+            })
+            .collect();
+        Index { corpus, vectors }
+    })
+}
+
+pub fn documents() -> Vec<DocumentInfo> {
+    index()
+        .corpus
+        .documents
+        .iter()
+        .map(|document| DocumentInfo {
+            filename: document.filename.clone(),
+            size: None,
+        })
+        .collect()
+}
+
+pub fn stats() -> CorpusStats {
+    let documents = &index().corpus.documents;
+    CorpusStats {
+        documents: documents.len(),
+        chunks: documents.iter().map(|d| d.chunks.len()).sum(),
+        languages: vec![LanguageCount {
+            name: "English".to_string(),
+            documents: documents.len(),
+        }],
+    }
+}
+
+/// Answers from the sample corpus: the canned answer of the best-matching
+/// chunk, citing the documents of the top matches.
+pub fn answer(query: &str) -> DemoAnswer {
+    let index = index();
+    let query = embed(query);
+
+    let mut scored: Vec<(f32, usize, usize)> = index
+        .vectors
+        .iter()
+        .map(|(doc, chunk, vector)| (cosine_similarity(&query, vector), *doc, *chunk))
+        .filter(|(score, _, _)| *score >= MIN_SIMILARITY)
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    scored.truncate(TOP_K);
+
+    let Some(&(_, best_doc, best_chunk)) = scored.first() else {
+        let names: Vec<&str> = index
+            .corpus
+            .documents
+            .iter()
+            .map(|d| d.filename.as_str())
+            .collect();
+        return DemoAnswer {
+            answer: format!(
+                "I don't know based on the provided documents. This demo only knows the bundled samples ({}); run the backend to chat with your own files.",
+                names.join(", ")
+            ),
+            sources: Vec::new(),
+        };
+    };
+
+    let mut sources: Vec<String> = Vec::new();
+    for &(_, doc, _) in &scored {
+        let filename = &index.corpus.documents[doc].filename;
+        if !sources.contains(filename) {
+            sources.push(filename.clone());
+        }
+    }
+
+    DemoAnswer {
+        answer: index.corpus.documents[best_doc].chunks[best_chunk]
+            .answer
+            .clone(),
+        sources,
+    }
+}
+
+/// Hashed bag-of-words vector: every word longer than two characters (bar
+/// stop words) adds to the bucket its FNV-1a hash falls in.
+fn embed(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; DIMENSIONS];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 2)
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+    {
+        let hash = word.bytes().fold(0x811c9dc5u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        });
+        vector[hash as usize % DIMENSIONS] += 1.0;
+    }
+    vector
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
@@ -1,24 +1,58 @@
 #![allow(unused_mut)]
 
 use dioxus::prelude::*;
-use gloo_net::http::Request;
+use dioxus::web::WebEventExt;
+use gloo_timers::future::TimeoutFuture;
+use pulldown_cmark::{Event as MdEvent, Options, Parser, Tag, TagEnd};
+use serde::Deserialize;
 use serde_json::json;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use wasm_bindgen::JsCast;
 use wasm_bindgen::JsValue;
 use web_sys::{FormData, HtmlInputElement};
 
+mod api;
+mod demo;
+
 const MAIN_CSS: Asset = asset!("/assets/main.css");
 
+/// Built with `--features demo`: answer from the bundled sample corpus in the
+/// browser instead of calling the backend.
+const DEMO_MODE: bool = cfg!(feature = "demo");
+
+/// Earlier turns sent with each question (the backend uses at most this many).
+const HISTORY_TURNS: usize = 6;
+
+/// Word overlap above which a new question is treated as a repeat of an earlier one.
+const SIMILAR_QUESTION_THRESHOLD: f32 = 0.8;
+
+/// How long a copy button shows "Copied" after copying.
+const COPIED_FLASH_MS: u32 = 1500;
+
 fn main() {
+    apply_theme(&load_setting(THEME_KEY).unwrap_or_default());
     dioxus::launch(App);
 }
 
 #[component]
 fn App() -> Element {
+    // `#share=<token>` opens a read-only shared conversation instead of the chat
+    let share_token = location_hash().and_then(|hash| {
+        hash.strip_prefix("#share=")
+            .filter(|token| !token.is_empty())
+            .map(|token| token.to_string())
+    });
+
     rsx! {
         document::Link { rel: "stylesheet", href: MAIN_CSS }
         document::Title { "Ahtohallan - RAG Chatbot" }
-        ChatApp {}
+        if let Some(token) = share_token {
+            SharedConversationView { token }
+        } else {
+            ChatApp {}
+        }
     }
 }
 
@@ -27,22 +61,773 @@ struct Message {
     role: String,
     content: String,
     sources: Vec<String>,
+    warning: Option<String>,
+    // Deep-think sub-questions and their answers, shown as the reasoning trail
+    steps: Vec<ThinkStep>,
+    // Context blocks the answer can cite as `[n]`
+    citations: Vec<Citation>,
+    // Sentence-level support check, when answer verification is on
+    grounding: Option<Grounding>,
+    // Per-stage timings, when they were asked for
+    timings: Option<Timings>,
+    // 👍 (true) or 👎 (false), once the answer has been rated
+    rating: Option<bool>,
+    // Unix seconds
+    created_at: u64,
+    // Milliseconds from sending the question to the whole answer, timed in
+    // the browser
+    response_ms: Option<u64>,
+}
+
+/// Milliseconds the backend spent in each stage of an answer.
+#[derive(Clone, PartialEq, Deserialize)]
+struct Timings {
+    rewrite_ms: u64,
+    think_ms: u64,
+    embed_ms: u64,
+    search_ms: u64,
+    prompt_ms: u64,
+    generate_ms: u64,
+    verify_ms: u64,
+    total_ms: u64,
+}
+
+impl Timings {
+    /// One line such as "4.2 s total · embed 35 ms · generate 4.1 s",
+    /// leaving out stages that didn't run.
+    fn summary(&self) -> String {
+        let format = format_duration;
+        let mut parts = vec![format!("{} total", format(self.total_ms))];
+        for (stage, ms) in [
+            ("rewrite", self.rewrite_ms),
+            ("think", self.think_ms),
+            ("embed", self.embed_ms),
+            ("search", self.search_ms),
+            ("prompt", self.prompt_ms),
+            ("generate", self.generate_ms),
+            ("verify", self.verify_ms),
+        ] {
+            if ms > 0 {
+                parts.push(format!("{} {}", stage, format(ms)));
+            }
+        }
+        parts.join(" · ")
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct Grounding {
+    score: f32,
+    sentences: Vec<SentenceSupport>,
+    #[serde(default)]
+    warning: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct SentenceSupport {
+    text: String,
+    supported: bool,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct Citation {
+    number: usize,
+    source: String,
+    score: f32,
+    text: String,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct ThinkStep {
+    question: String,
+    answer: String,
+    #[serde(default)]
+    sources: Vec<String>,
 }
 
+/// Per-request settings picked in the controls row, sent alongside the query.
+#[derive(Clone, PartialEq)]
+struct ChatOptions {
+    length: String,
+    deep_think: bool,
+    verify: bool,
+    search: bool,
+    // Documents to limit retrieval to; every document when empty
+    sources: Vec<String>,
+    // Collection to limit retrieval to; every collection when empty
+    collection: String,
+    // Category tags to limit retrieval to; every document when empty
+    tags: Vec<String>,
+    // Server-side session the question and answer are saved to
+    session_id: Option<String>,
+    // Aborts the request when the user clicks Stop
+    abort: Option<web_sys::AbortSignal>,
+    // Ask the backend for per-stage timings
+    debug: bool,
+    // Ollama model to answer with; the backend's default when empty
+    model: String,
+    // Passages to retrieve; the backend's default when unset
+    top_k: Option<usize>,
+}
+
+/// localStorage key remembering the Deep Think toggle between visits.
+const DEEP_THINK_KEY: &str = "ahtohallan.deep_think";
+/// localStorage key remembering the answer verification toggle.
+const VERIFY_KEY: &str = "ahtohallan.verify";
+/// localStorage key remembering the search-only mode toggle.
+const SEARCH_MODE_KEY: &str = "ahtohallan.search_mode";
+/// localStorage key remembering the per-stage timings toggle.
+const TIMINGS_KEY: &str = "ahtohallan.timings";
+/// How long the mic button must be held for its release to stop listening.
+const PUSH_TO_TALK_MS: f64 = 400.0;
+/// localStorage key remembering whether every answer is read aloud.
+const AUTO_SPEAK_KEY: &str = "ahtohallan.auto_speak";
+/// localStorage key remembering the conversation to resume on the next visit.
+const SESSION_KEY: &str = "ahtohallan.session";
+/// localStorage key remembering the model picked in the settings panel.
+const MODEL_KEY: &str = "ahtohallan.model";
+/// localStorage key remembering how many passages a question retrieves.
+const TOP_K_KEY: &str = "ahtohallan.top_k";
+/// localStorage key remembering the name of the voice answers are read in.
+const TTS_VOICE_KEY: &str = "ahtohallan.tts_voice";
+/// localStorage key remembering how fast answers are read aloud.
+const TTS_RATE_KEY: &str = "ahtohallan.tts_rate";
+/// localStorage key remembering the pitch answers are read aloud at.
+const TTS_PITCH_KEY: &str = "ahtohallan.tts_pitch";
+/// localStorage key remembering what reads answers aloud: `browser` for the
+/// Web Speech voices, `server` for the backend's `POST /tts`.
+const TTS_ENGINE_KEY: &str = "ahtohallan.tts_engine";
+/// localStorage key remembering what turns speech into text: `browser` for
+/// Web Speech recognition, `whisper` for the backend's `POST /transcribe`.
+const STT_ENGINE_KEY: &str = "ahtohallan.stt_engine";
+/// localStorage key remembering the language questions are dictated in.
+const STT_LANG_KEY: &str = "ahtohallan.stt_lang";
+/// Languages offered for dictation, as BCP 47 tags with their names.
+const STT_LANGUAGES: &[(&str, &str)] = &[
+    ("en-US", "English (US)"),
+    ("en-GB", "English (UK)"),
+    ("de-DE", "Deutsch"),
+    ("es-ES", "Español"),
+    ("fr-FR", "Français"),
+    ("it-IT", "Italiano"),
+    ("nl-NL", "Nederlands"),
+    ("pl-PL", "Polski"),
+    ("pt-BR", "Português (Brasil)"),
+    ("sv-SE", "Svenska"),
+    ("fi-FI", "Suomi"),
+    ("ru-RU", "Русский"),
+    ("uk-UA", "Українська"),
+    ("tr-TR", "Türkçe"),
+    ("ar-SA", "العربية"),
+    ("hi-IN", "हिन्दी"),
+    ("ta-IN", "தமிழ்"),
+    ("zh-CN", "中文 (简体)"),
+    ("ja-JP", "日本語"),
+    ("ko-KR", "한국어"),
+];
+/// localStorage key remembering the colour theme: system, light or dark.
+const THEME_KEY: &str = "ahtohallan.theme";
+/// localStorage key holding earlier questions, oldest first, as a JSON array.
+const QUERY_HISTORY_KEY: &str = "ahtohallan.query_history";
+/// Questions kept for recalling with the arrow keys.
+const MAX_QUERY_HISTORY: usize = 50;
+/// Most passages the settings panel lets a question retrieve, as the backend
+/// caps `top_k` for chat.
+const MAX_TOP_K: usize = 20;
+
 #[derive(Clone, PartialEq)]
 struct DocumentInfo {
     filename: String,
+    // From the listing; unknown in demo mode
+    size: Option<DocumentSize>,
+}
+
+/// `GET /documents`: how big one document is.
+#[derive(Clone, PartialEq, Deserialize)]
+struct DocumentSize {
+    chunks: usize,
+    words: usize,
+}
+
+/// `GET /shares/:token`: a published, read-only conversation.
+#[derive(Clone, PartialEq, Deserialize)]
+struct SharedConversation {
+    messages: Vec<SharedMessage>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct SharedMessage {
+    role: String,
+    content: String,
+    #[serde(default)]
+    sources: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ShareReply {
+    token: String,
+}
+
+/// `POST /search`: the passages retrieved for a query, without an answer.
+#[derive(Deserialize)]
+struct SearchReply {
+    results: Vec<SearchHit>,
+}
+
+#[derive(Deserialize)]
+struct SearchHit {
+    source: String,
+    score: f32,
+    text: String,
+}
+
+/// `GET /documents/:name/stats`: size and provenance of one document.
+#[derive(Clone, PartialEq, Deserialize)]
+struct DocumentStats {
+    chunks: usize,
+    words: usize,
+    characters: usize,
+    #[serde(default)]
+    pages: Option<usize>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+    #[serde(default)]
+    ingested_at: Option<u64>,
+    embedding_model: String,
+    embedding_dimensions: usize,
+}
+
+/// `GET /config`: what `/upload` accepts, and whether `/transcribe` and
+/// `/tts` are set up.
+#[derive(Clone, PartialEq, Deserialize)]
+struct UploadLimits {
+    // Per request, all files together
+    max_upload_mb: usize,
+    // Lowercase file name endings, e.g. ".md"
+    upload_extensions: Vec<String>,
+    // `POST /transcribe` is set up, so whisper.cpp can be the speech recognition
+    #[serde(default)]
+    transcription: bool,
+    // `POST /tts` is set up, so the backend can read answers aloud
+    #[serde(default)]
+    speech: bool,
+}
+
+impl Default for UploadLimits {
+    // The backend's defaults
+    fn default() -> Self {
+        Self {
+            max_upload_mb: 50,
+            upload_extensions: vec![".md".to_string(), ".pdf".to_string()],
+            transcription: false,
+            speech: false,
+        }
+    }
+}
+
+/// `POST /transcribe`: what a recording said.
+#[derive(Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+/// `GET /watch`: the folder the backend keeps indexed and what it did lately.
+#[derive(Clone, PartialEq, Default, Deserialize)]
+struct WatchStatus {
+    #[serde(default)]
+    dir: Option<String>,
+    documents: Vec<String>,
+    // Newest first
+    events: Vec<WatchEvent>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct WatchEvent {
+    file: String,
+    // "indexed", "removed" or "failed"
+    action: String,
+    #[serde(default)]
+    error: Option<String>,
+    at: u64,
+}
+
+/// `GET /documents/:name/preview`: the text a document was indexed from.
+#[derive(Clone, PartialEq, Deserialize)]
+struct DocumentPreview {
+    // "markdown", "pdf" or "text"
+    format: String,
+    // One entry per page for PDFs
+    pages: Vec<String>,
+    truncated: bool,
+}
+
+/// `GET /documents/:name/report`: how a document's ingestion went.
+#[derive(Clone, PartialEq, Deserialize)]
+struct IngestionReport {
+    extractor: String,
+    #[serde(default)]
+    pages: Option<usize>,
+    characters: usize,
+    chunks: usize,
+    filtered_chunks: usize,
+    #[serde(default)]
+    warnings: Vec<String>,
+    // Personal data kind ("email", "phone", "national_id") -> matches masked
+    #[serde(default)]
+    redactions: BTreeMap<String, usize>,
+    duration_ms: u64,
+    ingested_at: u64,
+}
+
+/// One entry of `GET /documents/:name/similar`.
+#[derive(Clone, PartialEq, Deserialize)]
+struct SimilarDocument {
+    name: String,
+    score: f32,
+    shared_chunks: usize,
+}
+
+#[derive(Deserialize)]
+struct SimilarDocumentsResponse {
+    similar: Vec<SimilarDocument>,
+}
+
+/// `GET /overview`: corpus statistics with a written overview.
+#[derive(Clone, PartialEq, Deserialize)]
+struct CorpusOverview {
+    #[serde(default)]
+    overview: Option<String>,
+    documents: usize,
+    chunks: usize,
+    words: usize,
+    topics: Vec<DocumentTopics>,
+    #[serde(default)]
+    warning: Option<String>,
+    generated_at: u64,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct DocumentTopics {
+    name: String,
+    #[serde(default)]
+    summary: Option<String>,
+    chunks: usize,
+    words: usize,
+}
+
+/// One entry of `GET /sessions`.
+#[derive(Clone, PartialEq, Deserialize)]
+struct SessionSummary {
+    id: String,
+    title: String,
+    messages: usize,
+}
+
+#[derive(Deserialize)]
+struct SessionsResponse {
+    sessions: Vec<SessionSummary>,
+}
+
+#[derive(Deserialize)]
+struct SessionMessages {
+    messages: Vec<SessionMessage>,
+}
+
+#[derive(Deserialize)]
+struct SessionMessage {
+    role: String,
+    content: String,
+    #[serde(default)]
+    sources: Vec<String>,
+    created_at: u64,
+}
+
+/// One entry of `GET /collections`.
+#[derive(Clone, PartialEq, Deserialize)]
+struct Collection {
+    name: String,
+    documents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CollectionsResponse {
+    collections: Vec<Collection>,
+}
+
+/// One entry of `GET /tags`.
+#[derive(Clone, PartialEq, Deserialize)]
+struct DocumentTag {
+    name: String,
+    documents: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TagsResponse {
+    tags: Vec<DocumentTag>,
+}
+
+#[derive(Clone, PartialEq, Default, Deserialize)]
+struct CorpusStats {
+    documents: usize,
+    chunks: usize,
+    languages: Vec<LanguageCount>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct LanguageCount {
+    name: String,
+    documents: usize,
+}
+
+/// `GET /health`: whether the backend can generate answers right now.
+#[derive(Clone, PartialEq, Deserialize)]
+struct Health {
+    embedding_ready: bool,
+    ollama: bool,
+    model: String,
+    model_available: bool,
+    model_loaded: bool,
+    documents: usize,
+    chunks: usize,
+}
+
+/// `GET /models`: the models Ollama has pulled.
+#[derive(Clone, PartialEq, Deserialize)]
+struct ModelList {
+    default: String,
+    models: Vec<LlmModel>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct LlmModel {
+    name: String,
+    size: u64,
+    #[serde(default)]
+    parameter_size: String,
+}
+
+/// The backend's error body: `{"error": {...}}`.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+    #[serde(default)]
+    request_id: String,
+    #[serde(default)]
+    retryable: bool,
+}
+
+impl ApiError {
+    /// User-facing text: the message, a retry hint and the id to quote when reporting.
+    fn describe(&self) -> String {
+        let mut text = self.message.clone();
+        if self.retryable {
+            text.push_str(" (temporary, try again)");
+        }
+        if !self.request_id.is_empty() {
+            text.push_str(&format!(" [ref {}]", self.request_id));
+        }
+        text
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct FileProgress {
+    filename: String,
+    stage: String,
+    percent: u8,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+struct UploadJob {
+    status: String,
+    files: Vec<FileProgress>,
+    #[serde(default)]
+    duplicates_skipped: usize,
 }
 
 #[component]
 fn ChatApp() -> Element {
     let mut messages = use_signal(|| Vec::<Message>::new());
     let mut input_value = use_signal(|| String::new());
+    // Questions asked before, oldest first, recalled with ArrowUp/ArrowDown
+    let mut query_history = use_signal(|| {
+        load_setting(QUERY_HISTORY_KEY)
+            .and_then(|saved| serde_json::from_str::<Vec<String>>(&saved).ok())
+            .unwrap_or_default()
+    });
+    // The recalled question's place in the history, and the draft it replaced
+    let mut history_position = use_signal(|| None::<(usize, String)>);
     let mut is_loading = use_signal(|| false);
+    // Aborts the request being answered, for the Stop button
+    let mut in_flight = use_signal(|| None::<web_sys::AbortController>);
     let mut is_listening = use_signal(|| false);
+    // The running speech recognition, so it can be stopped
+    let mut recognition_handle = use_signal(|| None::<wasm_bindgen::JsValue>);
+    // Whether the mic button is still held down, and since when
+    let mut mic_held = use_signal(|| false);
+    let mut mic_pressed_at = use_signal(|| 0.0);
+    let browser_dictation = use_hook(|| speech_recognition_class().is_some());
+    // Where the hands-free voice conversation is, when one is running
+    let mut conversation = use_signal(|| None::<VoicePhase>);
+    // Whether the current input was dictated, so its answer is read aloud as it streams
+    let mut voice_query = use_signal(|| false);
+    let mut answer_length = use_signal(|| "normal".to_string());
+    let mut deep_think = use_signal(|| load_setting(DEEP_THINK_KEY).as_deref() == Some("true"));
+    let mut verify = use_signal(|| load_setting(VERIFY_KEY).as_deref() == Some("true"));
+    // Read every answer aloud, not just those to dictated questions
+    let mut auto_speak = use_signal(|| load_setting(AUTO_SPEAK_KEY).as_deref() == Some("true"));
+    let mut show_timings = use_signal(|| load_setting(TIMINGS_KEY).as_deref() == Some("true"));
+    let mut search_mode =
+        use_signal(|| !DEMO_MODE && load_setting(SEARCH_MODE_KEY).as_deref() == Some("true"));
     let mut documents = use_signal(|| Vec::<DocumentInfo>::new());
+    let mut stats = use_signal(CorpusStats::default);
+    // Progress of the upload under way; how it ended is shown as a toast
     let mut upload_status = use_signal(|| String::new());
+    let toasts = use_signal(Vec::<Toast>::new);
     let mut is_uploading = use_signal(|| false);
+    // Re-uploaded filenames are stored as new versions instead of replacing
+    let mut keep_versions = use_signal(|| false);
+    // The upload section shows the paste box instead of the file picker
+    let mut paste_mode = use_signal(|| false);
+    // Files are being dragged over the upload section
+    let mut drag_over = use_signal(|| false);
+    // The last dropped or picked files that weren't uploaded, with why
+    let mut rejected_uploads = use_signal(Vec::<(String, String)>::new);
+    // What `/upload` accepts, as reported by `GET /config`
+    let mut upload_limits = use_signal(UploadLimits::default);
+    // Title and body of the text being pasted in
+    let mut paste_title = use_signal(String::new);
+    let mut paste_text = use_signal(String::new);
+    // Collection new uploads are filed under; none when empty
+    let mut upload_collection = use_signal(String::new);
+    let mut collections = use_signal(Vec::<Collection>::new);
+    // Collection questions are limited to; every document when empty
+    let mut chat_collection = use_signal(String::new);
+    let mut tags = use_signal(Vec::<DocumentTag>::new);
+    // Category tag questions are limited to; every document when empty
+    let mut chat_tag = use_signal(String::new);
+    let mut upload_progress = use_signal(Vec::<FileProgress>::new);
+    // Index of an earlier answer whose question matches the pending input
+    let mut similar_answer = use_signal(|| None::<usize>);
+    // Latest `/health` reply; `None` while the backend is unreachable
+    let mut health = use_signal(|| None::<Health>);
+    let mut health_checked = use_signal(|| false);
+    // Chunk and word counts shown on each document card, by filename
+    let mut document_stats = use_signal(HashMap::<String, DocumentStats>::new);
+    // Document card whose details are expanded
+    let mut expanded_document = use_signal(|| None::<String>);
+    // The backend's watch folder, if it has one
+    let mut watch_status = use_signal(WatchStatus::default);
+    // Document whose ingestion report is open, with the report or why it failed to load
+    let mut report = use_signal(|| None::<(String, Result<IngestionReport, String>)>);
+    // Documents overlapping with the one whose report is open
+    let mut related = use_signal(Vec::<SimilarDocument>::new);
+    // Document shown in the preview modal, and its text once loaded
+    let mut preview = use_signal(|| None::<(String, Option<Result<DocumentPreview, String>>)>);
+    // The answer and source whose passages are shown under its source tags
+    let mut open_source = use_signal(|| None::<(usize, String)>);
+    // Server-side conversation being continued; a new one starts with the
+    // first question when `None`
+    let mut session_id =
+        use_signal(|| load_setting(SESSION_KEY).filter(|id| !DEMO_MODE && !id.is_empty()));
+    let mut sessions = use_signal(Vec::<SessionSummary>::new);
+    // Conversation being renamed in the sidebar, with the title typed so far
+    let mut renaming = use_signal(|| None::<(String, String)>);
+    // Message whose text was just copied, for the "Copied" confirmation
+    let mut copied = use_signal(|| None::<usize>);
+    // Question being edited in place, with the text typed so far
+    let mut editing = use_signal(|| None::<(usize, String)>);
+    // Answer whose 👎 comment box is open, with the comment typed so far
+    let mut feedback_draft = use_signal(|| None::<(usize, String)>);
+    // Answer whose rating couldn't be saved, with why
+    let mut feedback_error = use_signal(|| None::<(usize, String)>);
+    // The open corpus overview, or why it failed to load
+    let mut overview = use_signal(|| None::<Result<CorpusOverview, String>>);
+    let mut overview_loading = use_signal(|| false);
+    // Documents questions are limited to; every document when empty
+    let mut scope = use_signal(Vec::<String>::new);
+    // Hours a share link stays valid; 0 never expires
+    let mut share_expiry = use_signal(|| 0u64);
+    let mut is_sharing = use_signal(|| false);
+    // The last share link created, or why creating it failed
+    let mut share_result = use_signal(|| None::<Result<String, String>>);
+    let mut show_settings = use_signal(|| false);
+    // Backend URL being typed in the settings panel, and why it was refused
+    let mut backend_draft = use_signal(api::backend_url);
+    let mut backend_error = use_signal(|| None::<String>);
+    let mut api_key = use_signal(|| api::api_key().unwrap_or_default());
+    // Passages retrieved per question; the backend's default when unset
+    let mut top_k = use_signal(|| {
+        load_setting(TOP_K_KEY)
+            .and_then(|value| value.parse::<usize>().ok())
+            .map(|value| value.clamp(1, MAX_TOP_K))
+    });
+    let mut tts_engine =
+        use_signal(|| load_setting(TTS_ENGINE_KEY).unwrap_or_else(|| "browser".to_string()));
+    // Voice and rate answers are read aloud with; the browser's default voice when empty
+    let mut tts_voice = use_signal(|| load_setting(TTS_VOICE_KEY).unwrap_or_default());
+    let mut stt_engine =
+        use_signal(|| load_setting(STT_ENGINE_KEY).unwrap_or_else(|| "browser".to_string()));
+    // Language questions are dictated in; detected when empty
+    let mut stt_lang = use_signal(|| load_setting(STT_LANG_KEY).unwrap_or_default());
+    let mut tts_rate = use_signal(|| {
+        load_setting(TTS_RATE_KEY)
+            .and_then(|rate| rate.parse::<f32>().ok())
+            .unwrap_or(1.0)
+    });
+    let mut tts_pitch = use_signal(|| {
+        load_setting(TTS_PITCH_KEY)
+            .and_then(|pitch| pitch.parse::<f32>().ok())
+            .unwrap_or(1.0)
+    });
+    // The message being read aloud, and whether the readout is paused
+    let mut speaking = use_signal(|| None::<usize>);
+    let mut speech_paused = use_signal(|| false);
+    // Voices the browser offers, listed when the settings panel opens
+    let mut voices = use_signal(Vec::<(String, String)>::new);
+    let mut theme = use_signal(|| load_setting(THEME_KEY).unwrap_or_else(|| "system".to_string()));
+    // Model answers are generated with; the backend's default when empty
+    let mut chat_model = use_signal(|| load_setting(MODEL_KEY).unwrap_or_default());
+    // Models Ollama has pulled, or why listing them failed
+    let mut model_list = use_signal(|| None::<Result<ModelList, String>>);
+    let mut pull_name = use_signal(String::new);
+    let mut is_pulling = use_signal(|| false);
+    // Progress of the running pull, or how the last one ended
+    let mut pull_status = use_signal(|| None::<Result<String, String>>);
+
+    // Load documents already indexed by the backend (they survive restarts)
+    use_future(move || async move {
+        if DEMO_MODE {
+            documents.set(demo::documents());
+            stats.set(demo::stats());
+            return;
+        }
+        load_documents(documents).await;
+        load_upload_limits(upload_limits).await;
+        load_stats(stats).await;
+        load_collections(collections).await;
+        load_tags(tags).await;
+        load_sessions(sessions).await;
+        if let Some(id) = session_id() {
+            match fetch_session_messages(&id).await {
+                Some(restored) => messages.set(restored),
+                None => {
+                    session_id.set(None);
+                    save_setting(SESSION_KEY, "");
+                }
+            }
+        }
+    });
+
+    // Follow the watch folder, picking up documents it adds or removes
+    use_future(move || async move {
+        if DEMO_MODE {
+            return;
+        }
+        let mut first = true;
+        loop {
+            if let Some(current) = fetch_watch_status().await {
+                if current.dir.is_none() {
+                    watch_status.set(current);
+                    return;
+                }
+                let newest = watch_status.peek().events.first().cloned();
+                let changed: Vec<String> = current
+                    .events
+                    .iter()
+                    .take_while(|event| Some(*event) != newest.as_ref())
+                    .map(|event| event.file.clone())
+                    .collect();
+                // The first poll only catches up; the documents are loaded already
+                if !first && !changed.is_empty() {
+                    load_documents(documents).await;
+                    document_stats
+                        .write()
+                        .retain(|name, _| !changed.contains(name));
+                    let expanded = expanded_document.peek().clone();
+                    if let Some(name) = expanded.filter(|name| changed.contains(name)) {
+                        load_document_stats(&name, document_stats).await;
+                    }
+                    load_stats(stats).await;
+                    load_collections(collections).await;
+                    load_tags(tags).await;
+                }
+                first = false;
+                watch_status.set(current);
+            }
+            TimeoutFuture::new(5_000).await;
+        }
+    });
+
+    // Keep the status indicator current
+    use_future(move || async move {
+        if DEMO_MODE {
+            return;
+        }
+        loop {
+            let current = fetch_health().await;
+            // Check again sooner while something is down, so recovery shows quickly
+            let ready = current
+                .as_ref()
+                .is_some_and(|h| h.embedding_ready && h.model_available);
+            if health_checked() {
+                match (health().is_some(), current.is_some()) {
+                    (true, false) => notify(
+                        toasts,
+                        ToastKind::Error,
+                        format!("Lost connection to the backend at {}", api::backend_url()),
+                    ),
+                    (false, true) => {
+                        notify(toasts, ToastKind::Success, "Reconnected to the backend")
+                    }
+                    _ => {}
+                }
+            }
+            health.set(current);
+            health_checked.set(true);
+            TimeoutFuture::new(if ready { 15_000 } else { 5_000 }).await;
+        }
+    });
+
+    // Why a question can't be answered right now, if it can't
+    let send_blocker = move || -> Option<String> {
+        if DEMO_MODE || !health_checked() {
+            return None;
+        }
+        match health() {
+            None => Some(format!(
+                "Backend is not reachable at {}",
+                api::backend_url()
+            )),
+            Some(h) if !h.embedding_ready => {
+                Some("The embedding model is failing; check the backend log".to_string())
+            }
+            // Searching only needs the embeddings, not the model
+            Some(_) if search_mode() && documents().is_empty() => {
+                Some("Upload a document first".to_string())
+            }
+            Some(_) if search_mode() => None,
+            Some(h) if !h.ollama => {
+                Some("Ollama is not running (start it with `ollama serve`)".to_string())
+            }
+            // A model picked in the settings was listed as pulled
+            Some(h) if !h.model_available && chat_model().is_empty() => Some(format!(
+                "Model '{}' is not installed (run `ollama pull {}`)",
+                h.model, h.model
+            )),
+            Some(_) if documents().is_empty() => Some("Upload a document first".to_string()),
+            Some(_) => None,
+        }
+    };
 
     // Auto-scroll to latest message
     use_effect(move || {
@@ -58,549 +843,4746 @@ fn ChatApp() -> Element {
         }
     });
 
-    // TTS function using Web Speech API
-    let speak_text = move |text: String| {
-        if let Some(window) = web_sys::window() {
-            let synthesis = window.speech_synthesis().unwrap();
-            if let Ok(utterance) = web_sys::SpeechSynthesisUtterance::new_with_text(&text) {
-                synthesis.speak(&utterance);
-            }
+    // Reads message `index` aloud, cutting off any other readout
+    let mut speak_message = move |index: usize, text: String| {
+        speaking.set(Some(index));
+        speech_paused.set(false);
+        speak_now(&speakable(&text), move || {
+            speaking.set(None);
+            speech_paused.set(false);
+        });
+    };
+
+    let mut stop_message = move || {
+        stop_speaking();
+        speaking.set(None);
+        speech_paused.set(false);
+    };
+
+    let mut toggle_speech_pause = move || {
+        if speech_paused() {
+            resume_speaking();
+        } else {
+            pause_speaking();
         }
+        speech_paused.toggle();
     };
 
-    // STT function with Web Speech Recognition
+    // Dictated questions are transcribed by the backend when picked in
+    // settings and it has whisper.cpp set up
+    let use_whisper = move || stt_engine() == "whisper" && upload_limits().transcription;
+    let dictation_supported = move || browser_dictation || use_whisper();
+
+    // STT function with Web Speech Recognition. Recognition runs continuously
+    // so a held mic button keeps listening through pauses; a tapped one stops
+    // at the first final result, as before. Recordings for whisper.cpp have
+    // no such end and run until the button is tapped again or released.
     let mut start_listening = move || {
-        let mut is_listening_clone = is_listening.clone();
-        let mut input_value_clone = input_value.clone();
+        if use_whisper() {
+            is_listening.set(true);
+            input_value.set(String::new());
+            spawn(async move {
+                let heard = match record_audio(recognition_handle).await {
+                    Ok(audio) => {
+                        transcribe_audio(audio, &load_setting(STT_LANG_KEY).unwrap_or_default())
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match heard {
+                    Ok(text) => {
+                        input_value.set(text);
+                        voice_query.set(true);
+                    }
+                    Err(e) => {
+                        // Listening again would only fail again
+                        conversation.set(None);
+                        notify(toasts, ToastKind::Error, e);
+                    }
+                }
+                recognition_handle.set(None);
+                mic_held.set(false);
+                is_listening.set(false);
+            });
+            return;
+        }
+        let Some(func) = speech_recognition_class() else {
+            return;
+        };
+        let Ok(recognition) = js_sys::Reflect::construct(&func, &js_sys::Array::new()) else {
+            return;
+        };
 
-        is_listening_clone.set(true);
+        // Set properties
+        let _ = js_sys::Reflect::set(&recognition, &"continuous".into(), &true.into());
+        let _ = js_sys::Reflect::set(&recognition, &"interimResults".into(), &true.into());
+        if let Some(lang) = load_setting(STT_LANG_KEY).filter(|lang| !lang.is_empty()) {
+            let _ = js_sys::Reflect::set(&recognition, &"lang".into(), &lang.into());
+        }
 
-        spawn(async move {
-            if let Some(window) = web_sys::window() {
-                // Try webkit prefixed version
-                let recognition_result =
-                    js_sys::Reflect::get(&window, &"webkitSpeechRecognition".into())
-                        .or_else(|_| js_sys::Reflect::get(&window, &"SpeechRecognition".into()));
-
-                if let Ok(recognition_class) = recognition_result {
-                    if let Ok(func) = recognition_class.dyn_into::<js_sys::Function>() {
-                        if let Ok(recognition) =
-                            js_sys::Reflect::construct(&func, &js_sys::Array::new())
-                        {
-                            // Set properties
-                            let _ = js_sys::Reflect::set(
-                                &recognition,
-                                &"continuous".into(),
-                                &false.into(),
-                            );
-                            let _ = js_sys::Reflect::set(
-                                &recognition,
-                                &"interimResults".into(),
-                                &false.into(),
-                            );
+        // Write the transcript so far into the input as it is heard
+        let onresult =
+            wasm_bindgen::closure::Closure::wrap(Box::new(move |event: web_sys::Event| {
+                let (text, finished) = transcript(&event);
+                input_value.set(text);
+                voice_query.set(true);
+                if finished && !mic_held() {
+                    if let Some(recognition) = recognition_handle() {
+                        call_method(&recognition, "stop");
+                    }
+                }
+            }) as Box<dyn FnMut(web_sys::Event)>);
+        let _ = js_sys::Reflect::set(&recognition, &"onresult".into(), onresult.as_ref());
 
-                            // Create onresult callback
-                            let mut input_clone = input_value_clone.clone();
-                            let mut listening_clone = is_listening_clone.clone();
-                            let onresult = wasm_bindgen::closure::Closure::wrap(Box::new(
-                                move |event: web_sys::Event| {
-                                    if let Ok(results) =
-                                        js_sys::Reflect::get(&event, &"results".into())
-                                    {
-                                        if let Ok(result) =
-                                            js_sys::Reflect::get(&results, &0.into())
-                                        {
-                                            if let Ok(alternative) =
-                                                js_sys::Reflect::get(&result, &0.into())
-                                            {
-                                                if let Ok(transcript) = js_sys::Reflect::get(
-                                                    &alternative,
-                                                    &"transcript".into(),
-                                                ) {
-                                                    if let Some(text) = transcript.as_string() {
-                                                        input_clone.set(text);
-                                                        listening_clone.set(false);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                },
-                            )
-                                as Box<dyn FnMut(web_sys::Event)>);
+        // A conversation can't go on without the microphone
+        let onerror = wasm_bindgen::closure::Closure::wrap(Box::new(move |event: web_sys::Event| {
+            let error = js_sys::Reflect::get(&event, &"error".into())
+                .ok()
+                .and_then(|error| error.as_string())
+                .unwrap_or_default();
+            if conversation().is_some()
+                && matches!(
+                    error.as_str(),
+                    "not-allowed" | "service-not-allowed" | "audio-capture" | "network"
+                )
+            {
+                conversation.set(None);
+                notify(
+                    toasts,
+                    ToastKind::Error,
+                    format!("Voice conversation ended: speech recognition failed ({error})"),
+                );
+            }
+        })
+            as Box<dyn FnMut(web_sys::Event)>);
+        let _ = js_sys::Reflect::set(&recognition, &"onerror".into(), onerror.as_ref());
 
-                            let _ = js_sys::Reflect::set(
-                                &recognition,
-                                &"onresult".into(),
-                                onresult.as_ref(),
-                            );
+        // Errors are followed by `end`, which resets the button
+        let onend = wasm_bindgen::closure::Closure::wrap(Box::new(move |_event: web_sys::Event| {
+            is_listening.set(false);
+            mic_held.set(false);
+            recognition_handle.set(None);
+        })
+            as Box<dyn FnMut(web_sys::Event)>);
+        let _ = js_sys::Reflect::set(&recognition, &"onend".into(), onend.as_ref());
 
-                            // Create onerror callback
-                            let mut listening_clone2 = is_listening_clone.clone();
-                            let onerror = wasm_bindgen::closure::Closure::wrap(Box::new(
-                                move |_event: web_sys::Event| {
-                                    listening_clone2.set(false);
-                                },
-                            )
-                                as Box<dyn FnMut(web_sys::Event)>);
+        // Keep closures alive
+        onresult.forget();
+        onerror.forget();
+        onend.forget();
 
-                            let _ = js_sys::Reflect::set(
-                                &recognition,
-                                &"onerror".into(),
-                                onerror.as_ref(),
-                            );
+        input_value.set(String::new());
+        call_method(&recognition, "start");
+        recognition_handle.set(Some(recognition));
+        is_listening.set(true);
+    };
 
-                            // Start recognition
-                            if let Ok(start_fn) =
-                                js_sys::Reflect::get(&recognition, &"start".into())
-                            {
-                                let _ = js_sys::Reflect::apply(
-                                    &start_fn.dyn_into::<js_sys::Function>().unwrap(),
-                                    &recognition,
-                                    &js_sys::Array::new(),
-                                );
-                            }
+    let stop_listening = move || {
+        if let Some(recognition) = recognition_handle() {
+            call_method(&recognition, "stop");
+        }
+    };
 
-                            // Keep closures alive
-                            onresult.forget();
-                            onerror.forget();
-                        }
-                    }
-                } else {
-                    // Fallback if speech recognition not available
-                    is_listening_clone.set(false);
-                }
-            }
-        });
+    // Pressing the mic starts listening, or stops a tapped session still
+    // running. Releasing it after a hold stops listening; releasing a tap
+    // leaves it running until the question is finished.
+    let mut press_mic = move || {
+        if is_listening() {
+            stop_listening();
+        } else {
+            start_listening();
+            mic_held.set(true);
+            mic_pressed_at.set(js_sys::Date::now());
+        }
+    };
+
+    let mut release_mic = move || {
+        if !mic_held() {
+            return;
+        }
+        mic_held.set(false);
+        if js_sys::Date::now() - mic_pressed_at() >= PUSH_TO_TALK_MS {
+            stop_listening();
+        }
     };
 
     // Chat handler
-    let handle_send = move || {
+    let mut send_now = move |query: String| {
+        similar_answer.set(None);
+        history_position.set(None);
+        if !query.is_empty() {
+            let mut history = query_history.write();
+            history.retain(|earlier| *earlier != query);
+            history.push(query.clone());
+            let excess = history.len().saturating_sub(MAX_QUERY_HISTORY);
+            history.drain(..excess);
+            if let Ok(saved) = serde_json::to_string(&*history) {
+                save_setting(QUERY_HISTORY_KEY, &saved);
+            }
+        }
         spawn({
             let mut messages = messages.clone();
-            let mut input_value = input_value.clone();
             let mut is_loading = is_loading.clone();
+            let speak_answer = voice_query() || auto_speak();
+            voice_query.set(false);
+            let controller = web_sys::AbortController::new().ok();
+            in_flight.set(controller.clone());
+            let options = ChatOptions {
+                length: answer_length(),
+                deep_think: deep_think(),
+                verify: verify(),
+                search: search_mode(),
+                sources: scope(),
+                collection: chat_collection(),
+                tags: Some(chat_tag())
+                    .filter(|tag| !tag.is_empty())
+                    .into_iter()
+                    .collect(),
+                session_id: None,
+                abort: controller.as_ref().map(|controller| controller.signal()),
+                debug: show_timings(),
+                model: chat_model(),
+                top_k: top_k(),
+            };
             async move {
-                send_message(messages, input_value, is_loading).await;
-            }
-        });
-    };
-
-    // Delete document handler
-    let handle_delete = move |filename: String| {
-        spawn({
-            let mut documents = documents.clone();
-            let mut upload_status = upload_status.clone();
-            async move {
-                match Request::post("http://localhost:3000/delete")
-                    .json(&json!({ "filename": filename }))
-                    .unwrap()
-                    .send()
-                    .await
-                {
-                    Ok(resp) => {
-                        if resp.ok() {
-                            documents.write().retain(|doc| doc.filename != filename);
-                            upload_status.set(format!("✅ Removed {}", filename));
-                        } else {
-                            upload_status.set(format!("❌ Failed to delete {}", filename));
-                        }
+                let mut options = options;
+                if !DEMO_MODE && !options.search {
+                    options.session_id = ensure_session(session_id).await;
+                }
+                let saved = options.session_id.is_some();
+                // The answer follows the question about to be added
+                let readout = speak_answer.then(|| {
+                    stop_speaking();
+                    speaking.set(Some(messages.read().len() + 1));
+                    speech_paused.set(false);
+                    speech_id()
+                });
+                send_message(messages, query, is_loading, options, speak_answer, toasts).await;
+                if in_flight() == controller {
+                    in_flight.set(None);
+                }
+                if let Some(readout) = readout {
+                    if conversation().is_some() {
+                        conversation.set(Some(VoicePhase::Speaking));
                     }
-                    Err(_) => {
-                        upload_status.set("❌ Failed to connect to backend".to_string());
+                    speech_finished(readout).await;
+                    if speech_id() == readout {
+                        speaking.set(None);
+                        speech_paused.set(false);
                     }
                 }
+                if conversation().is_some() {
+                    conversation.set(Some(VoicePhase::Listening));
+                    start_listening();
+                }
+                if saved {
+                    load_sessions(sessions).await;
+                }
             }
         });
     };
 
-    let handle_keydown = move |evt: Event<KeyboardData>| {
-        if evt.key() == Key::Enter && !evt.modifiers().shift() {
-            evt.prevent_default();
-            handle_send();
+    // Abandons the answer being generated; whatever streamed so far is kept
+    let mut stop_sending = move || {
+        if let Some(controller) = in_flight() {
+            controller.abort();
         }
+        in_flight.set(None);
+        is_loading.set(false);
     };
 
-    rsx! {
-        div {
-            class: "app-container",
+    let mut send_input = move || {
+        let query = input_value().trim().to_string();
+        input_value.set(String::new());
+        send_now(query);
+    };
 
-            // Header
-            header {
-                class: "header",
-                h1 { "❄️ Ahtohallan" }
-                p { class: "subtitle", "RAG-Powered Document Chat" }
-            }
+    // Hands-free conversation: when listening ends, send what was heard, or
+    // listen again after silence; `send_now` resumes listening once the
+    // answer has been read out
+    use_effect(move || {
+        if conversation() != Some(VoicePhase::Listening) || is_listening() {
+            return;
+        }
+        if input_value.peek().trim().is_empty() {
+            start_listening();
+        } else {
+            conversation.set(Some(VoicePhase::Thinking));
+            send_input();
+        }
+    });
 
-            // Main content
-            div {
-                class: "main-content",
+    let mut start_conversation = move || {
+        stop_message();
+        conversation.set(Some(VoicePhase::Listening));
+        start_listening();
+    };
 
-                // Upload section with document management
-                div {
-                    class: "upload-section",
-                    h2 { "📁 Document Upload" }
+    let mut end_conversation = move || {
+        conversation.set(None);
+        stop_listening();
+        stop_message();
+        if is_loading() {
+            stop_sending();
+        }
+    };
 
-                    div {
-                        class: "upload-controls",
-                        input {
-                            r#type: "file",
-                            accept: ".md,.pdf",
-                            multiple: true,
-                            id: "file-upload",
-                            style: "display: none;",
-                            onchange: move |_| {
-                                let mut documents_clone = documents.clone();
-                                let mut upload_status_clone = upload_status.clone();
-                                let mut is_uploading_clone = is_uploading.clone();
-
-                                spawn(async move {
-                                    is_uploading_clone.set(true);
-                                    upload_status_clone.set("📤 Uploading files...".to_string());
-
-                                    // Get the file input element
-                                    if let Some(window) = web_sys::window() {
-                                        if let Some(document) = window.document() {
-                                            if let Some(element) = document.get_element_by_id("file-upload") {
-                                                if let Ok(input) = element.dyn_into::<HtmlInputElement>() {
-                                                    if let Some(files) = input.files() {
-                                                        let file_count = files.length();
-
-                                                        if file_count == 0 {
-                                                            upload_status_clone.set("❌ No files selected".to_string());
-                                                            is_uploading_clone.set(false);
-                                                            return;
-                                                        }
+    // Offer an earlier answer before spending an LLM round-trip on a repeat question
+    let mut handle_send = move || {
+        if similar_answer().is_none() && !search_mode() {
+            if let Some(index) = find_similar_answer(&messages(), &input_value()) {
+                similar_answer.set(Some(index));
+                return;
+            }
+        }
+        send_input();
+    };
 
-                                                        // Create FormData with all files
-                                                        if let Ok(form_data) = FormData::new() {
-                                                            let mut filenames = Vec::new();
+    // Drops message `index` and everything after it, here and in the saved
+    // session, then asks `query` in its place
+    let mut resend_from = move |index: usize, query: String| {
+        // Every answer in the session was saved together with its question
+        let saved_turns = messages.read()[..index]
+            .iter()
+            .filter(|msg| msg.role == "assistant")
+            .count();
+        messages.write().truncate(index);
+        editing.set(None);
+        let id = session_id();
+        spawn(async move {
+            if let Some(id) = id {
+                truncate_session(&id, saved_turns * 2).await;
+            }
+            send_now(query);
+        });
+    };
 
-                                                            for i in 0..file_count {
-                                                                if let Some(file) = files.get(i) {
-                                                                    let filename = file.name();
-                                                                    filenames.push(filename.clone());
-                                                                    let _ = form_data.append_with_blob("files", &file);
-                                                                }
+    let mut regenerate = move |index: usize| {
+        let question = messages.read()[..index]
+            .iter()
+            .rposition(|msg| msg.role == "user");
+        if let Some(question) = question {
+            let query = messages.read()[question].content.clone();
+            resend_from(question, query);
+        }
+    };
+
+    // Records the rating of the answer at `index` along with the question it
+    // answered; the buttons are re-enabled if the backend can't store it
+    let mut rate_answer = move |index: usize, up: bool, comment: Option<String>| {
+        feedback_draft.set(None);
+        feedback_error.set(None);
+        let (query, answer, sources) = {
+            let messages = messages.read();
+            let query = messages[..index]
+                .iter()
+                .rfind(|msg| msg.role == "user")
+                .map(|msg| msg.content.clone())
+                .unwrap_or_default();
+            let answer = &messages[index];
+            (query, answer.content.clone(), answer.sources.clone())
+        };
+        if let Some(msg) = messages.write().get_mut(index) {
+            msg.rating = Some(up);
+        }
+        if DEMO_MODE {
+            return;
+        }
+        let session = session_id();
+        spawn(async move {
+            let sent = send_feedback(&query, &answer, &sources, up, comment, session).await;
+            if let Err(e) = sent {
+                feedback_error.set(Some((index, e)));
+                if let Some(msg) = messages.write().get_mut(index) {
+                    msg.rating = None;
+                }
+            }
+        });
+    };
+
+    let mut commit_edit = move || {
+        let Some((index, query)) = editing() else {
+            return;
+        };
+        let query = query.trim().to_string();
+        if !query.is_empty() {
+            resend_from(index, query);
+        }
+    };
+
+    let mut show_previous_answer = move |index: usize| {
+        let previous = messages.read().get(index).cloned();
+        if let Some(previous) = previous {
+            let query = input_value().trim().to_string();
+            messages.write().push(Message {
+                role: "user".to_string(),
+                content: query,
+                sources: vec![],
+                warning: None,
+                steps: vec![],
+                citations: vec![],
+                grounding: None,
+                timings: None,
+                rating: None,
+                created_at: unix_now(),
+                response_ms: None,
+            });
+            messages.write().push(previous);
+            input_value.set(String::new());
+            voice_query.set(false);
+        }
+        similar_answer.set(None);
+    };
+
+    // Delete document handler
+    let mut handle_report = move |filename: String| {
+        if report().is_some_and(|(open, _)| open == filename) {
+            report.set(None);
+            return;
+        }
+        spawn(async move {
+            let result = fetch_report(&filename).await;
+            related.set(fetch_similar_documents(&filename).await);
+            report.set(Some((filename, result)));
+        });
+    };
+
+    // Opens the preview of a document's indexed text, loading it meanwhile
+    let mut preview_document = move |filename: String| {
+        preview.set(Some((filename.clone(), None)));
+        spawn(async move {
+            let result = fetch_preview(&filename).await;
+            // Unless it was closed, or another document opened, while loading
+            if preview().is_some_and(|(open, _)| open == filename) {
+                preview.set(Some((filename, Some(result))));
+            }
+        });
+    };
+
+    let mut toggle_settings = move || {
+        show_settings.toggle();
+        if show_settings() {
+            voices.set(voice_options());
+            spawn(async move {
+                model_list.set(Some(fetch_models().await));
+            });
+        }
+    };
+
+    // Switches to the backend typed in the settings panel. The page reloads,
+    // so documents, conversations and health all come from the new one.
+    let mut save_backend_url = move || match api::set_backend_url(&backend_draft()) {
+        Ok(()) => {
+            if let Some(window) = web_sys::window() {
+                let _ = window.location().reload();
+            }
+        }
+        Err(error) => backend_error.set(Some(error)),
+    };
+
+    let mut start_pull = move || {
+        let name = pull_name().trim().to_string();
+        if name.is_empty() || is_pulling() {
+            return;
+        }
+        is_pulling.set(true);
+        pull_status.set(Some(Ok(format!("⬇️ Pulling {}...", name))));
+        spawn(async move {
+            match pull_model(&name, pull_status).await {
+                Ok(()) => {
+                    pull_status.set(Some(Ok(format!("✅ Pulled {}", name))));
+                    pull_name.set(String::new());
+                    model_list.set(Some(fetch_models().await));
+                }
+                Err(error) => pull_status.set(Some(Err(error))),
+            }
+            is_pulling.set(false);
+        });
+    };
+
+    let mut handle_overview = move |refresh: bool| {
+        overview_loading.set(true);
+        spawn(async move {
+            overview.set(Some(fetch_overview(refresh).await));
+            overview_loading.set(false);
+        });
+    };
+
+    let mut open_session = move |id: String| {
+        if id.is_empty() {
+            session_id.set(None);
+            save_setting(SESSION_KEY, "");
+            messages.set(Vec::new());
+            return;
+        }
+        spawn(async move {
+            if let Some(restored) = fetch_session_messages(&id).await {
+                messages.set(restored);
+                save_setting(SESSION_KEY, &id);
+                session_id.set(Some(id));
+            }
+        });
+    };
+
+    let mut commit_rename = move || {
+        let Some((id, title)) = renaming() else {
+            return;
+        };
+        renaming.set(None);
+        spawn(async move {
+            rename_session(&id, &title).await;
+            load_sessions(sessions).await;
+        });
+    };
+
+    let mut handle_delete_session = move |id: String| {
+        let confirmed = web_sys::window()
+            .and_then(|window| {
+                window
+                    .confirm_with_message("Delete this conversation?")
+                    .ok()
+            })
+            .unwrap_or(false);
+        if !confirmed {
+            return;
+        }
+        spawn(async move {
+            delete_session(&id).await;
+            if session_id().as_deref() == Some(id.as_str()) {
+                open_session(String::new());
+            }
+            load_sessions(sessions).await;
+        });
+    };
+
+    // Title of the open conversation, for exports
+    let conversation_title = move || {
+        session_id()
+            .and_then(|id| sessions().into_iter().find(|session| session.id == id))
+            .map(|session| session.title)
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| "Ahtohallan conversation".to_string())
+    };
+
+    let handle_export = move |markdown: bool| {
+        let title = conversation_title();
+        if markdown {
+            download_file(
+                &export_filename(&title, "md"),
+                "text/markdown",
+                &conversation_markdown(&title, &messages()),
+            );
+        } else {
+            download_file(
+                &export_filename(&title, "json"),
+                "application/json",
+                &conversation_json(&title, &messages()),
+            );
+        }
+    };
+
+    let mut handle_share = move || {
+        is_sharing.set(true);
+        spawn(async move {
+            let result = share_conversation(&messages(), share_expiry()).await;
+            share_result.set(Some(result));
+            is_sharing.set(false);
+        });
+    };
+
+    let mut toggle_scope = move |filename: String| {
+        let mut scope = scope.write();
+        if let Some(position) = scope.iter().position(|name| *name == filename) {
+            scope.remove(position);
+        } else {
+            scope.push(filename);
+        }
+    };
+
+    let handle_delete = move |filename: String| {
+        spawn({
+            let mut documents = documents.clone();
+            async move {
+                match api::post("/delete")
+                    .json(&json!({ "filename": filename }))
+                    .unwrap()
+                    .send()
+                    .await
+                {
+                    Ok(resp) => {
+                        if resp.ok() {
+                            documents.write().retain(|doc| doc.filename != filename);
+                            document_stats.write().remove(&filename);
+                            scope.write().retain(|name| *name != filename);
+                            notify(toasts, ToastKind::Success, format!("Removed {}", filename));
+                            load_stats(stats).await;
+                            load_collections(collections).await;
+                            if !collections
+                                .read()
+                                .iter()
+                                .any(|c| c.name == chat_collection())
+                            {
+                                chat_collection.set(String::new());
+                            }
+                            load_tags(tags).await;
+                            if !tags.read().iter().any(|t| t.name == chat_tag()) {
+                                chat_tag.set(String::new());
+                            }
+                        } else {
+                            let body = resp.text().await.unwrap_or_default();
+                            notify(
+                                toasts,
+                                ToastKind::Error,
+                                format!(
+                                    "Failed to delete {}: {}",
+                                    filename,
+                                    error_text(&body, resp.status())
+                                ),
+                            );
+                        }
+                    }
+                    Err(_) => {
+                        notify(toasts, ToastKind::Error, "Failed to connect to backend");
+                    }
+                }
+            }
+        });
+    };
+
+    // Follows a queued upload job of `count` files to the end, then lists
+    // what it indexed and sums up how it went; true when nothing failed
+    let track_upload = move |job_id: String, count: usize| async move {
+        upload_status.set(format!("⚙️ Processing {} file(s)...", count));
+
+        match poll_upload_job(&job_id, upload_progress).await {
+            Ok(job) => {
+                // Add indexed files to documents list
+                let indexed: Vec<String> = job
+                    .files
+                    .iter()
+                    .filter(|f| f.stage == "done")
+                    .map(|f| f.filename.clone())
+                    .collect();
+                load_documents(documents).await;
+                document_stats
+                    .write()
+                    .retain(|name, _| !indexed.contains(name));
+                let expanded = expanded_document.peek().clone();
+                if let Some(name) = expanded.filter(|name| indexed.contains(name)) {
+                    load_document_stats(&name, document_stats).await;
+                }
+                load_stats(stats).await;
+                load_collections(collections).await;
+                load_tags(tags).await;
+
+                let unchanged = job.files.iter().filter(|f| f.stage == "unchanged").count();
+                let failed = job.files.len() - indexed.len() - unchanged;
+                let mut notes = String::new();
+                if unchanged > 0 {
+                    notes.push_str(&format!(" ({} unchanged, already indexed)", unchanged));
+                }
+                if job.duplicates_skipped > 0 {
+                    notes.push_str(&format!(
+                        " ({} duplicate chunks skipped)",
+                        job.duplicates_skipped
+                    ));
+                }
+                if failed == 0 && indexed.is_empty() {
+                    notify(
+                        toasts,
+                        ToastKind::Info,
+                        format!("All {} file(s) already indexed", unchanged),
+                    );
+                } else if failed == 0 {
+                    notify(
+                        toasts,
+                        ToastKind::Success,
+                        format!("Successfully uploaded {} file(s){}", indexed.len(), notes),
+                    );
+                } else if indexed.is_empty() && unchanged == 0 {
+                    notify(
+                        toasts,
+                        ToastKind::Error,
+                        "Upload failed: no files could be indexed",
+                    );
+                } else {
+                    notify(
+                        toasts,
+                        ToastKind::Error,
+                        format!(
+                            "Uploaded {} file(s), {} failed{}",
+                            indexed.len(),
+                            failed,
+                            notes
+                        ),
+                    );
+                }
+                failed == 0
+            }
+            Err(e) => {
+                notify(toasts, ToastKind::Error, format!("Upload failed: {}", e));
+                false
+            }
+        }
+    };
+
+    // Uploads the files among `files` the backend takes, listing them as
+    // queued until it does, and says why the others were left out
+    let mut upload_files = move |files: Vec<web_sys::File>| {
+        let limits = upload_limits();
+        let limit = limits.max_upload_mb as f64 * 1024.0 * 1024.0;
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+        let mut total = 0.0;
+        for file in files {
+            let name = file.name().to_lowercase();
+            let problem = if !limits
+                .upload_extensions
+                .iter()
+                .any(|extension| name.ends_with(extension.as_str()))
+            {
+                Some("this file type can't be indexed".to_string())
+            } else if file.size() > limit {
+                Some(format!(
+                    "{} is over the {} MB limit",
+                    format_megabytes(file.size()),
+                    limits.max_upload_mb
+                ))
+            } else if total + file.size() > limit {
+                Some(format!(
+                    "the files together would be over the {} MB limit; upload it separately",
+                    limits.max_upload_mb
+                ))
+            } else {
+                None
+            };
+            match problem {
+                Some(problem) => rejected.push((file.name(), problem)),
+                None => {
+                    total += file.size();
+                    accepted.push(file);
+                }
+            }
+        }
+        rejected_uploads.set(rejected);
+        if accepted.is_empty() {
+            notify(
+                toasts,
+                ToastKind::Error,
+                "None of the files can be uploaded",
+            );
+            return;
+        }
+        upload_progress.set(
+            accepted
+                .iter()
+                .map(|file| FileProgress {
+                    filename: file.name(),
+                    stage: "queued".to_string(),
+                    percent: 0,
+                    error: None,
+                })
+                .collect(),
+        );
+        let on_conflict = if keep_versions() {
+            "version"
+        } else {
+            "replace"
+        };
+        let collection = upload_collection().trim().to_string();
+
+        spawn(async move {
+            is_uploading.set(true);
+            upload_status.set("📤 Uploading files...".to_string());
+
+            match FormData::new() {
+                Ok(form_data) => {
+                    for file in &accepted {
+                        let _ = form_data.append_with_blob("files", file);
+                    }
+                    match upload_files_formdata(&form_data, on_conflict, &collection, upload_status)
+                        .await
+                    {
+                        Ok(job_id) => {
+                            track_upload(job_id, accepted.len()).await;
+                        }
+                        Err(e) => {
+                            notify(toasts, ToastKind::Error, format!("Upload failed: {}", e));
+                        }
+                    }
+                }
+                Err(_) => {
+                    notify(toasts, ToastKind::Error, "Failed to create form data");
+                }
+            }
+
+            is_uploading.set(false);
+            upload_status.set(String::new());
+        });
+    };
+
+    // Indexes the pasted text as a document named after its title
+    let handle_paste = move |_| {
+        let title = paste_title().trim().to_string();
+        let text = paste_text();
+        if title.is_empty() || text.trim().is_empty() {
+            return;
+        }
+        let on_conflict = if keep_versions() {
+            "version"
+        } else {
+            "replace"
+        };
+        let collection = upload_collection().trim().to_string();
+        let body = json!({
+            "title": title,
+            "text": text,
+            "on_conflict": on_conflict,
+            "collection": (!collection.is_empty()).then_some(collection),
+        });
+        spawn(async move {
+            is_uploading.set(true);
+            upload_progress.set(Vec::new());
+            upload_status.set("📤 Uploading text...".to_string());
+
+            match api::post("/ingest_text").json(&body).unwrap().send().await {
+                Ok(resp) if resp.ok() => {
+                    let job_id = resp
+                        .json::<serde_json::Value>()
+                        .await
+                        .ok()
+                        .and_then(|body| body["job_id"].as_str().map(str::to_string));
+                    match job_id {
+                        Some(job_id) => {
+                            if track_upload(job_id, 1).await {
+                                paste_title.set(String::new());
+                                paste_text.set(String::new());
+                            }
+                        }
+                        None => notify(
+                            toasts,
+                            ToastKind::Error,
+                            "Upload failed: Missing job id in response",
+                        ),
+                    }
+                }
+                Ok(resp) => {
+                    let body = resp.text().await.unwrap_or_default();
+                    notify(
+                        toasts,
+                        ToastKind::Error,
+                        format!("Upload failed: {}", error_text(&body, resp.status())),
+                    );
+                }
+                Err(_) => {
+                    notify(toasts, ToastKind::Error, "Failed to connect to backend");
+                }
+            }
+
+            is_uploading.set(false);
+            upload_status.set(String::new());
+        });
+    };
+
+    let handle_reset = move |_| {
+        let confirmed = web_sys::window()
+            .and_then(|window| {
+                window
+                    .confirm_with_message(&format!(
+                        "Remove all {} documents? This can't be undone.",
+                        documents.read().len()
+                    ))
+                    .ok()
+            })
+            .unwrap_or(false);
+        if !confirmed {
+            return;
+        }
+        spawn(async move {
+            match api::post("/reset")
+                .json(&json!({ "confirm": true }))
+                .unwrap()
+                .send()
+                .await
+            {
+                Ok(resp) if resp.ok() => {
+                    documents.set(Vec::new());
+                    document_stats.write().clear();
+                    scope.write().clear();
+                    overview.set(None);
+                    chat_collection.set(String::new());
+                    chat_tag.set(String::new());
+                    notify(toasts, ToastKind::Success, "Removed all documents");
+                    load_stats(stats).await;
+                    load_collections(collections).await;
+                    load_tags(tags).await;
+                }
+                Ok(resp) => {
+                    let body = resp.text().await.unwrap_or_default();
+                    notify(
+                        toasts,
+                        ToastKind::Error,
+                        format!(
+                            "Failed to remove documents: {}",
+                            error_text(&body, resp.status())
+                        ),
+                    );
+                }
+                Err(_) => {
+                    notify(toasts, ToastKind::Error, "Failed to connect to backend");
+                }
+            }
+        });
+    };
+
+    let handle_keydown = move |evt: Event<KeyboardData>| {
+        if evt.key() == Key::Enter && !evt.modifiers().shift() {
+            evt.prevent_default();
+            if send_blocker().is_none() {
+                handle_send();
+            }
+            return;
+        }
+
+        // Like a shell: ArrowUp on the first line recalls the previous
+        // question, ArrowDown on the last line the next one, then the draft
+        let up = evt.key() == Key::ArrowUp;
+        if !up && evt.key() != Key::ArrowDown {
+            return;
+        }
+        let Some(textarea) = evt
+            .data()
+            .try_as_web_event()
+            .and_then(|event| event.target())
+            .and_then(|target| target.dyn_into::<web_sys::HtmlTextAreaElement>().ok())
+        else {
+            return;
+        };
+        let value = textarea.value();
+        // In UTF-16 code units, as the browser counts them
+        let caret = textarea.selection_start().ok().flatten().unwrap_or(0) as usize;
+        let newline = '\n' as u16;
+        let history = query_history();
+        if up {
+            if value.encode_utf16().take(caret).any(|unit| unit == newline) {
+                return;
+            }
+            let (previous, draft) = match history_position() {
+                Some((position, draft)) => (position.checked_sub(1), draft),
+                None => (history.len().checked_sub(1), value),
+            };
+            if let Some(previous) = previous {
+                evt.prevent_default();
+                input_value.set(history[previous].clone());
+                history_position.set(Some((previous, draft)));
+            }
+        } else if let Some((position, draft)) = history_position() {
+            if value.encode_utf16().skip(caret).any(|unit| unit == newline) {
+                return;
+            }
+            evt.prevent_default();
+            if let Some(next) = history.get(position + 1) {
+                input_value.set(next.clone());
+                history_position.set(Some((position + 1, draft)));
+            } else {
+                input_value.set(draft);
+                history_position.set(None);
+            }
+        }
+    };
+
+    rsx! {
+        div {
+            class: "app-container",
+
+            // Header
+            header {
+                class: "header",
+                if !DEMO_MODE {
+                    StatusIndicator { health: health(), checked: health_checked() }
+                    button {
+                        class: if show_settings() { "settings-toggle active" } else { "settings-toggle" },
+                        title: "Settings",
+                        onclick: move |_| toggle_settings(),
+                        "⚙️"
+                    }
+                }
+                h1 { "❄️ Ahtohallan" }
+                p { class: "subtitle", "RAG-Powered Document Chat" }
+                if DEMO_MODE {
+                    p { class: "demo-banner", "🧪 Offline demo: answers come from bundled sample documents" }
+                }
+            }
+
+            if show_settings() {
+                section {
+                    class: "settings-panel",
+                    h2 { "⚙️ Settings" }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "backend-url", "Backend URL" }
+                        input {
+                            id: "backend-url",
+                            class: "backend-url-input",
+                            placeholder: api::DEFAULT_BACKEND_URL,
+                            value: "{backend_draft}",
+                            oninput: move |evt| {
+                                backend_draft.set(evt.value());
+                                backend_error.set(None);
+                            },
+                            onkeydown: move |evt| {
+                                if evt.key() == Key::Enter {
+                                    save_backend_url();
+                                }
+                            },
+                        }
+                        button {
+                            class: "pull-button",
+                            title: "Use this backend and reload",
+                            disabled: backend_draft().trim().trim_end_matches('/') == api::backend_url(),
+                            onclick: move |_| save_backend_url(),
+                            "💾 Save"
+                        }
+                    }
+                    if let Some(error) = backend_error() {
+                        p { class: "settings-error", "❌ {error}" }
+                    } else {
+                        p { class: "settings-hint", "Leave it empty for {api::DEFAULT_BACKEND_URL}. The backend's cors_origins must include this page's origin." }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "api-key", "API key" }
+                        input {
+                            id: "api-key",
+                            class: "api-key-input",
+                            r#type: "password",
+                            autocomplete: "off",
+                            placeholder: "Only if the backend sets api_keys",
+                            value: "{api_key}",
+                            oninput: move |evt| {
+                                api::set_api_key(&evt.value());
+                                api_key.set(evt.value());
+                            },
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "model-picker", "Answer model" }
+                        match model_list() {
+                            None => rsx! { span { class: "settings-hint", "Loading models..." } },
+                            Some(Err(error)) => rsx! { span { class: "settings-error", "❌ {error}" } },
+                            Some(Ok(list)) => rsx! {
+                                select {
+                                    id: "model-picker",
+                                    class: "model-picker",
+                                    disabled: is_loading(),
+                                    onchange: move |evt| {
+                                        save_setting(MODEL_KEY, &evt.value());
+                                        chat_model.set(evt.value());
+                                    },
+                                    option { value: "", selected: chat_model().is_empty(), "Backend default ({list.default})" }
+                                    if !chat_model().is_empty() && !list.models.iter().any(|model| model.name == chat_model()) {
+                                        option { value: "{chat_model}", selected: true, "{chat_model} (not pulled)" }
+                                    }
+                                    for model in list.models.iter() {
+                                        option {
+                                            value: "{model.name}",
+                                            selected: chat_model() == model.name,
+                                            if model.parameter_size.is_empty() {
+                                                "{model.name} · {format_gigabytes(model.size)}"
+                                            } else {
+                                                "{model.name} · {model.parameter_size} · {format_gigabytes(model.size)}"
+                                            }
+                                        }
+                                    }
+                                }
+                            },
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "pull-model", "Pull a model" }
+                        input {
+                            id: "pull-model",
+                            class: "pull-input",
+                            placeholder: "llama3, mistral, phi3:mini...",
+                            value: "{pull_name}",
+                            disabled: is_pulling(),
+                            oninput: move |evt| pull_name.set(evt.value()),
+                            onkeydown: move |evt| {
+                                if evt.key() == Key::Enter {
+                                    start_pull();
+                                }
+                            },
+                        }
+                        button {
+                            class: "pull-button",
+                            disabled: is_pulling() || pull_name().trim().is_empty(),
+                            onclick: move |_| start_pull(),
+                            if is_pulling() { "⏳ Pulling..." } else { "⬇️ Pull" }
+                        }
+                    }
+                    match pull_status() {
+                        Some(Ok(status)) => rsx! { p { class: "settings-hint", "{status}" } },
+                        Some(Err(error)) => rsx! { p { class: "settings-error", "❌ {error}" } },
+                        None => rsx! {},
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "deep-think-default", "Deep Think" }
+                        input {
+                            id: "deep-think-default",
+                            r#type: "checkbox",
+                            checked: deep_think(),
+                            onchange: move |evt| {
+                                deep_think.set(evt.checked());
+                                save_setting(DEEP_THINK_KEY, &evt.checked().to_string());
+                            },
+                        }
+                        span { class: "settings-hint", "On by default for new questions" }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "top-k", "Passages" }
+                        input {
+                            id: "top-k",
+                            class: "top-k-input",
+                            r#type: "number",
+                            min: "1",
+                            max: "{MAX_TOP_K}",
+                            placeholder: "Default",
+                            value: top_k().map(|value| value.to_string()).unwrap_or_default(),
+                            oninput: move |evt| {
+                                let value = evt
+                                    .value()
+                                    .trim()
+                                    .parse::<usize>()
+                                    .ok()
+                                    .map(|value| value.clamp(1, MAX_TOP_K));
+                                save_setting(
+                                    TOP_K_KEY,
+                                    &value.map(|value| value.to_string()).unwrap_or_default(),
+                                );
+                                top_k.set(value);
+                            },
+                        }
+                        span { class: "settings-hint", "Retrieved per question, 1 to {MAX_TOP_K}; empty for the backend's default" }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "tts-engine", "Speech" }
+                        select {
+                            id: "tts-engine",
+                            class: "model-picker",
+                            onchange: move |evt| {
+                                stop_speaking();
+                                save_setting(TTS_ENGINE_KEY, &evt.value());
+                                tts_engine.set(evt.value());
+                            },
+                            option { value: "browser", selected: tts_engine() != "server", "Browser voices" }
+                            option {
+                                value: "server",
+                                selected: tts_engine() == "server",
+                                disabled: !upload_limits().speech,
+                                "Backend voice"
+                            }
+                        }
+                        if !upload_limits().speech {
+                            span { class: "settings-hint", "The backend has no speech set up" }
+                        } else if tts_engine() == "server" {
+                            span { class: "settings-hint", "Voice and pitch come from the backend; the browser's voice stands in if it fails" }
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "tts-voice", "Voice" }
+                        select {
+                            id: "tts-voice",
+                            class: "model-picker",
+                            onchange: move |evt| {
+                                save_setting(TTS_VOICE_KEY, &evt.value());
+                                tts_voice.set(evt.value());
+                            },
+                            option { value: "", selected: tts_voice().is_empty(), "Browser default" }
+                            for (name, lang) in voices() {
+                                option {
+                                    value: "{name}",
+                                    selected: tts_voice() == name,
+                                    "{name} ({lang})"
+                                }
+                            }
+                        }
+                        button {
+                            class: "pull-button",
+                            title: "Read a sentence aloud with this voice, rate and pitch",
+                            onclick: move |_| speak_now("This is how answers will sound.", || {}),
+                            "🔊 Try"
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "tts-rate", "Speech rate" }
+                        input {
+                            id: "tts-rate",
+                            r#type: "range",
+                            min: "0.5",
+                            max: "2",
+                            step: "0.1",
+                            value: "{tts_rate}",
+                            oninput: move |evt| {
+                                if let Ok(rate) = evt.value().parse::<f32>() {
+                                    save_setting(TTS_RATE_KEY, &rate.to_string());
+                                    tts_rate.set(rate);
+                                }
+                            },
+                        }
+                        span { class: "settings-hint", "{tts_rate:.1}×" }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "tts-pitch", "Speech pitch" }
+                        input {
+                            id: "tts-pitch",
+                            r#type: "range",
+                            min: "0",
+                            max: "2",
+                            step: "0.1",
+                            value: "{tts_pitch}",
+                            oninput: move |evt| {
+                                if let Ok(pitch) = evt.value().parse::<f32>() {
+                                    save_setting(TTS_PITCH_KEY, &pitch.to_string());
+                                    tts_pitch.set(pitch);
+                                }
+                            },
+                        }
+                        span { class: "settings-hint", "{tts_pitch:.1}" }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "stt-lang", "Dictation language" }
+                        select {
+                            id: "stt-lang",
+                            class: "model-picker",
+                            disabled: !browser_dictation && !upload_limits().transcription,
+                            onchange: move |evt| {
+                                save_setting(STT_LANG_KEY, &evt.value());
+                                stt_lang.set(evt.value());
+                            },
+                            option { value: "", selected: stt_lang().is_empty(), "Automatic" }
+                            for (tag, name) in STT_LANGUAGES {
+                                option {
+                                    value: "{tag}",
+                                    selected: stt_lang() == *tag,
+                                    "{name}"
+                                }
+                            }
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "stt-engine", "Speech recognition" }
+                        select {
+                            id: "stt-engine",
+                            class: "model-picker",
+                            onchange: move |evt| {
+                                save_setting(STT_ENGINE_KEY, &evt.value());
+                                stt_engine.set(evt.value());
+                            },
+                            option {
+                                value: "browser",
+                                selected: stt_engine() != "whisper",
+                                disabled: !browser_dictation,
+                                "Browser (Web Speech)"
+                            }
+                            option {
+                                value: "whisper",
+                                selected: stt_engine() == "whisper",
+                                disabled: !upload_limits().transcription,
+                                "Backend (whisper.cpp)"
+                            }
+                        }
+                        if !browser_dictation && !upload_limits().transcription {
+                            span { class: "settings-hint", "This browser has no speech recognition, and the backend has no whisper.cpp set up" }
+                        } else if !upload_limits().transcription {
+                            span { class: "settings-hint", "The backend has no whisper.cpp set up" }
+                        } else if stt_engine() == "whisper" {
+                            span { class: "settings-hint", "Recorded, then transcribed on the backend; tap 🎤 again to stop" }
+                        }
+                    }
+                    div {
+                        class: "settings-row",
+                        label { r#for: "theme", "Theme" }
+                        select {
+                            id: "theme",
+                            class: "model-picker",
+                            onchange: move |evt| {
+                                save_setting(THEME_KEY, &evt.value());
+                                apply_theme(&evt.value());
+                                theme.set(evt.value());
+                            },
+                            option { value: "system", selected: theme() == "system", "Follow the system" }
+                            option { value: "light", selected: theme() == "light", "Light" }
+                            option { value: "dark", selected: theme() == "dark", "Dark" }
+                        }
+                    }
+                }
+            }
+
+            // Main content
+            div {
+                class: if DEMO_MODE { "main-content" } else { "main-content with-conversations" },
+
+                // Saved conversations
+                if !DEMO_MODE {
+                    aside {
+                        class: "conversations",
+                        div {
+                            class: "conversations-header",
+                            h2 { "🗂️ Conversations" }
+                            button {
+                                class: "new-conversation",
+                                title: "Start a new conversation",
+                                disabled: is_loading(),
+                                onclick: move |_| open_session(String::new()),
+                                "➕ New"
+                            }
+                        }
+                        ul {
+                            class: "conversation-list",
+                            if session_id().is_none() {
+                                li {
+                                    class: "conversation active",
+                                    span { class: "conversation-title", "New conversation" }
+                                }
+                            }
+                            for session in sessions() {
+                                li {
+                                    class: if session_id().as_deref() == Some(session.id.as_str()) { "conversation active" } else { "conversation" },
+                                    if let Some((_, title)) = renaming().filter(|(id, _)| *id == session.id) {
+                                        input {
+                                            class: "conversation-rename",
+                                            value: "{title}",
+                                            autofocus: true,
+                                            oninput: move |evt| {
+                                                if let Some((_, title)) = renaming.write().as_mut() {
+                                                    *title = evt.value();
+                                                }
+                                            },
+                                            onkeydown: move |evt| match evt.key() {
+                                                Key::Enter => commit_rename(),
+                                                Key::Escape => renaming.set(None),
+                                                _ => {}
+                                            },
+                                            onblur: move |_| commit_rename(),
+                                        }
+                                    } else {
+                                        button {
+                                            class: "conversation-title",
+                                            title: "{session.title}",
+                                            disabled: is_loading(),
+                                            onclick: {
+                                                let id = session.id.clone();
+                                                move |_| open_session(id.clone())
+                                            },
+                                            if session.title.is_empty() { "Untitled" } else { "{session.title}" }
+                                        }
+                                        span { class: "conversation-count", title: "Questions asked", "{session.messages / 2}" }
+                                        button {
+                                            class: "conversation-action",
+                                            title: "Rename",
+                                            onclick: {
+                                                let id = session.id.clone();
+                                                let title = session.title.clone();
+                                                move |_| renaming.set(Some((id.clone(), title.clone())))
+                                            },
+                                            "✏️"
+                                        }
+                                        button {
+                                            class: "conversation-action",
+                                            title: "Delete",
+                                            disabled: is_loading(),
+                                            onclick: {
+                                                let id = session.id.clone();
+                                                move |_| handle_delete_session(id.clone())
+                                            },
+                                            "🗑️"
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Upload section with document management
+                div {
+                    class: if drag_over() { "upload-section drag-over" } else { "upload-section" },
+                    ondragover: move |evt| {
+                        if !DEMO_MODE && !is_uploading() {
+                            evt.prevent_default();
+                            drag_over.set(true);
+                        }
+                    },
+                    ondragleave: move |_| drag_over.set(false),
+                    ondrop: move |evt| {
+                        drag_over.set(false);
+                        if DEMO_MODE || is_uploading() {
+                            return;
+                        }
+                        evt.prevent_default();
+                        let files = evt
+                            .data()
+                            .try_as_web_event()
+                            .and_then(|event| event.data_transfer())
+                            .and_then(|transfer| transfer.files())
+                            .map(|files| file_list(&files))
+                            .unwrap_or_default();
+                        paste_mode.set(false);
+                        upload_files(files);
+                    },
+                    h2 { "📁 Document Upload" }
+
+                    if !DEMO_MODE {
+                        div {
+                            class: "upload-tabs",
+                            button {
+                                class: if paste_mode() { "upload-tab" } else { "upload-tab active" },
+                                onclick: move |_| paste_mode.set(false),
+                                "📁 Files"
+                            }
+                            button {
+                                class: if paste_mode() { "upload-tab active" } else { "upload-tab" },
+                                onclick: move |_| paste_mode.set(true),
+                                "📋 Paste text"
+                            }
+                        }
+                    }
+
+                    if paste_mode() {
+                        div {
+                            class: "paste-form",
+                            input {
+                                class: "paste-title",
+                                r#type: "text",
+                                placeholder: "Title",
+                                title: "The document is listed under this name",
+                                disabled: is_uploading(),
+                                value: "{paste_title}",
+                                oninput: move |evt| paste_title.set(evt.value()),
+                            }
+                            textarea {
+                                class: "paste-text",
+                                placeholder: "Paste or type the text to index (Markdown works)",
+                                rows: "8",
+                                disabled: is_uploading(),
+                                value: "{paste_text}",
+                                oninput: move |evt| paste_text.set(evt.value()),
+                            }
+                        }
+                    }
+
+                    div {
+                        class: "upload-controls",
+                        if paste_mode() {
+                            button {
+                                class: "upload-button",
+                                disabled: is_uploading() || paste_title().trim().is_empty() || paste_text().trim().is_empty(),
+                                onclick: handle_paste,
+                                if is_uploading() { "⏳ Uploading..." } else { "➕ Add text" }
+                            }
+                        } else {
+                            input {
+                                r#type: "file",
+                                accept: "{upload_limits().upload_extensions.join(\",\")}",
+                                multiple: true,
+                                id: "file-upload",
+                                style: "display: none;",
+                                onchange: move |evt| {
+                                    let Some(input) = evt
+                                        .data()
+                                        .try_as_web_event()
+                                        .and_then(|event| event.target())
+                                        .and_then(|target| target.dyn_into::<HtmlInputElement>().ok())
+                                    else {
+                                        return;
+                                    };
+                                    let files = input.files().map(|files| file_list(&files)).unwrap_or_default();
+                                    // Clear the input, so picking the same files again uploads them again
+                                    input.set_value("");
+                                    upload_files(files);
+                                }
+                            }
+                            button {
+                                class: "upload-button",
+                                disabled: is_uploading() || DEMO_MODE,
+                                title: if DEMO_MODE { "Uploading needs the backend; the demo uses bundled sample documents" } else { "" },
+                                onclick: move |_| {
+                                    if let Some(doc) = web_sys::window().and_then(|w| w.document()) {
+                                        if let Some(el) = doc.get_element_by_id("file-upload") {
+                                            if let Ok(input) = el.dyn_into::<HtmlInputElement>() {
+                                                let _ = input.click();
+                                            }
+                                        }
+                                    }
+                                },
+                                if is_uploading() {
+                                    "⏳ Uploading..."
+                                } else {
+                                    "📁 Choose Files (.md, .pdf, .png, .jpg)"
+                                }
+                            }
+                            if !DEMO_MODE {
+                                p {
+                                    class: if drag_over() { "drop-hint active" } else { "drop-hint" },
+                                    if drag_over() {
+                                        "Drop to upload"
+                                    } else {
+                                        "or drop files here · up to {upload_limits().max_upload_mb} MB per upload"
+                                    }
+                                }
+                            }
+                        }
+                        label {
+                            class: "upload-option",
+                            title: "When a file with the same name is already indexed, keep it and add the upload as a new version",
+                            input {
+                                r#type: "checkbox",
+                                checked: keep_versions(),
+                                disabled: is_uploading(),
+                                onchange: move |evt| keep_versions.set(evt.checked()),
+                            }
+                            "Keep previous versions"
+                        }
+                        if !DEMO_MODE {
+                            input {
+                                class: "upload-collection",
+                                r#type: "text",
+                                list: "collection-names",
+                                placeholder: "Collection (optional)",
+                                title: "File the uploaded documents under this collection",
+                                disabled: is_uploading(),
+                                value: "{upload_collection}",
+                                oninput: move |evt| upload_collection.set(evt.value()),
+                            }
+                            datalist {
+                                id: "collection-names",
+                                for collection in collections() {
+                                    option { value: "{collection.name}" }
+                                }
+                            }
+                        }
+                    }
+
+                    if is_uploading() && !upload_status().is_empty() {
+                        div { class: "status uploading", "{upload_status}" }
+                    }
+
+                    if !rejected_uploads().is_empty() {
+                        ul {
+                            class: "upload-rejected",
+                            for (name, problem) in rejected_uploads() {
+                                li { "⚠️ Skipped {name}: {problem}" }
+                            }
+                        }
+                    }
+
+                    if !upload_progress().is_empty() {
+                        div {
+                            class: "upload-progress",
+                            for file in upload_progress() {
+                                div {
+                                    class: "upload-progress-item",
+                                    div {
+                                        class: "upload-progress-header",
+                                        span {
+                                            class: "upload-progress-name",
+                                            title: "{file.filename}",
+                                            "{file.filename}"
+                                        }
+                                        span { class: "upload-progress-stage", "{file.stage} · {file.percent}%" }
+                                    }
+                                    div {
+                                        class: "progress-bar",
+                                        div {
+                                            class: if file.stage == "failed" { "progress-fill failed" } else { "progress-fill" },
+                                            style: "width: {file.percent}%;",
+                                        }
+                                    }
+                                    if let Some(error) = file.error {
+                                        div { class: "upload-progress-error", "{error}" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    div {
+                        class: "documents-list",
+                        h3 { "📚 Uploaded Documents" }
+
+                        if let Some(dir) = watch_status().dir {
+                            div {
+                                class: "watch-activity",
+                                div { class: "watch-dir", title: "New and changed files here are indexed automatically", "👁️ Watching {dir}" }
+                                for event in watch_status().events.into_iter().take(3) {
+                                    div {
+                                        class: if event.action == "failed" { "watch-event failed" } else { "watch-event" },
+                                        title: "{format_timestamp(event.at)}",
+                                        match event.action.as_str() {
+                                            "indexed" => "✅ Indexed ",
+                                            "removed" => "🗑️ Removed ",
+                                            _ => "❌ Failed ",
+                                        }
+                                        "{event.file}"
+                                        if let Some(error) = event.error {
+                                            span { class: "watch-error", ": {error}" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        if stats().documents > 0 {
+                            div {
+                                class: "corpus-stats",
+                                span { class: "corpus-stats-totals", "📊 {stats().documents} documents · {stats().chunks} chunks" }
+                                for language in stats().languages {
+                                    span { class: "language-tag", "{language.name} · {language.documents}" }
+                                }
+                                if !DEMO_MODE {
+                                    button {
+                                        class: "overview-button",
+                                        title: "What the documents cover, written by the model",
+                                        disabled: overview_loading(),
+                                        onclick: move |_| {
+                                            if overview().is_some() {
+                                                overview.set(None);
+                                            } else {
+                                                handle_overview(false);
+                                            }
+                                        },
+                                        if overview_loading() { "⏳ Overview" } else { "📋 Overview" }
+                                    }
+                                    button {
+                                        class: "reset-button",
+                                        title: "Remove every document from the index",
+                                        disabled: is_uploading(),
+                                        onclick: handle_reset,
+                                        "🗑️ Remove all"
+                                    }
+                                }
+                            }
+                        }
+
+                        if let Some(result) = overview() {
+                            div {
+                                class: "report-panel overview-panel",
+                                div {
+                                    class: "report-header",
+                                    h4 { "📋 Corpus overview" }
+                                    button {
+                                        class: "report-close",
+                                        title: "Regenerate the overview",
+                                        disabled: overview_loading(),
+                                        onclick: move |_| handle_overview(true),
+                                        "🔄"
+                                    }
+                                    button {
+                                        class: "report-close",
+                                        title: "Close",
+                                        onclick: move |_| overview.set(None),
+                                        "✕"
+                                    }
+                                }
+                                {match result {
+                                    Ok(corpus) => rsx! {
+                                        dl {
+                                            class: "report-fields",
+                                            dt { "Documents" }
+                                            dd { "{corpus.documents}" }
+                                            dt { "Chunks" }
+                                            dd { "{corpus.chunks}" }
+                                            dt { "Words" }
+                                            dd { "{corpus.words}" }
+                                            dt { "Written" }
+                                            dd { "{format_timestamp(corpus.generated_at)}" }
+                                        }
+                                        if let Some(text) = corpus.overview.clone() {
+                                            p { class: "overview-text", "{text}" }
+                                        }
+                                        if let Some(warning) = corpus.warning.clone() {
+                                            p { class: "report-warnings", "⚠️ {warning}" }
+                                        }
+                                        if !corpus.topics.is_empty() {
+                                            h5 { class: "report-section", "Topics by document" }
+                                            ul {
+                                                class: "overview-topics",
+                                                for topic in corpus.topics.iter() {
+                                                    li {
+                                                        div {
+                                                            class: "overview-topic-name",
+                                                            "{topic.name}"
+                                                            span { class: "related-score", " · {topic.words} words · {topic.chunks} chunks" }
+                                                        }
+                                                        if let Some(summary) = topic.summary.clone() {
+                                                            div { class: "overview-topic-summary", "{summary}" }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    },
+                                    Err(error) => rsx! {
+                                        p { class: "report-error", "❌ {error}" }
+                                    },
+                                }}
+                            }
+                        }
+
+                        if documents().is_empty() {
+                            p {
+                                class: "hint",
+                                style: "text-align: center; padding: 20px; color: var(--text-secondary);",
+                                "No documents uploaded yet. Upload .md or .pdf files to get started!"
+                            }
+                        } else {
+                            div {
+                                class: "documents-grid",
+                                for doc in documents() {
+                                    div {
+                                        class: if scope().contains(&doc.filename) { "document-card scoped" } else { "document-card" },
+                                        if !DEMO_MODE {
+                                            input {
+                                                class: "scope-checkbox",
+                                                r#type: "checkbox",
+                                                title: "Include in the documents questions are limited to",
+                                                checked: scope().contains(&doc.filename),
+                                                onchange: {
+                                                    let filename = doc.filename.clone();
+                                                    move |_| toggle_scope(filename.clone())
+                                                },
+                                            }
+                                        }
+                                        div {
+                                            class: if DEMO_MODE { "document-icon" } else { "document-icon previewable" },
+                                            title: if !DEMO_MODE { "Preview the indexed text" },
+                                            onclick: {
+                                                let filename = doc.filename.clone();
+                                                move |_| {
+                                                    if !DEMO_MODE {
+                                                        preview_document(filename.clone());
+                                                    }
+                                                }
+                                            },
+                                            if doc.filename.ends_with(".pdf") {
+                                                "📄"
+                                            } else if is_image(&doc.filename) {
+                                                "🖼️"
+                                            } else {
+                                                "📝"
+                                            }
+                                        }
+                                        div {
+                                            class: "document-details",
+                                            div {
+                                                class: if DEMO_MODE { "document-name" } else { "document-name previewable" },
+                                                title: if DEMO_MODE { "{doc.filename}" } else { "{doc.filename} · preview the indexed text" },
+                                                onclick: {
+                                                    let filename = doc.filename.clone();
+                                                    move |_| {
+                                                        if !DEMO_MODE {
+                                                            preview_document(filename.clone());
+                                                        }
+                                                    }
+                                                },
+                                                "{doc.filename}"
+                                            }
+                                            div {
+                                                class: "document-collections",
+                                                for collection in collections().into_iter().filter(|c| c.documents.contains(&doc.filename)) {
+                                                    span { class: "collection-tag", "📂 {collection.name}" }
+                                                }
+                                                for tag in tags().into_iter().filter(|t| t.documents.contains(&doc.filename)) {
+                                                    span { class: "collection-tag category-tag", "🏷️ {tag.name}" }
+                                                }
+                                                if watch_status.read().documents.contains(&doc.filename) {
+                                                    span { class: "collection-tag watched-tag", title: "Kept in step with the watch folder", "👁️ watched" }
+                                                }
+                                            }
+                                            if let Some(size) = doc.size.clone() {
+                                                {
+                                                    let expanded = expanded_document().as_deref() == Some(doc.filename.as_str());
+                                                    let details = document_stats.read().get(&doc.filename).cloned();
+                                                    rsx! {
+                                                        button {
+                                                            class: "document-meta",
+                                                            title: if expanded { "Hide details" } else { "Show details" },
+                                                            onclick: {
+                                                                let filename = doc.filename.clone();
+                                                                move |_| {
+                                                                    if expanded {
+                                                                        expanded_document.set(None);
+                                                                        return;
+                                                                    }
+                                                                    expanded_document.set(Some(filename.clone()));
+                                                                    // Detailed stats are only fetched once a card is opened
+                                                                    if !document_stats.peek().contains_key(&filename) {
+                                                                        let filename = filename.clone();
+                                                                        spawn(async move {
+                                                                            load_document_stats(&filename, document_stats).await;
+                                                                        });
+                                                                    }
+                                                                }
+                                                            },
+                                                            if expanded { "▾ " } else { "▸ " }
+                                                            "{size.chunks} chunks · {size.words} words"
+                                                        }
+                                                        if let Some(details) = details.filter(|_| expanded) {
+                                                            dl {
+                                                                class: "report-fields document-stats",
+                                                                dt { "Characters" }
+                                                                dd { "{details.characters}" }
+                                                                if let Some(pages) = details.pages {
+                                                                    dt { "Pages" }
+                                                                    dd { "{pages}" }
+                                                                }
+                                                                if let Some(duration_ms) = details.duration_ms {
+                                                                    dt { "Ingestion" }
+                                                                    dd { "{duration_ms as f64 / 1000.0:.1} s" }
+                                                                }
+                                                                if let Some(ingested_at) = details.ingested_at {
+                                                                    dt { "Ingested" }
+                                                                    dd { "{format_timestamp(ingested_at)}" }
+                                                                }
+                                                                dt { "Embedding model" }
+                                                                dd { "{details.embedding_model} ({details.embedding_dimensions} dims)" }
+                                                            }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        if !DEMO_MODE {
+                                            button {
+                                                class: "scope-button",
+                                                title: "Chat with this document only",
+                                                onclick: {
+                                                    let filename = doc.filename.clone();
+                                                    move |_| scope.set(vec![filename.clone()])
+                                                },
+                                                "💬"
+                                            }
+                                            button {
+                                                class: "report-button",
+                                                title: "Ingestion report and related documents",
+                                                onclick: {
+                                                    let filename = doc.filename.clone();
+                                                    move |_| handle_report(filename.clone())
+                                                },
+                                                "🔍"
+                                            }
+                                            button {
+                                                class: "delete-button",
+                                                title: "Remove document",
+                                                onclick: {
+                                                    let filename = doc.filename.clone();
+                                                    move |_| handle_delete(filename.clone())
+                                                },
+                                                "🗑️"
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+
+                            if let Some((filename, result)) = report() {
+                                div {
+                                    class: "report-panel",
+                                    div {
+                                        class: "report-header",
+                                        h4 { "🔍 {filename}" }
+                                        button {
+                                            class: "report-close",
+                                            title: "Close",
+                                            onclick: move |_| report.set(None),
+                                            "✕"
+                                        }
+                                    }
+                                    h5 { class: "report-section", "Ingestion report" }
+                                    {match result {
+                                        Ok(details) => rsx! {
+                                            dl {
+                                                class: "report-fields",
+                                                dt { "Extractor" }
+                                                dd { "{details.extractor}" }
+                                                if let Some(pages) = details.pages {
+                                                    dt { "Pages" }
+                                                    dd { "{pages}" }
+                                                }
+                                                dt { "Characters" }
+                                                dd { "{details.characters}" }
+                                                dt { "Chunks" }
+                                                dd { "{details.chunks}" }
+                                                dt { "Filtered chunks" }
+                                                dd { "{details.filtered_chunks}" }
+                                                if !details.redactions.is_empty() {
+                                                    dt { "Redactions" }
+                                                    dd { "{format_redactions(&details.redactions)}" }
+                                                }
+                                                dt { "Duration" }
+                                                dd { "{details.duration_ms as f64 / 1000.0:.1} s" }
+                                                dt { "Ingested" }
+                                                dd { "{format_timestamp(details.ingested_at)}" }
+                                            }
+                                            if details.warnings.is_empty() {
+                                                p { class: "report-ok", "✅ No warnings" }
+                                            } else {
+                                                ul {
+                                                    class: "report-warnings",
+                                                    for warning in details.warnings.iter() {
+                                                        li { "⚠️ {warning}" }
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        Err(error) => rsx! {
+                                            p { class: "report-error", "❌ {error}" }
+                                        },
+                                    }}
+                                    if !related().is_empty() {
+                                        h5 { class: "report-section", "🔗 Related documents" }
+                                        ul {
+                                            class: "related-documents",
+                                            for similar in related() {
+                                                li {
+                                                    button {
+                                                        class: "related-document",
+                                                        title: "Show this document's details",
+                                                        onclick: {
+                                                            let name = similar.name.clone();
+                                                            move |_| handle_report(name.clone())
+                                                        },
+                                                        "{similar.name}"
+                                                    }
+                                                    span { class: "related-score", "{similar.score * 100.0:.0}% similar" }
+                                                    if similar.shared_chunks > 0 {
+                                                        span { class: "related-shared", "{similar.shared_chunks} identical chunks" }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Chat section
+                div {
+                    class: "chat-section",
+                    div {
+                        class: "chat-header",
+                        h2 { "💬 Chat" }
+                        div {
+                            class: "export-controls",
+                            button {
+                                class: "share-button",
+                                title: "Download this conversation as a Markdown transcript",
+                                disabled: messages().is_empty(),
+                                onclick: move |_| handle_export(true),
+                                "⬇️ Markdown"
+                            }
+                            button {
+                                class: "share-button",
+                                title: "Download this conversation as JSON",
+                                disabled: messages().is_empty(),
+                                onclick: move |_| handle_export(false),
+                                "⬇️ JSON"
+                            }
+                        }
+                        if !DEMO_MODE {
+                            div {
+                                class: "share-controls",
+                                select {
+                                    class: "share-expiry",
+                                    title: "How long the link works",
+                                    onchange: move |evt| share_expiry.set(evt.value().parse().unwrap_or(0)),
+                                    option { value: "0", "Never expires" }
+                                    option { value: "24", "Expires in 1 day" }
+                                    option { value: "168", "Expires in 7 days" }
+                                }
+                                button {
+                                    class: "share-button",
+                                    title: "Publish this conversation as a read-only link",
+                                    disabled: is_sharing() || is_loading() || !messages().iter().any(|m| m.role == "assistant"),
+                                    onclick: move |_| handle_share(),
+                                    "🔗 Share"
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(result) = share_result() {
+                        div {
+                            class: "share-result",
+                            match result {
+                                Ok(link) => rsx! {
+                                    span { "Read-only link:" }
+                                    input {
+                                        class: "share-link",
+                                        readonly: true,
+                                        value: "{link}",
+                                    }
+                                },
+                                Err(error) => rsx! {
+                                    span { class: "report-error", "❌ {error}" }
+                                },
+                            }
+                            button {
+                                class: "report-close",
+                                title: "Dismiss",
+                                onclick: move |_| share_result.set(None),
+                                "✕"
+                            }
+                        }
+                    }
+
+                    // Messages
+                    div {
+                        class: "messages",
+                        id: "messages-container",
+
+                        if messages().is_empty() {
+                            div {
+                                class: "empty-state",
+                                p { "👋 Upload documents and start chatting!" }
+                                p { class: "hint", "Your questions will be answered using only the uploaded documents." }
+                            }
+                        }
+
+                        for (index, msg) in messages().into_iter().enumerate() {
+                            div {
+                                class: "message {msg.role}",
+                                div {
+                                    class: "message-content",
+                                    if msg.role == "user" {
+                                        strong { "You: " }
+                                    } else if msg.role == "assistant" {
+                                        strong { "🤖 Assistant: " }
+                                    } else if msg.role == "search" {
+                                        strong { "🔎 Search: " }
+                                    } else {
+                                        strong { "⚠️ Error: " }
+                                    }
+                                    if let Some((_, draft)) = editing().filter(|(i, _)| *i == index) {
+                                        div {
+                                            class: "message-edit",
+                                            textarea {
+                                                value: "{draft}",
+                                                rows: "3",
+                                                autofocus: true,
+                                                oninput: move |evt| {
+                                                    if let Some((_, draft)) = editing.write().as_mut() {
+                                                        *draft = evt.value();
+                                                    }
+                                                },
+                                                onkeydown: move |evt| {
+                                                    if evt.key() == Key::Enter && !evt.modifiers().shift() {
+                                                        evt.prevent_default();
+                                                        commit_edit();
+                                                    } else if evt.key() == Key::Escape {
+                                                        editing.set(None);
+                                                    }
+                                                },
+                                            }
+                                            div {
+                                                class: "message-edit-actions",
+                                                button {
+                                                    class: "similar-question-button",
+                                                    disabled: is_loading() || draft.trim().is_empty(),
+                                                    onclick: move |_| commit_edit(),
+                                                    "Send"
+                                                }
+                                                button {
+                                                    class: "similar-question-button secondary",
+                                                    onclick: move |_| editing.set(None),
+                                                    "Cancel"
+                                                }
+                                            }
+                                        }
+                                    } else if msg.role == "assistant" {
+                                        div {
+                                            class: "markdown",
+                                            onclick: move |evt| {
+                                                if let Some(number) = clicked_citation(&evt) {
+                                                    open_citation(index, number);
+                                                } else {
+                                                    copy_code_block(&evt);
+                                                }
+                                            },
+                                            dangerous_inner_html: render_markdown(&msg.content, &msg.citations),
+                                        }
+                                    } else {
+                                        span {
+                                        for (text, number) in citation_segments(&msg.content, &msg.citations) {
+                                            if let Some(number) = number {
+                                                sup {
+                                                    class: "citation-marker",
+                                                    title: "Show source",
+                                                    onclick: move |_| open_citation(index, number),
+                                                    "{text}"
+                                                }
+                                            } else {
+                                                "{text}"
+                                            }
+                                        }
+                                        }
+                                    }
+
+                                    // TTS button for assistant messages
+                                    if msg.role == "assistant" {
+                                        if speaking() == Some(index) {
+                                            button {
+                                                class: "tts-button",
+                                                title: if speech_paused() { "Resume reading" } else { "Pause reading" },
+                                                onclick: move |_| toggle_speech_pause(),
+                                                if speech_paused() { "▶️" } else { "⏸️" }
+                                            }
+                                            button {
+                                                class: "tts-button",
+                                                title: "Stop reading",
+                                                onclick: move |_| stop_message(),
+                                                "⏹️"
+                                            }
+                                        } else {
+                                            button {
+                                                class: "tts-button",
+                                                title: "Read aloud",
+                                                onclick: {
+                                                    let content = msg.content.clone();
+                                                    move |_| speak_message(index, content.clone())
+                                                },
+                                                "📢"
+                                            }
+                                        }
+                                        button {
+                                            class: if copied() == Some(index) { "copy-button copied" } else { "copy-button" },
+                                            title: "Copy answer",
+                                            onclick: {
+                                                let content = msg.content.clone();
+                                                move |_| {
+                                                    let content = content.clone();
+                                                    spawn(async move {
+                                                        if copy_to_clipboard(&content).await {
+                                                            copied.set(Some(index));
+                                                            TimeoutFuture::new(COPIED_FLASH_MS).await;
+                                                            if copied() == Some(index) {
+                                                                copied.set(None);
+                                                            }
+                                                        }
+                                                    });
+                                                }
+                                            },
+                                            if copied() == Some(index) { "✅ Copied" } else { "📋" }
+                                        }
+                                        button {
+                                            class: "message-action",
+                                            title: "Ask this question again",
+                                            disabled: is_loading(),
+                                            onclick: move |_| regenerate(index),
+                                            "🔄 Regenerate"
+                                        }
+                                        button {
+                                            class: if msg.rating == Some(true) { "message-action rating selected" } else { "message-action rating" },
+                                            title: "Good answer",
+                                            disabled: msg.rating.is_some(),
+                                            onclick: move |_| rate_answer(index, true, None),
+                                            "👍"
+                                        }
+                                        button {
+                                            class: if msg.rating == Some(false) { "message-action rating selected" } else { "message-action rating" },
+                                            title: "Bad answer",
+                                            disabled: msg.rating.is_some() || feedback_draft().is_some_and(|(i, _)| i == index),
+                                            onclick: move |_| feedback_draft.set(Some((index, String::new()))),
+                                            "👎"
+                                        }
+                                        if let Some((_, error)) = feedback_error().filter(|(i, _)| *i == index) {
+                                            span { class: "feedback-error", "Feedback not saved: {error}" }
+                                        }
+                                        if let Some((_, comment)) = feedback_draft().filter(|(i, _)| *i == index) {
+                                            div {
+                                                class: "feedback-comment",
+                                                textarea {
+                                                    rows: "2",
+                                                    placeholder: "What was wrong with this answer? (optional)",
+                                                    maxlength: "2000",
+                                                    value: "{comment}",
+                                                    oninput: move |evt| feedback_draft.set(Some((index, evt.value()))),
+                                                }
+                                                div {
+                                                    class: "message-edit-actions",
+                                                    button {
+                                                        class: "similar-question-button",
+                                                        onclick: move |_| {
+                                                            let comment = feedback_draft()
+                                                                .map(|(_, comment)| comment.trim().to_string())
+                                                                .filter(|comment| !comment.is_empty());
+                                                            rate_answer(index, false, comment);
+                                                        },
+                                                        "Send"
+                                                    }
+                                                    button {
+                                                        class: "similar-question-button secondary",
+                                                        onclick: move |_| rate_answer(index, false, None),
+                                                        "Skip"
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                    if msg.role == "user" && editing().is_none_or(|(i, _)| i != index) {
+                                        button {
+                                            class: "message-action",
+                                            title: "Edit and resend this question",
+                                            disabled: is_loading(),
+                                            onclick: {
+                                                let content = msg.content.clone();
+                                                move |_| editing.set(Some((index, content.clone())))
+                                            },
+                                            "✏️ Edit"
+                                        }
+                                    }
+                                }
+
+                                if let Some(warning) = msg.warning.clone() {
+                                    div { class: "message-warning", "🌐 {warning}" }
+                                }
+
+                                if let Some(grounding) = msg.grounding.clone() {
+                                    if let Some(warning) = grounding.warning.clone() {
+                                        div {
+                                            class: "grounding-warning",
+                                            "⚠️ {warning}"
+                                            details {
+                                                summary { "Unsupported sentences" }
+                                                ul {
+                                                    for sentence in grounding.sentences.iter().filter(|s| !s.supported) {
+                                                        li { "{sentence.text}" }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    } else {
+                                        div {
+                                            class: "grounding-ok",
+                                            "✅ Verified: {grounding.score * 100.0:.0}% of sentences supported by the documents"
+                                        }
+                                    }
+                                }
+
+                                div {
+                                    class: "message-meta",
+                                    span {
+                                        class: "message-time",
+                                        title: "{format_timestamp(msg.created_at)}",
+                                        "{format_time(msg.created_at)}"
+                                    }
+                                    if let Some(ms) = msg.response_ms {
+                                        span {
+                                            class: "message-latency",
+                                            title: "From sending the question to the whole answer",
+                                            "⏱️ {format_duration(ms)}"
+                                        }
+                                    }
+                                    if let Some(timings) = msg.timings.clone() {
+                                        span { class: "message-timings", "backend: {timings.summary()}" }
+                                    }
+                                }
+
+                                if !msg.steps.is_empty() {
+                                    details {
+                                        class: "think-steps",
+                                        summary { "🧠 Reasoning steps ({msg.steps.len()})" }
+                                        for step in msg.steps.iter() {
+                                            div {
+                                                class: "think-step",
+                                                div { class: "think-step-question", "{step.question}" }
+                                                div { class: "think-step-answer", "{step.answer}" }
+                                                if !step.sources.is_empty() {
+                                                    div {
+                                                        class: "think-step-sources",
+                                                        "📚 "
+                                                        for source in step.sources.iter() {
+                                                            span { class: "source-tag", "{source}" }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
+                                if !msg.sources.is_empty() {
+                                    div {
+                                        class: "sources",
+                                        strong { "📚 Sources: " }
+                                        for source in msg.sources.iter() {
+                                            if msg.citations.iter().any(|citation| &citation.source == source) {
+                                                button {
+                                                    class: if open_source().is_some_and(|(open, name)| open == index && &name == source) { "source-tag expandable open" } else { "source-tag expandable" },
+                                                    title: "Show the passages used from this document",
+                                                    onclick: {
+                                                        let source = source.clone();
+                                                        move |_| {
+                                                            let clicked = Some((index, source.clone()));
+                                                            open_source.set(if open_source() == clicked { None } else { clicked });
+                                                        }
+                                                    },
+                                                    "{source}"
+                                                }
+                                            } else {
+                                                span { class: "source-tag", "{source}" }
+                                            }
+                                        }
+                                    }
+                                    if let Some((_, name)) = open_source().filter(|(open, _)| *open == index) {
+                                        div {
+                                            class: "source-chunks",
+                                            for citation in msg.citations.iter().filter(|citation| citation.source == name) {
+                                                div {
+                                                    class: "source-chunk",
+                                                    div {
+                                                        class: "source-chunk-header",
+                                                        span { class: "citation-number", "[{citation.number}]" }
+                                                        span { class: "citation-score", "{citation.score:.2}" }
+                                                    }
+                                                    p {
+                                                        class: "citation-text",
+                                                        for (segment, hit) in highlight_segments(&citation.text, &highlight_terms(&messages.read(), index)) {
+                                                            if hit {
+                                                                mark { "{segment}" }
+                                                            } else {
+                                                                "{segment}"
                                                             }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                            if !DEMO_MODE {
+                                                button {
+                                                    class: "source-preview",
+                                                    onclick: {
+                                                        let name = name.clone();
+                                                        move |_| preview_document(name.clone())
+                                                    },
+                                                    "📄 Open document preview"
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
+                                if !msg.citations.is_empty() {
+                                    div {
+                                        class: "citations",
+                                        for citation in msg.citations.iter() {
+                                            details {
+                                                class: "citation-card",
+                                                id: "citation-{index}-{citation.number}",
+                                                open: msg.role == "search",
+                                                summary {
+                                                    span { class: "citation-number", "[{citation.number}]" }
+                                                    span { class: "citation-source", "{citation.source}" }
+                                                    span { class: "citation-score", "{citation.score:.2}" }
+                                                }
+                                                p {
+                                                    class: "citation-text",
+                                                    for (segment, hit) in highlight_segments(&citation.text, &highlight_terms(&messages.read(), index)) {
+                                                        if hit {
+                                                            mark { "{segment}" }
+                                                        } else {
+                                                            "{segment}"
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        if is_loading() && messages().last().is_some_and(|m| m.role == "user") {
+                            if search_mode() {
+                                div {
+                                    class: "message search loading",
+                                    div {
+                                        class: "message-content",
+                                        strong { "🔎 Search: " }
+                                        span { "Searching..." }
+                                    }
+                                }
+                            } else if deep_think() && !DEMO_MODE {
+                                div {
+                                    class: "message assistant loading deep",
+                                    div {
+                                        class: "message-content",
+                                        strong { "🤖 Assistant: " }
+                                        span { "🧠 Deep thinking... breaking the question down and researching each part" }
+                                    }
+                                    div { class: "loading-hint", "This usually takes 30–90 seconds." }
+                                }
+                            } else {
+                                div {
+                                    class: "message assistant loading",
+                                    div {
+                                        class: "message-content",
+                                        strong { "🤖 Assistant: " }
+                                        span { "💭 Thinking..." }
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    // Input area with Deep Think toggle
+                    div {
+                        class: "input-area",
+
+                        if !scope().is_empty() {
+                            div {
+                                class: "scope-bar",
+                                span { "🎯 Asking only:" }
+                                for name in scope() {
+                                    span { class: "source-tag", "{name}" }
+                                }
+                                button {
+                                    class: "scope-clear",
+                                    title: "Ask about every document again",
+                                    onclick: move |_| scope.set(Vec::new()),
+                                    "✕ All documents"
+                                }
+                            }
+                        }
+
+                        // The answer is read out sentence by sentence as it streams in
+                        if let Some(phase) = conversation().map(|phase| {
+                            let answering = speaking()
+                                .and_then(|index| messages.read().get(index).map(|msg| !msg.content.is_empty()))
+                                .unwrap_or(false);
+                            if phase == VoicePhase::Thinking && answering { VoicePhase::Speaking } else { phase }
+                        }) {
+                            div {
+                                class: "voice-conversation {phase.class()}",
+                                span {
+                                    class: "voice-conversation-phase",
+                                    match phase {
+                                        VoicePhase::Listening => "🎤 Listening…",
+                                        VoicePhase::Thinking => "🤔 Thinking…",
+                                        VoicePhase::Speaking => "🔊 Speaking…",
+                                    }
+                                }
+                                button {
+                                    class: "voice-conversation-end",
+                                    title: "Stop listening and speaking and end the voice conversation",
+                                    onclick: move |_| end_conversation(),
+                                    "✕ End conversation"
+                                }
+                            }
+                        }
+
+                        div {
+                            class: "input-row",
+                            // Microphone button
+                            button {
+                                class: if is_listening() { "mic-button listening" } else { "mic-button" },
+                                disabled: is_loading() || !dictation_supported(),
+                                onpointerdown: move |_| press_mic(),
+                                onpointerup: move |_| release_mic(),
+                                onpointerleave: move |_| release_mic(),
+                                onkeydown: move |evt: KeyboardEvent| {
+                                    if evt.key() == Key::Enter || evt.key() == Key::Character(" ".to_string()) {
+                                        evt.prevent_default();
+                                        press_mic();
+                                        mic_held.set(false);
+                                    }
+                                },
+                                title: if dictation_supported() {
+                                    "Voice input: tap to ask a question, or hold to talk and release when done"
+                                } else {
+                                    "Voice input isn't available: this browser has no speech recognition. Try Chrome, Edge or Safari, or set up whisper.cpp on the backend."
+                                },
+                                if is_listening() {
+                                    "🎤 Listening..."
+                                } else {
+                                    "🎤"
+                                }
+                            }
+                            button {
+                                class: "mic-button conversation-button",
+                                disabled: is_loading() || is_listening() || conversation().is_some() || !dictation_supported(),
+                                onclick: move |_| start_conversation(),
+                                title: "Voice conversation: ask out loud, hear the answer, then ask again, hands-free",
+                                "🗣️"
+                            }
+
+                            textarea {
+                                class: "chat-input",
+                                placeholder: "Ask a question about your documents...",
+                                value: "{input_value}",
+                                disabled: is_loading() || is_listening(),
+                                rows: 2,
+                                oninput: move |evt| {
+                                    input_value.set(evt.value());
+                                    voice_query.set(false);
+                                    similar_answer.set(None);
+                                    history_position.set(None);
+                                },
+                                onkeydown: handle_keydown,
+                            }
+                        }
+
+                        if let Some(index) = similar_answer() {
+                            div {
+                                class: "similar-question",
+                                span {
+                                    class: "similar-question-text",
+                                    if let Some(question) = index.checked_sub(1).and_then(|i| messages().get(i).cloned()) {
+                                        "🔁 You asked something similar: “{question.content}”"
+                                    }
+                                }
+                                div {
+                                    class: "similar-question-actions",
+                                    button {
+                                        class: "similar-question-button",
+                                        onclick: move |_| show_previous_answer(index),
+                                        "View previous answer"
+                                    }
+                                    button {
+                                        class: "similar-question-button secondary",
+                                        onclick: move |_| send_input(),
+                                        "Ask anyway"
+                                    }
+                                }
+                            }
+                        }
+
+                        div {
+                            class: "controls-row",
+                            if !collections().is_empty() {
+                                select {
+                                    class: "collection-picker",
+                                    title: "Only answer from documents in this collection",
+                                    disabled: is_loading(),
+                                    onchange: move |evt| chat_collection.set(evt.value()),
+                                    option { value: "", selected: chat_collection().is_empty(), "📂 All collections" }
+                                    for collection in collections() {
+                                        option {
+                                            value: "{collection.name}",
+                                            selected: chat_collection() == collection.name,
+                                            "📂 {collection.name} ({collection.documents.len()})"
+                                        }
+                                    }
+                                }
+                            }
+                            if !tags().is_empty() {
+                                select {
+                                    class: "collection-picker",
+                                    title: "Only answer from documents with this tag",
+                                    disabled: is_loading(),
+                                    onchange: move |evt| chat_tag.set(evt.value()),
+                                    option { value: "", selected: chat_tag().is_empty(), "🏷️ All tags" }
+                                    for tag in tags() {
+                                        option {
+                                            value: "{tag.name}",
+                                            selected: chat_tag() == tag.name,
+                                            "🏷️ {tag.name} ({tag.documents.len()})"
+                                        }
+                                    }
+                                }
+                            }
+                            div {
+                                class: "length-presets",
+                                title: "Answer length",
+                                for (value, label) in [("short", "Short"), ("normal", "Normal"), ("detailed", "Detailed")] {
+                                    button {
+                                        class: if answer_length() == value { "length-option active" } else { "length-option" },
+                                        disabled: is_loading(),
+                                        onclick: move |_| answer_length.set(value.to_string()),
+                                        "{label}"
+                                    }
+                                }
+                            }
+                            label {
+                                class: "search-toggle",
+                                title: "Only list the passages that match the question, without asking the model for an answer",
+                                input {
+                                    r#type: "checkbox",
+                                    checked: search_mode(),
+                                    disabled: is_loading() || DEMO_MODE,
+                                    onchange: move |evt| {
+                                        search_mode.set(evt.checked());
+                                        save_setting(SEARCH_MODE_KEY, &evt.checked().to_string());
+                                    },
+                                }
+                                "📑 Search documents"
+                            }
+                            label {
+                                class: "deep-think-toggle",
+                                title: "Split the question into sub-questions, research each, then combine the findings (slower)",
+                                input {
+                                    r#type: "checkbox",
+                                    checked: deep_think(),
+                                    disabled: is_loading() || DEMO_MODE || search_mode(),
+                                    onchange: move |evt| {
+                                        deep_think.set(evt.checked());
+                                        save_setting(DEEP_THINK_KEY, &evt.checked().to_string());
+                                    },
+                                }
+                                "🧠 Deep Think"
+                            }
+                            label {
+                                class: "verify-toggle",
+                                title: "Check each sentence of the answer against the retrieved passages and warn when support is weak",
+                                input {
+                                    r#type: "checkbox",
+                                    checked: verify(),
+                                    disabled: is_loading() || DEMO_MODE || search_mode(),
+                                    onchange: move |evt| {
+                                        verify.set(evt.checked());
+                                        save_setting(VERIFY_KEY, &evt.checked().to_string());
+                                    },
+                                }
+                                "🔎 Verify"
+                            }
+                            label {
+                                class: "timings-toggle",
+                                title: "Show how long each stage of answering took (embedding, search, prompt, generation)",
+                                input {
+                                    r#type: "checkbox",
+                                    checked: show_timings(),
+                                    disabled: is_loading() || DEMO_MODE || search_mode(),
+                                    onchange: move |evt| {
+                                        show_timings.set(evt.checked());
+                                        save_setting(TIMINGS_KEY, &evt.checked().to_string());
+                                    },
+                                }
+                                "⏱️ Timings"
+                            }
+                            label {
+                                class: "auto-speak-toggle",
+                                title: "Read each new answer aloud as it arrives, leaving out code and source lists",
+                                input {
+                                    r#type: "checkbox",
+                                    checked: auto_speak(),
+                                    onchange: move |evt| {
+                                        auto_speak.set(evt.checked());
+                                        save_setting(AUTO_SPEAK_KEY, &evt.checked().to_string());
+                                    },
+                                }
+                                "🔊 Read aloud"
+                            }
+                            if is_loading() && in_flight().is_some() {
+                                button {
+                                    class: "send-button stop",
+                                    title: "Stop generating",
+                                    onclick: move |_| stop_sending(),
+                                    "⏹️ Stop"
+                                }
+                            } else {
+                                button {
+                                    class: "send-button",
+                                    disabled: is_loading() || input_value().trim().is_empty() || is_listening() || send_blocker().is_some(),
+                                    title: send_blocker().unwrap_or_default(),
+                                    onclick: move |_| handle_send(),
+                                    if is_loading() {
+                                        "⏳ Sending..."
+                                    } else {
+                                        "🚀 Send"
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Footer
+            footer {
+                class: "footer",
+                p { "Built with Dioxus 🦀 | Powered by Ollama (phi3) + fastembed" }
+                if DEMO_MODE {
+                    p { class: "hint", "Demo mode: no backend, sample documents only" }
+                } else {
+                    p { class: "hint", "Backend: {api::backend_url()}" }
+                }
+            }
+
+            if let Some((name, loaded)) = preview() {
+                div {
+                    class: "modal-backdrop",
+                    onclick: move |_| preview.set(None),
+                    div {
+                        class: "modal preview-modal",
+                        role: "dialog",
+                        tabindex: "-1",
+                        onmounted: move |evt| async move {
+                            let _ = evt.set_focus(true).await;
+                        },
+                        onclick: move |evt| evt.stop_propagation(),
+                        onkeydown: move |evt| {
+                            if evt.key() == Key::Escape {
+                                preview.set(None);
+                            }
+                        },
+                        div {
+                            class: "modal-header",
+                            h3 { "📄 {name}" }
+                            button {
+                                class: "report-close",
+                                title: "Close",
+                                onclick: move |_| preview.set(None),
+                                "✕"
+                            }
+                        }
+                        div {
+                            class: "modal-body",
+                            match loaded {
+                                None => rsx! { p { class: "settings-hint", "Loading..." } },
+                                Some(Err(error)) => rsx! { p { class: "report-error", "❌ {error}" } },
+                                Some(Ok(document)) => rsx! {
+                                    if document.format == "markdown" {
+                                        div {
+                                            class: "markdown",
+                                            onclick: move |evt| copy_code_block(&evt),
+                                            dangerous_inner_html: render_markdown(&document.pages.concat(), &[]),
+                                        }
+                                    } else if document.format == "pdf" {
+                                        for (number, page) in document.pages.iter().enumerate() {
+                                            div { class: "preview-page-break", "Page {number + 1}" }
+                                            if page.trim().is_empty() {
+                                                p { class: "settings-hint", "No text on this page" }
+                                            } else {
+                                                pre { class: "preview-text", "{page}" }
+                                            }
+                                        }
+                                    } else {
+                                        pre { class: "preview-text", "{document.pages.concat()}" }
+                                    }
+                                    if document.truncated {
+                                        p { class: "settings-hint", "✂️ The rest of the text is left out of the preview." }
+                                    }
+                                },
+                            }
+                        }
+                    }
+                }
+            }
+
+            Toasts { toasts }
+        }
+    }
+}
+
+/// Stage of a hands-free voice conversation.
+#[derive(Clone, Copy, PartialEq)]
+enum VoicePhase {
+    Listening,
+    Thinking,
+    Speaking,
+}
+
+impl VoicePhase {
+    fn class(self) -> &'static str {
+        match self {
+            VoicePhase::Listening => "listening",
+            VoicePhase::Thinking => "thinking",
+            VoicePhase::Speaking => "speaking",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ToastKind {
+    Success,
+    Error,
+    Info,
+}
+
+/// A notification in the corner of the page, until it times out or is dismissed.
+#[derive(Clone, PartialEq)]
+struct Toast {
+    id: u64,
+    kind: ToastKind,
+    text: String,
+}
+
+/// Toasts shown at once; older ones make way for new ones.
+const MAX_TOASTS: usize = 5;
+
+static NEXT_TOAST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Shows `text` as a toast. Errors stay up longer, so there's time to read them.
+fn notify(mut toasts: Signal<Vec<Toast>>, kind: ToastKind, text: impl Into<String>) {
+    let id = NEXT_TOAST_ID.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut toasts = toasts.write();
+        toasts.push(Toast {
+            id,
+            kind,
+            text: text.into(),
+        });
+        let excess = toasts.len().saturating_sub(MAX_TOASTS);
+        toasts.drain(..excess);
+    }
+    let timeout = if kind == ToastKind::Error {
+        8_000
+    } else {
+        4_000
+    };
+    spawn(async move {
+        TimeoutFuture::new(timeout).await;
+        toasts.write().retain(|toast| toast.id != id);
+    });
+}
+
+/// The toast queue, newest at the bottom; each can be closed early.
+#[component]
+fn Toasts(toasts: Signal<Vec<Toast>>) -> Element {
+    rsx! {
+        div {
+            class: "toasts",
+            role: "status",
+            for toast in toasts() {
+                div {
+                    key: "{toast.id}",
+                    class: match toast.kind {
+                        ToastKind::Success => "toast success",
+                        ToastKind::Error => "toast error",
+                        ToastKind::Info => "toast info",
+                    },
+                    span {
+                        class: "toast-icon",
+                        match toast.kind {
+                            ToastKind::Success => "✅",
+                            ToastKind::Error => "❌",
+                            ToastKind::Info => "ℹ️",
+                        }
+                    }
+                    span { class: "toast-text", "{toast.text}" }
+                    button {
+                        class: "toast-close",
+                        title: "Dismiss",
+                        onclick: move |_| toasts.write().retain(|t| t.id != toast.id),
+                        "×"
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Header dot summarizing backend health: green when answers can be
+/// generated, amber when they can but the model is still cold, red otherwise.
+#[component]
+fn StatusIndicator(health: Option<Health>, checked: bool) -> Element {
+    let (level, label, detail) = match &health {
+        _ if !checked => (
+            "unknown",
+            "Checking…",
+            "Checking backend status".to_string(),
+        ),
+        None => (
+            "down",
+            "Offline",
+            "Backend unreachable — start it with `cargo run --release --bin backend`".to_string(),
+        ),
+        Some(h) if !h.embedding_ready => (
+            "down",
+            "Embeddings down",
+            "Backend up · embedding model failing — check the backend log".to_string(),
+        ),
+        Some(h) if !h.ollama => (
+            "down",
+            "Ollama down",
+            "Backend up · Ollama unreachable — run `ollama serve`".to_string(),
+        ),
+        Some(h) if !h.model_available => (
+            "down",
+            "No model",
+            format!(
+                "Backend up · Ollama up · {} not installed — run `ollama pull {}`",
+                h.model, h.model
+            ),
+        ),
+        Some(h) => (
+            if h.model_loaded { "up" } else { "warm" },
+            "Ready",
+            format!(
+                "Backend up · Ollama up · {} {} · {} documents ({} chunks) indexed",
+                h.model,
+                if h.model_loaded {
+                    "loaded"
+                } else {
+                    "installed, loads on first question"
+                },
+                h.documents,
+                h.chunks
+            ),
+        ),
+    };
+
+    rsx! {
+        div {
+            class: "status-indicator {level}",
+            title: "{detail}",
+            span { class: "status-dot" }
+            span { class: "status-label", "{label}" }
+        }
+    }
+}
+
+/// Read-only view of a shared conversation, opened from a `#share=` link.
+#[component]
+fn SharedConversationView(token: String) -> Element {
+    let mut share = use_signal(|| None::<Result<SharedConversation, String>>);
+
+    use_future(move || {
+        let token = token.clone();
+        async move {
+            share.set(Some(fetch_share(&token).await));
+        }
+    });
+
+    rsx! {
+        div {
+            class: "app-container",
+            header {
+                class: "header",
+                h1 { "❄️ Ahtohallan" }
+                p { class: "subtitle", "Shared conversation · read-only" }
+            }
+            div {
+                class: "chat-section shared-conversation",
+                match share() {
+                    None => rsx! {
+                        p { class: "hint", "Loading conversation..." }
+                    },
+                    Some(Err(error)) => rsx! {
+                        p { class: "report-error", "❌ {error}" }
+                    },
+                    Some(Ok(conversation)) => rsx! {
+                        div {
+                            class: "messages",
+                            for msg in conversation.messages {
+                                div {
+                                    class: "message {msg.role}",
+                                    div {
+                                        class: "message-content",
+                                        if msg.role == "user" {
+                                            strong { "You: " }
+                                            span { "{msg.content}" }
+                                        } else {
+                                            strong { "🤖 Assistant: " }
+                                            div {
+                                                class: "markdown",
+                                                dangerous_inner_html: render_markdown(&msg.content, &[]),
+                                            }
+                                        }
+                                    }
+                                    if !msg.sources.is_empty() {
+                                        div {
+                                            class: "sources",
+                                            strong { "📚 Sources: " }
+                                            for source in msg.sources.iter() {
+                                                span { class: "source-tag", "{source}" }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        if let Some(expires_at) = conversation.expires_at {
+                            p { class: "hint", "This link expires {format_timestamp(expires_at)}." }
+                        }
+                    },
+                }
+                a { class: "open-app-link", href: "{app_url()}", "❄️ Open Ahtohallan" }
+            }
+        }
+    }
+}
+
+/// Splits an answer into text and `[n]` citation markers, returning each
+/// piece with its citation number. Brackets that don't name known citations
+/// (`[n, m]` names several) stay plain text.
+fn citation_segments(text: &str, citations: &[Citation]) -> Vec<(String, Option<usize>)> {
+    let mut segments = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('[') {
+        let Some(len) = rest[start..].find(']') else {
+            break;
+        };
+        let numbers: Option<Vec<usize>> = rest[start + 1..start + len]
+            .split(',')
+            .map(|n| n.trim().parse().ok())
+            .collect();
+
+        match numbers {
+            Some(numbers)
+                if numbers
+                    .iter()
+                    .all(|n| citations.iter().any(|c| c.number == *n)) =>
+            {
+                if start > 0 {
+                    segments.push((rest[..start].to_string(), None));
+                }
+                for number in numbers {
+                    segments.push((format!("[{}]", number), Some(number)));
+                }
+                rest = &rest[start + len + 1..];
+            }
+            _ => {
+                segments.push((rest[..=start].to_string(), None));
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+
+    if !rest.is_empty() {
+        segments.push((rest.to_string(), None));
+    }
+    segments
+}
+
+/// Renders an answer's Markdown as HTML, turning `[n]` markers for known
+/// citations into clickable `sup.citation-marker` elements. Model output is
+/// untrusted: raw HTML is shown as text, images as their alt text, and links
+/// are kept only for http(s) and mailto targets.
+fn render_markdown(text: &str, citations: &[Citation]) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let mut events = Vec::new();
+    // Adjacent text events are joined so markers split across them are found
+    let mut text_run = String::new();
+    let mut in_code_block = false;
+    // Per open link, whether its tags were dropped
+    let mut dropped_links = Vec::new();
+
+    for event in Parser::new_ext(text, options) {
+        match event {
+            MdEvent::Text(part) | MdEvent::Html(part) | MdEvent::InlineHtml(part) => {
+                text_run.push_str(&part);
+                continue;
+            }
+            _ => push_text_run(&mut events, &mut text_run, citations, in_code_block),
+        }
+
+        match event {
+            MdEvent::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                events.push(MdEvent::Html(
+                    r#"<div class="code-block"><button class="copy-code" data-copy-code title="Copy code">📋</button>"#.into(),
+                ));
+                events.push(event);
+            }
+            MdEvent::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                events.push(event);
+                events.push(MdEvent::Html("</div>".into()));
+            }
+            MdEvent::Start(Tag::Link { ref dest_url, .. }) => {
+                let safe = ["http://", "https://", "mailto:"]
+                    .iter()
+                    .any(|scheme| dest_url.to_ascii_lowercase().starts_with(scheme));
+                dropped_links.push(!safe);
+                if safe {
+                    events.push(event);
+                }
+            }
+            MdEvent::End(TagEnd::Link) => {
+                if !dropped_links.pop().unwrap_or(false) {
+                    events.push(event);
+                }
+            }
+            MdEvent::Start(Tag::Image { .. }) | MdEvent::End(TagEnd::Image) => {}
+            _ => events.push(event),
+        }
+    }
+    push_text_run(&mut events, &mut text_run, citations, in_code_block);
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    html
+}
+
+/// Emits the collected text as escaped text events, with citation markers
+/// (outside code blocks) as inline HTML.
+fn push_text_run<'a>(
+    events: &mut Vec<MdEvent<'a>>,
+    text_run: &mut String,
+    citations: &[Citation],
+    in_code_block: bool,
+) {
+    if text_run.is_empty() {
+        return;
+    }
+    let text = std::mem::take(text_run);
+    if in_code_block {
+        events.push(MdEvent::Text(text.into()));
+        return;
+    }
+    for (segment, number) in citation_segments(&text, citations) {
+        events.push(match number {
+            Some(number) => MdEvent::InlineHtml(
+                format!(
+                    r#"<sup class="citation-marker" data-citation="{}" title="Show source">[{}]</sup>"#,
+                    number, number
+                )
+                .into(),
+            ),
+            None => MdEvent::Text(segment.into()),
+        });
+    }
+}
+
+/// The citation number of the marker a click in rendered Markdown landed on.
+fn clicked_citation(evt: &MouseEvent) -> Option<usize> {
+    let target = evt.data().try_as_web_event()?.target()?;
+    target
+        .dyn_into::<web_sys::Element>()
+        .ok()?
+        .closest("[data-citation]")
+        .ok()??
+        .get_attribute("data-citation")?
+        .parse()
+        .ok()
+}
+
+/// Copies the code block whose copy button a click in rendered Markdown landed
+/// on, and briefly shows "Copied" on the button.
+fn copy_code_block(evt: &MouseEvent) {
+    let Some(button) = evt
+        .data()
+        .try_as_web_event()
+        .and_then(|event| event.target())
+        .and_then(|target| target.dyn_into::<web_sys::Element>().ok())
+        .and_then(|element| element.closest("[data-copy-code]").ok().flatten())
+    else {
+        return;
+    };
+    let Some(code) = button
+        .parent_element()
+        .and_then(|block| block.query_selector("code").ok().flatten())
+        .and_then(|code| code.text_content())
+    else {
+        return;
+    };
+    spawn(async move {
+        if copy_to_clipboard(&code).await {
+            button.set_text_content(Some("✅ Copied"));
+            TimeoutFuture::new(COPIED_FLASH_MS).await;
+            button.set_text_content(Some("📋"));
+        }
+    });
+}
+
+/// Writes `text` to the clipboard; false when the browser refuses.
+async fn copy_to_clipboard(text: &str) -> bool {
+    let Some(window) = web_sys::window() else {
+        return false;
+    };
+    let promise = window.navigator().clipboard().write_text(text);
+    wasm_bindgen_futures::JsFuture::from(promise).await.is_ok()
+}
+
+/// Expands citation card `number` of message `message` and scrolls to it.
+fn open_citation(message: usize, number: usize) {
+    let Some(document) = web_sys::window().and_then(|w| w.document()) else {
+        return;
+    };
+    if let Some(card) = document.get_element_by_id(&format!("citation-{}-{}", message, number)) {
+        let _ = card.set_attribute("open", "");
+        card.scroll_into_view();
+    }
+}
+
+/// Finds the most recent answered question in the session that closely matches `query`,
+/// returning the index of its answer.
+fn find_similar_answer(messages: &[Message], query: &str) -> Option<usize> {
+    let query = question_words(query);
+    if query.is_empty() {
+        return None;
+    }
+
+    messages
+        .windows(2)
+        .enumerate()
+        .rev()
+        .find(|(_, pair)| {
+            pair[0].role == "user"
+                && pair[1].role == "assistant"
+                && !pair[1].content.is_empty()
+                && word_overlap(&query, &question_words(&pair[0].content))
+                    >= SIMILAR_QUESTION_THRESHOLD
+        })
+        .map(|(i, _)| i + 1)
+}
+
+fn question_words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Question words too common to be worth highlighting in a passage.
+const HIGHLIGHT_SKIP_WORDS: &[&str] = &[
+    "the", "and", "are", "was", "what", "which", "who", "how", "why", "when", "where", "does",
+    "for", "with", "about", "this", "that", "from", "into", "have", "has", "can",
+];
+
+/// Words of the question that message `index` answers, to highlight in its
+/// passages; empty when it doesn't follow a question.
+fn highlight_terms(messages: &[Message], index: usize) -> HashSet<String> {
+    index
+        .checked_sub(1)
+        .and_then(|previous| messages.get(previous))
+        .filter(|question| question.role == "user")
+        .map(|question| {
+            question_words(&question.content)
+                .into_iter()
+                .filter(|word| {
+                    word.chars().count() > 2 && !HIGHLIGHT_SKIP_WORDS.contains(&word.as_str())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Splits `text` into runs, marking whole words found in `terms` (compared
+/// lowercased) so they can be highlighted.
+fn highlight_segments(text: &str, terms: &HashSet<String>) -> Vec<(String, bool)> {
+    let mut segments: Vec<(String, bool)> = Vec::new();
+    let mut rest = text;
+    while let Some(first) = rest.chars().next() {
+        let is_word = first.is_alphanumeric();
+        let end = rest
+            .find(|c: char| c.is_alphanumeric() != is_word)
+            .unwrap_or(rest.len());
+        let (piece, tail) = rest.split_at(end);
+        let hit = is_word && terms.contains(&piece.to_lowercase());
+        match segments.last_mut() {
+            Some((run, run_hit)) if *run_hit == hit => run.push_str(piece),
+            _ => segments.push((piece.to_string(), hit)),
+        }
+        rest = tail;
+    }
+    segments
+}
+
+/// Jaccard similarity of two word sets.
+fn word_overlap(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+async fn send_message(
+    mut messages: Signal<Vec<Message>>,
+    query: String,
+    mut is_loading: Signal<bool>,
+    options: ChatOptions,
+    speak_answer: bool,
+    toasts: Signal<Vec<Toast>>,
+) {
+    if query.is_empty() {
+        return;
+    }
+
+    // Add user message
+    messages.write().push(Message {
+        role: "user".to_string(),
+        content: query.clone(),
+        sources: vec![],
+        warning: None,
+        steps: vec![],
+        citations: vec![],
+        grounding: None,
+        timings: None,
+        rating: None,
+        created_at: unix_now(),
+        response_ms: None,
+    });
+
+    is_loading.set(true);
+    let started = js_sys::Date::now();
+
+    if DEMO_MODE {
+        // A short pause so the demo reads like a real round-trip
+        TimeoutFuture::new(400).await;
+        let reply = demo::answer(&query);
+        if speak_answer {
+            speak(&speakable(&reply.answer));
+        }
+        messages.write().push(Message {
+            role: "assistant".to_string(),
+            content: reply.answer,
+            sources: reply.sources,
+            warning: None,
+            steps: vec![],
+            citations: vec![],
+            grounding: None,
+            timings: None,
+            rating: None,
+            created_at: unix_now(),
+            response_ms: Some((js_sys::Date::now() - started) as u64),
+        });
+        is_loading.set(false);
+        return;
+    }
+
+    // Send to backend
+    let result = if options.search {
+        search_documents(&query, &options)
+            .await
+            .map(|message| messages.write().push(message))
+    } else {
+        stream_answer(&query, &options, messages, speak_answer).await
+    };
+    if result.is_ok() {
+        let elapsed = (js_sys::Date::now() - started) as u64;
+        if let Some(answer) = messages
+            .write()
+            .last_mut()
+            .filter(|message| message.role != "user")
+        {
+            answer.response_ms = Some(elapsed);
+        }
+    }
+    // A stopped request fails too, but that's no error to show
+    let stopped = options.abort.as_ref().is_some_and(|abort| abort.aborted());
+    if let (Err(error), false) = (result, stopped) {
+        notify(toasts, ToastKind::Error, error);
+    }
+
+    is_loading.set(false);
+}
+
+/// Runs `query` through `/search` and lists the matching passages as
+/// expanded source cards, without generating an answer.
+async fn search_documents(query: &str, options: &ChatOptions) -> Result<Message, String> {
+    let resp = api::post("/search")
+        .abort_signal(options.abort.as_ref())
+        .json(&json!({
+            "query": query,
+            "sources": options.sources,
+            "collection": (!options.collection.is_empty()).then_some(&options.collection),
+            "tags": options.tags,
+            "top_k": options.top_k,
+        }))
+        .unwrap()
+        .send()
+        .await
+        .map_err(|_| {
+            format!(
+                "Failed to connect to backend. Is it running at {}?",
+                api::backend_url()
+            )
+        })?;
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+
+    let reply = resp
+        .json::<SearchReply>()
+        .await
+        .map_err(|e| format!("Unreadable search results: {}", e))?;
+    let content = match reply.results.len() {
+        0 => "No passages matched the query.".to_string(),
+        1 => "Found 1 matching passage.".to_string(),
+        n => format!("Found {} matching passages.", n),
+    };
+    let citations = reply
+        .results
+        .into_iter()
+        .enumerate()
+        .map(|(i, hit)| Citation {
+            number: i + 1,
+            source: hit.source,
+            score: hit.score,
+            text: hit.text,
+        })
+        .collect();
+
+    Ok(Message {
+        role: "search".to_string(),
+        content,
+        sources: vec![],
+        warning: None,
+        steps: vec![],
+        citations,
+        grounding: None,
+        timings: None,
+        rating: None,
+        created_at: unix_now(),
+        response_ms: None,
+    })
+}
+
+/// Streams `/chat/stream` into a new assistant message. With `speak_answer`,
+/// each sentence is queued for speech as soon as it is complete.
+async fn stream_answer(
+    query: &str,
+    options: &ChatOptions,
+    mut messages: Signal<Vec<Message>>,
+    speak_answer: bool,
+) -> Result<(), String> {
+    // Earlier turns, for prompt templates that use them; the last message is this query
+    let mut history: Vec<serde_json::Value> = {
+        let messages = messages.read();
+        messages[..messages.len().saturating_sub(1)]
+            .iter()
+            .filter(|m| m.role == "user" || m.role == "assistant")
+            .map(|m| json!({ "role": m.role, "content": m.content }))
+            .collect()
+    };
+    history.drain(..history.len().saturating_sub(HISTORY_TURNS));
+    // Stopping the readout, or reading another message, ends the queueing
+    let readout = speech_id();
+
+    let resp = api::post("/chat/stream")
+        .abort_signal(options.abort.as_ref())
+        .json(&json!({
+            "query": query,
+            "history": history,
+            "deep_think": options.deep_think,
+            "verify": options.verify,
+            "debug": options.debug,
+            "length": options.length,
+            "sources": options.sources,
+            "collection": (!options.collection.is_empty()).then_some(&options.collection),
+            "tags": options.tags,
+            "session_id": options.session_id,
+            "model": (!options.model.is_empty()).then_some(&options.model),
+            "top_k": options.top_k,
+        }))
+        .unwrap()
+        .send()
+        .await
+        .map_err(|_| {
+            format!(
+                "Failed to connect to backend. Is it running at {}?",
+                api::backend_url()
+            )
+        })?;
+
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+
+    let body = resp.body().ok_or("Empty response from backend")?;
+    let reader: web_sys::ReadableStreamDefaultReader = body.get_reader().unchecked_into();
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut sources: Vec<String> = Vec::new();
+    let mut warning: Option<String> = None;
+    let mut steps: Vec<ThinkStep> = Vec::new();
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut started = false;
+    // Bytes of the answer's `speakable` text already queued for speech
+    let mut spoken = 0;
+
+    loop {
+        let chunk = wasm_bindgen_futures::JsFuture::from(reader.read())
+            .await
+            .map_err(|_| "Connection to backend lost".to_string())?;
+
+        let done = js_sys::Reflect::get(&chunk, &"done".into())
+            .ok()
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        if done {
+            break;
+        }
+
+        if let Ok(value) = js_sys::Reflect::get(&chunk, &"value".into()) {
+            buffer.extend(js_sys::Uint8Array::new(&value).to_vec());
+        }
+
+        // Server-sent events are separated by a blank line
+        while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let raw: Vec<u8> = buffer.drain(..end + 2).collect();
+            let (event, data) = parse_sse_event(&String::from_utf8_lossy(&raw));
+            let data: serde_json::Value = serde_json::from_str(&data).unwrap_or_default();
+
+            match event.as_str() {
+                "warning" => {
+                    warning = data["message"].as_str().map(|s| s.to_string());
+                }
+                "steps" => {
+                    steps = serde_json::from_value(data).unwrap_or_default();
+                }
+                "citations" => {
+                    citations = serde_json::from_value(data).unwrap_or_default();
+                }
+                "sources" => {
+                    sources = data
+                        .as_array()
+                        .map(|arr| {
+                            arr.iter()
+                                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                }
+                "token" => {
+                    let token = data["content"].as_str().unwrap_or_default();
+                    let mut messages = messages.write();
+
+                    if !started {
+                        started = true;
+                        messages.push(Message {
+                            role: "assistant".to_string(),
+                            content: String::new(),
+                            sources: sources.clone(),
+                            warning: warning.clone(),
+                            steps: steps.clone(),
+                            citations: citations.clone(),
+                            grounding: None,
+                            timings: None,
+                            rating: None,
+                            created_at: unix_now(),
+                            response_ms: None,
+                        });
+                    }
+
+                    let answer = &mut messages.last_mut().unwrap().content;
+                    if answer.is_empty() {
+                        answer.push_str(token.trim_start());
+                    } else {
+                        answer.push_str(token);
+                    }
+
+                    if speak_answer && speech_id() == readout {
+                        let text = speakable(answer);
+                        if let Some(rest) = text.get(spoken..) {
+                            if let Some(len) = complete_sentences_len(rest) {
+                                speak(&rest[..len]);
+                                spoken += len;
+                            }
+                        }
+                    }
+                }
+                "grounding" if started => {
+                    if let Some(message) = messages.write().last_mut() {
+                        message.grounding = serde_json::from_value(data).ok();
+                    }
+                }
+                "timings" if started => {
+                    if let Some(message) = messages.write().last_mut() {
+                        message.timings = serde_json::from_value(data).ok();
+                    }
+                }
+                // The content filter changed an answer already shown
+                "moderation" if started => {
+                    if let Some(message) = messages.write().last_mut() {
+                        message.content = data["answer"].as_str().unwrap_or_default().to_string();
+                        spoken = speakable(&message.content).len();
+                    }
+                }
+                "error" => {
+                    return Err(serde_json::from_value::<ApiError>(data)
+                        .map(|error| error.describe())
+                        .unwrap_or_else(|_| "Generation failed".to_string()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if !started {
+        return Err("No answer received from backend".to_string());
+    }
+
+    // Speak whatever trails the last full sentence
+    if speak_answer && speech_id() == readout {
+        if let Some(answer) = messages.read().last().map(|m| speakable(&m.content)) {
+            let rest = answer.get(spoken..).unwrap_or_default().trim();
+            if !rest.is_empty() {
+                speak(rest);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits one server-sent event into its name and (joined) data lines.
+fn parse_sse_event(raw: &str) -> (String, String) {
+    let mut event = String::new();
+    let mut data = Vec::new();
+
+    for line in raw.lines() {
+        if let Some(name) = line.strip_prefix("event:") {
+            event = name.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+
+    (event, data.join("\n"))
+}
+
+/// Byte length of the run of complete sentences at the start of `text`: up to
+/// the last `.`, `!`, `?` or newline that is followed by whitespace.
+fn complete_sentences_len(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if matches!(c, '.' | '!' | '?' | '\n') {
+            if let Some(&(_, next)) = chars.peek() {
+                if next.is_whitespace() {
+                    end = Some(i + c.len_utf8());
+                }
+            }
+        }
+    }
+
+    end
+}
+
+/// What of an answer is worth reading aloud: its Markdown without code
+/// blocks, citation markers, a closing list of sources, or the symbols that
+/// only format it. Text is only ever dropped from the end of a growing
+/// answer, so a streamed answer can be read as it arrives.
+fn speakable(text: &str) -> String {
+    let mut speech = String::new();
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        let heading = trimmed
+            .trim_start_matches(|c: char| matches!(c, '#' | '*' | '_') || c.is_whitespace())
+            .to_lowercase();
+        if heading.starts_with("sources") || heading.starts_with("references") {
+            break;
+        }
+
+        let line = trimmed.trim_start_matches(['#', '>']);
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '*' | '_' | '`' => {}
+                // `[1]` and `[1, 3]`; an unclosed bracket may be a marker
+                // still streaming in, so the rest of the line waits
+                '[' => match line[i + 1..].find(']') {
+                    Some(close)
+                        if line[i + 1..i + 1 + close]
+                            .chars()
+                            .all(|c| c.is_ascii_digit() || c == ',' || c == ' ') =>
+                    {
+                        while chars.peek().is_some_and(|&(j, _)| j <= i + 1 + close) {
+                            chars.next();
+                        }
+                    }
+                    Some(_) => speech.push(c),
+                    None => break,
+                },
+                _ => speech.push(c),
+            }
+        }
+    }
+    speech
+}
+
+/// The browser's speech recognition constructor, `None` where dictation is
+/// unsupported (Firefox, for one).
+fn speech_recognition_class() -> Option<js_sys::Function> {
+    let window = web_sys::window()?;
+    // Try webkit prefixed version
+    ["webkitSpeechRecognition", "SpeechRecognition"]
+        .into_iter()
+        .filter_map(|name| js_sys::Reflect::get(&window, &name.into()).ok())
+        .find_map(|class| class.dyn_into::<js_sys::Function>().ok())
+}
+
+/// Records from the microphone until the recorder left in `handle` is
+/// stopped, returning the recording.
+async fn record_audio(mut handle: Signal<Option<JsValue>>) -> Result<web_sys::Blob, String> {
+    let unavailable = |_| "The microphone is not available".to_string();
+    let devices = web_sys::window()
+        .ok_or("The microphone is not available")?
+        .navigator()
+        .media_devices()
+        .map_err(unavailable)?;
+    let constraints = web_sys::MediaStreamConstraints::new();
+    constraints.set_audio(&true.into());
+    let request = devices
+        .get_user_media_with_constraints(&constraints)
+        .map_err(unavailable)?;
+    let stream: web_sys::MediaStream = wasm_bindgen_futures::JsFuture::from(request)
+        .await
+        .map_err(|_| "Microphone access was denied".to_string())?
+        .dyn_into()
+        .map_err(unavailable)?;
+    let recorder = web_sys::MediaRecorder::new_with_media_stream(&stream).map_err(unavailable)?;
+
+    let chunks = js_sys::Array::new();
+    let ondata = wasm_bindgen::closure::Closure::<dyn FnMut(web_sys::BlobEvent)>::new({
+        let chunks = chunks.clone();
+        move |event: web_sys::BlobEvent| {
+            if let Some(data) = event.data() {
+                chunks.push(&data);
+            }
+        }
+    });
+    recorder.set_ondataavailable(Some(ondata.as_ref().unchecked_ref()));
+    // The last data arrives before `stop`
+    let stopped = js_sys::Promise::new(&mut |resolve, _reject| recorder.set_onstop(Some(&resolve)));
+    recorder.start().map_err(unavailable)?;
+    handle.set(Some(recorder.clone().into()));
+    let _ = wasm_bindgen_futures::JsFuture::from(stopped).await;
+
+    for track in stream.get_tracks().iter() {
+        if let Ok(track) = track.dyn_into::<web_sys::MediaStreamTrack>() {
+            track.stop();
+        }
+    }
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(&recorder.mime_type());
+    web_sys::Blob::new_with_blob_sequence_and_options(&chunks, &options)
+        .map_err(|_| "The recording could not be read".to_string())
+}
+
+/// Transcribes a recording with the backend's whisper.cpp; `language` is a
+/// BCP 47 tag, detected when empty.
+async fn transcribe_audio(audio: web_sys::Blob, language: &str) -> Result<String, String> {
+    let mut path = "/transcribe".to_string();
+    if !language.is_empty() {
+        path += &format!(
+            "?language={}",
+            String::from(js_sys::encode_uri_component(language))
+        );
+    }
+    let resp = api::post(&path)
+        .header("Content-Type", &audio.type_())
+        .body(audio)
+        .map_err(|_| "The recording could not be sent".to_string())?
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        resp.json::<Transcription>()
+            .await
+            .map(|transcription| transcription.text)
+            .map_err(|e| format!("Unreadable transcription: {}", e))
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+/// Calls a method without arguments on a JavaScript object.
+fn call_method(target: &wasm_bindgen::JsValue, name: &str) {
+    if let Ok(method) = js_sys::Reflect::get(target, &name.into()) {
+        if let Ok(method) = method.dyn_into::<js_sys::Function>() {
+            let _ = method.call0(target);
+        }
+    }
+}
+
+/// The transcript heard so far in a speech recognition `result` event,
+/// interim results included, and whether its latest result is final.
+fn transcript(event: &web_sys::Event) -> (String, bool) {
+    let Ok(results) = js_sys::Reflect::get(event, &"results".into()) else {
+        return (String::new(), false);
+    };
+    let mut text = String::new();
+    let mut finished = false;
+    for result in js_sys::Array::from(&results).iter() {
+        finished = js_sys::Reflect::get(&result, &"isFinal".into())
+            .ok()
+            .and_then(|is_final| is_final.as_bool())
+            .unwrap_or(false);
+        if let Some(words) = js_sys::Reflect::get(&result, &0.into())
+            .and_then(|alternative| js_sys::Reflect::get(&alternative, &"transcript".into()))
+            .ok()
+            .and_then(|words| words.as_string())
+        {
+            text.push_str(&words);
+        }
+    }
+    (text.trim().to_string(), finished)
+}
+
+/// Bumped whenever a readout starts or is stopped, so one that was cut short
+/// neither resets the controls of the next nor queues more sentences.
+static SPEECH_ID: AtomicU64 = AtomicU64::new(0);
+
+fn speech_id() -> u64 {
+    SPEECH_ID.load(Ordering::Relaxed)
+}
+
+fn speech_synthesis() -> Option<web_sys::SpeechSynthesis> {
+    web_sys::window()?.speech_synthesis().ok()
+}
+
+/// An utterance of `text` with the voice, rate and pitch picked in the settings.
+fn utterance(
+    synthesis: &web_sys::SpeechSynthesis,
+    text: &str,
+) -> Option<web_sys::SpeechSynthesisUtterance> {
+    let utterance = web_sys::SpeechSynthesisUtterance::new_with_text(text).ok()?;
+    if let Some(rate) = load_setting(TTS_RATE_KEY).and_then(|rate| rate.parse().ok()) {
+        utterance.set_rate(rate);
+    }
+    if let Some(pitch) = load_setting(TTS_PITCH_KEY).and_then(|pitch| pitch.parse().ok()) {
+        utterance.set_pitch(pitch);
+    }
+    // A saved voice the browser no longer offers falls back to its default
+    if let Some(name) = load_setting(TTS_VOICE_KEY).filter(|name| !name.is_empty()) {
+        let voice = speech_voices(synthesis)
+            .into_iter()
+            .find(|voice| voice.name() == name);
+        utterance.set_voice(voice.as_ref());
+    }
+    Some(utterance)
+}
+
+thread_local! {
+    // Text waiting to be read by the backend's voice, in order
+    static SERVER_SPEECH: RefCell<VecDeque<String>> = const { RefCell::new(VecDeque::new()) };
+    // Whether `play_server_speech` is working through the queue
+    static SERVER_SPEAKING: Cell<bool> = const { Cell::new(false) };
+    // The backend audio playing, with what ends its wait when it is stopped
+    static SERVER_AUDIO: RefCell<Option<(web_sys::HtmlAudioElement, js_sys::Function)>> =
+        const { RefCell::new(None) };
+}
+
+/// Whether answers are read by the backend's `POST /tts` rather than the browser.
+fn server_speech() -> bool {
+    load_setting(TTS_ENGINE_KEY).as_deref() == Some("server")
+}
+
+/// Queues `text` for reading aloud; queued texts play back in order.
+fn speak(text: &str) {
+    if server_speech() {
+        SERVER_SPEECH.with_borrow_mut(|queue| queue.push_back(text.to_string()));
+        if !SERVER_SPEAKING.replace(true) {
+            wasm_bindgen_futures::spawn_local(play_server_speech());
+        }
+    } else {
+        speak_in_browser(text);
+    }
+}
+
+/// Queues `text` on the Web Speech API.
+fn speak_in_browser(text: &str) {
+    if let Some(synthesis) = speech_synthesis() {
+        if let Some(utterance) = utterance(&synthesis, text) {
+            synthesis.speak(&utterance);
+        }
+    }
+}
+
+/// Reads the queued texts with the backend's voice, one request each. Text
+/// the backend can't read is read by the browser instead.
+async fn play_server_speech() {
+    while let Some(text) = SERVER_SPEECH.with_borrow_mut(VecDeque::pop_front) {
+        let readout = speech_id();
+        match fetch_speech(&text).await {
+            Ok(url) => {
+                if speech_id() == readout {
+                    play_audio(&url).await;
+                }
+                let _ = web_sys::Url::revoke_object_url(&url);
+            }
+            Err(_) if speech_id() == readout => speak_in_browser(&text),
+            Err(_) => {}
+        }
+    }
+    SERVER_SPEAKING.set(false);
+}
+
+/// `text` read aloud by the backend at the rate picked in the settings, as
+/// an object URL of the audio.
+async fn fetch_speech(text: &str) -> Result<String, String> {
+    let speed = load_setting(TTS_RATE_KEY)
+        .and_then(|rate| rate.parse::<f32>().ok())
+        .unwrap_or(1.0);
+    let resp = api::post("/tts")
+        .json(&json!({ "text": text, "speed": speed }))
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+    let mime = resp.headers().get("content-type").unwrap_or_default();
+    let audio = resp
+        .binary()
+        .await
+        .map_err(|_| "Unreadable audio".to_string())?;
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(audio.as_slice()));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(&mime);
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options)
+        .map_err(|_| "Unreadable audio".to_string())?;
+    web_sys::Url::create_object_url_with_blob(&blob).map_err(|_| "Unreadable audio".to_string())
+}
+
+/// Plays the audio at `url`, returning once it has ended, failed or been
+/// stopped by `stop_speaking`.
+async fn play_audio(url: &str) {
+    let Ok(audio) = web_sys::HtmlAudioElement::new_with_src(url) else {
+        return;
+    };
+    let done = js_sys::Promise::new(&mut |resolve, _reject| {
+        audio.set_onended(Some(&resolve));
+        audio.set_onerror(Some(&resolve));
+        SERVER_AUDIO.set(Some((audio.clone(), resolve)));
+    });
+    if audio.play().is_ok() {
+        let _ = wasm_bindgen_futures::JsFuture::from(done).await;
+    }
+    SERVER_AUDIO.set(None);
+}
+
+/// Pauses reading aloud, where `resume_speaking` picks up again.
+fn pause_speaking() {
+    if let Some(synthesis) = speech_synthesis() {
+        synthesis.pause();
+    }
+    SERVER_AUDIO.with_borrow(|playing| {
+        if let Some((audio, _)) = playing {
+            let _ = audio.pause();
+        }
+    });
+}
+
+fn resume_speaking() {
+    if let Some(synthesis) = speech_synthesis() {
+        synthesis.resume();
+    }
+    SERVER_AUDIO.with_borrow(|playing| {
+        if let Some((audio, _)) = playing {
+            let _ = audio.play();
+        }
+    });
+}
+
+/// Cuts off whatever is being read and reads `text` instead. `on_end` runs
+/// once it has been read out, unless another readout took over first.
+fn speak_now(text: &str, mut on_end: impl FnMut() + 'static) {
+    stop_speaking();
+    let readout = speech_id();
+    if server_speech() {
+        speak(text);
+        wasm_bindgen_futures::spawn_local(async move {
+            speech_finished(readout).await;
+            if speech_id() == readout {
+                on_end();
+            }
+        });
+        return;
+    }
+    let Some(synthesis) = speech_synthesis() else {
+        return;
+    };
+    let Some(utterance) = utterance(&synthesis, text) else {
+        return;
+    };
+    let onend = wasm_bindgen::closure::Closure::wrap(Box::new(move |_event: web_sys::Event| {
+        if speech_id() == readout {
+            on_end();
+        }
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    utterance.set_onend(Some(onend.as_ref().unchecked_ref()));
+    utterance.set_onerror(Some(onend.as_ref().unchecked_ref()));
+    onend.forget();
+    synthesis.speak(&utterance);
+}
+
+/// Stops reading aloud, dropping anything still queued.
+fn stop_speaking() {
+    SPEECH_ID.fetch_add(1, Ordering::Relaxed);
+    if let Some(synthesis) = speech_synthesis() {
+        synthesis.cancel();
+    }
+    SERVER_SPEECH.with_borrow_mut(VecDeque::clear);
+    if let Some((audio, done)) = SERVER_AUDIO.take() {
+        let _ = audio.pause();
+        let _ = done.call0(&JsValue::NULL);
+    }
+}
+
+/// Waits until readout `readout` has been read out, stopped or replaced.
+async fn speech_finished(readout: u64) {
+    while speech_id() == readout
+        && (SERVER_SPEAKING.get()
+            || speech_synthesis()
+                .is_some_and(|synthesis| synthesis.speaking() || synthesis.pending()))
+    {
+        TimeoutFuture::new(500).await;
+    }
+}
+
+/// The voices the browser can read aloud in; empty until it has loaded them.
+fn speech_voices(synthesis: &web_sys::SpeechSynthesis) -> Vec<web_sys::SpeechSynthesisVoice> {
+    synthesis
+        .get_voices()
+        .iter()
+        .filter_map(|voice| voice.dyn_into().ok())
+        .collect()
+}
+
+/// Names and languages of the voices offered in the settings panel.
+fn voice_options() -> Vec<(String, String)> {
+    web_sys::window()
+        .and_then(|window| window.speech_synthesis().ok())
+        .map(|synthesis| {
+            speech_voices(&synthesis)
+                .iter()
+                .map(|voice| (voice.name(), voice.lang()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Sets `data-theme` on the page for the stylesheet; anything but `light` or
+/// `dark` follows the system's preference.
+fn apply_theme(theme: &str) {
+    let Some(root) = web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.document_element())
+    else {
+        return;
+    };
+    let _ = match theme {
+        "light" | "dark" => root.set_attribute("data-theme", theme),
+        _ => root.remove_attribute("data-theme"),
+    };
+}
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+/// Reads a UI preference saved by `save_setting`.
+fn load_setting(key: &str) -> Option<String> {
+    local_storage()?.get_item(key).ok().flatten()
+}
+
+fn save_setting(key: &str, value: &str) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(key, value);
+    }
+}
+
+async fn fetch_health() -> Option<Health> {
+    let resp = api::get("/health").send().await.ok()?;
+    resp.json::<Health>().await.ok()
+}
+
+async fn fetch_models() -> Result<ModelList, String> {
+    let resp = api::get("/models").send().await.map_err(|_| {
+        format!(
+            "Failed to connect to backend. Is it running at {}?",
+            api::backend_url()
+        )
+    })?;
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+    resp.json::<ModelList>()
+        .await
+        .map_err(|e| format!("Unexpected model list: {}", e))
+}
+
+/// Has the backend pull `name` through Ollama, showing each progress event
+/// in `status` until the pull is done.
+async fn pull_model(
+    name: &str,
+    mut status: Signal<Option<Result<String, String>>>,
+) -> Result<(), String> {
+    let resp = api::post("/models/pull")
+        .json(&json!({ "name": name }))
+        .unwrap()
+        .send()
+        .await
+        .map_err(|_| {
+            format!(
+                "Failed to connect to backend. Is it running at {}?",
+                api::backend_url()
+            )
+        })?;
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+
+    let body = resp.body().ok_or("Empty response from backend")?;
+    let reader: web_sys::ReadableStreamDefaultReader = body.get_reader().unchecked_into();
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let chunk = wasm_bindgen_futures::JsFuture::from(reader.read())
+            .await
+            .map_err(|_| "Connection to backend lost".to_string())?;
+        let done = js_sys::Reflect::get(&chunk, &"done".into())
+            .ok()
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        if done {
+            return Err("The pull ended before the model was ready".to_string());
+        }
+        if let Ok(value) = js_sys::Reflect::get(&chunk, &"value".into()) {
+            buffer.extend(js_sys::Uint8Array::new(&value).to_vec());
+        }
+
+        while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let raw: Vec<u8> = buffer.drain(..end + 2).collect();
+            let (event, data) = parse_sse_event(&String::from_utf8_lossy(&raw));
+            let data: serde_json::Value = serde_json::from_str(&data).unwrap_or_default();
+            match event.as_str() {
+                "progress" => {
+                    let step = data["status"].as_str().unwrap_or("pulling");
+                    status.set(Some(Ok(match data["percent"].as_u64() {
+                        Some(percent) => format!("⬇️ {}: {}%", step, percent),
+                        None => format!("⬇️ {}", step),
+                    })));
+                }
+                "done" => return Ok(()),
+                "error" => {
+                    return Err(serde_json::from_value::<ApiError>(data)
+                        .map(|error| error.describe())
+                        .unwrap_or_else(|_| "Pull failed".to_string()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn format_gigabytes(bytes: u64) -> String {
+    format!("{:.1} GB", bytes as f64 / 1e9)
+}
+
+fn format_megabytes(bytes: f64) -> String {
+    format!("{:.1} MB", bytes / (1024.0 * 1024.0))
+}
+
+async fn fetch_report(filename: &str) -> Result<IngestionReport, String> {
+    let path = format!(
+        "/documents/{}/report",
+        String::from(js_sys::encode_uri_component(filename))
+    );
+    let resp = api::get(&path)
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        resp.json::<IngestionReport>()
+            .await
+            .map_err(|e| format!("Unreadable report: {}", e))
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+async fn fetch_preview(filename: &str) -> Result<DocumentPreview, String> {
+    let path = format!(
+        "/documents/{}/preview",
+        String::from(js_sys::encode_uri_component(filename))
+    );
+    let resp = api::get(&path)
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        resp.json::<DocumentPreview>()
+            .await
+            .map_err(|e| format!("Unreadable preview: {}", e))
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+/// Publishes the conversation's questions and answers, returning the link
+/// that opens them read-only.
+async fn share_conversation(messages: &[Message], expires_in_hours: u64) -> Result<String, String> {
+    let shared: Vec<serde_json::Value> = messages
+        .iter()
+        .filter(|m| m.role == "user" || m.role == "assistant")
+        .map(|m| json!({ "role": m.role, "content": m.content, "sources": m.sources }))
+        .collect();
+    let body = json!({
+        "messages": shared,
+        "expires_in_hours": (expires_in_hours > 0).then_some(expires_in_hours),
+    });
+
+    let resp = api::post("/shares")
+        .json(&body)
+        .unwrap()
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if !resp.ok() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(error_text(&body, resp.status()));
+    }
+
+    let reply = resp
+        .json::<ShareReply>()
+        .await
+        .map_err(|e| format!("Unreadable reply: {}", e))?;
+    Ok(format!("{}#share={}", app_url(), reply.token))
+}
+
+/// Stores a 👍/👎 rating of `answer` on the backend for later analysis.
+async fn send_feedback(
+    query: &str,
+    answer: &str,
+    sources: &[String],
+    up: bool,
+    comment: Option<String>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let body = json!({
+        "query": query,
+        "answer": answer,
+        "sources": sources,
+        "rating": if up { "up" } else { "down" },
+        "comment": comment,
+        "session_id": session_id,
+    });
+
+    let resp = api::post("/feedback")
+        .json(&body)
+        .unwrap()
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        Ok(())
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+async fn fetch_share(token: &str) -> Result<SharedConversation, String> {
+    let path = format!(
+        "/shares/{}",
+        String::from(js_sys::encode_uri_component(token))
+    );
+    let resp = api::get(&path)
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        resp.json::<SharedConversation>()
+            .await
+            .map_err(|e| format!("Unreadable conversation: {}", e))
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+fn location_hash() -> Option<String> {
+    web_sys::window()?.location().hash().ok()
+}
+
+/// This page's address without query or fragment.
+fn app_url() -> String {
+    web_sys::window()
+        .and_then(|w| {
+            let location = w.location();
+            Some(format!(
+                "{}{}",
+                location.origin().ok()?,
+                location.pathname().ok()?
+            ))
+        })
+        .unwrap_or_default()
+}
+
+/// Documents most similar to `filename`; empty when the backend can't say.
+async fn fetch_similar_documents(filename: &str) -> Vec<SimilarDocument> {
+    let path = format!(
+        "/documents/{}/similar",
+        String::from(js_sys::encode_uri_component(filename))
+    );
+    match api::get(&path).send().await {
+        Ok(resp) if resp.ok() => resp
+            .json::<SimilarDocumentsResponse>()
+            .await
+            .map(|data| data.similar)
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+async fn fetch_overview(refresh: bool) -> Result<CorpusOverview, String> {
+    let path = format!("/overview?refresh={}", refresh);
+    let resp = api::get(&path)
+        .send()
+        .await
+        .map_err(|_| "Failed to connect to backend".to_string())?;
+    if resp.ok() {
+        resp.json::<CorpusOverview>()
+            .await
+            .map_err(|e| format!("Unreadable overview: {}", e))
+    } else {
+        let body = resp.text().await.unwrap_or_default();
+        Err(error_text(&body, resp.status()))
+    }
+}
+
+/// Unix seconds as a local date and time.
+fn format_timestamp(seconds: u64) -> String {
+    let date = js_sys::Date::new(&JsValue::from_f64(seconds as f64 * 1000.0));
+    String::from(date.to_locale_string("default", &JsValue::UNDEFINED))
+}
+
+/// Redaction counts as "2 email, 1 national id".
+fn format_redactions(redactions: &BTreeMap<String, usize>) -> String {
+    redactions
+        .iter()
+        .map(|(kind, count)| format!("{} {}", count, kind.replace('_', " ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// "4.2 s", or "350 ms" under a second.
+fn format_duration(ms: u64) -> String {
+    if ms >= 1000 {
+        format!("{:.1} s", ms as f64 / 1000.0)
+    } else {
+        format!("{} ms", ms)
+    }
+}
+
+/// Hours and minutes of a Unix time, in the browser's locale, e.g. "14:05".
+fn format_time(seconds: u64) -> String {
+    let date = js_sys::Date::new(&JsValue::from_f64(seconds as f64 * 1000.0));
+    let options = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&options, &"hour".into(), &"2-digit".into());
+    let _ = js_sys::Reflect::set(&options, &"minute".into(), &"2-digit".into());
+    String::from(date.to_locale_time_string_with_options("default", &options))
+}
+
+fn unix_now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+/// Sources a message draws on: its listed sources, else the documents of the
+/// passages it shows (search results).
+fn message_sources(message: &Message) -> Vec<String> {
+    if !message.sources.is_empty() {
+        return message.sources.clone();
+    }
+    let mut sources: Vec<String> = Vec::new();
+    for citation in &message.citations {
+        if !sources.contains(&citation.source) {
+            sources.push(citation.source.clone());
+        }
+    }
+    sources
+}
+
+/// The conversation as a Markdown transcript.
+fn conversation_markdown(title: &str, messages: &[Message]) -> String {
+    let mut markdown = format!(
+        "# {}\n\n_Exported {}_\n",
+        title,
+        format_timestamp(unix_now())
+    );
+    for message in messages {
+        let speaker = match message.role.as_str() {
+            "user" => "You",
+            "assistant" => "Assistant",
+            "search" => "Search",
+            _ => "Error",
+        };
+        markdown.push_str(&format!(
+            "\n## {} · {}\n\n{}\n",
+            speaker,
+            format_timestamp(message.created_at),
+            message.content.trim()
+        ));
+        for citation in &message.citations {
+            markdown.push_str(&format!(
+                "\n> [{}] {} ({:.2}): {}\n",
+                citation.number,
+                citation.source,
+                citation.score,
+                citation
+                    .text
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+        }
+        let sources = message_sources(message);
+        if !sources.is_empty() {
+            markdown.push_str(&format!("\n**Sources:** {}\n", sources.join(", ")));
+        }
+    }
+    markdown
+}
 
-                                                            // Upload using fetch API
-                                                            match upload_files_formdata(form_data).await {
-                                                                Ok(_response) => {
-                                                                    // Add uploaded files to documents list
-                                                                    for filename in &filenames {
-                                                                        if !documents_clone.read().iter().any(|d| &d.filename == filename) {
-                                                                            documents_clone.write().push(DocumentInfo {
-                                                                                filename: filename.clone(),
-                                                                            });
-                                                                        }
-                                                                    }
-                                                                    upload_status_clone.set(format!("✅ Successfully uploaded {} file(s)", filenames.len()));
-                                                                }
-                                                                Err(e) => {
-                                                                    upload_status_clone.set(format!("❌ Upload failed: {}", e));
-                                                                }
-                                                            }
+/// The conversation as pretty-printed JSON.
+fn conversation_json(title: &str, messages: &[Message]) -> String {
+    let messages: Vec<serde_json::Value> = messages
+        .iter()
+        .map(|message| {
+            json!({
+                "role": message.role,
+                "content": message.content,
+                "sources": message_sources(message),
+                "created_at": message.created_at,
+            })
+        })
+        .collect();
+    let export = json!({
+        "title": title,
+        "exported_at": unix_now(),
+        "messages": messages,
+    });
+    serde_json::to_string_pretty(&export).unwrap_or_default()
+}
 
-                                                            // Clear the input
-                                                            input.set_value("");
-                                                        } else {
-                                                            upload_status_clone.set("❌ Failed to create form data".to_string());
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
+/// Has the browser download `contents` as a file called `filename`.
+fn download_file(filename: &str, mime: &str, contents: &str) {
+    let Some(document) = web_sys::window().and_then(|window| window.document()) else {
+        return;
+    };
+    let parts = js_sys::Array::of1(&JsValue::from_str(contents));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type(mime);
+    let Ok(blob) = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options) else {
+        return;
+    };
+    let Ok(url) = web_sys::Url::create_object_url_with_blob(&blob) else {
+        return;
+    };
+    if let Some(link) = document
+        .create_element("a")
+        .ok()
+        .and_then(|element| element.dyn_into::<web_sys::HtmlAnchorElement>().ok())
+    {
+        link.set_href(&url);
+        link.set_download(filename);
+        link.click();
+    }
+    let _ = web_sys::Url::revoke_object_url(&url);
+}
 
-                                    is_uploading_clone.set(false);
-                                });
-                            }
-                        }
-                        button {
-                            class: "upload-button",
-                            disabled: is_uploading(),
-                            onclick: move |_| {
-                                if let Some(doc) = web_sys::window().and_then(|w| w.document()) {
-                                    if let Some(el) = doc.get_element_by_id("file-upload") {
-                                        if let Ok(input) = el.dyn_into::<HtmlInputElement>() {
-                                            let _ = input.click();
-                                        }
-                                    }
-                                }
-                            },
-                            if is_uploading() {
-                                "⏳ Uploading..."
-                            } else {
-                                "📁 Choose Files (.md, .pdf)"
-                            }
-                        }
-                    }
+/// A filename-safe version of a conversation title.
+fn export_filename(title: &str, extension: &str) -> String {
+    let stem: String = title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let stem = stem
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if stem.is_empty() {
+        format!("conversation.{}", extension)
+    } else {
+        format!("{}.{}", stem, extension)
+    }
+}
 
-                    if !upload_status().is_empty() {
-                        div {
-                            class: if is_uploading() { "status uploading" } else { "status" },
-                            "{upload_status}"
-                        }
-                    }
+/// Refreshes the list of indexed documents.
+async fn load_documents(mut documents: Signal<Vec<DocumentInfo>>) {
+    if let Ok(resp) = api::get("/documents").send().await {
+        if let Ok(data) = resp.json::<serde_json::Value>().await {
+            if let Some(names) = data["documents"].as_array() {
+                let mut sizes: HashMap<String, DocumentSize> =
+                    serde_json::from_value(data["sizes"].clone()).unwrap_or_default();
+                documents.set(
+                    names
+                        .iter()
+                        .filter_map(|v| v.as_str())
+                        .map(|filename| DocumentInfo {
+                            filename: filename.to_string(),
+                            size: sizes.remove(filename),
+                        })
+                        .collect(),
+                );
+            }
+        }
+    }
+}
 
-                    div {
-                        class: "documents-list",
-                        h3 { "📚 Uploaded Documents" }
+/// The watch folder's state, or `None` while the backend is unreachable.
+async fn fetch_watch_status() -> Option<WatchStatus> {
+    let resp = api::get("/watch").send().await.ok()?;
+    resp.json::<WatchStatus>().await.ok()
+}
 
-                        if documents().is_empty() {
-                            p {
-                                class: "hint",
-                                style: "text-align: center; padding: 20px; color: var(--text-secondary);",
-                                "No documents uploaded yet. Upload .md or .pdf files to get started!"
-                            }
-                        } else {
-                            div {
-                                class: "documents-grid",
-                                for doc in documents() {
-                                    div {
-                                        class: "document-card",
-                                        div {
-                                            class: "document-icon",
-                                            if doc.filename.ends_with(".pdf") {
-                                                "📄"
-                                            } else {
-                                                "📝"
-                                            }
-                                        }
-                                        div {
-                                            class: "document-details",
-                                            div {
-                                                class: "document-name",
-                                                title: "{doc.filename}",
-                                                "{doc.filename}"
-                                            }
-                                        }
-                                        button {
-                                            class: "delete-button",
-                                            title: "Remove document",
-                                            onclick: {
-                                                let filename = doc.filename.clone();
-                                                move |_| handle_delete(filename.clone())
-                                            },
-                                            "🗑️"
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+/// Refreshes the corpus overview (document/chunk counts, language breakdown).
+async fn load_stats(mut stats: Signal<CorpusStats>) {
+    if let Ok(resp) = api::get("/stats").send().await {
+        if let Ok(data) = resp.json::<CorpusStats>().await {
+            stats.set(data);
+        }
+    }
+}
 
-                // Chat section
-                div {
-                    class: "chat-section",
-                    h2 { "💬 Chat" }
+/// Refreshes the list of saved conversations.
+async fn load_sessions(mut sessions: Signal<Vec<SessionSummary>>) {
+    if let Ok(resp) = api::get("/sessions").send().await {
+        if let Ok(data) = resp.json::<SessionsResponse>().await {
+            sessions.set(data.sessions);
+        }
+    }
+}
 
-                    // Messages
-                    div {
-                        class: "messages",
-                        id: "messages-container",
+async fn rename_session(id: &str, title: &str) {
+    let path = format!(
+        "/sessions/{}",
+        String::from(js_sys::encode_uri_component(id))
+    );
+    if let Ok(request) = api::patch(&path).json(&json!({ "title": title })) {
+        let _ = request.send().await;
+    }
+}
 
-                        if messages().is_empty() {
-                            div {
-                                class: "empty-state",
-                                p { "👋 Upload documents and start chatting!" }
-                                p { class: "hint", "Your questions will be answered using only the uploaded documents." }
-                            }
-                        }
+async fn delete_session(id: &str) {
+    let path = format!(
+        "/sessions/{}",
+        String::from(js_sys::encode_uri_component(id))
+    );
+    let _ = api::delete(&path).send().await;
+}
 
-                        for msg in messages() {
-                            div {
-                                class: "message {msg.role}",
-                                div {
-                                    class: "message-content",
-                                    if msg.role == "user" {
-                                        strong { "You: " }
-                                    } else if msg.role == "assistant" {
-                                        strong { "🤖 Assistant: " }
-                                    } else {
-                                        strong { "⚠️ Error: " }
-                                    }
-                                    span { "{msg.content}" }
+/// Drops a session's messages from index `from` on.
+async fn truncate_session(id: &str, from: usize) {
+    let path = format!(
+        "/sessions/{}/messages?from={}",
+        String::from(js_sys::encode_uri_component(id)),
+        from
+    );
+    let _ = api::delete(&path).send().await;
+}
 
-                                    // TTS button for assistant messages
-                                    if msg.role == "assistant" {
-                                        button {
-                                            class: "tts-button",
-                                            onclick: {
-                                                let content = msg.content.clone();
-                                                move |_| speak_text(content.clone())
-                                            },
-                                            "📢"
-                                        }
-                                    }
-                                }
+/// The current session's id, creating one on the backend first if needed.
+/// `None` when the backend can't create it; the chat then goes unsaved.
+async fn ensure_session(mut session_id: Signal<Option<String>>) -> Option<String> {
+    if let Some(id) = session_id() {
+        return Some(id);
+    }
+    let resp = api::post("/sessions")
+        .json(&json!({}))
+        .ok()?
+        .send()
+        .await
+        .ok()?;
+    if !resp.ok() {
+        return None;
+    }
+    let session = resp.json::<SessionSummary>().await.ok()?;
+    save_setting(SESSION_KEY, &session.id);
+    session_id.set(Some(session.id.clone()));
+    Some(session.id)
+}
 
-                                if !msg.sources.is_empty() {
-                                    div {
-                                        class: "sources",
-                                        strong { "📚 Sources: " }
-                                        for source in msg.sources.iter() {
-                                            span { class: "source-tag", "{source}" }
-                                        }
-                                    }
-                                }
-                            }
-                        }
+/// A saved conversation's messages, or `None` if it no longer exists.
+async fn fetch_session_messages(id: &str) -> Option<Vec<Message>> {
+    let path = format!(
+        "/sessions/{}/messages",
+        String::from(js_sys::encode_uri_component(id))
+    );
+    let resp = api::get(&path).send().await.ok()?;
+    if !resp.ok() {
+        return None;
+    }
+    let session = resp.json::<SessionMessages>().await.ok()?;
+    Some(
+        session
+            .messages
+            .into_iter()
+            .map(|message| Message {
+                role: message.role,
+                content: message.content,
+                sources: message.sources,
+                warning: None,
+                steps: vec![],
+                citations: vec![],
+                grounding: None,
+                timings: None,
+                rating: None,
+                created_at: message.created_at,
+                response_ms: None,
+            })
+            .collect(),
+    )
+}
 
-                        if is_loading() {
-                            div {
-                                class: "message assistant loading",
-                                div {
-                                    class: "message-content",
-                                    strong { "🤖 Assistant: " }
-                                    span { "💭 Thinking..." }
-                                }
-                            }
-                        }
-                    }
+/// Refreshes the collections and the documents in each.
+async fn load_collections(mut collections: Signal<Vec<Collection>>) {
+    if let Ok(resp) = api::get("/collections").send().await {
+        if let Ok(data) = resp.json::<CollectionsResponse>().await {
+            collections.set(data.collections);
+        }
+    }
+}
 
-                    // Input area with Deep Think toggle
-                    div {
-                        class: "input-area",
+/// Fetches the detailed stats of document `name` into `document_stats`,
+/// leaving it out if the backend can't describe it.
+async fn load_document_stats(
+    name: &str,
+    mut document_stats: Signal<HashMap<String, DocumentStats>>,
+) {
+    let path = format!(
+        "/documents/{}/stats",
+        String::from(js_sys::encode_uri_component(name))
+    );
+    if let Ok(resp) = api::get(&path).send().await {
+        if let Ok(details) = resp.json::<DocumentStats>().await {
+            document_stats.write().insert(name.to_string(), details);
+        }
+    }
+}
 
-                        div {
-                            class: "input-row",
-                            // Microphone button
-                            button {
-                                class: "mic-button",
-                                disabled: is_listening() || is_loading(),
-                                onclick: move |_| start_listening(),
-                                title: "Voice input",
-                                if is_listening() {
-                                    "🎤 Listening..."
-                                } else {
-                                    "🎤"
-                                }
-                            }
+/// Refreshes the category tags and the documents carrying each.
+async fn load_tags(mut tags: Signal<Vec<DocumentTag>>) {
+    if let Ok(resp) = api::get("/tags").send().await {
+        if let Ok(data) = resp.json::<TagsResponse>().await {
+            tags.set(data.tags);
+        }
+    }
+}
 
-                            textarea {
-                                class: "chat-input",
-                                placeholder: "Ask a question about your documents...",
-                                value: "{input_value}",
-                                disabled: is_loading() || is_listening(),
-                                rows: 2,
-                                oninput: move |evt| input_value.set(evt.value()),
-                                onkeydown: handle_keydown,
-                            }
-                        }
+/// Polls `GET /jobs/:id` until the upload job finishes, mirroring per-file
+/// progress into `progress` along the way.
+async fn poll_upload_job(
+    job_id: &str,
+    mut progress: Signal<Vec<FileProgress>>,
+) -> Result<UploadJob, String> {
+    let path = format!("/jobs/{}", job_id);
 
-                        div {
-                            class: "controls-row",
-                            button {
-                                class: "send-button",
-                                disabled: is_loading() || input_value().trim().is_empty() || is_listening(),
-                                onclick: move |_| handle_send(),
-                                if is_loading() {
-                                    "⏳ Sending..."
-                                } else {
-                                    "🚀 Send"
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    loop {
+        TimeoutFuture::new(500).await;
 
-            // Footer
-            footer {
-                class: "footer",
-                p { "Built with Dioxus 🦀 | Powered by Ollama (phi3) + fastembed" }
-                p { class: "hint", "Backend: http://localhost:3000" }
-            }
+        let resp = api::get(&path)
+            .send()
+            .await
+            .map_err(|_| "Failed to connect to backend".to_string())?;
+
+        if !resp.ok() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(error_text(&body, resp.status()));
+        }
+
+        let job = resp
+            .json::<UploadJob>()
+            .await
+            .map_err(|_| "Failed to parse job status".to_string())?;
+
+        progress.set(job.files.clone());
+
+        if job.status == "completed" || job.status == "failed" {
+            return Ok(job);
         }
     }
 }
 
-async fn send_message(
-    mut messages: Signal<Vec<Message>>,
-    mut input_value: Signal<String>,
-    mut is_loading: Signal<bool>,
-) {
-    let query = input_value().trim().to_string();
-    if query.is_empty() {
-        return;
+/// Refreshes what uploads may be from the backend; the defaults stand in
+/// while it can't be reached.
+async fn load_upload_limits(mut limits: Signal<UploadLimits>) {
+    if let Ok(resp) = api::get("/config").send().await {
+        if let Ok(data) = resp.json::<UploadLimits>().await {
+            limits.set(data);
+        }
     }
+}
 
-    // Add user message
-    messages.write().push(Message {
-        role: "user".to_string(),
-        content: query.clone(),
-        sources: vec![],
-    });
+/// Whether `filename` is an image, indexed by the description the
+/// backend's vision model wrote of it.
+fn is_image(filename: &str) -> bool {
+    let lower = filename.to_lowercase();
+    [".png", ".jpg", ".jpeg"]
+        .iter()
+        .any(|extension| lower.ends_with(extension))
+}
 
-    input_value.set(String::new());
-    is_loading.set(true);
+/// The files of a file input or drop, in order.
+fn file_list(files: &web_sys::FileList) -> Vec<web_sys::File> {
+    (0..files.length()).filter_map(|i| files.get(i)).collect()
+}
 
-    // Send to backend
-    match Request::post("http://localhost:3000/chat")
-        .json(&json!({
-            "query": query,
-            "deep_think": false
-        }))
-        .unwrap()
-        .send()
-        .await
-    {
-        Ok(resp) => {
-            if resp.ok() {
-                match resp.json::<serde_json::Value>().await {
-                    Ok(data) => {
-                        let answer = data["answer"].as_str().unwrap_or("No answer").to_string();
-                        let sources: Vec<String> = data["sources"]
-                            .as_array()
-                            .map(|arr| {
-                                arr.iter()
-                                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                                    .collect()
-                            })
-                            .unwrap_or_default();
+/// Posts the files to `/upload` and returns the id of the queued ingestion job.
+/// While the backend's upload queue is full (503), waits as long as its
+/// `Retry-After` asks and tries again, reporting the wait in `status`.
+async fn upload_files_formdata(
+    form_data: &FormData,
+    on_conflict: &str,
+    collection: &str,
+    mut status: Signal<String>,
+) -> Result<String, String> {
+    const MAX_BUSY_RETRIES: u32 = 5;
 
-                        messages.write().push(Message {
-                            role: "assistant".to_string(),
-                            content: answer,
-                            sources,
-                        });
-                    }
-                    Err(_) => {
-                        messages.write().push(Message {
-                            role: "error".to_string(),
-                            content: "Failed to parse response".to_string(),
-                            sources: vec![],
-                        });
-                    }
-                }
-            } else {
-                let error_text = resp
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| format!("Status: {}", resp.status()));
-                messages.write().push(Message {
-                    role: "error".to_string(),
-                    content: format!("Server error: {}", error_text),
-                    sources: vec![],
-                });
+    for _ in 0..MAX_BUSY_RETRIES {
+        match post_upload(form_data, on_conflict, collection).await? {
+            UploadReply::Accepted(job_id) => return Ok(job_id),
+            UploadReply::Busy(retry_after) => {
+                status.set(format!(
+                    "⏳ Server busy, retrying upload in {}s...",
+                    retry_after
+                ));
+                TimeoutFuture::new(retry_after * 1000).await;
+                status.set("📤 Uploading files...".to_string());
             }
         }
-        Err(_) => {
-            messages.write().push(Message {
-                role: "error".to_string(),
-                content: "Failed to connect to backend. Is it running on port 3000?".to_string(),
-                sources: vec![],
-            });
-        }
     }
 
-    is_loading.set(false);
+    Err("Server is busy, please try again later".to_string())
+}
+
+enum UploadReply {
+    Accepted(String),
+    /// Upload queue full; seconds to wait before retrying
+    Busy(u32),
 }
 
-async fn upload_files_formdata(form_data: FormData) -> Result<String, String> {
+async fn post_upload(
+    form_data: &FormData,
+    on_conflict: &str,
+    collection: &str,
+) -> Result<UploadReply, String> {
     let window = web_sys::window().ok_or("No window")?;
 
     // Create request
@@ -608,8 +5590,20 @@ async fn upload_files_formdata(form_data: FormData) -> Result<String, String> {
     opts.set_method("POST");
     let form_data_value: &JsValue = form_data.as_ref();
     opts.set_body(form_data_value);
+    if let Some(authorization) = api::authorization() {
+        let headers = web_sys::Headers::new().map_err(|_| "Failed to create request")?;
+        headers
+            .append("Authorization", &authorization)
+            .map_err(|_| "Failed to create request")?;
+        opts.set_headers(&headers);
+    }
 
-    let request = web_sys::Request::new_with_str_and_init("http://localhost:3000/upload", &opts)
+    let mut url = format!("{}/upload?on_conflict={}", api::backend_url(), on_conflict);
+    if !collection.is_empty() {
+        url.push_str("&collection=");
+        url.push_str(&String::from(js_sys::encode_uri_component(collection)));
+    }
+    let request = web_sys::Request::new_with_str_and_init(&url, &opts)
         .map_err(|_| "Failed to create request")?;
 
     // Send request
@@ -619,9 +5613,47 @@ async fn upload_files_formdata(form_data: FormData) -> Result<String, String> {
 
     let resp: web_sys::Response = resp_value.dyn_into().map_err(|_| "Invalid response")?;
 
+    if resp.status() == 503 {
+        let retry_after = resp
+            .headers()
+            .get("Retry-After")
+            .ok()
+            .flatten()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(10);
+        return Ok(UploadReply::Busy(retry_after));
+    }
+
     if resp.ok() {
-        Ok("Upload successful".to_string())
+        let text_promise = resp.text().map_err(|_| "Failed to read response")?;
+        let text = wasm_bindgen_futures::JsFuture::from(text_promise)
+            .await
+            .map_err(|_| "Failed to read response")?
+            .as_string()
+            .unwrap_or_default();
+
+        let body: serde_json::Value =
+            serde_json::from_str(&text).map_err(|_| "Invalid response")?;
+        body["job_id"]
+            .as_str()
+            .map(|id| UploadReply::Accepted(id.to_string()))
+            .ok_or_else(|| "Missing job id in response".to_string())
     } else {
-        Err(format!("Upload failed with status {}", resp.status()))
+        let body = match resp.text() {
+            Ok(promise) => wasm_bindgen_futures::JsFuture::from(promise)
+                .await
+                .ok()
+                .and_then(|text| text.as_string())
+                .unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+        Err(error_text(&body, resp.status()))
     }
 }
+
+/// Message for a failed response, from its error envelope when it has one.
+fn error_text(body: &str, status: u16) -> String {
+    serde_json::from_str::<ErrorResponse>(body)
+        .map(|envelope| envelope.error.describe())
+        .unwrap_or_else(|_| format!("Server error (status {})", status))
+}